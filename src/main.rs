@@ -1,72 +1,427 @@
+extern crate libc;
 extern crate regex;
 
 use regex::Regex;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::env;
+use std::ffi::{CStr, CString, OsStr, OsString};
 use std::fs::{self, File};
-use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Seek, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs as other_fs;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::prelude::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-fn pwd() -> Result<(), io::Error> {
-    // Get and print the current directory
-    let path = env::current_dir()?;
-    println!("{}", path.display());
-    Ok(())
+fn strerror(e: &io::Error) -> String {
+    // Print system errors like the C library does, without the "(os error N)" part
+    let message = e.to_string();
+    match message.find(" (os error") {
+        Some(index) => message[..index].to_string(),
+        None => message,
+    }
 }
 
-fn echo(args: Vec<String>) -> Result<(), io::Error> {
-    match args.len() {
-        2 => Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Not enough arguments.",
-        )),
-        3 => {
-            if args[2] == "-n" {
-                Err(io::Error::new(
+fn pwd(args: Vec<String>) -> Result<(), io::Error> {
+    let mut logical = true;
+    for arg in args.iter().skip(2) {
+        match arg.as_str() {
+            "-L" | "--logical" => logical = true,
+            "-P" | "--physical" => logical = false,
+            _ => {
+                return Err(io::Error::new(
                     io::ErrorKind::InvalidInput,
-                    "Can't call 'echo -n' on nothing.",
+                    "Can't use 'pwd' like this.",
                 ))
-            } else {
-                println!("{}", args[2]);
-                Ok(())
             }
         }
-        _ => {
-            let last = args.len() - 1;
-            if args[2] == "-n" {
-                /*
-                    If we have the '-n' option, iterate over the args and print them on
-                    the same line with a space between them and without a newline at the final
-                */
-                for (index, arg) in args.iter().enumerate().skip(3) {
-                    if index == last {
-                        // Print the last element without the space
-                        print!("{}", arg);
-                    } else {
-                        print!("{} ", arg);
+    }
+    // Get the current directory as it was reached (through symbolic links) or the physical one
+    let path = match logical_directory() {
+        Some(path) if logical => path,
+        _ => env::current_dir()?,
+    };
+    println!("{}", path.display());
+    Ok(())
+}
+
+fn logical_directory() -> Option<PathBuf> {
+    // $PWD can be used only if it is absolute, has no '.' or '..' and is really the current directory
+    let path = PathBuf::from(env::var_os("PWD")?);
+    if !path.is_absolute()
+        || path.components().any(|c| {
+            matches!(
+                c,
+                std::path::Component::CurDir | std::path::Component::ParentDir
+            )
+        })
+    {
+        return None;
+    }
+    let logical = fs::metadata(&path).ok()?;
+    let current = fs::metadata(".").ok()?;
+    if logical.dev() == current.dev() && logical.ino() == current.ino() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+// Which components must exist when resolving a path
+#[derive(Clone, Copy, PartialEq)]
+enum Canonicalize {
+    // All of them ('-e')
+    Existing,
+    // All of them except the last one (the default)
+    AllButLast,
+    // None of them ('-m')
+    Missing,
+}
+
+fn resolve_path(path: &Path, mode: Canonicalize, follow_links: bool) -> io::Result<PathBuf> {
+    // Start from the absolute path and resolve its components one by one
+    let absolute = std::path::absolute(path)?;
+    let mut pending: Vec<std::ffi::OsString> = absolute
+        .components()
+        .skip(1)
+        .map(|c| c.as_os_str().to_os_string())
+        .collect();
+    pending.reverse();
+    let mut result = PathBuf::from("/");
+    let mut links_followed = 0;
+
+    while let Some(component) = pending.pop() {
+        if component == "." || component.is_empty() {
+            continue;
+        }
+        if component == ".." {
+            result.pop();
+            continue;
+        }
+        let candidate = result.join(&component);
+        // With '-s', the path is only cleaned, without looking at the file system
+        if !follow_links {
+            result = candidate;
+            continue;
+        }
+        let is_last = pending.iter().all(|c| c == "." || c.is_empty());
+        match fs::symlink_metadata(&candidate) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                // Replace the link with its target and continue resolving from there
+                links_followed += 1;
+                if links_followed > 40 {
+                    return Err(io::Error::from_raw_os_error(libc::ELOOP));
+                }
+                let target = fs::read_link(&candidate)?;
+                if target.is_absolute() {
+                    result = PathBuf::from("/");
+                }
+                for c in target.components().rev() {
+                    if c != std::path::Component::RootDir {
+                        pending.push(c.as_os_str().to_os_string());
                     }
                 }
-                Ok(())
-            } else {
-                /*
-                    If we don't have the '-n' option, iterate over the args and print them on the
-                    same line with a space between them
-                */
-                for (index, arg) in args.iter().enumerate().skip(2) {
-                    if index == last {
-                        // Print the last element without the space
-                        print!("{}", arg);
-                    } else {
-                        print!("{} ", arg);
+            }
+            Ok(metadata) => {
+                if !metadata.is_dir() && !is_last && mode != Canonicalize::Missing {
+                    return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+                }
+                result = candidate;
+            }
+            Err(e) => {
+                let allowed = match mode {
+                    Canonicalize::Existing => false,
+                    Canonicalize::AllButLast => is_last && e.kind() == io::ErrorKind::NotFound,
+                    Canonicalize::Missing => true,
+                };
+                if !allowed {
+                    return Err(e);
+                }
+                result = candidate;
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn realpath(args: Vec<String>) -> Result<(), io::Error> {
+    let mut mode = Canonicalize::AllButLast;
+    let mut follow_links = true;
+    let mut quiet = false;
+    let mut terminator = '\n';
+    let mut relative_to: Option<String> = None;
+    let mut relative_base: Option<String> = None;
+    let mut operands: Vec<&String> = Vec::new();
+    for arg in args.iter().skip(2) {
+        match arg.as_str() {
+            "--canonicalize-existing" => mode = Canonicalize::Existing,
+            "--canonicalize-missing" => mode = Canonicalize::Missing,
+            "--no-symlinks" | "--strip" => follow_links = false,
+            "--quiet" => quiet = true,
+            "--zero" => terminator = '\0',
+            _ if arg.starts_with("--relative-to=") => {
+                relative_to = Some(arg["--relative-to=".len()..].to_string())
+            }
+            _ if arg.starts_with("--relative-base=") => {
+                relative_base = Some(arg["--relative-base=".len()..].to_string())
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                // Short options can be grouped, like '-ms'
+                for option in arg.chars().skip(1) {
+                    match option {
+                        'e' => mode = Canonicalize::Existing,
+                        'm' => mode = Canonicalize::Missing,
+                        's' => follow_links = false,
+                        'q' => quiet = true,
+                        'z' => terminator = '\0',
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "Can't use 'realpath' with this option.",
+                            ))
+                        }
                     }
                 }
-                // Print a newline at the end
-                println!();
-                Ok(())
+            }
+            _ => operands.push(arg),
+        }
+    }
+    if operands.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use 'realpath' on nothing.",
+        ));
+    }
+
+    // The directories for relative output are resolved like the operands
+    let resolve_directory = |dir: Option<String>| -> io::Result<Option<PathBuf>> {
+        match dir {
+            Some(dir) => match resolve_path(Path::new(&dir), mode, follow_links) {
+                Ok(path) => Ok(Some(path)),
+                Err(e) => {
+                    eprintln!("realpath: {}: {}", dir, strerror(&e));
+                    Err(e)
+                }
+            },
+            None => Ok(None),
+        }
+    };
+    let relative_to = resolve_directory(relative_to)?;
+    let relative_base = resolve_directory(relative_base)?;
+
+    let mut failed = false;
+    for arg in operands {
+        let path = match resolve_path(Path::new(arg), mode, follow_links) {
+            Ok(path) => path,
+            Err(e) => {
+                if !quiet {
+                    eprintln!("realpath: {}: {}", arg, strerror(&e));
+                }
+                failed = true;
+                continue;
+            }
+        };
+        // Paths are printed relative only if they (and the start directory) are under the base
+        let start = match (&relative_to, &relative_base) {
+            (Some(to), Some(base)) if path.starts_with(base) && to.starts_with(base) => Some(to),
+            (Some(_), Some(_)) => None,
+            (Some(to), None) => Some(to),
+            (None, Some(base)) if path.starts_with(base) => Some(base),
+            _ => None,
+        };
+        match start {
+            Some(start) => print!("{}{}", relative_path(&path, start).display(), terminator),
+            None => print!("{}{}", path.display(), terminator),
+        }
+    }
+    if failed {
+        return Err(io::Error::other("Some paths couldn't be resolved."));
+    }
+    Ok(())
+}
+
+fn readlink(args: Vec<String>) -> Result<(), io::Error> {
+    let mut mode: Option<Canonicalize> = None;
+    let mut newline = true;
+    let mut quiet = true;
+    let mut terminator = '\n';
+    let mut operands: Vec<&String> = Vec::new();
+    for arg in args.iter().skip(2) {
+        match arg.as_str() {
+            "--canonicalize" => mode = Some(Canonicalize::AllButLast),
+            "--canonicalize-existing" => mode = Some(Canonicalize::Existing),
+            "--canonicalize-missing" => mode = Some(Canonicalize::Missing),
+            "--no-newline" => newline = false,
+            "--verbose" => quiet = false,
+            "--quiet" | "--silent" => quiet = true,
+            "--zero" => terminator = '\0',
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                // Short options can be grouped, like '-fn'
+                for option in arg.chars().skip(1) {
+                    match option {
+                        'f' => mode = Some(Canonicalize::AllButLast),
+                        'e' => mode = Some(Canonicalize::Existing),
+                        'm' => mode = Some(Canonicalize::Missing),
+                        'n' => newline = false,
+                        'v' => quiet = false,
+                        'q' | 's' => quiet = true,
+                        'z' => terminator = '\0',
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "Can't use 'readlink' with this option.",
+                            ))
+                        }
+                    }
+                }
+            }
+            _ => operands.push(arg),
+        }
+    }
+    if operands.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use 'readlink' on nothing.",
+        ));
+    }
+    // '-n' is ignored when printing several names, so they stay separated
+    if operands.len() > 1 {
+        newline = true;
+    }
+
+    let mut failed = false;
+    for arg in operands {
+        // Without options, print the target of the symbolic link, else the resolved path
+        let result = match mode {
+            Some(mode) => resolve_path(Path::new(arg), mode, true),
+            None => fs::read_link(arg),
+        };
+        match result {
+            Ok(path) => {
+                print!("{}", path.display());
+                if newline {
+                    print!("{}", terminator);
+                }
+            }
+            Err(e) => {
+                if !quiet {
+                    eprintln!("readlink: {}: {}", arg, strerror(&e));
+                }
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        return Err(io::Error::other("Some paths couldn't be read."));
+    }
+    Ok(())
+}
+
+fn echo(args: Vec<String>) -> Result<(), io::Error> {
+    // In XSI mode (with POSIXLY_CORRECT) escapes are always interpreted and only '-n' is an option
+    let xsi = env::var_os("POSIXLY_CORRECT").is_some();
+    let mut newline = true;
+    let mut escapes = xsi;
+    let mut first = 2;
+    /*
+        Options can be clustered (like '-neE'), but an argument with any other
+        character (like '-x' or '-') is printed as it is
+    */
+    for arg in args.iter().skip(2) {
+        let is_option = arg.len() > 1
+            && arg.starts_with('-')
+            && arg.chars().skip(1).all(|c| matches!(c, 'n' | 'e' | 'E'));
+        if !is_option || (xsi && arg != "-n") {
+            break;
+        }
+        for option in arg.chars().skip(1) {
+            match option {
+                'n' => newline = false,
+                'e' => escapes = true,
+                _ => escapes = xsi,
+            }
+        }
+        first += 1;
+    }
+
+    // Join the arguments with a space between them
+    let mut output: Vec<u8> = Vec::new();
+    for (index, arg) in args.iter().enumerate().skip(first) {
+        if index > first {
+            output.push(b' ');
+        }
+        if escapes {
+            // '\c' stops all the output, including the newline
+            if !push_escaped(&mut output, arg.as_bytes()) {
+                return io::stdout().write_all(&output);
+            }
+        } else {
+            output.extend_from_slice(arg.as_bytes());
+        }
+    }
+    // Print a newline at the end (no operands print only the newline)
+    if newline {
+        output.push(b'\n');
+    }
+    io::stdout().write_all(&output)
+}
+
+fn push_escaped(output: &mut Vec<u8>, text: &[u8]) -> bool {
+    // Interpret backslash escapes, returns false if '\c' was found
+    let mut index = 0;
+    while index < text.len() {
+        if text[index] != b'\\' || index + 1 == text.len() {
+            output.push(text[index]);
+            index += 1;
+            continue;
+        }
+        index += 1;
+        let escape = text[index];
+        index += 1;
+        match escape {
+            b'\\' => output.push(b'\\'),
+            b'a' => output.push(0x07),
+            b'b' => output.push(0x08),
+            b'c' => return false,
+            b'e' => output.push(0x1b),
+            b'f' => output.push(0x0c),
+            b'n' => output.push(b'\n'),
+            b'r' => output.push(b'\r'),
+            b't' => output.push(b'\t'),
+            b'v' => output.push(0x0b),
+            b'0' => {
+                // '\0NNN' is a byte with up to three octal digits
+                let mut value: u32 = 0;
+                let mut digits = 0;
+                while digits < 3 && index < text.len() && (b'0'..=b'7').contains(&text[index]) {
+                    value = value * 8 + (text[index] - b'0') as u32;
+                    index += 1;
+                    digits += 1;
+                }
+                output.push(value as u8);
+            }
+            b'x' if index < text.len() && text[index].is_ascii_hexdigit() => {
+                // '\xHH' is a byte with one or two hexadecimal digits
+                let mut value: u32 = 0;
+                let mut digits = 0;
+                while digits < 2 && index < text.len() && text[index].is_ascii_hexdigit() {
+                    value = value * 16 + (text[index] as char).to_digit(16).unwrap_or(0);
+                    index += 1;
+                    digits += 1;
+                }
+                output.push(value as u8);
+            }
+            _ => {
+                // Unknown escapes are printed as they are
+                output.push(b'\\');
+                output.push(escape);
             }
         }
     }
+    true
 }
 
 fn cat(args: Vec<String>) -> Result<(), io::Error> {
@@ -86,184 +441,12497 @@ fn cat(args: Vec<String>) -> Result<(), io::Error> {
     }
 }
 
-fn mkdir(args: Vec<String>) -> Result<(), io::Error> {
-    if args.len() == 2 {
-        Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Can't use 'mkdir' on nothing.",
-        ))
-    } else {
-        // Iterate over the arguments
-        for arg in args.iter().skip(2) {
-            // Create each directory
-            fs::DirBuilder::new().create(arg)?;
+fn parse_size(text: &str) -> Option<u64> {
+    // Numbers can have a size suffix, like 10K (1024) or 10KB (1000)
+    let end = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let number: u64 = text[..end].parse().ok()?;
+    let multiplier: u64 = match &text[end..] {
+        "" => 1,
+        "b" => 512,
+        "kB" | "KB" => 1000,
+        "K" | "k" | "KiB" => 1024,
+        "MB" => 1000u64.pow(2),
+        "M" | "MiB" => 1024u64.pow(2),
+        "GB" => 1000u64.pow(3),
+        "G" | "GiB" => 1024u64.pow(3),
+        "TB" => 1000u64.pow(4),
+        "T" | "TiB" => 1024u64.pow(4),
+        "PB" => 1000u64.pow(5),
+        "P" | "PiB" => 1024u64.pow(5),
+        "EB" => 1000u64.pow(6),
+        "E" | "EiB" => 1024u64.pow(6),
+        _ => return None,
+    };
+    number.checked_mul(multiplier)
+}
+
+fn parse_count(text: &str, command: &str, unit: &str) -> io::Result<(char, u64)> {
+    // Get the sign ('+', '-' or ' ' if it is missing) and the value of a count
+    let (sign, number) = match text.chars().next() {
+        Some(sign @ ('+' | '-')) => (sign, &text[1..]),
+        _ => (' ', text),
+    };
+    match parse_size(number) {
+        Some(count) => Ok((sign, count)),
+        None => {
+            eprintln!("{}: invalid number of {}: '{}'", command, unit, text);
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid number.",
+            ))
         }
-        Ok(())
     }
 }
 
-fn mv(args: Vec<String>) -> Result<(), io::Error> {
-    if args.len() == 2 {
-        Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Can't use 'mv' on nothing.",
-        ))
+fn open_input(name: &str) -> io::Result<Box<dyn io::Read>> {
+    // '-' is the standard input
+    if name == "-" {
+        Ok(Box::new(io::stdin()))
     } else {
-        // Get the last argument (destination)
-        let dest = args.len() - 1;
-        // Iterate over all arguments and move them to the destination
-        for arg in args.iter().skip(2) {
-            fs::rename(arg, &args[dest])?;
-        }
-        Ok(())
+        Ok(Box::new(File::open(name)?))
     }
 }
 
-fn ln(args: Vec<String>) -> Result<(), io::Error> {
-    if args.len() == 2 {
-        Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Can't use 'ln' on nothing.",
-        ))
+fn print_header(out: &mut impl Write, name: &str, first: bool) -> io::Result<()> {
+    // Print the file name before its contents, like '==> name <=='
+    let name = if name == "-" { "standard input" } else { name };
+    if first {
+        writeln!(out, "==> {} <==", name)
     } else {
-        if args[2] == "-s" || args[2] == "--symbolic" {
-            // Make a symbolic link if option '-s' or '--symbolic' is provided
-            other_fs::symlink(&args[3], &args[4])?;
-        } else {
-            if args[2].starts_with("-") {
-                // If any other option is provided, return error
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "Can't use this option on 'ln'.",
-                ));
-            } else {
-                // If no option is provided, make a hard link
-                fs::hard_link(&args[2], &args[3])?;
+        writeln!(out, "\n==> {} <==", name)
+    }
+}
+
+fn head(args: Vec<String>) -> Result<(), io::Error> {
+    let mut count = (' ', 10);
+    let mut bytes = false;
+    let mut headers: Option<bool> = None;
+    let mut operands: Vec<String> = Vec::new();
+    // Separate the options from the files
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        let value = |iter: &mut std::iter::Skip<std::slice::Iter<String>>| match iter.next() {
+            Some(value) => Ok(value.clone()),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Option requires a number.",
+            )),
+        };
+        match arg.as_str() {
+            "-n" | "--lines" => {
+                count = parse_count(&value(&mut iter)?, "head", "lines")?;
+                bytes = false;
+            }
+            "-c" | "--bytes" => {
+                count = parse_count(&value(&mut iter)?, "head", "bytes")?;
+                bytes = true;
+            }
+            "-q" | "--quiet" | "--silent" => headers = Some(false),
+            "-v" | "--verbose" => headers = Some(true),
+            _ if arg.starts_with("--lines=") => {
+                count = parse_count(&arg["--lines=".len()..], "head", "lines")?;
+                bytes = false;
+            }
+            _ if arg.starts_with("--bytes=") => {
+                count = parse_count(&arg["--bytes=".len()..], "head", "bytes")?;
+                bytes = true;
+            }
+            _ if arg.starts_with("-n") => {
+                count = parse_count(&arg[2..], "head", "lines")?;
+                bytes = false;
+            }
+            _ if arg.starts_with("-c") => {
+                count = parse_count(&arg[2..], "head", "bytes")?;
+                bytes = true;
+            }
+            _ => match arg.strip_prefix('-') {
+                // The old form '-5' means '-n 5'
+                Some(digits)
+                    if !digits.is_empty() && digits.bytes().all(|c| c.is_ascii_digit()) =>
+                {
+                    count = (' ', parse_count(digits, "head", "lines")?.1);
+                    bytes = false;
+                }
+                Some(rest) if !rest.is_empty() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Can't use 'head' with this option.",
+                    ))
+                }
+                _ => operands.push(arg.clone()),
+            },
+        }
+    }
+    // Without files, read the standard input
+    if operands.is_empty() {
+        operands.push(String::from("-"));
+    }
+    let headers = headers.unwrap_or(operands.len() > 1);
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let mut failed = false;
+    let mut first = true;
+    for name in &operands {
+        let input = match open_input(name) {
+            Ok(input) => input,
+            Err(e) => {
+                eprintln!("head: cannot open '{}' for reading: {}", name, strerror(&e));
+                failed = true;
+                continue;
             }
+        };
+        if headers {
+            print_header(&mut out, name, first)?;
         }
-        Ok(())
+        first = false;
+        let mut reader = BufReader::new(input);
+        let result = match (bytes, count.0 == '-') {
+            (false, false) => head_lines(&mut reader, &mut out, count.1),
+            (false, true) => head_lines_except(&mut reader, &mut out, count.1),
+            (true, false) => io::copy(&mut reader.take(count.1), &mut out).map(|_| ()),
+            (true, true) => head_bytes_except(&mut reader, &mut out, count.1),
+        };
+        if let Err(e) = result {
+            eprintln!("head: error reading '{}': {}", name, strerror(&e));
+            failed = true;
+        }
+    }
+    out.flush()?;
+    if failed {
+        return Err(io::Error::other("Some files couldn't be read."));
     }
+    Ok(())
 }
 
-fn rmdir(args: Vec<String>) -> Result<(), io::Error> {
-    if args.len() == 2 {
-        Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Can't use 'rmdir' on nothing.",
-        ))
-    } else {
-        // Iterate over the arguments and remove them
-        for arg in args.iter().skip(2) {
-            if Path::new(arg).is_dir() {
-                fs::remove_dir(arg)?;
-            } else {
-                // Handle case when rmdir is used on files
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "Not a directory.",
-                ));
+fn head_lines(reader: &mut impl BufRead, out: &mut impl Write, count: u64) -> io::Result<()> {
+    // Print the first lines
+    let mut line = Vec::new();
+    for _ in 0..count {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        out.write_all(&line)?;
+    }
+    Ok(())
+}
+
+fn head_lines_except(
+    reader: &mut impl BufRead,
+    out: &mut impl Write,
+    count: u64,
+) -> io::Result<()> {
+    // Print all lines except the last ones, keeping only those in memory
+    let mut kept: std::collections::VecDeque<Vec<u8>> = std::collections::VecDeque::new();
+    loop {
+        let mut line = Vec::new();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        kept.push_back(line);
+        if kept.len() as u64 > count {
+            if let Some(line) = kept.pop_front() {
+                out.write_all(&line)?;
             }
         }
-        Ok(())
     }
+    Ok(())
 }
 
-fn rm(args: Vec<String>) -> Result<(), io::Error> {
-    if args.len() == 2 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Can't use 'rm' on nothing.",
-        ));
-    } else if args[2] == "-d" || args[2] == "--dir" {
-        if args.len() == 3 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Can't use 'rm -d' like this.",
-            ));
-        } else {
-            if args[3] == "-r" || args[3] == "-R" || args[3] == "--recursive" {
-                // Iterate over the arguments
-                for arg in args.iter().skip(4) {
-                    let path = Path::new(arg);
-                    if path.is_file() {
-                        // Remove the file
-                        fs::remove_file(path)?;
-                    } else {
-                        // Remove directory recursively
-                        fs::remove_dir_all(path)?;
-                    }
+fn head_bytes_except(
+    reader: &mut impl io::Read,
+    out: &mut impl Write,
+    count: u64,
+) -> io::Result<()> {
+    // Print all bytes except the last ones, keeping only those in memory
+    let mut kept: Vec<u8> = Vec::new();
+    let mut buffer = [0; 8192];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        kept.extend_from_slice(&buffer[..read]);
+        if kept.len() as u64 > count {
+            let extra = kept.len() - count as usize;
+            out.write_all(&kept[..extra])?;
+            kept.drain(..extra);
+        }
+    }
+    Ok(())
+}
+
+// A file followed by 'tail -f'
+struct Followed {
+    name: String,
+    file: Option<File>,
+    position: u64,
+    // The device and inode, to notice when the name points to another file
+    id: (u64, u64),
+}
+
+struct TailOptions {
+    count: (char, u64),
+    bytes: bool,
+    follow: bool,
+    // Follow the name instead of the descriptor ('-F'), and reopen rotated files
+    by_name: bool,
+    retry: bool,
+    pid: Option<i32>,
+    sleep: f64,
+}
+
+fn tail(args: Vec<String>) -> Result<(), io::Error> {
+    let mut options = TailOptions {
+        count: ('-', 10),
+        bytes: false,
+        follow: false,
+        by_name: false,
+        retry: false,
+        pid: None,
+        sleep: 1.0,
+    };
+    let mut headers: Option<bool> = None;
+    let mut operands: Vec<String> = Vec::new();
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message.to_string());
+    // The sleep interval is a number of seconds, like '0.5'
+    let sleep_interval = |text: &str| match text.parse::<f64>() {
+        Ok(seconds) if seconds.is_finite() && seconds >= 0.0 => Ok(seconds),
+        _ => {
+            eprintln!("tail: invalid number of seconds: '{}'", text);
+            Err(invalid("Invalid sleep interval."))
+        }
+    };
+    // Separate the options from the files
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        let mut value = || match iter.next() {
+            Some(value) => Ok(value.clone()),
+            None => {
+                eprintln!("tail: option requires an argument '{}'", arg);
+                Err(invalid("Option requires a value."))
+            }
+        };
+        match arg.as_str() {
+            "-n" | "--lines" => {
+                options.count = parse_count(&value()?, "tail", "lines")?;
+                options.bytes = false;
+            }
+            "-c" | "--bytes" => {
+                options.count = parse_count(&value()?, "tail", "bytes")?;
+                options.bytes = true;
+            }
+            "-s" | "--sleep-interval" => options.sleep = sleep_interval(&value()?)?,
+            "-q" | "--quiet" | "--silent" => headers = Some(false),
+            "-v" | "--verbose" => headers = Some(true),
+            "-f" | "--follow" | "--follow=descriptor" => options.follow = true,
+            "--follow=name" => {
+                options.follow = true;
+                options.by_name = true;
+            }
+            "-F" => {
+                options.follow = true;
+                options.by_name = true;
+                options.retry = true;
+            }
+            "--retry" => options.retry = true,
+            _ if arg.starts_with("--pid=") => {
+                options.pid = Some(arg[6..].parse().map_err(|_| invalid("Invalid PID."))?)
+            }
+            _ if arg.starts_with("--sleep-interval=") => {
+                options.sleep = sleep_interval(&arg["--sleep-interval=".len()..])?
+            }
+            _ if arg.starts_with("--lines=") => {
+                options.count = parse_count(&arg["--lines=".len()..], "tail", "lines")?;
+                options.bytes = false;
+            }
+            _ if arg.starts_with("--bytes=") => {
+                options.count = parse_count(&arg["--bytes=".len()..], "tail", "bytes")?;
+                options.bytes = true;
+            }
+            _ if arg.starts_with("-n") => {
+                options.count = parse_count(&arg[2..], "tail", "lines")?;
+                options.bytes = false;
+            }
+            _ if arg.starts_with("-c") => {
+                options.count = parse_count(&arg[2..], "tail", "bytes")?;
+                options.bytes = true;
+            }
+            _ if arg.starts_with("-s") => options.sleep = sleep_interval(&arg[2..])?,
+            _ => match arg.strip_prefix('-') {
+                // The old form '-5' means '-n 5'
+                Some(digits)
+                    if !digits.is_empty() && digits.bytes().all(|c| c.is_ascii_digit()) =>
+                {
+                    options.count = ('-', parse_count(digits, "tail", "lines")?.1);
+                    options.bytes = false;
                 }
-            } else {
-                // Iterate over the arguments
-                for arg in args.iter().skip(3) {
-                    let path = Path::new(arg);
-                    if path.is_file() {
-                        // Remove the file
-                        fs::remove_file(path)?;
-                    } else {
-                        // Remove directory without content
-                        fs::remove_dir(path)?;
-                    }
+                Some(rest) if !rest.is_empty() => {
+                    eprintln!("tail: invalid option '{}'", arg);
+                    return Err(invalid("Can't use 'tail' with this option."));
                 }
+                _ => operands.push(arg.clone()),
+            },
+        }
+    }
+    // Without files, read the standard input
+    if operands.is_empty() {
+        operands.push(String::from("-"));
+    }
+    let headers = headers.unwrap_or(operands.len() > 1);
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let mut failed = false;
+    let mut followed: Vec<Followed> = Vec::new();
+    let mut first = true;
+    for name in &operands {
+        if name == "-" {
+            // The standard input is printed but never followed
+            if headers {
+                print_header(&mut out, name, first)?;
             }
+            first = false;
+            let mut reader = BufReader::new(io::stdin());
+            tail_stream(&mut reader, &mut out, &options)?;
+            continue;
         }
-    } else if args[2] == "-r" || args[2] == "-R" || args[2] == "--recursive" {
-        if args.len() == 3 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Can't use 'rm -r' like this.",
-            ));
-        } else {
-            if args[3] == "-d" || args[3] == "--dir" {
-                // Iterate over the arguments
-                for arg in args.iter().skip(4) {
-                    let path = Path::new(arg);
-                    if path.is_file() {
-                        // Remove the file
-                        fs::remove_file(path)?;
-                    } else {
-                        // Remove directory recursively
-                        fs::remove_dir_all(path)?;
-                    }
-                }
-            } else {
-                // Iterate over the arguments
-                for arg in args.iter().skip(3) {
-                    let path = Path::new(arg);
-                    if path.is_file() {
-                        // Remove the file
-                        fs::remove_file(path)?;
-                    } else if path.is_dir() {
-                        // Remove directory recursively
-                        fs::remove_dir_all(path)?;
-                    }
+        let mut file = match File::open(name) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("tail: cannot open '{}' for reading: {}", name, strerror(&e));
+                failed = true;
+                // With '--retry', wait for the file to appear
+                if options.follow && options.retry {
+                    followed.push(Followed {
+                        name: name.clone(),
+                        file: None,
+                        position: 0,
+                        id: (0, 0),
+                    });
                 }
+                continue;
             }
+        };
+        if headers {
+            print_header(&mut out, name, first)?;
         }
-    } else {
-        // Iterate over the arguments
-        let mut is_directory = false;
-        for arg in args.iter().skip(2) {
-            let path = Path::new(arg);
-            // Check if each argument is a file
-            if path.is_file() {
-                // Remove only files (in this case, when we don't have options)
-                fs::remove_file(arg)?;
-            } else {
-                // If not, you can't remove directories without an option
-                is_directory = true;
-            }
+        first = false;
+        let metadata = file.metadata()?;
+        if metadata.is_file() {
+            tail_file(&mut file, &mut out, &options)?;
+        } else {
+            let mut reader = BufReader::new(&file);
+            tail_stream(&mut reader, &mut out, &options)?;
         }
-        // Check if we encountered directories and return error
-        if is_directory {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Can't remove directory without options.",
-            ));
+        if options.follow {
+            let position = file.stream_position()?;
+            followed.push(Followed {
+                name: name.clone(),
+                file: Some(file),
+                position,
+                id: (metadata.dev(), metadata.ino()),
+            });
         }
     }
-    Ok(())
-}
+    out.flush()?;
+    drop(out);
+
+    if options.follow && !followed.is_empty() {
+        follow(&mut followed, &options, headers)?;
+    }
+    if failed {
+        return Err(io::Error::other("Some files couldn't be read."));
+    }
+    Ok(())
+}
+
+fn tail_file(file: &mut File, out: &mut impl Write, options: &TailOptions) -> io::Result<()> {
+    // Regular files are read from the end, so big files are not read entirely
+    let length = file.metadata()?.len();
+    let (sign, count) = options.count;
+    let start = match (options.bytes, sign == '+') {
+        // '+N' starts from the byte or line N
+        (true, true) => count.saturating_sub(1).min(length),
+        (true, false) => length.saturating_sub(count),
+        (false, true) => {
+            let mut reader = BufReader::new(&mut *file);
+            let mut line = Vec::new();
+            for _ in 1..count {
+                line.clear();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    break;
+                }
+            }
+            reader.stream_position()?
+        }
+        (false, false) => {
+            // Search backwards for the newlines, ignoring the one at the end of the file
+            let mut position = length;
+            let mut newlines = 0;
+            let mut buffer = vec![0; 8192];
+            let mut start = 0;
+            'search: while position > 0 && count > 0 {
+                let size = position.min(buffer.len() as u64) as usize;
+                position -= size as u64;
+                file.seek(io::SeekFrom::Start(position))?;
+                file.read_exact(&mut buffer[..size])?;
+                for index in (0..size).rev() {
+                    let offset = position + index as u64;
+                    if buffer[index] == b'\n' && offset != length - 1 {
+                        newlines += 1;
+                        if newlines == count {
+                            start = offset + 1;
+                            break 'search;
+                        }
+                    }
+                }
+            }
+            if count == 0 {
+                length
+            } else {
+                start
+            }
+        }
+    };
+    file.seek(io::SeekFrom::Start(start))?;
+    io::copy(file, out)?;
+    Ok(())
+}
+
+fn tail_stream(
+    reader: &mut impl BufRead,
+    out: &mut impl Write,
+    options: &TailOptions,
+) -> io::Result<()> {
+    // Pipes can't be read from the end, so keep only the last lines or bytes in memory
+    let (sign, count) = options.count;
+    if sign == '+' {
+        // Skip the first lines or bytes and print the rest
+        if options.bytes {
+            io::copy(&mut reader.take(count.saturating_sub(1)), &mut io::sink())?;
+        } else {
+            let mut line = Vec::new();
+            for _ in 1..count {
+                line.clear();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    break;
+                }
+            }
+        }
+        io::copy(reader, out)?;
+        return Ok(());
+    }
+    if options.bytes {
+        let mut kept: std::collections::VecDeque<u8> = std::collections::VecDeque::new();
+        let mut buffer = [0; 8192];
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            kept.extend(&buffer[..read]);
+            let excess = (kept.len() as u64).saturating_sub(count) as usize;
+            kept.drain(..excess);
+        }
+        let (front, back) = kept.as_slices();
+        out.write_all(front)?;
+        out.write_all(back)?;
+    } else {
+        let mut kept: std::collections::VecDeque<Vec<u8>> = std::collections::VecDeque::new();
+        loop {
+            let mut line = Vec::new();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            kept.push_back(line);
+            if kept.len() as u64 > count {
+                kept.pop_front();
+            }
+        }
+        for line in kept {
+            out.write_all(&line)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_followed(
+    entry: &mut Followed,
+    index: usize,
+    headers: bool,
+    last_printed: &mut Option<usize>,
+) -> io::Result<()> {
+    // Print what was added to a followed file since the last read
+    let file = match entry.file.as_mut() {
+        Some(file) => file,
+        None => return Ok(()),
+    };
+    let length = match file.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return Ok(()),
+    };
+    if length < entry.position {
+        // The file was truncated, so start again from its beginning
+        eprintln!("tail: {}: file truncated", entry.name);
+        entry.position = 0;
+    }
+    if length > entry.position {
+        let mut out = io::stdout().lock();
+        // Print the name when the output switches to another file
+        if headers && *last_printed != Some(index) {
+            print_header(&mut out, &entry.name, false)?;
+        }
+        *last_printed = Some(index);
+        file.seek(io::SeekFrom::Start(entry.position))?;
+        entry.position += io::copy(&mut file.take(length - entry.position), &mut out)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+fn process_alive(pid: i32) -> bool {
+    // Signal 0 only checks if the process exists
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn follow(followed: &mut [Followed], options: &TailOptions, headers: bool) -> io::Result<()> {
+    // Use inotify to wake up when the files change, and polling if it is not available
+    let inotify = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    let watch = |name: &str| {
+        if inotify == -1 {
+            return;
+        }
+        let mask = libc::IN_MODIFY
+            | libc::IN_ATTRIB
+            | libc::IN_DELETE_SELF
+            | libc::IN_MOVE_SELF
+            | libc::IN_CLOSE_WRITE;
+        if let Ok(path) = path_to_cstring(Path::new(name)) {
+            unsafe { libc::inotify_add_watch(inotify, path.as_ptr(), mask) };
+        }
+        // Watch the directory too, to notice when the file is created again
+        let parent = match Path::new(name).parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        if let Ok(path) = path_to_cstring(parent) {
+            let mask = libc::IN_CREATE | libc::IN_MOVED_TO;
+            unsafe { libc::inotify_add_watch(inotify, path.as_ptr(), mask) };
+        }
+    };
+    for file in followed.iter() {
+        watch(&file.name);
+    }
+
+    let mut last_printed: Option<usize> = None;
+    loop {
+        // Stop following when the process given with '--pid' ends (after a last read)
+        let finished = options.pid.is_some_and(|pid| !process_alive(pid));
+
+        for (index, entry) in followed.iter_mut().enumerate() {
+            if options.by_name {
+                // Check if the name points to another file (rotation) or disappeared
+                match fs::metadata(&entry.name) {
+                    Ok(metadata) if (metadata.dev(), metadata.ino()) != entry.id => {
+                        if let Ok(file) = File::open(&entry.name) {
+                            // Print what was still written to the old file before switching
+                            read_followed(entry, index, headers, &mut last_printed)?;
+                            if entry.file.is_some() {
+                                eprintln!(
+                                    "tail: '{}' has been replaced;  following new file",
+                                    entry.name
+                                );
+                            } else {
+                                eprintln!(
+                                    "tail: '{}' has appeared;  following new file",
+                                    entry.name
+                                );
+                            }
+                            entry.file = Some(file);
+                            entry.position = 0;
+                            entry.id = (metadata.dev(), metadata.ino());
+                            watch(&entry.name);
+                        }
+                    }
+                    Err(e) if entry.file.is_some() => {
+                        read_followed(entry, index, headers, &mut last_printed)?;
+                        eprintln!(
+                            "tail: '{}' has become inaccessible: {}",
+                            entry.name,
+                            strerror(&e)
+                        );
+                        entry.file = None;
+                        entry.id = (0, 0);
+                    }
+                    _ => (),
+                }
+            }
+            read_followed(entry, index, headers, &mut last_printed)?;
+        }
+        if finished {
+            break;
+        }
+
+        // Wait for a change (or the sleep interval, to check the process and the names)
+        let timeout = (options.sleep * 1000.0) as i32;
+        if inotify == -1 {
+            std::thread::sleep(std::time::Duration::from_millis(timeout.max(0) as u64));
+        } else {
+            let mut poll = libc::pollfd {
+                fd: inotify,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            unsafe { libc::poll(&mut poll, 1, timeout.max(0)) };
+            // The events only wake us up, so just drain them
+            let mut buffer = [0u8; 4096];
+            while unsafe {
+                libc::read(
+                    inotify,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            } > 0
+            {}
+        }
+    }
+    if inotify != -1 {
+        unsafe { libc::close(inotify) };
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Counts {
+    lines: u64,
+    words: u64,
+    chars: u64,
+    bytes: u64,
+    max_length: u64,
+}
+
+fn count_input(input: &mut impl io::Read) -> io::Result<Counts> {
+    // Read fixed chunks, so big files and pipes don't need to fit in memory
+    let mut counts = Counts::default();
+    let mut buffer = [0; 65536];
+    let mut in_word = false;
+    let mut position: u64 = 0;
+    loop {
+        let read = match input.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        counts.bytes += read as u64;
+        for &byte in &buffer[..read] {
+            // UTF-8 continuation bytes belong to the previous character
+            if byte & 0xC0 != 0x80 {
+                counts.chars += 1;
+            }
+            match byte {
+                b'\n' | b'\r' | b'\x0c' => {
+                    if byte == b'\n' {
+                        counts.lines += 1;
+                    }
+                    counts.max_length = counts.max_length.max(position);
+                    position = 0;
+                }
+                b'\t' => position = (position + 8) & !7,
+                _ if byte.is_ascii_control() || byte & 0xC0 == 0x80 => (),
+                _ => position += 1,
+            }
+            // Words are separated by white space
+            if matches!(byte, b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r') {
+                in_word = false;
+            } else if !in_word {
+                in_word = true;
+                counts.words += 1;
+            }
+        }
+    }
+    counts.max_length = counts.max_length.max(position);
+    Ok(counts)
+}
+
+fn wc(args: Vec<String>) -> Result<(), io::Error> {
+    let (mut lines, mut words, mut chars, mut bytes, mut max_length) =
+        (false, false, false, false, false);
+    let mut operands: Vec<String> = Vec::new();
+    for arg in args.iter().skip(2) {
+        match arg.as_str() {
+            "--lines" => lines = true,
+            "--words" => words = true,
+            "--chars" => chars = true,
+            "--bytes" => bytes = true,
+            "--max-line-length" => max_length = true,
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                // Short options can be grouped, like '-lw'
+                for c in arg.chars().skip(1) {
+                    match c {
+                        'l' => lines = true,
+                        'w' => words = true,
+                        'm' => chars = true,
+                        'c' => bytes = true,
+                        'L' => max_length = true,
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "Can't use 'wc' with this option.",
+                            ))
+                        }
+                    }
+                }
+            }
+            _ => operands.push(arg.clone()),
+        }
+    }
+    // Without options, print the lines, words and bytes
+    if !(lines || words || chars || bytes || max_length) {
+        lines = true;
+        words = true;
+        bytes = true;
+    }
+    let selected = [lines, words, chars, bytes, max_length]
+        .iter()
+        .filter(|&&x| x)
+        .count();
+    let use_stdin = operands.is_empty();
+    if use_stdin {
+        operands.push(String::from("-"));
+    }
+
+    // Like coreutils, the columns are as wide as the total size of the files,
+    // and at least 7 when some input can't be measured before reading it
+    let mut width = 1;
+    if selected > 1 || operands.len() > 1 {
+        let mut total_size: u64 = 0;
+        let mut minimum = 1;
+        for name in &operands {
+            match fs::metadata(name) {
+                Ok(metadata) if name != "-" && metadata.is_file() => total_size += metadata.len(),
+                Err(_) if name != "-" => (),
+                _ => minimum = 7,
+            }
+        }
+        width = total_size.to_string().len().max(minimum);
+    }
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let print = |out: &mut io::BufWriter<io::StdoutLock>, counts: &Counts, name: Option<&str>| {
+        let mut fields: Vec<String> = Vec::new();
+        for (enabled, value) in [
+            (lines, counts.lines),
+            (words, counts.words),
+            (chars, counts.chars),
+            (bytes, counts.bytes),
+            (max_length, counts.max_length),
+        ] {
+            if enabled {
+                fields.push(format!("{:>width$}", value, width = width));
+            }
+        }
+        match name {
+            Some(name) => writeln!(out, "{} {}", fields.join(" "), name),
+            None => writeln!(out, "{}", fields.join(" ")),
+        }
+    };
+
+    let mut total = Counts::default();
+    let mut failed = false;
+    for name in &operands {
+        let result = if name == "-" {
+            count_input(&mut io::stdin().lock())
+        } else {
+            File::open(name).and_then(|mut file| count_input(&mut file))
+        };
+        let counts = match result {
+            Ok(counts) => counts,
+            Err(e) => {
+                eprintln!("wc: {}: {}", name, strerror(&e));
+                failed = true;
+                continue;
+            }
+        };
+        // The standard input has no name when there are no operands
+        print(&mut out, &counts, if use_stdin { None } else { Some(name) })?;
+        total.lines += counts.lines;
+        total.words += counts.words;
+        total.chars += counts.chars;
+        total.bytes += counts.bytes;
+        total.max_length = total.max_length.max(counts.max_length);
+    }
+    if operands.len() > 1 {
+        print(&mut out, &total, Some("total"))?;
+    }
+    out.flush()?;
+    if failed {
+        return Err(io::Error::other("Some files couldn't be read."));
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+enum SortKind {
+    #[default]
+    Text,
+    Numeric,
+    General,
+    Human,
+    Month,
+    Version,
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+struct SortOrder {
+    kind: SortKind,
+    reverse: bool,
+    fold: bool,
+    dictionary: bool,
+    nonprinting: bool,
+    // '-b' can be given separately for the start and the end of a key
+    blanks_start: bool,
+    blanks_end: bool,
+}
+
+#[derive(Clone)]
+struct SortKey {
+    // Fields and characters are counted from 0 here
+    start_field: usize,
+    start_char: usize,
+    // The end field and character (0 means the end of the field), or the end of the line
+    end: Option<(usize, usize)>,
+    order: SortOrder,
+}
+
+struct SortConfig {
+    keys: Vec<SortKey>,
+    separator: Option<u8>,
+    stable: bool,
+    unique: bool,
+    reverse: bool,
+}
+
+fn sort(args: Vec<String>) -> Result<(), io::Error> {
+    let mut global = SortOrder::default();
+    // The keys and whether they had their own ordering options
+    let mut keys: Vec<(SortKey, bool)> = Vec::new();
+    let mut separator: Option<u8> = None;
+    let (mut stable, mut unique, mut zero, mut merge) = (false, false, false, false);
+    let mut check: Option<bool> = None;
+    let mut output: Option<String> = None;
+    let mut buffer_size: Option<u64> = None;
+    let mut temp_dirs: Vec<PathBuf> = Vec::new();
+    let mut threads = std::thread::available_parallelism().map_or(1, |n| n.get().min(8));
+    let mut operands: Vec<String> = Vec::new();
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        // Options that take a value, like '-k 2' or '-k2'
+        let mut option = |name: char, value: String| -> io::Result<()> {
+            match name {
+                'k' => {
+                    let key = parse_sort_key(&value)
+                        .ok_or_else(|| invalid(format!("sort: invalid key '{}'", value)))?;
+                    keys.push(key);
+                }
+                't' => {
+                    separator = match value.as_bytes() {
+                        [byte] => Some(*byte),
+                        b"\\0" => Some(0),
+                        _ => return Err(invalid(String::from("sort: invalid separator"))),
+                    }
+                }
+                'o' => output = Some(value),
+                'S' => {
+                    buffer_size =
+                        Some(parse_buffer_size(&value).ok_or_else(|| {
+                            invalid(format!("sort: invalid buffer size '{}'", value))
+                        })?)
+                }
+                'T' => temp_dirs.push(PathBuf::from(value)),
+                _ => (),
+            }
+            Ok(())
+        };
+        match arg.as_str() {
+            "--ignore-leading-blanks" => {
+                global.blanks_start = true;
+                global.blanks_end = true;
+            }
+            "--dictionary-order" => global.dictionary = true,
+            "--ignore-case" => global.fold = true,
+            "--ignore-nonprinting" => global.nonprinting = true,
+            "--general-numeric-sort" => global.kind = SortKind::General,
+            "--human-numeric-sort" => global.kind = SortKind::Human,
+            "--month-sort" => global.kind = SortKind::Month,
+            "--numeric-sort" => global.kind = SortKind::Numeric,
+            "--version-sort" => global.kind = SortKind::Version,
+            "--reverse" => global.reverse = true,
+            "--stable" => stable = true,
+            "--unique" => unique = true,
+            "--zero-terminated" => zero = true,
+            "--merge" => merge = true,
+            "--check" | "--check=diagnose-first" => check = Some(false),
+            "--check=quiet" | "--check=silent" => check = Some(true),
+            _ if arg.starts_with("--") => {
+                let (name, value) = match arg.split_once('=') {
+                    Some((name, value)) => (name, value.to_string()),
+                    None => match iter.next() {
+                        Some(value) => (arg.as_str(), value.clone()),
+                        None => {
+                            return Err(invalid(format!(
+                                "sort: option '{}' requires an argument",
+                                arg
+                            )))
+                        }
+                    },
+                };
+                match name {
+                    "--key" => option('k', value)?,
+                    "--field-separator" => option('t', value)?,
+                    "--output" => option('o', value)?,
+                    "--buffer-size" => option('S', value)?,
+                    "--temporary-directory" => option('T', value)?,
+                    "--parallel" => {
+                        threads = value
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|&n| n > 0)
+                            .ok_or_else(|| invalid(format!("sort: invalid number '{}'", value)))?
+                    }
+                    _ => return Err(invalid(String::from("Can't use 'sort' with this option."))),
+                }
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                // Short options can be grouped, like '-nr' or '-nk2'
+                for (index, c) in arg.char_indices().skip(1) {
+                    match c {
+                        'b' => {
+                            global.blanks_start = true;
+                            global.blanks_end = true;
+                        }
+                        'd' => global.dictionary = true,
+                        'f' => global.fold = true,
+                        'i' => global.nonprinting = true,
+                        'g' => global.kind = SortKind::General,
+                        'h' => global.kind = SortKind::Human,
+                        'M' => global.kind = SortKind::Month,
+                        'n' => global.kind = SortKind::Numeric,
+                        'V' => global.kind = SortKind::Version,
+                        'r' => global.reverse = true,
+                        's' => stable = true,
+                        'u' => unique = true,
+                        'z' => zero = true,
+                        'm' => merge = true,
+                        'c' => check = Some(false),
+                        'C' => check = Some(true),
+                        'k' | 't' | 'o' | 'S' | 'T' => {
+                            // The value is the rest of the argument or the next one
+                            let rest = &arg[index + c.len_utf8()..];
+                            let value = if rest.is_empty() {
+                                match iter.next() {
+                                    Some(value) => value.clone(),
+                                    None => {
+                                        return Err(invalid(format!(
+                                            "sort: option requires an argument -- '{}'",
+                                            c
+                                        )))
+                                    }
+                                }
+                            } else {
+                                rest.to_string()
+                            };
+                            option(c, value)?;
+                            break;
+                        }
+                        _ => {
+                            return Err(invalid(String::from("Can't use 'sort' with this option.")))
+                        }
+                    }
+                }
+            }
+            _ => operands.push(arg.clone()),
+        }
+    }
+    if operands.is_empty() {
+        operands.push(String::from("-"));
+    }
+
+    // Keys without their own options use the global ones
+    let mut keys: Vec<SortKey> = keys
+        .into_iter()
+        .map(|(mut key, own)| {
+            if !own {
+                key.order = global;
+            }
+            key
+        })
+        .collect();
+    if keys.is_empty() {
+        keys.push(SortKey {
+            start_field: 0,
+            start_char: 0,
+            end: None,
+            order: global,
+        });
+    }
+    let config = SortConfig {
+        keys,
+        separator,
+        stable,
+        unique,
+        reverse: global.reverse,
+    };
+    let terminator = if zero { b'\0' } else { b'\n' };
+
+    if let Some(quiet) = check {
+        if operands.len() > 1 {
+            eprintln!("sort: extra operand '{}' not allowed with -c", operands[1]);
+            return Err(invalid(String::from("Too many operands.")));
+        }
+        // Like 'diff', 1 means the input is not sorted, other errors are for trouble
+        if !check_sorted(&operands[0], &config, terminator, quiet)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if temp_dirs.is_empty() {
+        temp_dirs.push(PathBuf::from(
+            env::var("TMPDIR").unwrap_or_else(|_| String::from("/tmp")),
+        ));
+    }
+    let mut runs = SortRuns {
+        files: Vec::new(),
+        dirs: temp_dirs,
+    };
+
+    let open = |name: &str| -> io::Result<Box<dyn BufRead>> {
+        match open_input(name) {
+            Ok(input) => Ok(Box::new(BufReader::new(input))),
+            Err(e) => {
+                eprintln!("sort: cannot read: {}: {}", name, strerror(&e));
+                Err(e)
+            }
+        }
+    };
+
+    if merge {
+        // The inputs are already sorted, so only merge them. An input that is also
+        // the output is copied first, so it isn't overwritten while it is read
+        let target = output.as_ref().and_then(|o| fs::metadata(o).ok());
+        let mut sources: Vec<Box<dyn BufRead>> = Vec::new();
+        for name in &operands {
+            let same = target.as_ref().is_some_and(|t| {
+                fs::metadata(name).is_ok_and(|m| (m.dev(), m.ino()) == (t.dev(), t.ino()))
+            });
+            if same {
+                let mut input = open(name)?;
+                let path = runs.create(|file| io::copy(&mut input, file).map(|_| ()))?;
+                sources.push(Box::new(BufReader::new(File::open(path)?)));
+            } else {
+                sources.push(open(name)?);
+            }
+        }
+        let mut out = sort_output(output.as_deref())?;
+        merge_sources(sources, &config, terminator, &mut out)?;
+        return out.flush();
+    }
+
+    // Without '-S', use an eighth of the physical memory
+    let budget = buffer_size.unwrap_or_else(|| {
+        let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if pages > 0 && page_size > 0 {
+            (pages as u64 * page_size as u64 / 8).max(1 << 20)
+        } else {
+            64 << 20
+        }
+    });
+
+    // Read the records, and when they don't fit in the budget anymore, sort them
+    // and write them in a temporary file (a run) which is merged at the end
+    let mut records: Vec<Vec<u8>> = Vec::new();
+    let mut used: u64 = 0;
+    for name in &operands {
+        let mut input = open(name)?;
+        loop {
+            let mut record = Vec::new();
+            if input.read_until(terminator, &mut record)? == 0 {
+                break;
+            }
+            if record.last() == Some(&terminator) {
+                record.pop();
+            }
+            used += (record.len() + std::mem::size_of::<Vec<u8>>()) as u64;
+            records.push(record);
+            if used > budget {
+                let sorted = sort_records(std::mem::take(&mut records), &config, threads);
+                runs.create(|file| write_records(file, &sorted, &config, terminator))?;
+                used = 0;
+            }
+        }
+    }
+    let records = sort_records(records, &config, threads);
+    if runs.files.is_empty() {
+        let mut out = sort_output(output.as_deref())?;
+        write_records(&mut out, &records, &config, terminator)?;
+        return out.flush();
+    }
+    if !records.is_empty() {
+        runs.create(|file| write_records(file, &records, &config, terminator))?;
+    }
+    drop(records);
+
+    // Don't open too many files at once: merge the first runs into a bigger one.
+    // It replaces them at the front, so equal lines keep their order
+    const MERGE_WIDTH: usize = 32;
+    while runs.files.len() > MERGE_WIDTH {
+        let mut sources: Vec<Box<dyn BufRead>> = Vec::new();
+        for path in &runs.files[..MERGE_WIDTH] {
+            sources.push(Box::new(BufReader::new(File::open(path)?)));
+        }
+        let merged = runs.create(|file| merge_sources(sources, &config, terminator, file))?;
+        runs.files.pop();
+        for path in runs.files.drain(..MERGE_WIDTH) {
+            let _ = fs::remove_file(path);
+        }
+        runs.files.insert(0, merged);
+    }
+    let mut sources: Vec<Box<dyn BufRead>> = Vec::new();
+    for path in &runs.files {
+        sources.push(Box::new(BufReader::new(File::open(path)?)));
+    }
+    let mut out = sort_output(output.as_deref())?;
+    merge_sources(sources, &config, terminator, &mut out)?;
+    out.flush()
+}
+
+fn temp_file(dir: &Path, prefix: &str) -> io::Result<(File, PathBuf)> {
+    // Create a new file with a unique name, like 'prefixXXXXXX'
+    let template = path_to_cstring(&dir.join(format!("{}XXXXXX", prefix)))?;
+    let mut template = template.into_bytes_with_nul();
+    let fd = unsafe { libc::mkstemp(template.as_mut_ptr() as *mut libc::c_char) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    template.pop();
+    let path = PathBuf::from(OsStr::from_bytes(&template));
+    Ok((unsafe { File::from_raw_fd(fd) }, path))
+}
+
+// The temporary files holding sorted runs, removed when sort ends
+struct SortRuns {
+    files: Vec<PathBuf>,
+    dirs: Vec<PathBuf>,
+}
+
+impl SortRuns {
+    fn create(
+        &mut self,
+        fill: impl FnOnce(&mut io::BufWriter<File>) -> io::Result<()>,
+    ) -> io::Result<PathBuf> {
+        // Use the temporary directories in turn
+        let dir = &self.dirs[self.files.len() % self.dirs.len()];
+        let (file, path) = match temp_file(dir, "sort") {
+            Ok(temp) => temp,
+            Err(e) => {
+                eprintln!(
+                    "sort: cannot create temporary file in '{}': {}",
+                    dir.display(),
+                    strerror(&e)
+                );
+                return Err(e);
+            }
+        };
+        self.files.push(path.clone());
+        let mut file = io::BufWriter::new(file);
+        fill(&mut file)?;
+        file.flush()?;
+        Ok(path)
+    }
+}
+
+impl Drop for SortRuns {
+    fn drop(&mut self) {
+        for path in &self.files {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn sort_output(output: Option<&str>) -> io::Result<Box<dyn Write>> {
+    // The output file is opened only after the input was read, so it can be an input too
+    match output {
+        Some(name) => match File::create(name) {
+            Ok(file) => Ok(Box::new(io::BufWriter::new(file))),
+            Err(e) => {
+                eprintln!("sort: open failed: {}: {}", name, strerror(&e));
+                Err(e)
+            }
+        },
+        None => Ok(Box::new(io::BufWriter::new(io::stdout()))),
+    }
+}
+
+fn write_records(
+    out: &mut impl Write,
+    records: &[Vec<u8>],
+    config: &SortConfig,
+    terminator: u8,
+) -> io::Result<()> {
+    // With '-u', only the first of the equal records is written
+    let mut last: Option<&Vec<u8>> = None;
+    for record in records {
+        if config.unique
+            && last.is_some_and(|l| config.compare(l, record) == std::cmp::Ordering::Equal)
+        {
+            continue;
+        }
+        out.write_all(record)?;
+        out.write_all(&[terminator])?;
+        last = Some(record);
+    }
+    Ok(())
+}
+
+fn sort_records(mut records: Vec<Vec<u8>>, config: &SortConfig, threads: usize) -> Vec<Vec<u8>> {
+    // Small inputs are not worth the threads
+    if threads <= 1 || records.len() < 10000 {
+        records.sort_by(|a, b| config.compare(a, b));
+        return records;
+    }
+    // Sort a part in every thread, then merge the parts two by two
+    let size = records.len().div_ceil(threads);
+    let mut parts: Vec<Vec<Vec<u8>>> = Vec::new();
+    while records.len() > size {
+        let rest = records.split_off(size);
+        parts.push(records);
+        records = rest;
+    }
+    parts.push(records);
+    std::thread::scope(|scope| {
+        for part in parts.iter_mut() {
+            scope.spawn(move || part.sort_by(|a, b| config.compare(a, b)));
+        }
+    });
+    while parts.len() > 1 {
+        let mut pairs = Vec::new();
+        let mut iter = parts.into_iter();
+        while let Some(left) = iter.next() {
+            match iter.next() {
+                Some(right) => pairs.push((left, right)),
+                None => pairs.push((left, Vec::new())),
+            }
+        }
+        parts = std::thread::scope(|scope| {
+            let handles: Vec<_> = pairs
+                .into_iter()
+                .map(|(left, right)| scope.spawn(move || merge_two(left, right, config)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap_or_default())
+                .collect()
+        });
+    }
+    parts.pop().unwrap_or_default()
+}
+
+fn merge_two(left: Vec<Vec<u8>>, right: Vec<Vec<u8>>, config: &SortConfig) -> Vec<Vec<u8>> {
+    // On equal records the left one goes first, so the sort stays stable
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        if config.compare(a, b) == std::cmp::Ordering::Greater {
+            merged.extend(right.next());
+        } else {
+            merged.extend(left.next());
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    merged
+}
+
+// The next record of a merged input, ordered so the heap gives the smallest one
+struct MergeHead<'a> {
+    record: Vec<u8>,
+    source: usize,
+    config: &'a SortConfig,
+}
+
+impl PartialEq for MergeHead<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for MergeHead<'_> {}
+
+impl PartialOrd for MergeHead<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MergeHead<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Reversed, because BinaryHeap is a max-heap; equal records come from the earlier input first
+        self.config
+            .compare(&other.record, &self.record)
+            .then(other.source.cmp(&self.source))
+    }
+}
+
+fn read_record(input: &mut dyn BufRead, terminator: u8) -> io::Result<Option<Vec<u8>>> {
+    let mut record = Vec::new();
+    if input.read_until(terminator, &mut record)? == 0 {
+        return Ok(None);
+    }
+    if record.last() == Some(&terminator) {
+        record.pop();
+    }
+    Ok(Some(record))
+}
+
+fn merge_sources(
+    mut sources: Vec<Box<dyn BufRead>>,
+    config: &SortConfig,
+    terminator: u8,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    // K-way merge of sorted inputs, keeping only the next record of every input in memory
+    let mut heap = std::collections::BinaryHeap::new();
+    for (source, input) in sources.iter_mut().enumerate() {
+        if let Some(record) = read_record(input.as_mut(), terminator)? {
+            heap.push(MergeHead {
+                record,
+                source,
+                config,
+            });
+        }
+    }
+    let mut last: Option<Vec<u8>> = None;
+    while let Some(head) = heap.pop() {
+        if let Some(record) = read_record(sources[head.source].as_mut(), terminator)? {
+            heap.push(MergeHead {
+                record,
+                source: head.source,
+                config,
+            });
+        }
+        if config.unique {
+            if last
+                .as_ref()
+                .is_some_and(|l| config.compare(l, &head.record) == std::cmp::Ordering::Equal)
+            {
+                continue;
+            }
+            out.write_all(&head.record)?;
+            out.write_all(&[terminator])?;
+            last = Some(head.record);
+        } else {
+            out.write_all(&head.record)?;
+            out.write_all(&[terminator])?;
+        }
+    }
+    Ok(())
+}
+
+fn check_sorted(name: &str, config: &SortConfig, terminator: u8, quiet: bool) -> io::Result<bool> {
+    let mut input = match open_input(name) {
+        Ok(input) => BufReader::new(input),
+        Err(e) => {
+            eprintln!("sort: cannot read: {}: {}", name, strerror(&e));
+            return Err(e);
+        }
+    };
+    let mut previous: Option<Vec<u8>> = None;
+    let mut number = 0;
+    while let Some(record) = read_record(&mut input, terminator)? {
+        number += 1;
+        if let Some(previous) = &previous {
+            // With '-u', equal records are a disorder too
+            let ordering = config.compare(previous, &record);
+            if ordering == std::cmp::Ordering::Greater
+                || (config.unique && ordering == std::cmp::Ordering::Equal)
+            {
+                if !quiet {
+                    eprintln!(
+                        "sort: {}:{}: disorder: {}",
+                        name,
+                        number,
+                        String::from_utf8_lossy(&record)
+                    );
+                }
+                return Ok(false);
+            }
+        }
+        previous = Some(record);
+    }
+    Ok(true)
+}
+
+fn parse_sort_key(spec: &str) -> Option<(SortKey, bool)> {
+    // A key is 'F[.C][OPTS][,F[.C][OPTS]]'
+    let mut order = SortOrder::default();
+    let mut own = false;
+    let mut position = |text: &str, end: bool| -> Option<(usize, Option<usize>)> {
+        let digits = text
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len());
+        let field: usize = text[..digits].parse().ok()?;
+        let mut rest = &text[digits..];
+        let mut character = None;
+        if let Some(after) = rest.strip_prefix('.') {
+            let digits = after
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(after.len());
+            character = Some(after[..digits].parse().ok()?);
+            rest = &after[digits..];
+        }
+        for c in rest.chars() {
+            own = true;
+            match c {
+                'b' if end => order.blanks_end = true,
+                'b' => order.blanks_start = true,
+                'd' => order.dictionary = true,
+                'f' => order.fold = true,
+                'i' => order.nonprinting = true,
+                'g' => order.kind = SortKind::General,
+                'h' => order.kind = SortKind::Human,
+                'M' => order.kind = SortKind::Month,
+                'n' => order.kind = SortKind::Numeric,
+                'V' => order.kind = SortKind::Version,
+                'r' => order.reverse = true,
+                _ => return None,
+            }
+        }
+        Some((field, character))
+    };
+    let (start, end) = match spec.split_once(',') {
+        Some((start, end)) => (start, Some(end)),
+        None => (spec, None),
+    };
+    let (start_field, start_char) = position(start, false)?;
+    // The start field and character can't be 0
+    if start_field == 0 || start_char == Some(0) {
+        return None;
+    }
+    let end = match end {
+        Some(end) => {
+            let (field, character) = position(end, true)?;
+            if field == 0 {
+                return None;
+            }
+            Some((field - 1, character.unwrap_or(0)))
+        }
+        None => None,
+    };
+    let key = SortKey {
+        start_field: start_field - 1,
+        start_char: start_char.unwrap_or(1) - 1,
+        end,
+        order,
+    };
+    Some((key, own))
+}
+
+fn parse_buffer_size(text: &str) -> Option<u64> {
+    // The default unit is KiB, and '%' is a part of the physical memory
+    let end = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let number: u64 = text[..end].parse().ok()?;
+    let multiplier: u64 = match &text[end..] {
+        "b" => 1,
+        "" | "K" | "k" => 1 << 10,
+        "M" | "m" => 1 << 20,
+        "G" | "g" => 1 << 30,
+        "T" | "t" => 1 << 40,
+        "P" | "p" => 1 << 50,
+        "E" | "e" => 1 << 60,
+        "%" => {
+            let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+            return Some(
+                (pages.max(0) as u64 * page_size.max(0) as u64 / 100).saturating_mul(number),
+            );
+        }
+        _ => return None,
+    };
+    number.checked_mul(multiplier)
+}
+
+fn is_blank(byte: u8) -> bool {
+    byte == b' ' || byte == b'\t' || byte == b'\n'
+}
+
+fn key_range<'a>(line: &'a [u8], key: &SortKey, separator: Option<u8>) -> &'a [u8] {
+    // Like coreutils, without '-t' the blanks before a field belong to it
+    let limit = line.len();
+    let skip_fields = |mut fields: usize, keep_separator: bool| {
+        let mut index = 0;
+        while index < limit && fields > 0 {
+            fields -= 1;
+            match separator {
+                Some(separator) => {
+                    while index < limit && line[index] != separator {
+                        index += 1;
+                    }
+                    if index < limit && (fields > 0 || !keep_separator) {
+                        index += 1;
+                    }
+                }
+                None => {
+                    while index < limit && is_blank(line[index]) {
+                        index += 1;
+                    }
+                    while index < limit && !is_blank(line[index]) {
+                        index += 1;
+                    }
+                }
+            }
+        }
+        index
+    };
+    let mut start = skip_fields(key.start_field, false);
+    if key.order.blanks_start {
+        while start < limit && is_blank(line[start]) {
+            start += 1;
+        }
+    }
+    let start = (start + key.start_char).min(limit);
+    let end = match key.end {
+        None => limit,
+        // Character 0 means the whole end field
+        Some((field, 0)) => skip_fields(field + 1, true),
+        Some((field, character)) => {
+            let mut end = skip_fields(field, false);
+            if key.order.blanks_end {
+                while end < limit && is_blank(line[end]) {
+                    end += 1;
+                }
+            }
+            (end + character).min(limit)
+        }
+    };
+    if end <= start {
+        &line[start..start]
+    } else {
+        &line[start..end]
+    }
+}
+
+impl SortConfig {
+    fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+        for key in &self.keys {
+            let x = key_range(a, key, self.separator);
+            let y = key_range(b, key, self.separator);
+            let ordering = match key.order.kind {
+                SortKind::Text => compare_text(x, y, &key.order),
+                SortKind::Numeric => compare_numeric(x, y),
+                SortKind::General => compare_general(x, y),
+                SortKind::Human => compare_human(x, y),
+                SortKind::Month => sort_month(x).cmp(&sort_month(y)),
+                SortKind::Version => compare_version(x, y),
+            };
+            let ordering = if key.order.reverse {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+            if ordering != std::cmp::Ordering::Equal {
+                return ordering;
+            }
+        }
+        // When the keys are equal, compare the whole lines, unless '-s' or '-u'
+        if self.stable || self.unique {
+            return std::cmp::Ordering::Equal;
+        }
+        if self.reverse {
+            b.cmp(a)
+        } else {
+            a.cmp(b)
+        }
+    }
+}
+
+fn compare_text(a: &[u8], b: &[u8], order: &SortOrder) -> std::cmp::Ordering {
+    if !(order.fold || order.dictionary || order.nonprinting) {
+        return a.cmp(b);
+    }
+    // '-d' keeps only blanks and alphanumerics, '-i' only printable characters
+    let keep = |c: &&u8| {
+        (!order.dictionary || c.is_ascii_alphanumeric() || is_blank(**c))
+            && (!order.nonprinting || (0x20..0x7f).contains(*c))
+    };
+    let fold = |c: &u8| {
+        if order.fold {
+            c.to_ascii_uppercase()
+        } else {
+            *c
+        }
+    };
+    a.iter()
+        .filter(keep)
+        .map(fold)
+        .cmp(b.iter().filter(keep).map(fold))
+}
+
+fn split_number(text: &[u8]) -> (bool, &[u8], &[u8], usize) {
+    // Get the sign, the integer part without leading zeros, the fraction without
+    // trailing zeros and the length of the number (after the leading blanks)
+    let mut index = 0;
+    while index < text.len() && is_blank(text[index]) {
+        index += 1;
+    }
+    let negative = text.get(index) == Some(&b'-');
+    if negative {
+        index += 1;
+    }
+    let start = index;
+    while index < text.len() && text[index].is_ascii_digit() {
+        index += 1;
+    }
+    let mut integer = &text[start..index];
+    while integer.first() == Some(&b'0') {
+        integer = &integer[1..];
+    }
+    let mut fraction: &[u8] = &[];
+    if text.get(index) == Some(&b'.') {
+        let start = index + 1;
+        index = start;
+        while index < text.len() && text[index].is_ascii_digit() {
+            index += 1;
+        }
+        fraction = &text[start..index];
+        while fraction.last() == Some(&b'0') {
+            fraction = &fraction[..fraction.len() - 1];
+        }
+    }
+    // '-0' is the same as '0'
+    let negative = negative && !(integer.is_empty() && fraction.is_empty());
+    (negative, integer, fraction, index)
+}
+
+fn compare_numeric(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+    // Compare the digits, so long numbers don't lose precision
+    let (a_negative, a_integer, a_fraction, _) = split_number(a);
+    let (b_negative, b_integer, b_fraction, _) = split_number(b);
+    if a_negative != b_negative {
+        return if a_negative {
+            std::cmp::Ordering::Less
+        } else {
+            std::cmp::Ordering::Greater
+        };
+    }
+    let ordering = a_integer
+        .len()
+        .cmp(&b_integer.len())
+        .then(a_integer.cmp(b_integer))
+        .then(a_fraction.cmp(b_fraction));
+    if a_negative {
+        ordering.reverse()
+    } else {
+        ordering
+    }
+}
+
+fn compare_human(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+    // Compare the sign and the suffix (K, M, G, ...) first, then the numbers
+    let unit = |text: &[u8]| -> i32 {
+        let (negative, integer, fraction, end) = split_number(text);
+        if integer.is_empty() && fraction.is_empty() {
+            return 0;
+        }
+        let order = match text.get(end) {
+            Some(b'k' | b'K') => 1,
+            Some(b'M') => 2,
+            Some(b'G') => 3,
+            Some(b'T') => 4,
+            Some(b'P') => 5,
+            Some(b'E') => 6,
+            Some(b'Z') => 7,
+            Some(b'Y') => 8,
+            _ => 0,
+        };
+        if negative {
+            -order - 1
+        } else {
+            order + 1
+        }
+    };
+    unit(a).cmp(&unit(b)).then_with(|| compare_numeric(a, b))
+}
+
+fn parse_general(text: &[u8]) -> Option<f64> {
+    // Like strtod, use the longest prefix which is a number
+    let text = String::from_utf8_lossy(text);
+    let text = text.trim_start_matches([' ', '\t', '\n']);
+    let lower = text.to_ascii_lowercase();
+    let unsigned = lower.trim_start_matches(['+', '-']);
+    let sign = &lower[..lower.len() - unsigned.len()];
+    if sign.len() <= 1 {
+        for word in ["infinity", "inf", "nan"] {
+            if unsigned.starts_with(word) {
+                return format!("{}{}", sign, word).parse().ok();
+            }
+        }
+    }
+    if let Some(hex) = unsigned.strip_prefix("0x") {
+        let digits = hex
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .unwrap_or(hex.len());
+        if let Ok(value) = u64::from_str_radix(&hex[..digits], 16) {
+            return Some(if sign == "-" {
+                -(value as f64)
+            } else {
+                value as f64
+            });
+        }
+    }
+    let length = text
+        .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+        .unwrap_or(text.len());
+    (1..=length).rev().find_map(|end| {
+        let prefix = &text[..end];
+        if prefix.ends_with(|c: char| c.is_ascii_digit() || c == '.') {
+            prefix.parse::<f64>().ok()
+        } else {
+            None
+        }
+    })
+}
+
+fn compare_general(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+    // Text which is not a number goes first, then NaN, then the numbers
+    match (parse_general(a), parse_general(b)) {
+        (None, None) => std::cmp::Ordering::Equal,
+        (None, Some(_)) => std::cmp::Ordering::Less,
+        (Some(_), None) => std::cmp::Ordering::Greater,
+        (Some(x), Some(y)) => match (x.is_nan(), y.is_nan()) {
+            (true, true) => std::cmp::Ordering::Equal,
+            (true, false) => std::cmp::Ordering::Less,
+            (false, true) => std::cmp::Ordering::Greater,
+            _ => x.partial_cmp(&y).unwrap_or(std::cmp::Ordering::Equal),
+        },
+    }
+}
+
+fn sort_month(text: &[u8]) -> i64 {
+    // Unknown names go before January
+    let text = String::from_utf8_lossy(text).to_ascii_lowercase();
+    month_number(text.trim_start_matches([' ', '\t', '\n']))
+}
+
+fn version_order(text: &[u8], index: usize) -> i32 {
+    // Like dpkg, '~' goes before everything (even the end), and letters before other characters
+    match text.get(index) {
+        None => 0,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => *c as i32,
+        Some(b'~') => -1,
+        Some(c) => *c as i32 + 256,
+    }
+}
+
+fn compare_version_part(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+    let (mut i, mut j) = (0, 0);
+    let digit = |text: &[u8], index: usize| text.get(index).is_some_and(|c| c.is_ascii_digit());
+    while i < a.len() || j < b.len() {
+        // Compare the parts without digits
+        while (i < a.len() && !digit(a, i)) || (j < b.len() && !digit(b, j)) {
+            let (x, y) = (version_order(a, i), version_order(b, j));
+            if x != y {
+                return x.cmp(&y);
+            }
+            i += 1;
+            j += 1;
+        }
+        // Then the numbers, ignoring leading zeros
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+        let mut first_difference = std::cmp::Ordering::Equal;
+        while digit(a, i) && digit(b, j) {
+            if first_difference == std::cmp::Ordering::Equal {
+                first_difference = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+        if digit(a, i) {
+            return std::cmp::Ordering::Greater;
+        }
+        if digit(b, j) {
+            return std::cmp::Ordering::Less;
+        }
+        if first_difference != std::cmp::Ordering::Equal {
+            return first_difference;
+        }
+    }
+    std::cmp::Ordering::Equal
+}
+
+fn version_prefix(text: &[u8]) -> usize {
+    // The length without the file suffixes, like '.tar.gz'
+    let mut prefix = 0;
+    let mut index = 0;
+    while index < text.len() {
+        index += 1;
+        prefix = index;
+        while index + 1 < text.len()
+            && text[index] == b'.'
+            && (text[index + 1].is_ascii_alphabetic() || text[index + 1] == b'~')
+        {
+            index += 2;
+            while index < text.len() && (text[index].is_ascii_alphanumeric() || text[index] == b'~')
+            {
+                index += 1;
+            }
+        }
+    }
+    prefix
+}
+
+fn compare_version(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    if a == b {
+        return Ordering::Equal;
+    }
+    // Like coreutils, empty names go first, then '.', '..' and the hidden files
+    if a.is_empty() || b.is_empty() {
+        return a.len().cmp(&b.len());
+    }
+    if a[0] == b'.' || b[0] == b'.' {
+        if a[0] != b[0] {
+            return if a[0] == b'.' {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            };
+        }
+        for special in [&b"."[..], &b".."[..]] {
+            if a == special || b == special {
+                return if a == special {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                };
+            }
+        }
+    }
+    // Compare without the file suffixes first
+    let (a_prefix, b_prefix) = (version_prefix(a), version_prefix(b));
+    let ordering = compare_version_part(&a[..a_prefix], &b[..b_prefix]);
+    if ordering != Ordering::Equal || (a_prefix == a.len() && b_prefix == b.len()) {
+        return ordering;
+    }
+    compare_version_part(a, b)
+}
+
+#[derive(PartialEq)]
+enum Repeated {
+    None,
+    Prepend,
+    Separate,
+}
+
+struct UniqOptions {
+    count: bool,
+    // Print only the duplicated or only the unique lines
+    duplicated: bool,
+    unique: bool,
+    // '-D' prints all the duplicated lines, maybe with empty lines between groups
+    all: Option<Repeated>,
+    ignore_case: bool,
+    skip_fields: usize,
+    skip_chars: usize,
+    check_chars: Option<usize>,
+}
+
+fn uniq(args: Vec<String>) -> Result<(), io::Error> {
+    let mut options = UniqOptions {
+        count: false,
+        duplicated: false,
+        unique: false,
+        all: None,
+        ignore_case: false,
+        skip_fields: 0,
+        skip_chars: 0,
+        check_chars: None,
+    };
+    let mut zero = false;
+    let mut operands: Vec<String> = Vec::new();
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let number = |value: Option<&String>| -> io::Result<usize> {
+        match value {
+            Some(value) => value
+                .parse()
+                .map_err(|_| invalid(format!("uniq: invalid number: '{}'", value))),
+            None => Err(invalid(String::from("Option requires a number."))),
+        }
+    };
+
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--count" => options.count = true,
+            "--repeated" => options.duplicated = true,
+            "--all-repeated" | "--all-repeated=none" => options.all = Some(Repeated::None),
+            "--all-repeated=prepend" => options.all = Some(Repeated::Prepend),
+            "--all-repeated=separate" => options.all = Some(Repeated::Separate),
+            "--unique" => options.unique = true,
+            "--ignore-case" => options.ignore_case = true,
+            "--zero-terminated" => zero = true,
+            "-f" | "--skip-fields" => options.skip_fields = number(iter.next())?,
+            "-s" | "--skip-chars" => options.skip_chars = number(iter.next())?,
+            "-w" | "--check-chars" => options.check_chars = Some(number(iter.next())?),
+            _ if arg.starts_with("--skip-fields=") => {
+                options.skip_fields = number(Some(&arg["--skip-fields=".len()..].to_string()))?
+            }
+            _ if arg.starts_with("--skip-chars=") => {
+                options.skip_chars = number(Some(&arg["--skip-chars=".len()..].to_string()))?
+            }
+            _ if arg.starts_with("--check-chars=") => {
+                options.check_chars =
+                    Some(number(Some(&arg["--check-chars=".len()..].to_string()))?)
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                // Short options can be grouped, like '-ci' or '-f2'
+                for (index, c) in arg.char_indices().skip(1) {
+                    match c {
+                        'c' => options.count = true,
+                        'd' => options.duplicated = true,
+                        'D' => options.all = Some(Repeated::None),
+                        'u' => options.unique = true,
+                        'i' => options.ignore_case = true,
+                        'z' => zero = true,
+                        'f' | 's' | 'w' => {
+                            let rest = &arg[index + 1..];
+                            let value = if rest.is_empty() {
+                                number(iter.next())?
+                            } else {
+                                number(Some(&rest.to_string()))?
+                            };
+                            match c {
+                                'f' => options.skip_fields = value,
+                                's' => options.skip_chars = value,
+                                _ => options.check_chars = Some(value),
+                            }
+                            break;
+                        }
+                        _ => {
+                            return Err(invalid(String::from("Can't use 'uniq' with this option.")))
+                        }
+                    }
+                }
+            }
+            _ => operands.push(arg.clone()),
+        }
+    }
+    if options.count && options.all.is_some() {
+        eprintln!("uniq: printing all duplicated lines and repeat counts is meaningless");
+        return Err(invalid(String::from("Can't use '-c' with '-D'.")));
+    }
+    if operands.len() > 2 {
+        eprintln!("uniq: extra operand '{}'", operands[2]);
+        return Err(invalid(String::from("Too many operands.")));
+    }
+
+    // The input is the first operand and the output the second one
+    let input_name = operands.first().map_or("-", |name| name.as_str());
+    let mut input = match open_input(input_name) {
+        Ok(input) => BufReader::new(input),
+        Err(e) => {
+            eprintln!("uniq: {}: {}", input_name, strerror(&e));
+            return Err(e);
+        }
+    };
+    let mut out: Box<dyn Write> = match operands.get(1) {
+        Some(name) if name != "-" => match File::create(name) {
+            Ok(file) => Box::new(io::BufWriter::new(file)),
+            Err(e) => {
+                eprintln!("uniq: {}: {}", name, strerror(&e));
+                return Err(e);
+            }
+        },
+        _ => Box::new(io::BufWriter::new(io::stdout())),
+    };
+    let terminator = if zero { b'\0' } else { b'\n' };
+
+    // The first line of the current group and how many lines it has
+    let mut group: Option<Vec<u8>> = None;
+    let mut count: u64 = 0;
+    let mut groups_printed = false;
+    loop {
+        let record = read_record(&mut input, terminator)?;
+        if let (Some(first), Some(record)) = (&group, &record) {
+            if uniq_equal(first, record, &options) {
+                count += 1;
+                if let Some(method) = &options.all {
+                    // Print the duplicated lines as soon as they are found
+                    if count == 2 {
+                        if *method == Repeated::Prepend
+                            || (*method == Repeated::Separate && groups_printed)
+                        {
+                            out.write_all(&[terminator])?;
+                        }
+                        groups_printed = true;
+                        out.write_all(first)?;
+                        out.write_all(&[terminator])?;
+                    }
+                    out.write_all(record)?;
+                    out.write_all(&[terminator])?;
+                }
+                continue;
+            }
+        }
+        // The group ended, so print its line if it was selected
+        if let Some(first) = &group {
+            let selected = if count > 1 {
+                !options.unique
+            } else {
+                !options.duplicated
+            };
+            if selected && options.all.is_none() {
+                if options.count {
+                    write!(out, "{:>7} ", count)?;
+                }
+                out.write_all(first)?;
+                out.write_all(&[terminator])?;
+            }
+        }
+        match record {
+            Some(record) => {
+                group = Some(record);
+                count = 1;
+            }
+            None => break,
+        }
+    }
+    out.flush()
+}
+
+fn uniq_key<'a>(line: &'a [u8], options: &UniqOptions) -> &'a [u8] {
+    // Skip the fields (blanks followed by other characters), then the characters
+    let mut index = 0;
+    for _ in 0..options.skip_fields {
+        while index < line.len() && is_blank(line[index]) {
+            index += 1;
+        }
+        while index < line.len() && !is_blank(line[index]) {
+            index += 1;
+        }
+    }
+    index = (index + options.skip_chars).min(line.len());
+    let end = match options.check_chars {
+        Some(chars) => (index + chars).min(line.len()),
+        None => line.len(),
+    };
+    &line[index..end]
+}
+
+fn uniq_equal(a: &[u8], b: &[u8], options: &UniqOptions) -> bool {
+    let (a, b) = (uniq_key(a, options), uniq_key(b, options));
+    if options.ignore_case {
+        a.eq_ignore_ascii_case(b)
+    } else {
+        a == b
+    }
+}
+
+fn parse_list(list: &str) -> io::Result<Vec<(usize, usize)>> {
+    // A list is like '1,3-5,7-' ('-3' is '1-3'), counted from 1
+    let invalid = |message: String| {
+        eprintln!("cut: {}", message);
+        io::Error::new(io::ErrorKind::InvalidInput, message)
+    };
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for part in list.split([',', ' ']) {
+        let number = |text: &str, default: usize| -> io::Result<usize> {
+            if text.is_empty() {
+                return Ok(default);
+            }
+            match text.parse::<usize>() {
+                Ok(0) => Err(invalid(String::from(
+                    "fields and positions are numbered from 1",
+                ))),
+                Ok(n) => Ok(n),
+                Err(_) => Err(invalid(format!("invalid field value '{}'", text))),
+            }
+        };
+        let range = match part.split_once('-') {
+            Some(("", "")) => {
+                return Err(invalid(String::from("invalid range with no endpoint: -")))
+            }
+            Some((start, end)) => (number(start, 1)?, number(end, usize::MAX)?),
+            None => {
+                let n = number(part, 0)?;
+                if n == 0 {
+                    return Err(invalid(String::from(
+                        "fields and positions are numbered from 1",
+                    )));
+                }
+                (n, n)
+            }
+        };
+        if range.0 > range.1 {
+            return Err(invalid(String::from("invalid decreasing range")));
+        }
+        ranges.push(range);
+    }
+    // Sort the ranges and merge the ones that overlap or touch
+    ranges.sort();
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    Ok(merged)
+}
+
+fn complement_ranges(ranges: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let mut result = Vec::new();
+    let mut next = 1;
+    for &(start, end) in ranges {
+        if start > next {
+            result.push((next, start - 1));
+        }
+        if end == usize::MAX {
+            return result;
+        }
+        next = end + 1;
+    }
+    result.push((next, usize::MAX));
+    result
+}
+
+fn in_ranges(ranges: &[(usize, usize)], position: usize) -> bool {
+    ranges
+        .iter()
+        .any(|&(start, end)| start <= position && position <= end)
+}
+
+fn cut(args: Vec<String>) -> Result<(), io::Error> {
+    let mut list: Option<(char, String)> = None;
+    let mut delimiter: Option<u8> = None;
+    let mut output_delimiter: Option<Vec<u8>> = None;
+    let (mut only_delimited, mut complement, mut zero) = (false, false, false);
+    let mut operands: Vec<String> = Vec::new();
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message.to_string());
+
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        // The letters of grouped short options given before a value
+        let mut flags = "";
+        // Options with a value, like '-f 2', '-f2', '-sf2' or '--fields=2'
+        let (name, value) = match arg.as_str() {
+            "--bytes" | "--characters" | "--fields" | "--delimiter" | "--output-delimiter" => {
+                match iter.next() {
+                    Some(value) => (arg.as_str(), value.clone()),
+                    None => return Err(invalid("Option requires a value.")),
+                }
+            }
+            _ if arg.starts_with("--") && arg.contains('=') => match arg.split_once('=') {
+                Some((name, value)) => (name, value.to_string()),
+                None => (arg.as_str(), String::new()),
+            },
+            _ if arg.starts_with('-') && !arg.starts_with("--") && arg.len() > 1 => {
+                // Short options can be grouped, like '-sz', the last one may take a value
+                match arg
+                    .char_indices()
+                    .skip(1)
+                    .find(|(_, c)| "bcfd".contains(*c))
+                {
+                    Some((index, option)) => {
+                        flags = &arg[1..index];
+                        let rest = &arg[index + 1..];
+                        let value = if !rest.is_empty() {
+                            rest.to_string()
+                        } else {
+                            match iter.next() {
+                                Some(value) => value.clone(),
+                                None => return Err(invalid("Option requires a value.")),
+                            }
+                        };
+                        let name = match option {
+                            'b' => "-b",
+                            'c' => "-c",
+                            'f' => "-f",
+                            _ => "-d",
+                        };
+                        (name, value)
+                    }
+                    None => {
+                        flags = &arg[1..];
+                        ("", String::new())
+                    }
+                }
+            }
+            _ => (arg.as_str(), String::new()),
+        };
+        for c in flags.chars() {
+            match c {
+                's' => only_delimited = true,
+                'z' => zero = true,
+                // '-n' is accepted and ignored, like coreutils
+                'n' => (),
+                _ => return Err(invalid("Can't use 'cut' with this option.")),
+            }
+        }
+        match name {
+            "" => (),
+            "-b" | "--bytes" => list = Some(('b', value)),
+            "-c" | "--characters" => list = Some(('b', value)),
+            "-f" | "--fields" => list = Some(('f', value)),
+            "-d" | "--delimiter" => match value.as_bytes() {
+                [byte] => delimiter = Some(*byte),
+                _ => {
+                    eprintln!("cut: the delimiter must be a single character");
+                    return Err(invalid("Invalid delimiter."));
+                }
+            },
+            "--output-delimiter" => output_delimiter = Some(value.into_bytes()),
+            "--only-delimited" => only_delimited = true,
+            "--complement" => complement = true,
+            "--zero-terminated" => zero = true,
+            _ if name.starts_with('-') && name.len() > 1 => {
+                return Err(invalid("Can't use 'cut' with this option."))
+            }
+            _ => operands.push(arg.clone()),
+        }
+    }
+    let (mode, list) = match list {
+        Some(list) => list,
+        None => {
+            eprintln!("cut: you must specify a list of bytes, characters, or fields");
+            return Err(invalid("Missing list."));
+        }
+    };
+    if mode == 'b' && (delimiter.is_some() || only_delimited) {
+        eprintln!("cut: an input delimiter may be specified only when operating on fields");
+        return Err(invalid("Delimiter without fields."));
+    }
+    let mut ranges = parse_list(&list)?;
+    if complement {
+        ranges = complement_ranges(&ranges);
+    }
+    let delimiter = delimiter.unwrap_or(b'\t');
+    let terminator = if zero { b'\0' } else { b'\n' };
+    if operands.is_empty() {
+        operands.push(String::from("-"));
+    }
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let mut failed = false;
+    for name in &operands {
+        let mut input = match open_input(name) {
+            Ok(input) => BufReader::new(input),
+            Err(e) => {
+                eprintln!("cut: {}: {}", name, strerror(&e));
+                failed = true;
+                continue;
+            }
+        };
+        while let Some(record) = read_record(&mut input, terminator)? {
+            if mode == 'b' {
+                // Between separate ranges, print the output delimiter if there is one
+                let mut last: Option<usize> = None;
+                for (index, byte) in record.iter().enumerate() {
+                    let position = index + 1;
+                    if !in_ranges(&ranges, position) {
+                        continue;
+                    }
+                    if let (Some(output), Some(last)) = (&output_delimiter, last) {
+                        if last + 1 != position {
+                            out.write_all(output)?;
+                        }
+                    }
+                    out.write_all(&[*byte])?;
+                    last = Some(position);
+                }
+            } else {
+                // Lines without the delimiter are printed whole, unless '-s'
+                if !record.contains(&delimiter) {
+                    if !only_delimited {
+                        out.write_all(&record)?;
+                        out.write_all(&[terminator])?;
+                    }
+                    continue;
+                }
+                let output = output_delimiter.clone().unwrap_or_else(|| vec![delimiter]);
+                let mut first = true;
+                for (index, field) in record.split(|&b| b == delimiter).enumerate() {
+                    if in_ranges(&ranges, index + 1) {
+                        if !first {
+                            out.write_all(&output)?;
+                        }
+                        out.write_all(field)?;
+                        first = false;
+                    }
+                }
+            }
+            out.write_all(&[terminator])?;
+        }
+    }
+    out.flush()?;
+    if failed {
+        return Err(io::Error::other("Some files couldn't be read."));
+    }
+    Ok(())
+}
+
+fn unescape_delimiters(list: &str) -> Vec<Option<u8>> {
+    // The delimiters are used in turn; '\0' means no delimiter
+    let mut delimiters = Vec::new();
+    let mut bytes = list.bytes();
+    while let Some(byte) = bytes.next() {
+        let delimiter = if byte == b'\\' {
+            match bytes.next() {
+                Some(b'n') => Some(b'\n'),
+                Some(b't') => Some(b'\t'),
+                Some(b'0') => None,
+                Some(other) => Some(other),
+                None => Some(b'\\'),
+            }
+        } else {
+            Some(byte)
+        };
+        delimiters.push(delimiter);
+    }
+    delimiters
+}
+
+fn paste_read(input: &mut Option<BufReader<File>>, terminator: u8) -> io::Result<Option<Vec<u8>>> {
+    // 'None' is the standard input, which can be given more than once
+    match input {
+        Some(reader) => read_record(reader, terminator),
+        None => read_record(&mut io::stdin().lock(), terminator),
+    }
+}
+
+fn paste(args: Vec<String>) -> Result<(), io::Error> {
+    let mut delimiters = vec![Some(b'\t')];
+    let (mut serial, mut zero) = (false, false);
+    let mut operands: Vec<String> = Vec::new();
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-d" | "--delimiters" => match iter.next() {
+                Some(list) => delimiters = unescape_delimiters(list),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Option requires a value.",
+                    ))
+                }
+            },
+            "-s" | "--serial" => serial = true,
+            "-z" | "--zero-terminated" => zero = true,
+            _ if arg.starts_with("--delimiters=") => {
+                delimiters = unescape_delimiters(&arg["--delimiters=".len()..])
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                // Short options can be grouped, like '-sz' or '-sd,'
+                for (index, c) in arg.char_indices().skip(1) {
+                    match c {
+                        's' => serial = true,
+                        'z' => zero = true,
+                        'd' => {
+                            // The list is the rest of the argument or the next argument
+                            let rest = &arg[index + 1..];
+                            let list = if !rest.is_empty() {
+                                rest
+                            } else {
+                                match iter.next() {
+                                    Some(list) => list.as_str(),
+                                    None => {
+                                        return Err(io::Error::new(
+                                            io::ErrorKind::InvalidInput,
+                                            "Option requires a value.",
+                                        ))
+                                    }
+                                }
+                            };
+                            delimiters = unescape_delimiters(list);
+                            break;
+                        }
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "Can't use 'paste' with this option.",
+                            ))
+                        }
+                    }
+                }
+            }
+            _ => operands.push(arg.clone()),
+        }
+    }
+    // An empty list is the same as '\0'
+    if delimiters.is_empty() {
+        delimiters.push(None);
+    }
+    if operands.is_empty() {
+        operands.push(String::from("-"));
+    }
+    let terminator = if zero { b'\0' } else { b'\n' };
+
+    let mut inputs: Vec<Option<BufReader<File>>> = Vec::new();
+    for name in &operands {
+        if name == "-" {
+            inputs.push(None);
+            continue;
+        }
+        match File::open(name) {
+            Ok(file) => inputs.push(Some(BufReader::new(file))),
+            Err(e) => {
+                eprintln!("paste: {}: {}", name, strerror(&e));
+                return Err(e);
+            }
+        }
+    }
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    if serial {
+        // Join all the lines of every file
+        for input in inputs.iter_mut() {
+            let mut count = 0;
+            while let Some(line) = paste_read(input, terminator)? {
+                if count > 0 {
+                    if let Some(delimiter) = delimiters[(count - 1) % delimiters.len()] {
+                        out.write_all(&[delimiter])?;
+                    }
+                }
+                out.write_all(&line)?;
+                count += 1;
+            }
+            out.write_all(&[terminator])?;
+        }
+    } else {
+        // Join the lines with the same number from every file, until all of them end
+        let mut ended = vec![false; inputs.len()];
+        loop {
+            let mut row: Vec<u8> = Vec::new();
+            let mut any = false;
+            for (index, input) in inputs.iter_mut().enumerate() {
+                if !ended[index] {
+                    match paste_read(input, terminator)? {
+                        Some(line) => {
+                            row.extend_from_slice(&line);
+                            any = true;
+                        }
+                        None => ended[index] = true,
+                    }
+                }
+                if index + 1 < ended.len() {
+                    if let Some(delimiter) = delimiters[index % delimiters.len()] {
+                        row.push(delimiter);
+                    }
+                }
+            }
+            if !any {
+                break;
+            }
+            out.write_all(&row)?;
+            out.write_all(&[terminator])?;
+        }
+    }
+    out.flush()
+}
+
+enum TrItem {
+    Byte(u8),
+    Range(u8, u8),
+    Class(String),
+    // '[x*n]', where no count (or 0) fills SET2 up to the length of SET1
+    Repeat(u8, usize),
+}
+
+fn tr_class(name: &str) -> Option<Vec<u8>> {
+    let test: fn(&u8) -> bool = match name {
+        "alnum" => u8::is_ascii_alphanumeric,
+        "alpha" => u8::is_ascii_alphabetic,
+        "blank" => |c| *c == b' ' || *c == b'\t',
+        "cntrl" => u8::is_ascii_control,
+        "digit" => u8::is_ascii_digit,
+        "graph" => u8::is_ascii_graphic,
+        "lower" => u8::is_ascii_lowercase,
+        "print" => |c| c.is_ascii_graphic() || *c == b' ',
+        "punct" => u8::is_ascii_punctuation,
+        "space" => |c| c.is_ascii_whitespace() || *c == b'\x0b',
+        "upper" => u8::is_ascii_uppercase,
+        "xdigit" => u8::is_ascii_hexdigit,
+        _ => return None,
+    };
+    Some((0..=255u8).filter(test).collect())
+}
+
+fn tr_byte(spec: &[u8], index: &mut usize) -> u8 {
+    // Read a byte, which can be an escape like '\n' or '\101'
+    let byte = spec[*index];
+    *index += 1;
+    if byte != b'\\' || *index >= spec.len() {
+        return byte;
+    }
+    let next = spec[*index];
+    *index += 1;
+    match next {
+        b'a' => 0x07,
+        b'b' => 0x08,
+        b'f' => 0x0c,
+        b'n' => b'\n',
+        b'r' => b'\r',
+        b't' => b'\t',
+        b'v' => 0x0b,
+        b'0'..=b'7' => {
+            let mut value = (next - b'0') as u32;
+            for _ in 0..2 {
+                match spec.get(*index) {
+                    Some(digit @ b'0'..=b'7') if value * 8 + ((digit - b'0') as u32) < 256 => {
+                        value = value * 8 + (digit - b'0') as u32;
+                        *index += 1;
+                    }
+                    _ => break,
+                }
+            }
+            value as u8
+        }
+        other => other,
+    }
+}
+
+fn parse_tr_set(spec: &str) -> io::Result<Vec<TrItem>> {
+    let invalid = |message: String| {
+        eprintln!("tr: {}", message);
+        io::Error::new(io::ErrorKind::InvalidInput, message)
+    };
+    let spec = spec.as_bytes();
+    let mut items = Vec::new();
+    let mut index = 0;
+    while index < spec.len() {
+        if spec[index] == b'[' && index + 1 < spec.len() {
+            let rest = &spec[index + 1..];
+            // '[:class:]'
+            if rest[0] == b':' {
+                if let Some(end) = rest[1..].windows(2).position(|w| w == b":]") {
+                    let name = String::from_utf8_lossy(&rest[1..1 + end]).to_string();
+                    if tr_class(&name).is_none() {
+                        return Err(invalid(format!("invalid character class '{}'", name)));
+                    }
+                    items.push(TrItem::Class(name));
+                    index += end + 4;
+                    continue;
+                }
+            }
+            // '[=c=]' is just the character, since there are only bytes
+            if rest[0] == b'=' && rest.len() >= 4 && &rest[2..4] == b"=]" {
+                items.push(TrItem::Byte(rest[1]));
+                index += 5;
+                continue;
+            }
+            // '[c*n]'
+            let mut position = index + 1;
+            let byte = tr_byte(spec, &mut position);
+            if spec.get(position) == Some(&b'*') {
+                if let Some(end) = spec[position..].iter().position(|&b| b == b']') {
+                    let digits =
+                        String::from_utf8_lossy(&spec[position + 1..position + end]).to_string();
+                    // The count is octal when it starts with 0
+                    let count = if digits.is_empty() {
+                        Some(0)
+                    } else if digits.starts_with('0') {
+                        usize::from_str_radix(&digits, 8).ok()
+                    } else {
+                        digits.parse().ok()
+                    };
+                    match count {
+                        Some(count) => {
+                            items.push(TrItem::Repeat(byte, count));
+                            index = position + end + 1;
+                            continue;
+                        }
+                        None => {
+                            return Err(invalid(format!(
+                                "invalid repeat count '{}' in [c*n] construct",
+                                digits
+                            )))
+                        }
+                    }
+                }
+            }
+        }
+        let byte = tr_byte(spec, &mut index);
+        // 'a-z' is a range, but a '-' at the end is itself
+        if spec.get(index) == Some(&b'-') && index + 1 < spec.len() {
+            index += 1;
+            let end = tr_byte(spec, &mut index);
+            if end < byte {
+                return Err(invalid(format!(
+                    "range-endpoints of '{}-{}' are in reverse collating sequence order",
+                    byte as char, end as char
+                )));
+            }
+            items.push(TrItem::Range(byte, end));
+        } else {
+            items.push(TrItem::Byte(byte));
+        }
+    }
+    Ok(items)
+}
+
+fn expand_tr_set(items: &[TrItem], length: usize) -> Vec<u8> {
+    // A repeat without a count fills the set up to the given length
+    let fixed: usize = items
+        .iter()
+        .map(|item| match item {
+            TrItem::Byte(_) => 1,
+            TrItem::Range(start, end) => (end - start) as usize + 1,
+            TrItem::Class(name) => tr_class(name).map_or(0, |c| c.len()),
+            TrItem::Repeat(_, count) => *count,
+        })
+        .sum();
+    let mut set = Vec::new();
+    for item in items {
+        match item {
+            TrItem::Byte(byte) => set.push(*byte),
+            TrItem::Range(start, end) => set.extend(*start..=*end),
+            TrItem::Class(name) => set.extend(tr_class(name).unwrap_or_default()),
+            TrItem::Repeat(byte, 0) => {
+                set.extend(std::iter::repeat_n(*byte, length.saturating_sub(fixed)))
+            }
+            TrItem::Repeat(byte, count) => set.extend(std::iter::repeat_n(*byte, *count)),
+        }
+    }
+    set
+}
+
+fn tr(args: Vec<String>) -> Result<(), io::Error> {
+    let (mut complement, mut delete, mut squeeze, mut truncate) = (false, false, false, false);
+    let mut operands: Vec<String> = Vec::new();
+    for arg in args.iter().skip(2) {
+        match arg.as_str() {
+            "--complement" => complement = true,
+            "--delete" => delete = true,
+            "--squeeze-repeats" => squeeze = true,
+            "--truncate-set1" => truncate = true,
+            _ if arg.starts_with('-') && arg.len() > 1 && operands.is_empty() => {
+                // Short options can be grouped, like '-ds'
+                for c in arg.chars().skip(1) {
+                    match c {
+                        'c' | 'C' => complement = true,
+                        'd' => delete = true,
+                        's' => squeeze = true,
+                        't' => truncate = true,
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "Can't use 'tr' with this option.",
+                            ))
+                        }
+                    }
+                }
+            }
+            _ => operands.push(arg.clone()),
+        }
+    }
+    // Deleting without squeezing takes one set, translating takes two
+    let (least, most) = match (delete, squeeze) {
+        (true, false) => (1, 1),
+        (false, true) => (1, 2),
+        _ => (2, 2),
+    };
+    if operands.len() < least || operands.len() > most {
+        if operands.len() < least {
+            eprintln!("tr: missing operand");
+        } else {
+            eprintln!("tr: extra operand '{}'", operands[most]);
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Wrong number of operands.",
+        ));
+    }
+
+    let mut set1 = expand_tr_set(&parse_tr_set(&operands[0])?, 0);
+    if complement {
+        // The complement is every other byte, in ascending order
+        let mut member = [false; 256];
+        for &byte in &set1 {
+            member[byte as usize] = true;
+        }
+        set1 = (0..=255u8).filter(|&b| !member[b as usize]).collect();
+    }
+    let set2 = match operands.get(1) {
+        Some(spec) => Some(expand_tr_set(&parse_tr_set(spec)?, set1.len())),
+        None => None,
+    };
+
+    // Build the tables: what to delete, what to squeeze and how to translate
+    let mut deleted = [false; 256];
+    let mut squeezed = [false; 256];
+    let mut map: Vec<u8> = (0..=255u8).collect();
+    if delete {
+        for &byte in &set1 {
+            deleted[byte as usize] = true;
+        }
+        for &byte in set2.iter().flatten() {
+            squeezed[byte as usize] = true;
+        }
+    } else if let Some(set2) = &set2 {
+        if set2.is_empty() && !set1.is_empty() && !truncate {
+            eprintln!("tr: when not truncating set1, string2 must be non-empty");
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Empty set2."));
+        }
+        // SET2 is extended with its last byte, or SET1 is truncated with '-t'
+        for (index, &byte) in set1.iter().enumerate() {
+            match set2.get(index).or(set2.last()) {
+                Some(&to) if index < set2.len() || !truncate => map[byte as usize] = to,
+                _ => break,
+            }
+        }
+        if squeeze {
+            for &byte in set2 {
+                squeezed[byte as usize] = true;
+            }
+        }
+    } else {
+        for &byte in &set1 {
+            squeezed[byte as usize] = true;
+        }
+    }
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let mut buffer = [0; 65536];
+    let mut last: Option<u8> = None;
+    let mut output = Vec::with_capacity(buffer.len());
+    loop {
+        let read = match input.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        output.clear();
+        for &byte in &buffer[..read] {
+            if deleted[byte as usize] {
+                continue;
+            }
+            let byte = map[byte as usize];
+            // Squeeze a run of the same byte into one
+            if squeezed[byte as usize] && last == Some(byte) {
+                continue;
+            }
+            output.push(byte);
+            last = Some(byte);
+        }
+        out.write_all(&output)?;
+    }
+    out.flush()
+}
+
+enum SedAddress {
+    Line(usize),
+    Last,
+    // 'None' is the empty regex, which means the last one used
+    Regex(Option<regex::bytes::Regex>),
+    Step(usize, usize),
+}
+
+enum SedRangeEnd {
+    Address(SedAddress),
+    // 'addr1,+N' and 'addr1,~N'
+    Relative(usize),
+    Multiple(usize),
+}
+
+enum SedReplacement {
+    Literal(Vec<u8>),
+    Group(usize),
+    // '\U', '\L' and '\E' change the case until the next one, '\u' and '\l' only of the next character
+    Case(u8),
+}
+
+struct SedSubstitution {
+    regex: Option<regex::bytes::Regex>,
+    replacement: Vec<SedReplacement>,
+    global: bool,
+    occurrence: usize,
+    print: bool,
+    write: Option<usize>,
+}
+
+enum SedKind {
+    // The index of the matching '}'
+    Block(usize),
+    BlockEnd,
+    Label,
+    // 'b', 't' or 'T', with the index of the label ('None' is the end of the script)
+    Branch(char, Option<usize>),
+    Append(Vec<u8>),
+    Insert(Vec<u8>),
+    Change(Vec<u8>),
+    ReadFile(String),
+    WriteFile(usize),
+    Substitute(Box<SedSubstitution>),
+    Translate(Vec<(Vec<u8>, Vec<u8>)>),
+    Quit(char, i32),
+    // The commands without arguments, like 'd', 'p' or 'x'
+    Simple(char),
+}
+
+struct SedCommand {
+    first: Option<SedAddress>,
+    second: Option<SedRangeEnd>,
+    negate: bool,
+    // The state of a range: if it is active and the line where it ends, when it is known
+    active: bool,
+    end_line: Option<usize>,
+    kind: SedKind,
+}
+
+fn posix_regex(pattern: &[u8], extended: bool, flags: &str) -> Result<regex::bytes::Regex, String> {
+    // Translate a POSIX regex (basic or extended, with the GNU extensions) to the regex crate syntax
+    let pattern = String::from_utf8_lossy(pattern);
+    let chars: Vec<char> = pattern.chars().collect();
+    // '.' matches the newlines in the pattern space too
+    let mut out = format!("(?s{})", flags);
+    let mut at_start = true;
+    let mut index = 0;
+    while index < chars.len() {
+        let c = chars[index];
+        let start = at_start;
+        at_start = false;
+        match c {
+            '\\' => {
+                index += 1;
+                let next = match chars.get(index) {
+                    Some(next) => *next,
+                    None => return Err(String::from("trailing backslash (\\)")),
+                };
+                match next {
+                    '(' | '|' if !extended => {
+                        out.push(next);
+                        at_start = true;
+                    }
+                    ')' | '+' | '?' if !extended => out.push(next),
+                    '{' if !extended => {
+                        let rest: String = chars[index + 1..].iter().collect();
+                        let end = rest
+                            .find("\\}")
+                            .ok_or_else(|| String::from("unmatched \\{"))?;
+                        out.push('{');
+                        out.push_str(&rest[..end]);
+                        out.push('}');
+                        index += rest[..end].chars().count() + 2;
+                    }
+                    '1'..='9' => return Err(String::from("back-references are not supported")),
+                    'n' => out.push_str("\\n"),
+                    't' => out.push_str("\\t"),
+                    'w' | 'W' | 's' | 'S' | 'b' | 'B' => {
+                        out.push('\\');
+                        out.push(next);
+                    }
+                    '<' => out.push_str("\\b{start}"),
+                    '>' => out.push_str("\\b{end}"),
+                    '`' => out.push_str("\\A"),
+                    '\'' => out.push_str("\\z"),
+                    other => out.push_str(&regex::escape(&other.to_string())),
+                }
+            }
+            '[' => {
+                let mut class = String::from("[");
+                let mut position = index + 1;
+                if chars.get(position) == Some(&'^') {
+                    class.push('^');
+                    position += 1;
+                }
+                let first = position;
+                loop {
+                    let d = match chars.get(position) {
+                        Some(d) => *d,
+                        None => return Err(String::from("unterminated bracket expression")),
+                    };
+                    if d == ']' && position != first {
+                        break;
+                    }
+                    // '[:alpha:]', '[=a=]' and '[.a.]'
+                    if d == '[' && matches!(chars.get(position + 1), Some(':' | '=' | '.')) {
+                        let kind = chars[position + 1];
+                        let close = (position + 2..chars.len().saturating_sub(1))
+                            .find(|&j| chars[j] == kind && chars[j + 1] == ']')
+                            .ok_or_else(|| String::from("unterminated bracket expression"))?;
+                        let name: String = chars[position + 2..close].iter().collect();
+                        if kind == ':' {
+                            class.push_str(&format!("[:{}:]", name));
+                        } else {
+                            class.push_str(&regex::escape(&name));
+                        }
+                        position = close + 2;
+                        continue;
+                    }
+                    match d {
+                        '\\' => match chars.get(position + 1) {
+                            Some('n') => {
+                                class.push_str("\\n");
+                                position += 1;
+                            }
+                            Some('t') => {
+                                class.push_str("\\t");
+                                position += 1;
+                            }
+                            Some('\\') | Some(']') => {
+                                class.push('\\');
+                                class.push(chars[position + 1]);
+                                position += 1;
+                            }
+                            _ => class.push_str("\\\\"),
+                        },
+                        // A '-' at the start or the end is itself
+                        '-' if position == first || chars.get(position + 1) == Some(&']') => {
+                            class.push_str("\\-")
+                        }
+                        '[' | ']' | '&' | '~' | '^' => {
+                            class.push('\\');
+                            class.push(d);
+                        }
+                        _ => class.push(d),
+                    }
+                    position += 1;
+                }
+                class.push(']');
+                out.push_str(&class);
+                index = position;
+            }
+            '*' if start => out.push_str("\\*"),
+            '^' if extended || start => {
+                out.push('^');
+                at_start = true;
+            }
+            '$' if extended
+                || index + 1 == chars.len()
+                || (chars[index + 1] == '\\'
+                    && matches!(chars.get(index + 2), Some(')' | '|'))) =>
+            {
+                out.push('$')
+            }
+            '(' | '|' if extended => {
+                out.push(c);
+                at_start = true;
+            }
+            ')' | '+' | '?' | '{' | '}' if extended => out.push(c),
+            '.' | '*' => out.push(c),
+            _ => out.push_str(&regex::escape(&c.to_string())),
+        }
+        index += 1;
+    }
+    regex::bytes::Regex::new(&out).map_err(|e| e.to_string())
+}
+
+struct SedParser<'a> {
+    script: &'a [u8],
+    position: usize,
+    extended: bool,
+    labels: Vec<(String, usize)>,
+    branches: Vec<(usize, String)>,
+}
+
+impl SedParser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.script.get(self.position).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t')) {
+            self.position += 1;
+        }
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.position += 1;
+        }
+        String::from_utf8_lossy(&self.script[start..self.position])
+            .parse()
+            .ok()
+    }
+
+    fn delimited(&mut self, delimiter: u8, regex: bool) -> Result<Vec<u8>, String> {
+        // Read up to the delimiter; '\delimiter' is the delimiter itself
+        let mut text = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(String::from("unterminated command")),
+                Some(c) if c == delimiter => {
+                    self.position += 1;
+                    return Ok(text);
+                }
+                Some(b'\\') => {
+                    self.position += 1;
+                    match self.peek() {
+                        Some(c) if c == delimiter => text.push(c),
+                        // In a regex, '\n' is kept for the translation
+                        Some(b'n') if !regex => text.push(b'\n'),
+                        Some(c) => {
+                            text.push(b'\\');
+                            text.push(c);
+                        }
+                        None => return Err(String::from("unterminated command")),
+                    }
+                    self.position += 1;
+                }
+                Some(c) => {
+                    text.push(c);
+                    self.position += 1;
+                }
+            }
+        }
+    }
+
+    fn regex_flags(&mut self) -> String {
+        // The 'I' and 'M' flags after an address regex
+        let mut flags = String::new();
+        loop {
+            match self.peek() {
+                Some(b'I') => flags.push('i'),
+                Some(b'M') => flags.push('m'),
+                _ => return flags,
+            }
+            self.position += 1;
+        }
+    }
+
+    fn regex(
+        &mut self,
+        pattern: &[u8],
+        flags: &str,
+    ) -> Result<Option<regex::bytes::Regex>, String> {
+        if pattern.is_empty() {
+            return Ok(None);
+        }
+        posix_regex(pattern, self.extended, flags).map(Some)
+    }
+
+    fn address(&mut self) -> Result<Option<SedAddress>, String> {
+        match self.peek() {
+            Some(c) if c.is_ascii_digit() => {
+                let first = self.number().unwrap_or(0);
+                if self.peek() == Some(b'~') {
+                    self.position += 1;
+                    let step = self.number().unwrap_or(0);
+                    return Ok(Some(SedAddress::Step(first, step)));
+                }
+                Ok(Some(SedAddress::Line(first)))
+            }
+            Some(b'$') => {
+                self.position += 1;
+                Ok(Some(SedAddress::Last))
+            }
+            Some(b'/') | Some(b'\\') => {
+                // '\cREGEXc' uses another delimiter
+                if self.peek() == Some(b'\\') {
+                    self.position += 1;
+                }
+                let delimiter = self.peek().ok_or("unexpected end of script")?;
+                self.position += 1;
+                let pattern = self
+                    .delimited(delimiter, true)
+                    .map_err(|_| String::from("unterminated address regex"))?;
+                let flags = self.regex_flags();
+                let regex = self.regex(&pattern, &flags).map_err(|e| match e.as_str() {
+                    "unterminated bracket expression" => String::from("unterminated address regex"),
+                    _ => e,
+                })?;
+                Ok(Some(SedAddress::Regex(regex)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn rest_of_line(&mut self) -> String {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c != b'\n') {
+            self.position += 1;
+        }
+        String::from_utf8_lossy(&self.script[start..self.position]).to_string()
+    }
+
+    fn label(&mut self) -> String {
+        // Labels end at a newline or ';'
+        self.skip_spaces();
+        let start = self.position;
+        while self.peek().is_some_and(|c| c != b'\n' && c != b';') {
+            self.position += 1;
+        }
+        String::from_utf8_lossy(&self.script[start..self.position])
+            .trim_end()
+            .to_string()
+    }
+
+    fn text(&mut self) -> Vec<u8> {
+        // The text of 'a', 'i' and 'c': 'a text', or 'a\' and the text on the next lines,
+        // where a line ending with '\' continues on the next one
+        self.skip_spaces();
+        if self.peek() == Some(b'\\') {
+            self.position += 1;
+            if self.peek() == Some(b'\n') {
+                self.position += 1;
+            }
+        }
+        let mut text = Vec::new();
+        while let Some(c) = self.peek() {
+            self.position += 1;
+            match c {
+                b'\n' => break,
+                b'\\' => {
+                    if let Some(next) = self.peek() {
+                        text.push(next);
+                        self.position += 1;
+                    }
+                }
+                _ => text.push(c),
+            }
+        }
+        text
+    }
+
+    fn end_of_command(&mut self) -> Result<(), String> {
+        self.skip_spaces();
+        match self.peek() {
+            None | Some(b'\n' | b';' | b'}' | b'#') => Ok(()),
+            _ => {
+                self.position += 1;
+                Err(String::from("extra characters after command"))
+            }
+        }
+    }
+
+    fn replacement(text: &[u8]) -> Vec<SedReplacement> {
+        // '&' is the whole match and '\1' to '\9' the groups
+        let mut parts = Vec::new();
+        let mut literal = Vec::new();
+        let mut index = 0;
+        while index < text.len() {
+            let group = match text[index] {
+                b'&' => Some(0),
+                b'\\' if index + 1 < text.len() => {
+                    index += 1;
+                    match text[index] {
+                        c @ b'0'..=b'9' => Some((c - b'0') as usize),
+                        c @ (b'U' | b'L' | b'E' | b'u' | b'l') => {
+                            if !literal.is_empty() {
+                                parts.push(SedReplacement::Literal(std::mem::take(&mut literal)));
+                            }
+                            parts.push(SedReplacement::Case(c));
+                            None
+                        }
+                        b'n' => {
+                            literal.push(b'\n');
+                            None
+                        }
+                        b't' => {
+                            literal.push(b'\t');
+                            None
+                        }
+                        c => {
+                            literal.push(c);
+                            None
+                        }
+                    }
+                }
+                c => {
+                    literal.push(c);
+                    None
+                }
+            };
+            if let Some(group) = group {
+                if !literal.is_empty() {
+                    parts.push(SedReplacement::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(SedReplacement::Group(group));
+            }
+            index += 1;
+        }
+        if !literal.is_empty() {
+            parts.push(SedReplacement::Literal(literal));
+        }
+        parts
+    }
+
+    fn parse(
+        &mut self,
+        writes: &mut Vec<(String, Option<Box<dyn Write>>)>,
+    ) -> Result<Vec<SedCommand>, String> {
+        let mut commands: Vec<SedCommand> = Vec::new();
+        let mut blocks: Vec<usize> = Vec::new();
+        let mut labels: Vec<(String, usize)> = Vec::new();
+        let mut branches: Vec<(usize, String)> = Vec::new();
+        loop {
+            while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b';')) {
+                self.position += 1;
+            }
+            let c = match self.peek() {
+                Some(b'#') => {
+                    self.rest_of_line();
+                    continue;
+                }
+                Some(_) => {
+                    let first = self.address()?;
+                    let mut second = None;
+                    if first.is_some() && self.peek() == Some(b',') {
+                        self.position += 1;
+                        self.skip_spaces();
+                        second = match self.peek() {
+                            Some(b'+') => {
+                                self.position += 1;
+                                Some(SedRangeEnd::Relative(
+                                    self.number().ok_or("expected number")?,
+                                ))
+                            }
+                            Some(b'~') => {
+                                self.position += 1;
+                                Some(SedRangeEnd::Multiple(
+                                    self.number().ok_or("expected number")?,
+                                ))
+                            }
+                            _ => Some(SedRangeEnd::Address(
+                                self.address()?.ok_or("unexpected ','")?,
+                            )),
+                        };
+                    }
+                    self.skip_spaces();
+                    let mut negate = false;
+                    while self.peek() == Some(b'!') {
+                        negate = true;
+                        self.position += 1;
+                        self.skip_spaces();
+                    }
+                    let c = self.peek().ok_or("missing command")?;
+                    self.position += 1;
+                    // 'addr0,/re/' is active from the start
+                    let active = matches!(first, Some(SedAddress::Line(0)));
+                    commands.push(SedCommand {
+                        first,
+                        second,
+                        negate,
+                        active,
+                        end_line: None,
+                        kind: SedKind::Label,
+                    });
+                    c
+                }
+                None => break,
+            };
+            let index = commands.len() - 1;
+            let kind = match c {
+                b'{' => {
+                    blocks.push(index);
+                    SedKind::Block(0)
+                }
+                b'}' => {
+                    let start = blocks.pop().ok_or("unexpected `}'")?;
+                    commands[start].kind = SedKind::Block(index);
+                    self.end_of_command()?;
+                    SedKind::BlockEnd
+                }
+                b':' => {
+                    if commands[index].first.is_some() {
+                        return Err(String::from(": doesn't want any addresses"));
+                    }
+                    let label = self.label();
+                    if label.is_empty() {
+                        return Err(String::from("\":\" lacks a label"));
+                    }
+                    labels.push((label, index));
+                    SedKind::Label
+                }
+                b'b' | b't' | b'T' => {
+                    let label = self.label();
+                    if !label.is_empty() {
+                        branches.push((index, label));
+                    }
+                    SedKind::Branch(c as char, None)
+                }
+                b'a' => SedKind::Append(self.text()),
+                b'i' => SedKind::Insert(self.text()),
+                b'c' => SedKind::Change(self.text()),
+                b'r' => {
+                    self.skip_spaces();
+                    SedKind::ReadFile(self.rest_of_line())
+                }
+                b'w' => {
+                    self.skip_spaces();
+                    let name = self.rest_of_line();
+                    SedKind::WriteFile(sed_write_file(writes, &name)?)
+                }
+                b'q' | b'Q' => {
+                    self.skip_spaces();
+                    let code = self.number().unwrap_or(0) as i32;
+                    self.end_of_command()?;
+                    SedKind::Quit(c as char, code)
+                }
+                b's' => {
+                    let delimiter = self.peek().ok_or("unterminated `s' command")?;
+                    if delimiter == b'\n' || delimiter == b'\\' {
+                        return Err(String::from("unterminated `s' command"));
+                    }
+                    self.position += 1;
+                    let unterminated = |_| String::from("unterminated `s' command");
+                    let pattern = self.delimited(delimiter, true).map_err(unterminated)?;
+                    let replacement = self.delimited(delimiter, true).map_err(unterminated)?;
+                    let mut substitution = SedSubstitution {
+                        regex: None,
+                        replacement: Self::replacement(&replacement),
+                        global: false,
+                        occurrence: 1,
+                        print: false,
+                        write: None,
+                    };
+                    let mut flags = String::new();
+                    loop {
+                        match self.peek() {
+                            Some(b'g') => substitution.global = true,
+                            Some(b'p') => substitution.print = true,
+                            Some(b'i' | b'I') => flags.push('i'),
+                            Some(b'm' | b'M') => flags.push('m'),
+                            Some(c) if c.is_ascii_digit() => {
+                                substitution.occurrence = self.number().unwrap_or(0);
+                                if substitution.occurrence == 0 {
+                                    return Err(String::from(
+                                        "number option to `s' command may not be zero",
+                                    ));
+                                }
+                                continue;
+                            }
+                            Some(b'w') => {
+                                self.position += 1;
+                                self.skip_spaces();
+                                let name = self.rest_of_line();
+                                substitution.write = Some(sed_write_file(writes, &name)?);
+                                break;
+                            }
+                            _ => {
+                                self.end_of_command()
+                                    .map_err(|_| String::from("unknown option to `s'"))?;
+                                break;
+                            }
+                        }
+                        self.position += 1;
+                    }
+                    substitution.regex = self.regex(&pattern, &flags)?;
+                    // The groups used in the replacement must exist
+                    if let Some(regex) = &substitution.regex {
+                        for part in &substitution.replacement {
+                            if let SedReplacement::Group(group) = part {
+                                if *group >= regex.captures_len() {
+                                    return Err(format!(
+                                        "invalid reference \\{} on `s' command's RHS",
+                                        group
+                                    ));
+                                }
+                            }
+                        }
+                    }
+                    SedKind::Substitute(Box::new(substitution))
+                }
+                b'y' => {
+                    let delimiter = self.peek().ok_or("unterminated `y' command")?;
+                    self.position += 1;
+                    let unescape = |text: Vec<u8>| -> Vec<u8> {
+                        let mut result = Vec::new();
+                        let mut iter = text.into_iter();
+                        while let Some(c) = iter.next() {
+                            if c == b'\\' {
+                                match iter.next() {
+                                    Some(b'n') => result.push(b'\n'),
+                                    Some(b't') => result.push(b'\t'),
+                                    Some(c) => result.push(c),
+                                    None => result.push(b'\\'),
+                                }
+                            } else {
+                                result.push(c);
+                            }
+                        }
+                        result
+                    };
+                    let unterminated = |_| String::from("unterminated `y' command");
+                    let source = unescape(self.delimited(delimiter, true).map_err(unterminated)?);
+                    let target = unescape(self.delimited(delimiter, true).map_err(unterminated)?);
+                    self.end_of_command()?;
+                    // Map characters, so multibyte UTF-8 characters work too
+                    let split = |text: &[u8]| -> Vec<Vec<u8>> {
+                        match std::str::from_utf8(text) {
+                            Ok(text) => text.chars().map(|c| c.to_string().into_bytes()).collect(),
+                            Err(_) => text.iter().map(|&b| vec![b]).collect(),
+                        }
+                    };
+                    let (source, target) = (split(&source), split(&target));
+                    if source.len() != target.len() {
+                        return Err(String::from(
+                            "strings for `y' command are different lengths",
+                        ));
+                    }
+                    SedKind::Translate(source.into_iter().zip(target).collect())
+                }
+                b'=' | b'd' | b'D' | b'g' | b'G' | b'h' | b'H' | b'x' | b'n' | b'N' | b'p'
+                | b'P' | b'z' => {
+                    self.end_of_command()?;
+                    SedKind::Simple(c as char)
+                }
+                _ => return Err(format!("unknown command: `{}'", c as char)),
+            };
+            commands[index].kind = kind;
+        }
+        if !blocks.is_empty() {
+            return Err(String::from("unmatched `{'"));
+        }
+        self.labels = labels;
+        self.branches = branches;
+        Ok(commands)
+    }
+
+    fn resolve(&self, commands: &mut [SedCommand]) -> Result<(), String> {
+        // Resolve the labels of the branches
+        for (index, label) in &self.branches {
+            let target = self
+                .labels
+                .iter()
+                .find(|(name, _)| name == label)
+                .map(|(_, target)| *target)
+                .ok_or_else(|| format!("can't find label for jump to `{}'", label))?;
+            if let SedKind::Branch(c, _) = commands[*index].kind {
+                commands[*index].kind = SedKind::Branch(c, Some(target));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn sed_write_file(
+    writes: &mut Vec<(String, Option<Box<dyn Write>>)>,
+    name: &str,
+) -> Result<usize, String> {
+    // Every 'w' file is truncated once, when the script is read
+    if let Some(index) = writes.iter().position(|(n, _)| n == name) {
+        return Ok(index);
+    }
+    // '/dev/stdout' is the normal output ('None'), so the lines stay in order
+    let file: Option<Box<dyn Write>> = match name {
+        "/dev/stdout" => None,
+        "/dev/stderr" => Some(Box::new(io::stderr())),
+        _ => Some(Box::new(io::BufWriter::new(File::create(name).map_err(
+            |e| format!("couldn't open file {}: {}", name, strerror(&e)),
+        )?))),
+    };
+    writes.push((name.to_string(), file));
+    Ok(writes.len() - 1)
+}
+
+struct SedInput {
+    names: Vec<String>,
+    next: usize,
+    reader: Option<Box<dyn BufRead>>,
+    // The next line is read in advance, to know if the current one is the last
+    pending: Option<(Vec<u8>, bool)>,
+    terminator: u8,
+    failed: bool,
+}
+
+impl SedInput {
+    fn fill(&mut self) -> io::Result<()> {
+        while self.pending.is_none() {
+            if let Some(reader) = &mut self.reader {
+                let mut line = Vec::new();
+                if reader.read_until(self.terminator, &mut line)? > 0 {
+                    // Remember if the line had a newline, to print it the same way
+                    let chomped = line.last() == Some(&self.terminator);
+                    if chomped {
+                        line.pop();
+                    }
+                    self.pending = Some((line, chomped));
+                    return Ok(());
+                }
+                self.reader = None;
+            }
+            if self.next >= self.names.len() {
+                return Ok(());
+            }
+            let name = &self.names[self.next];
+            self.next += 1;
+            match open_input(name) {
+                Ok(input) => self.reader = Some(Box::new(BufReader::new(input))),
+                Err(e) => {
+                    eprintln!("sed: can't read {}: {}", name, strerror(&e));
+                    self.failed = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn next_line(&mut self) -> io::Result<Option<(Vec<u8>, bool)>> {
+        self.fill()?;
+        Ok(self.pending.take())
+    }
+
+    fn is_last(&mut self) -> bool {
+        self.fill().is_err() || self.pending.is_none()
+    }
+}
+
+struct Sed {
+    quiet: bool,
+    terminator: u8,
+    hold: Vec<u8>,
+    last_regex: Option<regex::bytes::Regex>,
+    writes: Vec<(String, Option<Box<dyn Write>>)>,
+    out: Box<dyn Write>,
+    // Like GNU sed, a last line without a newline is printed without it, unless
+    // something else is printed after it
+    missing_newline: bool,
+    line: usize,
+    quit: Option<i32>,
+}
+
+impl Sed {
+    fn finish_line(&mut self) -> io::Result<()> {
+        if self.missing_newline {
+            self.out.write_all(&[self.terminator])?;
+            self.missing_newline = false;
+        }
+        Ok(())
+    }
+
+    fn write_file(&mut self, index: usize, text: &[u8], newline: bool) -> io::Result<()> {
+        match &mut self.writes[index].1 {
+            Some(file) => {
+                file.write_all(text)?;
+                if newline {
+                    file.write_all(&[self.terminator])?;
+                }
+                Ok(())
+            }
+            None => self.emit(text, newline),
+        }
+    }
+
+    fn emit(&mut self, text: &[u8], newline: bool) -> io::Result<()> {
+        self.finish_line()?;
+        self.out.write_all(text)?;
+        if newline {
+            self.out.write_all(&[self.terminator])?;
+        } else {
+            self.missing_newline = true;
+        }
+        Ok(())
+    }
+
+    fn address(&mut self, address: &SedAddress, space: &[u8], input: &mut SedInput) -> bool {
+        match address {
+            SedAddress::Line(line) => self.line == *line,
+            SedAddress::Last => input.is_last(),
+            SedAddress::Step(first, step) => {
+                if *step == 0 {
+                    self.line == *first
+                } else {
+                    self.line >= *first && (self.line - first).is_multiple_of(*step)
+                }
+            }
+            SedAddress::Regex(regex) => {
+                if let Some(regex) = regex {
+                    self.last_regex = Some(regex.clone());
+                }
+                self.last_regex
+                    .as_ref()
+                    .is_some_and(|regex| regex.is_match(space))
+            }
+        }
+    }
+
+    fn selected(&mut self, command: &mut SedCommand, space: &[u8], input: &mut SedInput) -> bool {
+        let matched = match (&command.first, &command.second) {
+            (None, _) => true,
+            (Some(first), None) => self.address(first, space, input),
+            (Some(first), Some(second)) => {
+                if command.active {
+                    match (command.end_line, second) {
+                        // A numeric end: like GNU sed, a line after it only matches the first address
+                        (Some(end), _) => {
+                            if self.line >= end {
+                                command.active = false;
+                            }
+                            self.line <= end || self.address(first, space, input)
+                        }
+                        (None, SedRangeEnd::Address(address)) => {
+                            if self.address(address, space, input) {
+                                command.active = false;
+                            }
+                            true
+                        }
+                        _ => true,
+                    }
+                } else if self.address(first, space, input) {
+                    // The end is checked starting with the next line, unless it is a number
+                    command.end_line = match second {
+                        SedRangeEnd::Address(SedAddress::Line(line)) => Some(*line),
+                        SedRangeEnd::Relative(count) => Some(self.line + count),
+                        SedRangeEnd::Multiple(0) => Some(self.line),
+                        SedRangeEnd::Multiple(multiple) => {
+                            Some(self.line.div_ceil(*multiple) * multiple)
+                        }
+                        _ => None,
+                    };
+                    command.active = command.end_line.is_none_or(|end| end > self.line);
+                    true
+                } else {
+                    false
+                }
+            }
+        };
+        matched != command.negate
+    }
+
+    fn substitute(&mut self, substitution: &SedSubstitution, space: &mut Vec<u8>) -> bool {
+        let regex = match &substitution.regex {
+            Some(regex) => {
+                self.last_regex = Some(regex.clone());
+                regex.clone()
+            }
+            None => match &self.last_regex {
+                Some(regex) => regex.clone(),
+                None => return false,
+            },
+        };
+        let mut result = Vec::new();
+        let mut copied = 0;
+        let mut position = 0;
+        let mut previous_end: Option<usize> = None;
+        let mut count = 0;
+        let mut replaced = false;
+        while position <= space.len() {
+            let captures = match regex.captures_at(space, position) {
+                Some(captures) => captures,
+                None => break,
+            };
+            let found = captures.get(0).map_or(0..0, |m| m.range());
+            // Like GNU sed, an empty match right after a match is skipped
+            if found.is_empty() && previous_end == Some(found.start) {
+                if found.start >= space.len() {
+                    break;
+                }
+                position = found.start + utf8_length(space[found.start]);
+                continue;
+            }
+            count += 1;
+            result.extend_from_slice(&space[copied..found.start]);
+            if count == substitution.occurrence
+                || (substitution.global && count > substitution.occurrence)
+            {
+                let mut case = (b'E', None);
+                for part in &substitution.replacement {
+                    match part {
+                        SedReplacement::Literal(text) => push_case(&mut result, text, &mut case),
+                        SedReplacement::Group(group) => {
+                            if let Some(m) = captures.get(*group) {
+                                push_case(&mut result, m.as_bytes(), &mut case);
+                            }
+                        }
+                        SedReplacement::Case(c @ (b'u' | b'l')) => case.1 = Some(*c),
+                        SedReplacement::Case(c) => case = (*c, None),
+                    }
+                }
+                replaced = true;
+            } else {
+                result.extend_from_slice(&space[found.clone()]);
+            }
+            copied = found.end;
+            previous_end = Some(found.end);
+            if replaced && !substitution.global {
+                break;
+            }
+            position = if found.is_empty() {
+                if found.end >= space.len() {
+                    break;
+                }
+                found.end + utf8_length(space[found.end])
+            } else {
+                found.end
+            };
+        }
+        if replaced {
+            result.extend_from_slice(&space[copied..]);
+            *space = result;
+        }
+        replaced
+    }
+
+    fn run(&mut self, commands: &mut [SedCommand], input: &mut SedInput) -> io::Result<()> {
+        let mut space: Vec<u8> = Vec::new();
+        let mut chomped = true;
+        // 'D' starts the next cycle without reading a new line
+        let mut restart = false;
+        let mut substituted = false;
+        while self.quit.is_none() {
+            if !restart {
+                match input.next_line()? {
+                    Some((line, had_newline)) => {
+                        space = line;
+                        chomped = had_newline;
+                        self.line += 1;
+                        substituted = false;
+                    }
+                    None => break,
+                }
+            }
+            restart = false;
+            let mut autoprint = !self.quiet;
+            let mut stop = false;
+            // The text of 'a' and the files of 'r', printed at the end of the cycle
+            let mut appended: Vec<(bool, Vec<u8>)> = Vec::new();
+            let mut index = 0;
+            while index < commands.len() {
+                if !self.selected(&mut commands[index], &space, input) {
+                    index = match commands[index].kind {
+                        SedKind::Block(end) => end + 1,
+                        _ => index + 1,
+                    };
+                    continue;
+                }
+                match &commands[index].kind {
+                    SedKind::Block(_) | SedKind::BlockEnd | SedKind::Label => (),
+                    SedKind::Branch(c, target) => {
+                        let jump = match c {
+                            't' => substituted,
+                            'T' => !substituted,
+                            _ => true,
+                        };
+                        if *c != 'b' {
+                            substituted = false;
+                        }
+                        if jump {
+                            index = target.unwrap_or(commands.len());
+                            continue;
+                        }
+                    }
+                    SedKind::Append(text) => appended.push((false, text.clone())),
+                    SedKind::ReadFile(name) => appended.push((true, name.clone().into_bytes())),
+                    SedKind::Insert(text) => {
+                        let text = text.clone();
+                        self.emit(&text, true)?;
+                    }
+                    SedKind::Change(text) => {
+                        // In a range, the text is printed once, at its end
+                        let command = &commands[index];
+                        if command.second.is_none() || command.negate || !command.active {
+                            let text = text.clone();
+                            self.emit(&text, true)?;
+                        }
+                        autoprint = false;
+                        break;
+                    }
+                    SedKind::WriteFile(file) => self.write_file(*file, &space, chomped)?,
+                    SedKind::Substitute(substitution) => {
+                        if self.substitute(substitution, &mut space) {
+                            substituted = true;
+                            if substitution.print {
+                                self.emit(&space, chomped)?;
+                            }
+                            if let Some(file) = substitution.write {
+                                self.write_file(file, &space, chomped)?;
+                            }
+                        }
+                    }
+                    SedKind::Translate(pairs) => {
+                        let mut result = Vec::with_capacity(space.len());
+                        let mut position = 0;
+                        while position < space.len() {
+                            let length = utf8_length(space[position]).min(space.len() - position);
+                            let c = &space[position..position + length];
+                            match pairs.iter().find(|(from, _)| from.as_slice() == c) {
+                                Some((_, to)) => result.extend_from_slice(to),
+                                None => result.extend_from_slice(c),
+                            }
+                            position += length;
+                        }
+                        space = result;
+                    }
+                    SedKind::Quit(c, code) => {
+                        self.quit = Some(*code);
+                        if *c == 'Q' {
+                            autoprint = false;
+                            appended.clear();
+                        }
+                        break;
+                    }
+                    SedKind::Simple(c) => match c {
+                        '=' => {
+                            let number = format!("{}\n", self.line);
+                            self.finish_line()?;
+                            self.out.write_all(number.as_bytes())?;
+                        }
+                        'd' => {
+                            autoprint = false;
+                            break;
+                        }
+                        'D' => {
+                            autoprint = false;
+                            if let Some(newline) = space.iter().position(|&b| b == b'\n') {
+                                space.drain(..=newline);
+                                restart = true;
+                            }
+                            break;
+                        }
+                        'g' => space = self.hold.clone(),
+                        'G' => {
+                            space.push(b'\n');
+                            space.extend_from_slice(&self.hold);
+                        }
+                        'h' => self.hold = space.clone(),
+                        'H' => {
+                            self.hold.push(b'\n');
+                            self.hold.extend_from_slice(&space);
+                        }
+                        'x' => std::mem::swap(&mut space, &mut self.hold),
+                        'n' | 'N' => {
+                            // Without a next line, GNU sed prints the pattern space and ends
+                            if input.is_last() {
+                                stop = true;
+                                break;
+                            }
+                            if *c == 'n' {
+                                if !self.quiet {
+                                    self.emit(&space, chomped)?;
+                                }
+                                self.flush_appended(&mut appended)?;
+                            }
+                            if let Some((line, had_newline)) = input.next_line()? {
+                                if *c == 'n' {
+                                    space = line;
+                                } else {
+                                    space.push(b'\n');
+                                    space.extend_from_slice(&line);
+                                }
+                                chomped = had_newline;
+                                self.line += 1;
+                            }
+                        }
+                        'p' => self.emit(&space, chomped)?,
+                        'P' => match space.iter().position(|&b| b == b'\n') {
+                            Some(end) => {
+                                let first = space[..end].to_vec();
+                                self.emit(&first, true)?;
+                            }
+                            None => self.emit(&space, chomped)?,
+                        },
+                        'z' => space.clear(),
+                        _ => (),
+                    },
+                }
+                index += 1;
+            }
+            if autoprint {
+                self.emit(&space, chomped)?;
+            }
+            self.flush_appended(&mut appended)?;
+            if stop {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_appended(&mut self, appended: &mut Vec<(bool, Vec<u8>)>) -> io::Result<()> {
+        for (file, text) in appended.drain(..) {
+            if file {
+                // A file which can't be read is ignored
+                if let Ok(contents) = fs::read(OsStr::from_bytes(&text)) {
+                    if !contents.is_empty() {
+                        self.finish_line()?;
+                        self.out.write_all(&contents)?;
+                    }
+                }
+            } else {
+                self.emit(&text, true)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn push_case(result: &mut Vec<u8>, text: &[u8], case: &mut (u8, Option<u8>)) {
+    // Convert the text with the case mode and the case of the next character
+    let text = match std::str::from_utf8(text) {
+        Ok(text) => text,
+        Err(_) => {
+            result.extend_from_slice(text);
+            return;
+        }
+    };
+    for c in text.chars() {
+        let mode = case.1.take().unwrap_or(case.0);
+        let converted: String = match mode {
+            b'U' | b'u' => c.to_uppercase().collect(),
+            b'L' | b'l' => c.to_lowercase().collect(),
+            _ => c.to_string(),
+        };
+        result.extend_from_slice(converted.as_bytes());
+    }
+}
+
+fn utf8_length(first: u8) -> usize {
+    match first {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 1,
+    }
+}
+
+fn sed(args: Vec<String>) -> Result<(), io::Error> {
+    let mut quiet = false;
+    let mut extended = false;
+    let mut separate = false;
+    let mut zero = false;
+    // 'Some' with the suffix of the backups (maybe empty) when editing in place
+    let mut in_place: Option<String> = None;
+    let mut scripts: Vec<String> = Vec::new();
+    let mut operands: Vec<String> = Vec::new();
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let read_script = |name: &str| -> io::Result<String> {
+        let mut text = String::new();
+        let result = if name == "-" {
+            io::stdin().read_to_string(&mut text).map(|_| ())
+        } else {
+            File::open(name).and_then(|mut file| file.read_to_string(&mut text).map(|_| ()))
+        };
+        match result {
+            Ok(()) => Ok(text.strip_suffix('\n').unwrap_or(&text).to_string()),
+            Err(e) => {
+                eprintln!("sed: couldn't open file {}: {}", name, strerror(&e));
+                Err(e)
+            }
+        }
+    };
+
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--quiet" | "--silent" => quiet = true,
+            "--regexp-extended" => extended = true,
+            "--separate" => separate = true,
+            "--null-data" => zero = true,
+            "--in-place" => in_place = Some(String::new()),
+            "--posix" | "--unbuffered" => (),
+            "--expression" | "--file" => {
+                let value = iter
+                    .next()
+                    .ok_or_else(|| invalid(String::from("Option requires a value.")))?;
+                if arg == "--file" {
+                    scripts.push(read_script(value)?);
+                } else {
+                    scripts.push(value.clone());
+                }
+            }
+            _ if arg.starts_with("--expression=") => {
+                scripts.push(arg["--expression=".len()..].to_string())
+            }
+            _ if arg.starts_with("--file=") => scripts.push(read_script(&arg["--file=".len()..])?),
+            _ if arg.starts_with("--in-place=") => {
+                in_place = Some(arg["--in-place=".len()..].to_string())
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                // Short options can be grouped, like '-ne p' or '-i.bak'
+                for (index, c) in arg.char_indices().skip(1) {
+                    match c {
+                        'n' => quiet = true,
+                        'E' | 'r' => extended = true,
+                        's' => separate = true,
+                        'z' => zero = true,
+                        'u' => (),
+                        'i' => {
+                            in_place = Some(arg[index + c.len_utf8()..].to_string());
+                            break;
+                        }
+                        'e' | 'f' => {
+                            // The value is the rest of the argument or the next argument
+                            let rest = &arg[index + c.len_utf8()..];
+                            let value = if rest.is_empty() {
+                                iter.next()
+                                    .ok_or_else(|| {
+                                        invalid(String::from("Option requires a value."))
+                                    })?
+                                    .clone()
+                            } else {
+                                rest.to_string()
+                            };
+                            if c == 'f' {
+                                scripts.push(read_script(&value)?);
+                            } else {
+                                scripts.push(value);
+                            }
+                            break;
+                        }
+                        _ => {
+                            eprintln!("sed: invalid option -- '{}'", c);
+                            return Err(invalid(String::from("Can't use 'sed' with this option.")));
+                        }
+                    }
+                }
+            }
+            _ => operands.push(arg.clone()),
+        }
+    }
+    // Without '-e' or '-f', the first operand is the script
+    if scripts.is_empty() {
+        if operands.is_empty() {
+            eprintln!("sed: no script specified");
+            return Err(invalid(String::from("Missing script.")));
+        }
+        scripts.push(operands.remove(0));
+    }
+    let script = scripts.join("\n");
+    // '#n' on the first line is the same as '-n'
+    if script == "#n" || script.starts_with("#n\n") {
+        quiet = true;
+    }
+    let mut writes: Vec<(String, Option<Box<dyn Write>>)> = Vec::new();
+    let mut parser = SedParser {
+        script: script.as_bytes(),
+        position: 0,
+        extended,
+        labels: Vec::new(),
+        branches: Vec::new(),
+    };
+    let mut commands = match parser.parse(&mut writes) {
+        Ok(commands) => commands,
+        Err(message) => {
+            eprintln!(
+                "sed: -e expression #1, char {}: {}",
+                parser.position, message
+            );
+            return Err(invalid(message));
+        }
+    };
+    if let Err(message) = parser.resolve(&mut commands) {
+        eprintln!("sed: {}", message);
+        return Err(invalid(message));
+    }
+
+    let terminator = if zero { b'\0' } else { b'\n' };
+    let mut state = Sed {
+        quiet,
+        terminator,
+        hold: Vec::new(),
+        last_regex: None,
+        writes,
+        out: Box::new(io::BufWriter::new(io::stdout())),
+        missing_newline: false,
+        line: 0,
+        quit: None,
+    };
+    if operands.is_empty() {
+        operands.push(String::from("-"));
+    }
+    let new_input = |names: Vec<String>| SedInput {
+        names,
+        next: 0,
+        reader: None,
+        pending: None,
+        terminator,
+        failed: false,
+    };
+    let mut failed = false;
+
+    if let Some(suffix) = &in_place {
+        for name in &operands {
+            if state.quit.is_some() {
+                break;
+            }
+            match sed_in_place(&mut state, &mut commands, name, suffix, &new_input) {
+                Ok(read) => failed |= !read,
+                Err(e) => {
+                    eprintln!("sed: couldn't edit {}: {}", name, strerror(&e));
+                    failed = true;
+                }
+            }
+        }
+    } else if separate {
+        // Every file has its own line numbers, last line and ranges
+        for name in &operands {
+            if state.quit.is_some() {
+                break;
+            }
+            reset_ranges(&mut commands);
+            state.line = 0;
+            let mut input = new_input(vec![name.clone()]);
+            state.run(&mut commands, &mut input)?;
+            failed |= input.failed;
+        }
+    } else {
+        let mut input = new_input(operands.clone());
+        state.run(&mut commands, &mut input)?;
+        failed |= input.failed;
+    }
+    state.out.flush()?;
+    for file in state
+        .writes
+        .iter_mut()
+        .filter_map(|(_, file)| file.as_mut())
+    {
+        file.flush()?;
+    }
+    if let Some(code) = state.quit.filter(|&code| code != 0) {
+        std::process::exit(code);
+    }
+    if failed {
+        return Err(io::Error::other("Some files couldn't be read."));
+    }
+    Ok(())
+}
+
+fn reset_ranges(commands: &mut [SedCommand]) {
+    for command in commands.iter_mut() {
+        command.active = matches!(command.first, Some(SedAddress::Line(0)));
+        command.end_line = None;
+    }
+}
+
+fn sed_in_place(
+    state: &mut Sed,
+    commands: &mut [SedCommand],
+    name: &str,
+    suffix: &str,
+    new_input: &dyn Fn(Vec<String>) -> SedInput,
+) -> io::Result<bool> {
+    let path = Path::new(name);
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            eprintln!("sed: can't read {}: {}", name, strerror(&e));
+            return Ok(false);
+        }
+    };
+    if !metadata.is_file() {
+        return Err(io::Error::other("not a regular file"));
+    }
+    // Write the output in a temporary file next to the original, with its mode and owner,
+    // and rename it over the original at the end, so the file is replaced atomically
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let (file, temp) = temp_file(dir, "sed")?;
+    let result = (|| {
+        file.set_permissions(metadata.permissions())?;
+        unsafe { libc::fchown(file.as_raw_fd(), metadata.uid(), metadata.gid()) };
+        reset_ranges(commands);
+        state.line = 0;
+        state.missing_newline = false;
+        let stdout = std::mem::replace(&mut state.out, Box::new(io::BufWriter::new(file)));
+        let mut input = new_input(vec![name.to_string()]);
+        let result = state.run(commands, &mut input);
+        let mut output = std::mem::replace(&mut state.out, stdout);
+        result?;
+        output.flush()?;
+        drop(output);
+        // The backup name is the file name with the suffix, or the suffix with '*' replaced by it
+        if !suffix.is_empty() {
+            let backup = if suffix.contains('*') {
+                let base = path
+                    .file_name()
+                    .map_or(String::new(), |n| n.to_string_lossy().to_string());
+                dir.join(suffix.replace('*', &base))
+            } else {
+                PathBuf::from(format!("{}{}", name, suffix))
+            };
+            fs::rename(path, backup)?;
+        }
+        fs::rename(&temp, path)?;
+        Ok(!input.failed)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    state.missing_newline = false;
+    result
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum AwkToken {
+    Number(f64),
+    Str(String),
+    Regex(String),
+    Name(String),
+    // A name directly followed by '(', the call of a user function
+    Func(String),
+    Builtin(String),
+    Keyword(String),
+    Punct(&'static str),
+    Newline,
+    End,
+}
+
+const AWK_KEYWORDS: &[&str] = &[
+    "BEGIN", "END", "function", "func", "if", "else", "while", "for", "do", "break", "continue",
+    "next", "nextfile", "exit", "return", "delete", "in", "getline", "print", "printf",
+];
+
+const AWK_BUILTINS: &[&str] = &[
+    "length", "substr", "index", "split", "sub", "gsub", "match", "sprintf", "sin", "cos", "atan2",
+    "exp", "log", "sqrt", "int", "rand", "srand", "tolower", "toupper", "system", "close",
+    "fflush",
+];
+
+// Longer operators first, so '+=' isn't read as '+' and '='
+const AWK_PUNCTS: &[&str] = &[
+    "**=", "+=", "-=", "*=", "/=", "%=", "^=", "==", "<=", ">=", "!=", "++", "--", "&&", "||",
+    ">>", "!~", "**", "{", "}", "(", ")", "[", "]", ";", ",", "+", "-", "*", "/", "%", "^", "!",
+    ">", "<", "|", "?", ":", "~", "$", "=",
+];
+
+// Calls of user functions can't be nested deeper than this
+const AWK_MAX_DEPTH: usize = 10000;
+
+// The special variables, always the first globals
+const AWK_NR: usize = 0;
+const AWK_NF: usize = 1;
+const AWK_FNR: usize = 2;
+const AWK_FS: usize = 3;
+const AWK_OFS: usize = 4;
+const AWK_ORS: usize = 5;
+const AWK_RS: usize = 6;
+const AWK_FILENAME: usize = 7;
+const AWK_SUBSEP: usize = 8;
+const AWK_RSTART: usize = 9;
+const AWK_RLENGTH: usize = 10;
+const AWK_CONVFMT: usize = 11;
+const AWK_OFMT: usize = 12;
+const AWK_ENVIRON: usize = 13;
+const AWK_ARGC: usize = 14;
+const AWK_ARGV: usize = 15;
+const AWK_SPECIALS: [&str; 16] = [
+    "NR", "NF", "FNR", "FS", "OFS", "ORS", "RS", "FILENAME", "SUBSEP", "RSTART", "RLENGTH",
+    "CONVFMT", "OFMT", "ENVIRON", "ARGC", "ARGV",
+];
+
+fn awk_escape(chars: &[char], position: &mut usize, out: &mut String) {
+    // 'position' is on the backslash, and is moved after the escape sequence
+    *position += 1;
+    let c = match chars.get(*position) {
+        Some(c) => *c,
+        None => {
+            out.push('\\');
+            return;
+        }
+    };
+    *position += 1;
+    match c {
+        'n' => out.push('\n'),
+        't' => out.push('\t'),
+        'r' => out.push('\r'),
+        'a' => out.push('\x07'),
+        'b' => out.push('\x08'),
+        'f' => out.push('\x0c'),
+        'v' => out.push('\x0b'),
+        '\\' | '"' | '/' => out.push(c),
+        '0'..='7' => {
+            // Up to three octal digits
+            let mut value = c.to_digit(8).unwrap_or(0);
+            for _ in 0..2 {
+                match chars.get(*position).and_then(|d| d.to_digit(8)) {
+                    Some(d) => {
+                        value = value * 8 + d;
+                        *position += 1;
+                    }
+                    None => break,
+                }
+            }
+            out.push(char::from_u32(value).unwrap_or('?'));
+        }
+        // Unknown escapes are kept, so "\." still means a literal dot in a dynamic regex
+        _ => {
+            out.push('\\');
+            out.push(c);
+        }
+    }
+}
+
+fn awk_lex(source: &str) -> Result<Vec<(AwkToken, usize)>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens: Vec<(AwkToken, usize)> = Vec::new();
+    let mut line = 1;
+    let mut index = 0;
+    let is_digit = |c: Option<&char>| c.is_some_and(|c| c.is_ascii_digit());
+    while index < chars.len() {
+        let c = chars[index];
+        // A '/' after an operand is a division, otherwise it starts a regex
+        let operand = matches!(
+            tokens.last(),
+            Some((
+                AwkToken::Number(_)
+                    | AwkToken::Str(_)
+                    | AwkToken::Name(_)
+                    | AwkToken::Builtin(_)
+                    | AwkToken::Punct(")" | "]" | "$" | "++" | "--"),
+                _
+            ))
+        );
+        match c {
+            ' ' | '\t' | '\r' => index += 1,
+            '\\' if chars.get(index + 1) == Some(&'\n') => {
+                index += 2;
+                line += 1;
+            }
+            '#' => {
+                while index < chars.len() && chars[index] != '\n' {
+                    index += 1;
+                }
+            }
+            '\n' => {
+                tokens.push((AwkToken::Newline, line));
+                line += 1;
+                index += 1;
+            }
+            '"' => {
+                let mut text = String::new();
+                index += 1;
+                loop {
+                    match chars.get(index) {
+                        None | Some('\n') => {
+                            return Err(format!("non-terminated string at source line {}", line))
+                        }
+                        Some('"') => {
+                            index += 1;
+                            break;
+                        }
+                        Some('\\') if chars.get(index + 1) == Some(&'\n') => {
+                            index += 2;
+                            line += 1;
+                        }
+                        Some('\\') => awk_escape(&chars, &mut index, &mut text),
+                        Some(d) => {
+                            text.push(*d);
+                            index += 1;
+                        }
+                    }
+                }
+                tokens.push((AwkToken::Str(text), line));
+            }
+            '/' if !operand => {
+                let mut text = String::new();
+                // A '/' inside a bracket expression doesn't end the regex
+                let mut bracket = false;
+                index += 1;
+                loop {
+                    let d = match chars.get(index) {
+                        None | Some('\n') => {
+                            return Err(format!(
+                                "non-terminated regular expression at source line {}",
+                                line
+                            ))
+                        }
+                        Some(d) => *d,
+                    };
+                    index += 1;
+                    match d {
+                        '/' if !bracket => break,
+                        '\\' if chars.get(index) == Some(&'/') => {
+                            text.push('/');
+                            index += 1;
+                        }
+                        '\\' => {
+                            text.push('\\');
+                            if let Some(e) = chars.get(index) {
+                                text.push(*e);
+                                index += 1;
+                            }
+                        }
+                        '[' if !bracket => {
+                            bracket = true;
+                            text.push('[');
+                            if chars.get(index) == Some(&'^') {
+                                text.push('^');
+                                index += 1;
+                            }
+                            if chars.get(index) == Some(&']') {
+                                text.push(']');
+                                index += 1;
+                            }
+                        }
+                        // '[:alpha:]' inside a bracket expression
+                        '[' if chars.get(index) == Some(&':') => {
+                            text.push('[');
+                            while let Some(e) = chars.get(index) {
+                                text.push(*e);
+                                index += 1;
+                                if *e == ']' && text.ends_with(":]") {
+                                    break;
+                                }
+                            }
+                        }
+                        ']' if bracket => {
+                            bracket = false;
+                            text.push(']');
+                        }
+                        _ => text.push(d),
+                    }
+                }
+                tokens.push((AwkToken::Regex(text), line));
+            }
+            _ if c.is_ascii_digit() || (c == '.' && is_digit(chars.get(index + 1))) => {
+                let start = index;
+                while is_digit(chars.get(index)) {
+                    index += 1;
+                }
+                if chars.get(index) == Some(&'.') {
+                    index += 1;
+                    while is_digit(chars.get(index)) {
+                        index += 1;
+                    }
+                }
+                if matches!(chars.get(index), Some('e' | 'E')) {
+                    let mut next = index + 1;
+                    if matches!(chars.get(next), Some('+' | '-')) {
+                        next += 1;
+                    }
+                    if is_digit(chars.get(next)) {
+                        index = next;
+                        while is_digit(chars.get(index)) {
+                            index += 1;
+                        }
+                    }
+                }
+                let text: String = chars[start..index].iter().collect();
+                tokens.push((AwkToken::Number(text.parse().unwrap_or(0.0)), line));
+            }
+            _ if c.is_ascii_alphabetic() || c == '_' => {
+                let start = index;
+                while chars
+                    .get(index)
+                    .is_some_and(|d| d.is_ascii_alphanumeric() || *d == '_')
+                {
+                    index += 1;
+                }
+                let word: String = chars[start..index].iter().collect();
+                let token = if AWK_KEYWORDS.contains(&word.as_str()) {
+                    AwkToken::Keyword(if word == "func" {
+                        String::from("function")
+                    } else {
+                        word
+                    })
+                } else if AWK_BUILTINS.contains(&word.as_str()) {
+                    AwkToken::Builtin(word)
+                } else if chars.get(index) == Some(&'(') {
+                    AwkToken::Func(word)
+                } else {
+                    AwkToken::Name(word)
+                };
+                tokens.push((token, line));
+            }
+            _ => {
+                let rest: String = chars[index..(index + 3).min(chars.len())].iter().collect();
+                match AWK_PUNCTS.iter().find(|punct| rest.starts_with(**punct)) {
+                    Some(punct) => {
+                        index += punct.len();
+                        tokens.push((AwkToken::Punct(punct), line));
+                    }
+                    None => return Err(format!("syntax error at source line {}", line)),
+                }
+            }
+        }
+    }
+    tokens.push((AwkToken::End, line));
+    Ok(tokens)
+}
+
+#[derive(Clone, Copy, Debug)]
+enum AwkVar {
+    Global(usize),
+    // A parameter of the function being run
+    Local(usize),
+}
+
+enum AwkSource {
+    Main,
+    File(Box<AwkExpr>),
+    Command(Box<AwkExpr>),
+}
+
+enum AwkExpr {
+    Number(f64),
+    Str(String),
+    // A regex by itself matches the current record
+    Regex(Rc<regex::bytes::Regex>),
+    Var(AwkVar),
+    Field(Box<AwkExpr>),
+    Index(AwkVar, Vec<AwkExpr>),
+    // The operator of compound assignments, like '+' for '+='
+    Assign(Box<AwkExpr>, Option<char>, Box<AwkExpr>),
+    Condition(Box<AwkExpr>, Box<AwkExpr>, Box<AwkExpr>),
+    And(Box<AwkExpr>, Box<AwkExpr>),
+    Or(Box<AwkExpr>, Box<AwkExpr>),
+    Not(Box<AwkExpr>),
+    In(Vec<AwkExpr>, AwkVar),
+    // 'true' for '!~'
+    Match(bool, Box<AwkExpr>, Box<AwkExpr>),
+    Binary(char, Box<AwkExpr>, Box<AwkExpr>),
+    Compare(&'static str, Box<AwkExpr>, Box<AwkExpr>),
+    Concat(Box<AwkExpr>, Box<AwkExpr>),
+    Negate(Box<AwkExpr>),
+    Plus(Box<AwkExpr>),
+    // The target, the change and whether the new value is the result
+    Increment(Box<AwkExpr>, f64, bool),
+    Call(String, Vec<AwkExpr>),
+    Builtin(String, Vec<AwkExpr>),
+    Getline(AwkSource, Option<Box<AwkExpr>>),
+    // '(a, b)', only valid as the arguments of print and printf
+    List(Vec<AwkExpr>),
+}
+
+type AwkRedirect = Option<(&'static str, AwkExpr)>;
+
+enum AwkStmt {
+    Expr(AwkExpr),
+    Print(Vec<AwkExpr>, AwkRedirect),
+    Printf(Vec<AwkExpr>, AwkRedirect),
+    If(AwkExpr, Box<AwkStmt>, Option<Box<AwkStmt>>),
+    While(AwkExpr, Box<AwkStmt>),
+    Do(Box<AwkStmt>, AwkExpr),
+    For(
+        Option<Box<AwkStmt>>,
+        Option<AwkExpr>,
+        Option<Box<AwkStmt>>,
+        Box<AwkStmt>,
+    ),
+    ForIn(AwkExpr, AwkVar, Box<AwkStmt>),
+    Block(Vec<AwkStmt>),
+    Delete(AwkVar, Option<Vec<AwkExpr>>),
+    Next,
+    NextFile,
+    Break,
+    Continue,
+    Exit(Option<AwkExpr>),
+    Return(Option<AwkExpr>),
+}
+
+enum AwkPattern {
+    Begin,
+    End,
+    All,
+    Expr(AwkExpr),
+    Range(AwkExpr, AwkExpr),
+}
+
+struct AwkItem {
+    pattern: AwkPattern,
+    // Without an action, the record is printed
+    action: Option<Vec<AwkStmt>>,
+}
+
+struct AwkFunction {
+    // One entry per parameter, 'true' if it's used as an array
+    arrays: Vec<bool>,
+    body: Vec<AwkStmt>,
+}
+
+struct AwkParser {
+    tokens: Vec<(AwkToken, usize)>,
+    position: usize,
+    globals: HashMap<String, usize>,
+    // The parameters of the function being parsed
+    locals: Option<(Vec<String>, Vec<bool>)>,
+}
+
+impl AwkParser {
+    fn peek(&self) -> &AwkToken {
+        &self.tokens[self.position].0
+    }
+
+    fn peek_at(&self, offset: usize) -> &AwkToken {
+        &self.tokens[(self.position + offset).min(self.tokens.len() - 1)].0
+    }
+
+    fn advance(&mut self) -> AwkToken {
+        let token = self.tokens[self.position].0.clone();
+        if self.position + 1 < self.tokens.len() {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn is(&self, punct: &str) -> bool {
+        matches!(self.peek(), AwkToken::Punct(p) if *p == punct)
+    }
+
+    fn is_keyword(&self, word: &str) -> bool {
+        matches!(self.peek(), AwkToken::Keyword(w) if w == word)
+    }
+
+    fn error<T>(&self) -> Result<T, String> {
+        Err(format!(
+            "syntax error at source line {}",
+            self.tokens[self.position].1
+        ))
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), String> {
+        if !self.is(punct) {
+            return self.error();
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn newlines(&mut self) {
+        while *self.peek() == AwkToken::Newline {
+            self.advance();
+        }
+    }
+
+    fn separators(&mut self) {
+        while *self.peek() == AwkToken::Newline || self.is(";") {
+            self.advance();
+        }
+    }
+
+    fn variable(&mut self, name: &str) -> AwkVar {
+        if let Some((names, _)) = &self.locals {
+            if let Some(index) = names.iter().position(|local| local == name) {
+                return AwkVar::Local(index);
+            }
+        }
+        let next = self.globals.len();
+        AwkVar::Global(*self.globals.entry(name.to_string()).or_insert(next))
+    }
+
+    fn array(&mut self, name: &str) -> AwkVar {
+        let var = self.variable(name);
+        if let (AwkVar::Local(index), Some((_, arrays))) = (var, self.locals.as_mut()) {
+            arrays[index] = true;
+        }
+        var
+    }
+
+    fn regex(&self, text: &str) -> Result<Rc<regex::bytes::Regex>, String> {
+        posix_regex(text.as_bytes(), true, "")
+            .map(Rc::new)
+            .map_err(|e| format!("{}: /{}/", e, text))
+    }
+
+    fn program(
+        &mut self,
+        functions: &mut HashMap<String, Rc<AwkFunction>>,
+    ) -> Result<Vec<AwkItem>, String> {
+        let mut items = Vec::new();
+        loop {
+            self.separators();
+            let word = match self.peek() {
+                AwkToken::End => break,
+                AwkToken::Keyword(word) => word.clone(),
+                _ => String::new(),
+            };
+            match word.as_str() {
+                "function" => self.function(functions)?,
+                "BEGIN" | "END" => {
+                    self.advance();
+                    if !self.is("{") {
+                        return self.error();
+                    }
+                    items.push(AwkItem {
+                        pattern: if word == "BEGIN" {
+                            AwkPattern::Begin
+                        } else {
+                            AwkPattern::End
+                        },
+                        action: Some(self.block()?),
+                    });
+                }
+                _ if self.is("{") => items.push(AwkItem {
+                    pattern: AwkPattern::All,
+                    action: Some(self.block()?),
+                }),
+                _ => {
+                    let first = self.expr(false)?;
+                    let pattern = if self.is(",") {
+                        self.advance();
+                        self.newlines();
+                        AwkPattern::Range(first, self.expr(false)?)
+                    } else {
+                        AwkPattern::Expr(first)
+                    };
+                    let action = if self.is("{") {
+                        Some(self.block()?)
+                    } else {
+                        None
+                    };
+                    items.push(AwkItem { pattern, action });
+                }
+            }
+        }
+        Ok(items)
+    }
+
+    fn function(&mut self, functions: &mut HashMap<String, Rc<AwkFunction>>) -> Result<(), String> {
+        self.advance();
+        let name = match self.advance() {
+            AwkToken::Name(name) | AwkToken::Func(name) => name,
+            _ => return self.error(),
+        };
+        self.expect("(")?;
+        let mut params = Vec::new();
+        while !self.is(")") {
+            match self.advance() {
+                AwkToken::Name(param) => params.push(param),
+                _ => return self.error(),
+            }
+            if self.is(",") {
+                self.advance();
+                self.newlines();
+            } else if !self.is(")") {
+                return self.error();
+            }
+        }
+        self.advance();
+        self.newlines();
+        let count = params.len();
+        self.locals = Some((params, vec![false; count]));
+        let body = self.block()?;
+        let arrays = self
+            .locals
+            .take()
+            .map(|(_, arrays)| arrays)
+            .unwrap_or_default();
+        if functions.contains_key(&name) {
+            return Err(format!("function {} redefined", name));
+        }
+        functions.insert(name, Rc::new(AwkFunction { arrays, body }));
+        Ok(())
+    }
+
+    fn block(&mut self) -> Result<Vec<AwkStmt>, String> {
+        self.expect("{")?;
+        let mut statements = Vec::new();
+        loop {
+            self.separators();
+            if self.is("}") {
+                self.advance();
+                return Ok(statements);
+            }
+            if *self.peek() == AwkToken::End {
+                return self.error();
+            }
+            statements.push(self.statement()?);
+        }
+    }
+
+    // The body of 'if', 'while' and 'for', where a lone ';' is an empty statement
+    fn body(&mut self) -> Result<AwkStmt, String> {
+        if self.is(";") {
+            self.advance();
+            return Ok(AwkStmt::Block(Vec::new()));
+        }
+        self.newlines();
+        self.statement()
+    }
+
+    fn statement(&mut self) -> Result<AwkStmt, String> {
+        if self.is("{") {
+            return Ok(AwkStmt::Block(self.block()?));
+        }
+        let word = match self.peek() {
+            AwkToken::Keyword(word) => word.clone(),
+            _ => String::new(),
+        };
+        match word.as_str() {
+            "if" => {
+                self.advance();
+                self.expect("(")?;
+                let condition = self.expr(false)?;
+                self.expect(")")?;
+                let then = self.body()?;
+                // The 'else' may be on a later line
+                let save = self.position;
+                self.separators();
+                if self.is_keyword("else") {
+                    self.advance();
+                    self.newlines();
+                    let other = self.statement()?;
+                    Ok(AwkStmt::If(
+                        condition,
+                        Box::new(then),
+                        Some(Box::new(other)),
+                    ))
+                } else {
+                    self.position = save;
+                    Ok(AwkStmt::If(condition, Box::new(then), None))
+                }
+            }
+            "while" => {
+                self.advance();
+                self.expect("(")?;
+                let condition = self.expr(false)?;
+                self.expect(")")?;
+                Ok(AwkStmt::While(condition, Box::new(self.body()?)))
+            }
+            "do" => {
+                self.advance();
+                self.newlines();
+                let body = self.statement()?;
+                self.separators();
+                if !self.is_keyword("while") {
+                    return self.error();
+                }
+                self.advance();
+                self.expect("(")?;
+                let condition = self.expr(false)?;
+                self.expect(")")?;
+                self.end_simple()?;
+                Ok(AwkStmt::Do(Box::new(body), condition))
+            }
+            "for" => {
+                self.advance();
+                self.expect("(")?;
+                // 'for (name in array)'
+                if let (AwkToken::Name(name), AwkToken::Keyword(word), AwkToken::Name(array)) = (
+                    self.peek().clone(),
+                    self.peek_at(1).clone(),
+                    self.peek_at(2).clone(),
+                ) {
+                    if word == "in" && *self.peek_at(3) == AwkToken::Punct(")") {
+                        for _ in 0..4 {
+                            self.advance();
+                        }
+                        let var = AwkExpr::Var(self.variable(&name));
+                        let array = self.array(&array);
+                        return Ok(AwkStmt::ForIn(var, array, Box::new(self.body()?)));
+                    }
+                }
+                let init = if self.is(";") {
+                    None
+                } else {
+                    Some(Box::new(self.simple_statement()?))
+                };
+                self.expect(";")?;
+                self.newlines();
+                let condition = if self.is(";") {
+                    None
+                } else {
+                    Some(self.expr(false)?)
+                };
+                self.expect(";")?;
+                self.newlines();
+                let step = if self.is(")") {
+                    None
+                } else {
+                    Some(Box::new(self.simple_statement()?))
+                };
+                self.expect(")")?;
+                Ok(AwkStmt::For(init, condition, step, Box::new(self.body()?)))
+            }
+            _ => {
+                let statement = self.simple_statement()?;
+                self.end_simple()?;
+                Ok(statement)
+            }
+        }
+    }
+
+    fn end_simple(&mut self) -> Result<(), String> {
+        match self.peek() {
+            AwkToken::Newline | AwkToken::Punct(";") => {
+                self.advance();
+            }
+            AwkToken::Punct("}") | AwkToken::End => (),
+            _ => return self.error(),
+        }
+        Ok(())
+    }
+
+    fn at_statement_end(&self) -> bool {
+        matches!(
+            self.peek(),
+            AwkToken::Newline | AwkToken::End | AwkToken::Punct(";" | "}")
+        )
+    }
+
+    fn simple_statement(&mut self) -> Result<AwkStmt, String> {
+        let word = match self.peek() {
+            AwkToken::Keyword(word) => word.clone(),
+            _ => String::new(),
+        };
+        match word.as_str() {
+            "print" | "printf" => {
+                self.advance();
+                let mut args = Vec::new();
+                if !self.at_statement_end() && !self.is(">") && !self.is(">>") && !self.is("|") {
+                    args = self.expr_list(true)?;
+                    // 'print (a, b)' is the same as 'print a, b'
+                    if let [AwkExpr::List(_)] = args.as_slice() {
+                        if let Some(AwkExpr::List(list)) = args.pop() {
+                            args = list;
+                        }
+                    }
+                }
+                let redirect = match self.peek() {
+                    AwkToken::Punct(mode @ (">" | ">>" | "|")) => {
+                        let mode: &'static str = mode;
+                        self.advance();
+                        Some((mode, self.concat()?))
+                    }
+                    _ => None,
+                };
+                if word == "print" {
+                    Ok(AwkStmt::Print(args, redirect))
+                } else if args.is_empty() {
+                    self.error()
+                } else {
+                    Ok(AwkStmt::Printf(args, redirect))
+                }
+            }
+            "delete" => {
+                self.advance();
+                let name = match self.advance() {
+                    AwkToken::Name(name) => name,
+                    _ => return self.error(),
+                };
+                let array = self.array(&name);
+                if !self.is("[") {
+                    return Ok(AwkStmt::Delete(array, None));
+                }
+                self.advance();
+                let subscripts = self.expr_list(false)?;
+                self.expect("]")?;
+                Ok(AwkStmt::Delete(array, Some(subscripts)))
+            }
+            "next" | "nextfile" | "break" | "continue" => {
+                self.advance();
+                Ok(match word.as_str() {
+                    "next" => AwkStmt::Next,
+                    "nextfile" => AwkStmt::NextFile,
+                    "break" => AwkStmt::Break,
+                    _ => AwkStmt::Continue,
+                })
+            }
+            "exit" | "return" => {
+                self.advance();
+                let value = if self.at_statement_end() {
+                    None
+                } else {
+                    Some(self.expr(false)?)
+                };
+                if word == "exit" {
+                    Ok(AwkStmt::Exit(value))
+                } else if self.locals.is_none() {
+                    self.error()
+                } else {
+                    Ok(AwkStmt::Return(value))
+                }
+            }
+            _ => Ok(AwkStmt::Expr(self.expr(false)?)),
+        }
+    }
+
+    fn expr_list(&mut self, print: bool) -> Result<Vec<AwkExpr>, String> {
+        let mut list = vec![self.expr(print)?];
+        while self.is(",") {
+            self.advance();
+            self.newlines();
+            list.push(self.expr(print)?);
+        }
+        Ok(list)
+    }
+
+    // In 'print' arguments, '>' is a redirection and not a comparison
+    fn expr(&mut self, print: bool) -> Result<AwkExpr, String> {
+        let left = self.ternary(print)?;
+        let op = match self.peek() {
+            AwkToken::Punct(op @ ("=" | "+=" | "-=" | "*=" | "/=" | "%=" | "^=" | "**=")) => *op,
+            _ => return Ok(left),
+        };
+        if !matches!(
+            left,
+            AwkExpr::Var(_) | AwkExpr::Field(_) | AwkExpr::Index(..)
+        ) {
+            return self.error();
+        }
+        self.advance();
+        self.newlines();
+        let right = self.expr(print)?;
+        let op = match op {
+            "=" => None,
+            "**=" => Some('^'),
+            _ => op.chars().next(),
+        };
+        Ok(AwkExpr::Assign(Box::new(left), op, Box::new(right)))
+    }
+
+    fn ternary(&mut self, print: bool) -> Result<AwkExpr, String> {
+        let condition = self.or(print)?;
+        if !self.is("?") {
+            return Ok(condition);
+        }
+        self.advance();
+        self.newlines();
+        let yes = self.expr(print)?;
+        self.newlines();
+        self.expect(":")?;
+        self.newlines();
+        let no = self.expr(print)?;
+        Ok(AwkExpr::Condition(
+            Box::new(condition),
+            Box::new(yes),
+            Box::new(no),
+        ))
+    }
+
+    fn or(&mut self, print: bool) -> Result<AwkExpr, String> {
+        let mut left = self.and(print)?;
+        while self.is("||") {
+            self.advance();
+            self.newlines();
+            left = AwkExpr::Or(Box::new(left), Box::new(self.and(print)?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self, print: bool) -> Result<AwkExpr, String> {
+        let mut left = self.membership(print)?;
+        while self.is("&&") {
+            self.advance();
+            self.newlines();
+            left = AwkExpr::And(Box::new(left), Box::new(self.membership(print)?));
+        }
+        Ok(left)
+    }
+
+    fn membership(&mut self, print: bool) -> Result<AwkExpr, String> {
+        let mut left = self.matching(print)?;
+        while self.is_keyword("in") {
+            self.advance();
+            let name = match self.advance() {
+                AwkToken::Name(name) => name,
+                _ => return self.error(),
+            };
+            left = AwkExpr::In(vec![left], self.array(&name));
+        }
+        Ok(left)
+    }
+
+    fn matching(&mut self, print: bool) -> Result<AwkExpr, String> {
+        let mut left = self.comparison(print)?;
+        while self.is("~") || self.is("!~") {
+            let negate = self.is("!~");
+            self.advance();
+            left = AwkExpr::Match(negate, Box::new(left), Box::new(self.comparison(print)?));
+        }
+        Ok(left)
+    }
+
+    fn comparison(&mut self, print: bool) -> Result<AwkExpr, String> {
+        let left = self.pipe_getline()?;
+        let op = match self.peek() {
+            AwkToken::Punct(">") if print => return Ok(left),
+            AwkToken::Punct(op @ ("<" | "<=" | "!=" | "==" | ">=" | ">")) => *op,
+            _ => return Ok(left),
+        };
+        self.advance();
+        let right = self.pipe_getline()?;
+        Ok(AwkExpr::Compare(op, Box::new(left), Box::new(right)))
+    }
+
+    fn pipe_getline(&mut self) -> Result<AwkExpr, String> {
+        let mut left = self.concat()?;
+        // 'command | getline [var]'
+        while self.is("|") && *self.peek_at(1) == AwkToken::Keyword(String::from("getline")) {
+            self.advance();
+            self.advance();
+            let target = self.getline_target()?;
+            left = AwkExpr::Getline(AwkSource::Command(Box::new(left)), target);
+        }
+        Ok(left)
+    }
+
+    fn getline_target(&mut self) -> Result<Option<Box<AwkExpr>>, String> {
+        if !matches!(self.peek(), AwkToken::Name(_) | AwkToken::Punct("$")) {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(self.primary()?)))
+    }
+
+    fn starts_operand(&self) -> bool {
+        match self.peek() {
+            AwkToken::Number(_)
+            | AwkToken::Str(_)
+            | AwkToken::Regex(_)
+            | AwkToken::Name(_)
+            | AwkToken::Func(_)
+            | AwkToken::Builtin(_) => true,
+            AwkToken::Punct(punct) => matches!(*punct, "$" | "(" | "++" | "--"),
+            _ => false,
+        }
+    }
+
+    fn concat(&mut self) -> Result<AwkExpr, String> {
+        let mut left = self.additive()?;
+        while self.starts_operand() {
+            left = AwkExpr::Concat(Box::new(left), Box::new(self.additive()?));
+        }
+        Ok(left)
+    }
+
+    fn additive(&mut self) -> Result<AwkExpr, String> {
+        let mut left = self.multiplicative()?;
+        while self.is("+") || self.is("-") {
+            let op = if self.is("+") { '+' } else { '-' };
+            self.advance();
+            left = AwkExpr::Binary(op, Box::new(left), Box::new(self.multiplicative()?));
+        }
+        Ok(left)
+    }
+
+    fn multiplicative(&mut self) -> Result<AwkExpr, String> {
+        let mut left = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                AwkToken::Punct("*") => '*',
+                AwkToken::Punct("/") => '/',
+                AwkToken::Punct("%") => '%',
+                _ => return Ok(left),
+            };
+            self.advance();
+            left = AwkExpr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<AwkExpr, String> {
+        match self.peek() {
+            AwkToken::Punct("!") => {
+                self.advance();
+                Ok(AwkExpr::Not(Box::new(self.unary()?)))
+            }
+            AwkToken::Punct("-") => {
+                self.advance();
+                Ok(AwkExpr::Negate(Box::new(self.unary()?)))
+            }
+            AwkToken::Punct("+") => {
+                self.advance();
+                Ok(AwkExpr::Plus(Box::new(self.unary()?)))
+            }
+            _ => self.power(),
+        }
+    }
+
+    fn power(&mut self) -> Result<AwkExpr, String> {
+        let base = self.postfix()?;
+        if !self.is("^") && !self.is("**") {
+            return Ok(base);
+        }
+        self.advance();
+        // Right associative, and the exponent can have a sign
+        let exponent = match self.peek() {
+            AwkToken::Punct("-" | "+" | "!") => self.unary()?,
+            _ => self.power()?,
+        };
+        Ok(AwkExpr::Binary('^', Box::new(base), Box::new(exponent)))
+    }
+
+    fn postfix(&mut self) -> Result<AwkExpr, String> {
+        let operand = self.primary()?;
+        if matches!(
+            operand,
+            AwkExpr::Var(_) | AwkExpr::Field(_) | AwkExpr::Index(..)
+        ) && (self.is("++") || self.is("--"))
+        {
+            let delta = if self.is("++") { 1.0 } else { -1.0 };
+            self.advance();
+            return Ok(AwkExpr::Increment(Box::new(operand), delta, false));
+        }
+        Ok(operand)
+    }
+
+    fn primary(&mut self) -> Result<AwkExpr, String> {
+        let start = self.position;
+        match self.advance() {
+            AwkToken::Number(number) => Ok(AwkExpr::Number(number)),
+            AwkToken::Str(text) => Ok(AwkExpr::Str(text)),
+            AwkToken::Regex(text) => Ok(AwkExpr::Regex(self.regex(&text)?)),
+            AwkToken::Punct("$") => Ok(AwkExpr::Field(Box::new(self.primary()?))),
+            AwkToken::Punct(op @ ("++" | "--")) => {
+                let target = self.primary()?;
+                if !matches!(
+                    target,
+                    AwkExpr::Var(_) | AwkExpr::Field(_) | AwkExpr::Index(..)
+                ) {
+                    return self.error();
+                }
+                let delta = if op == "++" { 1.0 } else { -1.0 };
+                Ok(AwkExpr::Increment(Box::new(target), delta, true))
+            }
+            AwkToken::Punct("-") => Ok(AwkExpr::Negate(Box::new(self.unary()?))),
+            AwkToken::Punct("+") => Ok(AwkExpr::Plus(Box::new(self.unary()?))),
+            AwkToken::Punct("!") => Ok(AwkExpr::Not(Box::new(self.unary()?))),
+            AwkToken::Punct("(") => {
+                self.newlines();
+                let mut list = self.expr_list(false)?;
+                self.newlines();
+                self.expect(")")?;
+                if list.len() == 1 {
+                    return Ok(list.remove(0));
+                }
+                // '(a, b) in array'
+                if self.is_keyword("in") {
+                    self.advance();
+                    let name = match self.advance() {
+                        AwkToken::Name(name) => name,
+                        _ => return self.error(),
+                    };
+                    return Ok(AwkExpr::In(list, self.array(&name)));
+                }
+                Ok(AwkExpr::List(list))
+            }
+            AwkToken::Name(name) => {
+                if !self.is("[") {
+                    return Ok(AwkExpr::Var(self.variable(&name)));
+                }
+                self.advance();
+                let subscripts = self.expr_list(false)?;
+                self.expect("]")?;
+                Ok(AwkExpr::Index(self.array(&name), subscripts))
+            }
+            AwkToken::Func(name) => {
+                self.expect("(")?;
+                let args = if self.is(")") {
+                    Vec::new()
+                } else {
+                    self.expr_list(false)?
+                };
+                self.newlines();
+                self.expect(")")?;
+                Ok(AwkExpr::Call(name, args))
+            }
+            AwkToken::Builtin(name) => {
+                let mut args = Vec::new();
+                if self.is("(") {
+                    self.advance();
+                    if !self.is(")") {
+                        args = self.expr_list(false)?;
+                    }
+                    self.newlines();
+                    self.expect(")")?;
+                } else if name != "length" {
+                    return self.error();
+                }
+                // The second argument of split is an array
+                if name == "split" {
+                    if let Some(AwkExpr::Var(AwkVar::Local(index))) = args.get(1) {
+                        if let Some((_, arrays)) = self.locals.as_mut() {
+                            arrays[*index] = true;
+                        }
+                    }
+                }
+                Ok(AwkExpr::Builtin(name, args))
+            }
+            AwkToken::Keyword(word) if word == "getline" => {
+                let target = self.getline_target()?;
+                if !self.is("<") {
+                    return Ok(AwkExpr::Getline(AwkSource::Main, target));
+                }
+                self.advance();
+                let file = self.primary()?;
+                Ok(AwkExpr::Getline(AwkSource::File(Box::new(file)), target))
+            }
+            _ => {
+                self.position = start;
+                self.error()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum AwkValue {
+    Uninit,
+    Number(f64),
+    Str(String),
+    // Input data: a number if it looks like one, a string otherwise
+    StrNum(String),
+}
+
+fn awk_number_prefix(text: &str) -> (f64, bool) {
+    // The value of the leading number, and whether the whole text (but blanks) is a number
+    let bytes = text.as_bytes();
+    let blank = |b: u8| matches!(b, b' ' | b'\t' | b'\n' | b'\r' | b'\x0b' | b'\x0c');
+    let digits = |mut position: usize| {
+        while position < bytes.len() && bytes[position].is_ascii_digit() {
+            position += 1;
+        }
+        position
+    };
+    let mut start = 0;
+    while start < bytes.len() && blank(bytes[start]) {
+        start += 1;
+    }
+    let mut end = start;
+    if end < bytes.len() && matches!(bytes[end], b'+' | b'-') {
+        end += 1;
+    }
+    let integer = end;
+    end = digits(end);
+    let mut count = end - integer;
+    if end < bytes.len() && bytes[end] == b'.' {
+        let fraction = end + 1;
+        end = digits(fraction);
+        count += end - fraction;
+    }
+    if count == 0 {
+        return (0.0, false);
+    }
+    if end < bytes.len() && matches!(bytes[end], b'e' | b'E') {
+        let mut next = end + 1;
+        if next < bytes.len() && matches!(bytes[next], b'+' | b'-') {
+            next += 1;
+        }
+        if next < bytes.len() && bytes[next].is_ascii_digit() {
+            end = digits(next);
+        }
+    }
+    let value = text[start..end].parse().unwrap_or(0.0);
+    let mut rest = end;
+    while rest < bytes.len() && blank(bytes[rest]) {
+        rest += 1;
+    }
+    (value, rest == bytes.len())
+}
+
+fn awk_number_string(value: f64, format: &str) -> String {
+    // Integers are printed as such, other numbers with CONVFMT or OFMT
+    if value == value.trunc() && value.abs() < 1e30 {
+        format!("{:.0}", value)
+    } else {
+        awk_sprintf(format, &[AwkValue::Number(value)], "%.6g").unwrap_or_default()
+    }
+}
+
+impl AwkValue {
+    fn number(&self) -> f64 {
+        match self {
+            AwkValue::Uninit => 0.0,
+            AwkValue::Number(number) => *number,
+            AwkValue::Str(text) | AwkValue::StrNum(text) => awk_number_prefix(text).0,
+        }
+    }
+
+    fn string(&self, convfmt: &str) -> String {
+        match self {
+            AwkValue::Uninit => String::new(),
+            AwkValue::Number(number) => awk_number_string(*number, convfmt),
+            AwkValue::Str(text) | AwkValue::StrNum(text) => text.clone(),
+        }
+    }
+
+    // The value when compared, if it compares as a number
+    fn numeric(&self) -> Option<f64> {
+        match self {
+            AwkValue::Uninit => Some(0.0),
+            AwkValue::Number(number) => Some(*number),
+            AwkValue::Str(_) => None,
+            AwkValue::StrNum(text) => match awk_number_prefix(text) {
+                (number, true) => Some(number),
+                _ => None,
+            },
+        }
+    }
+
+    fn truth(&self) -> bool {
+        match self {
+            AwkValue::Uninit => false,
+            AwkValue::Number(number) => *number != 0.0,
+            AwkValue::Str(text) => !text.is_empty(),
+            AwkValue::StrNum(text) => match awk_number_prefix(text) {
+                (number, true) => number != 0.0,
+                _ => !text.is_empty(),
+            },
+        }
+    }
+}
+
+fn awk_bool(value: bool) -> AwkValue {
+    AwkValue::Number(if value { 1.0 } else { 0.0 })
+}
+
+fn awk_format_e(value: f64, precision: usize, upper: bool) -> String {
+    // Rust writes '1.5e2', C writes '1.5e+02'
+    let text = format!("{:.*e}", precision, value);
+    let (mantissa, exponent) = text.split_once('e').unwrap_or((&text, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    format!(
+        "{}{}{}{:02}",
+        mantissa,
+        if upper { 'E' } else { 'e' },
+        if exponent < 0 { '-' } else { '+' },
+        exponent.abs()
+    )
+}
+
+fn awk_format_g(value: f64, precision: usize, upper: bool, alternate: bool) -> String {
+    let precision = precision.max(1);
+    let exponent = if value == 0.0 {
+        0
+    } else {
+        let text = format!("{:.*e}", precision - 1, value);
+        text.split_once('e')
+            .and_then(|(_, exponent)| exponent.parse::<i32>().ok())
+            .unwrap_or(0)
+    };
+    let text = if exponent < -4 || exponent >= precision as i32 {
+        awk_format_e(value, precision - 1, upper)
+    } else {
+        format!("{:.*}", (precision as i32 - 1 - exponent) as usize, value)
+    };
+    if alternate || !text.contains('.') {
+        return text;
+    }
+    // Without '#', the trailing zeros of the fraction are removed
+    let (number, suffix) = text.split_at(text.find(['e', 'E']).unwrap_or(text.len()));
+    format!(
+        "{}{}",
+        number.trim_end_matches('0').trim_end_matches('.'),
+        suffix
+    )
+}
+
+fn awk_pad(out: &mut String, prefix: &str, body: &str, width: usize, left: bool, zero: bool) {
+    let fill = width.saturating_sub(prefix.chars().count() + body.chars().count());
+    if left {
+        out.push_str(prefix);
+        out.push_str(body);
+        out.extend(std::iter::repeat_n(' ', fill));
+    } else if zero {
+        out.push_str(prefix);
+        out.extend(std::iter::repeat_n('0', fill));
+        out.push_str(body);
+    } else {
+        out.extend(std::iter::repeat_n(' ', fill));
+        out.push_str(prefix);
+        out.push_str(body);
+    }
+}
+
+fn awk_sprintf(format: &str, args: &[AwkValue], convfmt: &str) -> Result<String, String> {
+    let chars: Vec<char> = format.chars().collect();
+    let mut out = String::new();
+    let mut next = 0;
+    let mut index = 0;
+    let number_at = |position: usize| {
+        args.get(position)
+            .map(|value| value.number())
+            .unwrap_or(0.0)
+    };
+    while index < chars.len() {
+        let c = chars[index];
+        index += 1;
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        if chars.get(index) == Some(&'%') {
+            out.push('%');
+            index += 1;
+            continue;
+        }
+        let start = index - 1;
+        let mut flags = String::new();
+        while let Some(flag) = chars.get(index).filter(|flag| "-+ #0".contains(**flag)) {
+            flags.push(*flag);
+            index += 1;
+        }
+        let mut left = flags.contains('-');
+        let mut width = 0;
+        if chars.get(index) == Some(&'*') {
+            let value = number_at(next) as i64;
+            next += 1;
+            index += 1;
+            left |= value < 0;
+            width = value.unsigned_abs() as usize;
+        } else {
+            while let Some(digit) = chars.get(index).and_then(|d| d.to_digit(10)) {
+                width = width * 10 + digit as usize;
+                index += 1;
+            }
+        }
+        let mut precision = None;
+        if chars.get(index) == Some(&'.') {
+            index += 1;
+            if chars.get(index) == Some(&'*') {
+                let value = number_at(next) as i64;
+                next += 1;
+                index += 1;
+                precision = (value >= 0).then_some(value as usize);
+            } else {
+                let mut value = 0;
+                while let Some(digit) = chars.get(index).and_then(|d| d.to_digit(10)) {
+                    value = value * 10 + digit as usize;
+                    index += 1;
+                }
+                precision = Some(value);
+            }
+        }
+        while matches!(
+            chars.get(index),
+            Some('h' | 'l' | 'L' | 'q' | 'j' | 'z' | 't')
+        ) {
+            index += 1;
+        }
+        let conversion = match chars.get(index) {
+            Some(conversion) if "cdiouxXeEfFgGs".contains(*conversion) => *conversion,
+            // An incomplete or unknown conversion is printed as is
+            _ => {
+                out.extend(&chars[start..(index + 1).min(chars.len())]);
+                index += 1;
+                continue;
+            }
+        };
+        index += 1;
+        let value = match args.get(next) {
+            Some(value) => value.clone(),
+            None => {
+                return Err(format!(
+                    "not enough arguments passed to printf(\"{}\")",
+                    format
+                ))
+            }
+        };
+        next += 1;
+        let zero = flags.contains('0') && !left;
+        let sign = |negative: bool| {
+            if negative {
+                "-"
+            } else if flags.contains('+') {
+                "+"
+            } else if flags.contains(' ') {
+                " "
+            } else {
+                ""
+            }
+        };
+        match conversion {
+            's' => {
+                let mut text = value.string(convfmt);
+                if let Some(precision) = precision {
+                    text = text.chars().take(precision).collect();
+                }
+                awk_pad(&mut out, "", &text, width, left, false);
+            }
+            'c' => {
+                // A number is a character code, a string gives its first character
+                let text = match value
+                    .numeric()
+                    .filter(|_| !matches!(value, AwkValue::Uninit))
+                {
+                    Some(code) => char::from_u32(code as u32)
+                        .map(String::from)
+                        .unwrap_or_default(),
+                    None => value.string(convfmt).chars().take(1).collect(),
+                };
+                awk_pad(&mut out, "", &text, width, left, false);
+            }
+            _ => {
+                let number = value.number();
+                let upper = conversion.is_ascii_uppercase();
+                if !number.is_finite() {
+                    let mut text = String::from(if number.is_nan() { "nan" } else { "inf" });
+                    if upper {
+                        text = text.to_uppercase();
+                    }
+                    let negative = number.is_sign_negative();
+                    awk_pad(&mut out, sign(negative), &text, width, left, false);
+                    continue;
+                }
+                match conversion {
+                    'd' | 'i' | 'o' | 'u' | 'x' | 'X' => {
+                        let number = number.trunc();
+                        let negative = number < 0.0 && matches!(conversion, 'd' | 'i');
+                        let magnitude = if matches!(conversion, 'd' | 'i') {
+                            number.abs()
+                        } else if number < 0.0 {
+                            // Negative values are taken as two's complement, like in C
+                            (number as i64) as u64 as f64
+                        } else {
+                            number
+                        };
+                        let integer = magnitude as u64;
+                        let mut digits = match conversion {
+                            'o' => format!("{:o}", integer),
+                            'x' => format!("{:x}", integer),
+                            'X' => format!("{:X}", integer),
+                            _ if magnitude >= 1e19 => format!("{:.0}", magnitude),
+                            _ => integer.to_string(),
+                        };
+                        if let Some(precision) = precision {
+                            if precision == 0 && integer == 0 {
+                                digits.clear();
+                            }
+                            while digits.len() < precision {
+                                digits.insert(0, '0');
+                            }
+                        }
+                        let mut prefix = String::from(sign(negative));
+                        if matches!(conversion, 'o' | 'x' | 'X' | 'u') {
+                            prefix.clear();
+                        }
+                        if flags.contains('#') && integer != 0 {
+                            match conversion {
+                                'o' if !digits.starts_with('0') => digits.insert(0, '0'),
+                                'x' => prefix.push_str("0x"),
+                                'X' => prefix.push_str("0X"),
+                                _ => (),
+                            }
+                        }
+                        awk_pad(
+                            &mut out,
+                            &prefix,
+                            &digits,
+                            width,
+                            left,
+                            zero && precision.is_none(),
+                        );
+                    }
+                    _ => {
+                        let precision = precision.unwrap_or(6);
+                        let magnitude = number.abs();
+                        let mut text = match conversion {
+                            'f' | 'F' => format!("{:.*}", precision, magnitude),
+                            'e' | 'E' => awk_format_e(magnitude, precision, upper),
+                            _ => awk_format_g(magnitude, precision, upper, flags.contains('#')),
+                        };
+                        if flags.contains('#') && !text.contains('.') {
+                            let at = text.find(['e', 'E']).unwrap_or(text.len());
+                            text.insert(at, '.');
+                        }
+                        let negative = number.is_sign_negative();
+                        awk_pad(&mut out, sign(negative), &text, width, left, zero);
+                    }
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn awk_replacement(out: &mut String, replacement: &str, matched: &str) {
+    // '&' is the matched text, '\&' a literal '&' and '\\' a backslash
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if matches!(chars.peek(), Some('&' | '\\')) => out.extend(chars.next()),
+            '&' => out.push_str(matched),
+            _ => out.push(c),
+        }
+    }
+}
+
+fn awk_substitute(
+    regex: &regex::bytes::Regex,
+    text: &str,
+    replacement: &str,
+    global: bool,
+) -> (String, usize) {
+    let bytes = text.as_bytes();
+    let mut out = String::new();
+    let mut count = 0;
+    // 'copied' is where the unchanged text starts, 'search' where the next match is looked for
+    let mut copied = 0;
+    let mut search = 0;
+    let mut last_end = None;
+    let next_char =
+        |position: usize| position + text[position..].chars().next().map_or(1, |c| c.len_utf8());
+    while search <= bytes.len() {
+        let found = match regex.find_at(bytes, search) {
+            Some(found) => found,
+            None => break,
+        };
+        // An empty match right after the previous match isn't replaced
+        if found.is_empty() && last_end == Some(found.start()) {
+            if found.start() >= bytes.len() {
+                break;
+            }
+            search = next_char(found.start());
+            continue;
+        }
+        out.push_str(&text[copied..found.start()]);
+        awk_replacement(&mut out, replacement, &text[found.start()..found.end()]);
+        count += 1;
+        copied = found.end();
+        last_end = Some(found.end());
+        if !global {
+            break;
+        }
+        if found.is_empty() {
+            if found.end() >= bytes.len() {
+                break;
+            }
+            let next = next_char(found.end());
+            out.push_str(&text[found.end()..next]);
+            copied = next;
+            search = next;
+        } else {
+            search = found.end();
+        }
+    }
+    out.push_str(&text[copied..]);
+    (out, count)
+}
+
+fn awk_assignment(text: &str) -> Option<(&str, String)> {
+    // 'name=value' operands and '-v' arguments, the value has escape sequences
+    let (name, value) = text.split_once('=')?;
+    let mut chars = name.chars();
+    if !chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        || AWK_KEYWORDS.contains(&name)
+        || AWK_BUILTINS.contains(&name)
+    {
+        return None;
+    }
+    let chars: Vec<char> = value.chars().collect();
+    let mut out = String::new();
+    let mut index = 0;
+    while index < chars.len() {
+        if chars[index] == '\\' {
+            awk_escape(&chars, &mut index, &mut out);
+        } else {
+            out.push(chars[index]);
+            index += 1;
+        }
+    }
+    Some((name, out))
+}
+
+fn awk_status(status: io::Result<std::process::ExitStatus>) -> f64 {
+    use std::os::unix::process::ExitStatusExt;
+    match status {
+        Ok(status) => match status.code() {
+            Some(code) => code as f64,
+            None => 256.0 + status.signal().unwrap_or(0) as f64,
+        },
+        Err(_) => -1.0,
+    }
+}
+
+type AwkArray = Rc<RefCell<HashMap<String, AwkValue>>>;
+
+enum AwkCell {
+    Uninit,
+    Scalar(AwkValue),
+    Array(AwkArray),
+}
+
+// Something that can be assigned
+enum AwkPlace {
+    Var(AwkVar),
+    Field(usize),
+    Element(AwkArray, String),
+}
+
+// Statements that stop the normal flow, propagated as errors up to where they're handled
+enum AwkJump {
+    Next,
+    NextFile,
+    Exit,
+    Error(String),
+}
+
+enum AwkFlow {
+    Normal,
+    Break,
+    Continue,
+    Return(AwkValue),
+}
+
+enum AwkSeparator {
+    Byte(u8),
+    // An empty RS: records are separated by blank lines
+    Paragraph,
+    Regex(Rc<regex::bytes::Regex>),
+}
+
+struct AwkReader {
+    input: Box<dyn BufRead>,
+    // With a regex RS, the whole input and the position in it
+    buffer: Option<(Vec<u8>, usize)>,
+}
+
+impl AwkReader {
+    fn new(input: Box<dyn BufRead>) -> AwkReader {
+        AwkReader {
+            input,
+            buffer: None,
+        }
+    }
+
+    fn read(&mut self, separator: &AwkSeparator) -> io::Result<Option<String>> {
+        let mut record = Vec::new();
+        match separator {
+            AwkSeparator::Byte(byte) => {
+                if self.input.read_until(*byte, &mut record)? == 0 {
+                    return Ok(None);
+                }
+                if record.last() == Some(byte) {
+                    record.pop();
+                }
+            }
+            AwkSeparator::Paragraph => {
+                let mut line = Vec::new();
+                // Skip the blank lines before the record
+                loop {
+                    line.clear();
+                    if self.input.read_until(b'\n', &mut line)? == 0 {
+                        return Ok(None);
+                    }
+                    if line != b"\n" {
+                        break;
+                    }
+                }
+                loop {
+                    if line.last() == Some(&b'\n') {
+                        line.pop();
+                    }
+                    record.append(&mut line);
+                    if self.input.read_until(b'\n', &mut line)? == 0 || line == b"\n" {
+                        break;
+                    }
+                    record.push(b'\n');
+                }
+            }
+            AwkSeparator::Regex(regex) => {
+                if self.buffer.is_none() {
+                    let mut data = Vec::new();
+                    self.input.read_to_end(&mut data)?;
+                    self.buffer = Some((data, 0));
+                }
+                let (data, position) = match self.buffer.as_mut() {
+                    Some((data, position)) => (data, position),
+                    None => return Ok(None),
+                };
+                if *position >= data.len() {
+                    return Ok(None);
+                }
+                let found = regex
+                    .find_iter(&data[*position..])
+                    .find(|found| !found.is_empty());
+                match found {
+                    Some(found) => {
+                        record.extend_from_slice(&data[*position..*position + found.start()]);
+                        *position += found.end();
+                    }
+                    None => {
+                        record.extend_from_slice(&data[*position..]);
+                        *position = data.len();
+                    }
+                }
+            }
+        }
+        Ok(Some(String::from_utf8_lossy(&record).into_owned()))
+    }
+}
+
+enum AwkOutput {
+    File(io::BufWriter<File>),
+    Pipe(std::process::Child, io::BufWriter<std::process::ChildStdin>),
+}
+
+struct AwkInput {
+    reader: AwkReader,
+    child: Option<std::process::Child>,
+}
+
+struct Awk {
+    globals: Vec<AwkCell>,
+    names: HashMap<String, usize>,
+    frames: Vec<Vec<AwkCell>>,
+    functions: HashMap<String, Rc<AwkFunction>>,
+    record: String,
+    fields: Vec<String>,
+    regexes: HashMap<String, Rc<regex::bytes::Regex>>,
+    outputs: HashMap<String, AwkOutput>,
+    inputs: HashMap<String, AwkInput>,
+    // The current file of the operands, and the index of the next operand in ARGV
+    main: Option<AwkReader>,
+    next_argument: usize,
+    used_operand: bool,
+    out: io::BufWriter<io::Stdout>,
+    seed: f64,
+    random: u64,
+    exit_code: i32,
+}
+
+fn awk_error(message: String) -> AwkJump {
+    AwkJump::Error(message)
+}
+
+fn awk_write_error(e: io::Error) -> AwkJump {
+    // A closed pipe just ends the program, like the signal would
+    if e.kind() == ErrorKind::BrokenPipe {
+        return AwkJump::Error(String::new());
+    }
+    AwkJump::Error(format!("write failure ({})", strerror(&e)))
+}
+
+impl Awk {
+    fn cell(&mut self, var: AwkVar) -> &mut AwkCell {
+        match var {
+            AwkVar::Global(index) => &mut self.globals[index],
+            AwkVar::Local(index) => {
+                let frame = self.frames.len() - 1;
+                &mut self.frames[frame][index]
+            }
+        }
+    }
+
+    fn special(&self, index: usize) -> String {
+        match &self.globals[index] {
+            AwkCell::Scalar(value) => value.string("%.6g"),
+            _ => String::new(),
+        }
+    }
+
+    fn special_number(&self, index: usize) -> f64 {
+        match &self.globals[index] {
+            AwkCell::Scalar(value) => value.number(),
+            _ => 0.0,
+        }
+    }
+
+    fn set_special(&mut self, index: usize, value: AwkValue) {
+        self.globals[index] = AwkCell::Scalar(value);
+    }
+
+    fn text(&self, value: AwkValue) -> String {
+        match value {
+            AwkValue::Number(number) => awk_number_string(number, &self.special(AWK_CONVFMT)),
+            AwkValue::Str(text) | AwkValue::StrNum(text) => text,
+            AwkValue::Uninit => String::new(),
+        }
+    }
+
+    fn array(&mut self, var: AwkVar) -> Result<AwkArray, AwkJump> {
+        let cell = self.cell(var);
+        match cell {
+            AwkCell::Array(array) => Ok(array.clone()),
+            AwkCell::Uninit => {
+                let array = Rc::new(RefCell::new(HashMap::new()));
+                *cell = AwkCell::Array(array.clone());
+                Ok(array)
+            }
+            AwkCell::Scalar(_) => Err(awk_error(String::from("can't use scalar as array"))),
+        }
+    }
+
+    fn regex(&mut self, text: &str) -> Result<Rc<regex::bytes::Regex>, AwkJump> {
+        if let Some(regex) = self.regexes.get(text) {
+            return Ok(regex.clone());
+        }
+        let regex = posix_regex(text.as_bytes(), true, "")
+            .map_err(|e| awk_error(format!("{}: /{}/", e, text)))?;
+        let regex = Rc::new(regex);
+        self.regexes.insert(text.to_string(), regex.clone());
+        Ok(regex)
+    }
+
+    // The regex of a regex literal, or of the value of a dynamic one
+    fn regex_of(&mut self, expr: &AwkExpr) -> Result<Rc<regex::bytes::Regex>, AwkJump> {
+        if let AwkExpr::Regex(regex) = expr {
+            return Ok(regex.clone());
+        }
+        let value = self.eval(expr)?;
+        let text = self.text(value);
+        self.regex(&text)
+    }
+
+    fn split(&mut self, text: &str, separator: &str) -> Result<Vec<String>, AwkJump> {
+        if text.is_empty() {
+            return Ok(Vec::new());
+        }
+        // A space splits on runs of blanks, other single characters are literal
+        if separator == " " {
+            return Ok(text
+                .split([' ', '\t', '\n'])
+                .filter(|field| !field.is_empty())
+                .map(String::from)
+                .collect());
+        }
+        let mut chars = separator.chars();
+        match (chars.next(), chars.next()) {
+            (None, _) => return Ok(text.chars().map(String::from).collect()),
+            (Some(c), None) if c != '\\' => return Ok(text.split(c).map(String::from).collect()),
+            _ => (),
+        }
+        let regex = self.regex(separator)?;
+        Ok(awk_regex_split(&regex, text))
+    }
+
+    fn set_record(&mut self, text: String) -> Result<(), AwkJump> {
+        let separator = self.special(AWK_FS);
+        // In paragraph mode, newlines separate the fields too
+        let fields = if self.special(AWK_RS).is_empty() && separator != " " {
+            let mut fields = Vec::new();
+            for line in text.split('\n') {
+                fields.extend(self.split(line, &separator)?);
+            }
+            fields
+        } else {
+            self.split(&text, &separator)?
+        };
+        self.record = text;
+        self.fields = fields;
+        self.set_special(AWK_NF, AwkValue::Number(self.fields.len() as f64));
+        Ok(())
+    }
+
+    fn rebuild_record(&mut self) {
+        self.record = self.fields.join(&self.special(AWK_OFS));
+        self.set_special(AWK_NF, AwkValue::Number(self.fields.len() as f64));
+    }
+
+    fn field(&self, index: usize) -> AwkValue {
+        if index == 0 {
+            return AwkValue::StrNum(self.record.clone());
+        }
+        match self.fields.get(index - 1) {
+            Some(field) => AwkValue::StrNum(field.clone()),
+            None => AwkValue::Uninit,
+        }
+    }
+
+    fn field_index(&mut self, expr: &AwkExpr) -> Result<usize, AwkJump> {
+        let index = self.eval(expr)?.number();
+        if index < 0.0 || index.is_nan() {
+            return Err(awk_error(format!(
+                "trying to access out of range field {}",
+                index as i64
+            )));
+        }
+        Ok(index as usize)
+    }
+
+    fn subscript(&mut self, subscripts: &[AwkExpr]) -> Result<String, AwkJump> {
+        let mut key = String::new();
+        for (index, subscript) in subscripts.iter().enumerate() {
+            if index > 0 {
+                key.push_str(&self.special(AWK_SUBSEP));
+            }
+            let value = self.eval(subscript)?;
+            key.push_str(&self.text(value));
+        }
+        Ok(key)
+    }
+
+    fn place(&mut self, target: &AwkExpr) -> Result<AwkPlace, AwkJump> {
+        match target {
+            AwkExpr::Var(var) => Ok(AwkPlace::Var(*var)),
+            AwkExpr::Field(index) => Ok(AwkPlace::Field(self.field_index(index)?)),
+            AwkExpr::Index(var, subscripts) => {
+                let key = self.subscript(subscripts)?;
+                Ok(AwkPlace::Element(self.array(*var)?, key))
+            }
+            _ => Err(awk_error(String::from("assignment to a non-variable"))),
+        }
+    }
+
+    fn get(&mut self, place: &AwkPlace) -> Result<AwkValue, AwkJump> {
+        match place {
+            AwkPlace::Var(var) => match self.cell(*var) {
+                AwkCell::Uninit => Ok(AwkValue::Uninit),
+                AwkCell::Scalar(value) => Ok(value.clone()),
+                AwkCell::Array(_) => Err(awk_error(String::from(
+                    "attempt to use array in a scalar context",
+                ))),
+            },
+            AwkPlace::Field(index) => Ok(self.field(*index)),
+            AwkPlace::Element(array, key) => Ok(array
+                .borrow_mut()
+                .entry(key.clone())
+                .or_insert(AwkValue::Uninit)
+                .clone()),
+        }
+    }
+
+    fn set(&mut self, place: &AwkPlace, value: AwkValue) -> Result<(), AwkJump> {
+        match place {
+            AwkPlace::Var(AwkVar::Global(AWK_NF)) => {
+                // Changing NF adds empty fields or removes the last ones
+                let count = value.number().max(0.0) as usize;
+                self.fields.resize(count, String::new());
+                self.rebuild_record();
+            }
+            AwkPlace::Var(var) => {
+                let cell = self.cell(*var);
+                if let AwkCell::Array(_) = cell {
+                    return Err(awk_error(String::from(
+                        "attempt to use array in a scalar context",
+                    )));
+                }
+                *cell = AwkCell::Scalar(value);
+            }
+            AwkPlace::Field(0) => {
+                let text = self.text(value);
+                self.set_record(text)?;
+            }
+            AwkPlace::Field(index) => {
+                let text = self.text(value);
+                if self.fields.len() < *index {
+                    self.fields.resize(*index, String::new());
+                }
+                self.fields[index - 1] = text;
+                self.rebuild_record();
+            }
+            AwkPlace::Element(array, key) => {
+                array.borrow_mut().insert(key.clone(), value);
+            }
+        }
+        Ok(())
+    }
+
+    fn compare(&self, left: &AwkValue, right: &AwkValue) -> std::cmp::Ordering {
+        // Numbers compare as numbers, anything else as strings
+        match (left.numeric(), right.numeric()) {
+            (Some(left), Some(right)) => left
+                .partial_cmp(&right)
+                .unwrap_or(std::cmp::Ordering::Equal),
+            _ => self.text(left.clone()).cmp(&self.text(right.clone())),
+        }
+    }
+
+    fn eval(&mut self, expr: &AwkExpr) -> Result<AwkValue, AwkJump> {
+        Ok(match expr {
+            AwkExpr::Number(number) => AwkValue::Number(*number),
+            AwkExpr::Str(text) => AwkValue::Str(text.clone()),
+            AwkExpr::Regex(regex) => awk_bool(regex.is_match(self.record.as_bytes())),
+            AwkExpr::Var(var) => self.get(&AwkPlace::Var(*var))?,
+            AwkExpr::Field(index) => {
+                let index = self.field_index(index)?;
+                self.field(index)
+            }
+            AwkExpr::Index(..) => {
+                let place = self.place(expr)?;
+                self.get(&place)?
+            }
+            AwkExpr::Assign(target, op, value) => {
+                let value = self.eval(value)?;
+                let place = self.place(target)?;
+                let value = match op {
+                    None => value,
+                    Some(op) => {
+                        let current = self.get(&place)?.number();
+                        AwkValue::Number(awk_arithmetic(*op, current, value.number())?)
+                    }
+                };
+                self.set(&place, value.clone())?;
+                value
+            }
+            AwkExpr::Condition(condition, yes, no) => {
+                if self.eval(condition)?.truth() {
+                    self.eval(yes)?
+                } else {
+                    self.eval(no)?
+                }
+            }
+            AwkExpr::And(left, right) => {
+                awk_bool(self.eval(left)?.truth() && self.eval(right)?.truth())
+            }
+            AwkExpr::Or(left, right) => {
+                awk_bool(self.eval(left)?.truth() || self.eval(right)?.truth())
+            }
+            AwkExpr::Not(operand) => awk_bool(!self.eval(operand)?.truth()),
+            AwkExpr::In(subscripts, var) => {
+                let key = self.subscript(subscripts)?;
+                let array = self.array(*var)?;
+                let found = array.borrow().contains_key(&key);
+                awk_bool(found)
+            }
+            AwkExpr::Match(negate, left, right) => {
+                let value = self.eval(left)?;
+                let text = self.text(value);
+                let regex = self.regex_of(right)?;
+                awk_bool(regex.is_match(text.as_bytes()) != *negate)
+            }
+            AwkExpr::Binary(op, left, right) => {
+                let left = self.eval(left)?.number();
+                let right = self.eval(right)?.number();
+                AwkValue::Number(awk_arithmetic(*op, left, right)?)
+            }
+            AwkExpr::Compare(op, left, right) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                let ordering = self.compare(&left, &right);
+                awk_bool(match *op {
+                    "<" => ordering.is_lt(),
+                    "<=" => ordering.is_le(),
+                    ">" => ordering.is_gt(),
+                    ">=" => ordering.is_ge(),
+                    "==" => ordering.is_eq(),
+                    _ => ordering.is_ne(),
+                })
+            }
+            AwkExpr::Concat(left, right) => {
+                let left = self.eval(left)?;
+                let mut text = self.text(left);
+                let right = self.eval(right)?;
+                text.push_str(&self.text(right));
+                AwkValue::Str(text)
+            }
+            AwkExpr::Negate(operand) => AwkValue::Number(-self.eval(operand)?.number()),
+            AwkExpr::Plus(operand) => AwkValue::Number(self.eval(operand)?.number()),
+            AwkExpr::Increment(target, delta, prefix) => {
+                let place = self.place(target)?;
+                let old = self.get(&place)?.number();
+                self.set(&place, AwkValue::Number(old + delta))?;
+                AwkValue::Number(if *prefix { old + delta } else { old })
+            }
+            AwkExpr::Call(name, args) => self.call(name, args)?,
+            AwkExpr::Builtin(name, args) => self.builtin(name, args)?,
+            AwkExpr::Getline(source, target) => self.getline(source, target.as_deref())?,
+            AwkExpr::List(_) => {
+                return Err(awk_error(String::from(
+                    "a list of expressions is only allowed in print and printf",
+                )))
+            }
+        })
+    }
+
+    fn call(&mut self, name: &str, args: &[AwkExpr]) -> Result<AwkValue, AwkJump> {
+        let function = match self.functions.get(name) {
+            Some(function) => function.clone(),
+            None => return Err(awk_error(format!("calling undefined function {}", name))),
+        };
+        if self.frames.len() >= AWK_MAX_DEPTH {
+            return Err(awk_error(format!(
+                "function {} called recursively too deeply",
+                name
+            )));
+        }
+        if args.len() > function.arrays.len() {
+            return Err(awk_error(format!(
+                "function {} called with more arguments than declared",
+                name
+            )));
+        }
+        let mut frame = Vec::with_capacity(function.arrays.len());
+        for (index, arg) in args.iter().enumerate() {
+            // Arrays are passed by reference, scalars by value
+            let cell = match arg {
+                AwkExpr::Var(var) => match self.cell(*var) {
+                    AwkCell::Array(array) => AwkCell::Array(array.clone()),
+                    AwkCell::Scalar(value) => AwkCell::Scalar(value.clone()),
+                    AwkCell::Uninit if function.arrays[index] => AwkCell::Array(self.array(*var)?),
+                    AwkCell::Uninit => AwkCell::Uninit,
+                },
+                _ => AwkCell::Scalar(self.eval(arg)?),
+            };
+            frame.push(cell);
+        }
+        frame.resize_with(function.arrays.len(), || AwkCell::Uninit);
+        self.frames.push(frame);
+        let result = self.execute_all(&function.body);
+        self.frames.pop();
+        match result? {
+            AwkFlow::Return(value) => Ok(value),
+            _ => Ok(AwkValue::Uninit),
+        }
+    }
+
+    fn flush_all(&mut self) -> Result<(), AwkJump> {
+        self.out.flush().map_err(awk_write_error)?;
+        for output in self.outputs.values_mut() {
+            let _ = match output {
+                AwkOutput::File(file) => file.flush(),
+                AwkOutput::Pipe(_, stdin) => stdin.flush(),
+            };
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, name: &str) -> f64 {
+        let mut result = -1.0;
+        if let Some(output) = self.outputs.remove(name) {
+            result = match output {
+                AwkOutput::File(mut file) => {
+                    if file.flush().is_ok() {
+                        0.0
+                    } else {
+                        -1.0
+                    }
+                }
+                AwkOutput::Pipe(mut child, mut stdin) => {
+                    let _ = stdin.flush();
+                    drop(stdin);
+                    awk_status(child.wait())
+                }
+            };
+        }
+        if let Some(input) = self.inputs.remove(name) {
+            drop(input.reader);
+            result = match input.child {
+                Some(mut child) => awk_status(child.wait()),
+                None => 0.0,
+            };
+        }
+        result
+    }
+
+    fn builtin(&mut self, name: &str, args: &[AwkExpr]) -> Result<AwkValue, AwkJump> {
+        let (least, most) = match name {
+            "length" | "srand" | "fflush" => (0, 1),
+            "rand" => (0, 0),
+            "substr" => (2, 3),
+            "split" | "sub" | "gsub" => (2, 3),
+            "index" | "match" | "atan2" => (2, 2),
+            "sprintf" => (1, usize::MAX),
+            _ => (1, 1),
+        };
+        if args.len() < least || args.len() > most {
+            return Err(awk_error(format!(
+                "function {} called with the wrong number of arguments",
+                name
+            )));
+        }
+        let mut values = Vec::new();
+        // These take an array or an assignable target, the others only values
+        if !matches!(name, "length" | "split" | "sub" | "gsub" | "match") {
+            for arg in args {
+                values.push(self.eval(arg)?);
+            }
+        }
+        let number = |index: usize| values.get(index).map(|value| value.number()).unwrap_or(0.0);
+        let result = match name {
+            "length" => match args.first() {
+                None => self.record.chars().count() as f64,
+                Some(AwkExpr::Var(var)) if matches!(self.cell(*var), AwkCell::Array(_)) => {
+                    self.array(*var)?.borrow().len() as f64
+                }
+                Some(arg) => {
+                    let value = self.eval(arg)?;
+                    self.text(value).chars().count() as f64
+                }
+            },
+            "substr" => {
+                let text: Vec<char> = self.text(values[0].clone()).chars().collect();
+                // The positions are rounded, and the part out of the string is ignored
+                let start = number(1).round();
+                let end = if values.len() > 2 {
+                    start + number(2).round()
+                } else {
+                    f64::INFINITY
+                };
+                let start = if start.is_nan() { 1.0 } else { start.max(1.0) };
+                let end = if end.is_nan() {
+                    start
+                } else {
+                    end.min(text.len() as f64 + 1.0)
+                };
+                let part: String = if end <= start {
+                    String::new()
+                } else {
+                    text[start as usize - 1..end as usize - 1].iter().collect()
+                };
+                return Ok(AwkValue::Str(part));
+            }
+            "index" => {
+                let text = self.text(values[0].clone());
+                let find = self.text(values[1].clone());
+                match text.find(&find) {
+                    Some(position) => (text[..position].chars().count() + 1) as f64,
+                    None => 0.0,
+                }
+            }
+            "split" => {
+                let value = self.eval(&args[0])?;
+                let text = self.text(value);
+                let pieces = match args.get(2) {
+                    None => {
+                        let separator = self.special(AWK_FS);
+                        self.split(&text, &separator)?
+                    }
+                    Some(AwkExpr::Regex(regex)) if !text.is_empty() => {
+                        awk_regex_split(regex, &text)
+                    }
+                    Some(arg) => {
+                        let value = self.eval(arg)?;
+                        let separator = self.text(value);
+                        self.split(&text, &separator)?
+                    }
+                };
+                let array = match &args[1] {
+                    AwkExpr::Var(var) => self.array(*var)?,
+                    _ => {
+                        return Err(awk_error(String::from(
+                            "split: second argument is not an array",
+                        )))
+                    }
+                };
+                let mut array = array.borrow_mut();
+                array.clear();
+                for (index, piece) in pieces.iter().enumerate() {
+                    array.insert((index + 1).to_string(), AwkValue::StrNum(piece.clone()));
+                }
+                pieces.len() as f64
+            }
+            "sub" | "gsub" => {
+                let regex = self.regex_of(&args[0])?;
+                let value = self.eval(&args[1])?;
+                let replacement = self.text(value);
+                // The target is $0 by default
+                let record = AwkExpr::Field(Box::new(AwkExpr::Number(0.0)));
+                let place = self.place(args.get(2).unwrap_or(&record))?;
+                let value = self.get(&place)?;
+                let text = self.text(value);
+                let (result, count) = awk_substitute(&regex, &text, &replacement, name == "gsub");
+                if count > 0 {
+                    self.set(&place, AwkValue::Str(result))?;
+                }
+                count as f64
+            }
+            "match" => {
+                let value = self.eval(&args[0])?;
+                let text = self.text(value);
+                let regex = self.regex_of(&args[1])?;
+                let (start, length) = match regex.find(text.as_bytes()) {
+                    Some(found) => (
+                        text[..found.start()].chars().count() as f64 + 1.0,
+                        text[found.start()..found.end()].chars().count() as f64,
+                    ),
+                    None => (0.0, -1.0),
+                };
+                self.set_special(AWK_RSTART, AwkValue::Number(start));
+                self.set_special(AWK_RLENGTH, AwkValue::Number(length));
+                start
+            }
+            "sprintf" => {
+                let format = self.text(values[0].clone());
+                let text = awk_sprintf(&format, &values[1..], &self.special(AWK_CONVFMT))
+                    .map_err(awk_error)?;
+                return Ok(AwkValue::Str(text));
+            }
+            "sin" => number(0).sin(),
+            "cos" => number(0).cos(),
+            "atan2" => number(0).atan2(number(1)),
+            "exp" => number(0).exp(),
+            "log" => number(0).ln(),
+            "sqrt" => number(0).sqrt(),
+            "int" => number(0).trunc(),
+            "rand" => {
+                // splitmix64, seeded by srand
+                self.random = self.random.wrapping_add(0x9e3779b97f4a7c15);
+                let mut z = self.random;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+                z ^= z >> 31;
+                (z >> 11) as f64 / (1u64 << 53) as f64
+            }
+            "srand" => {
+                let previous = self.seed;
+                self.seed = match values.first() {
+                    Some(value) => value.number(),
+                    None => std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|time| time.as_secs() as f64)
+                        .unwrap_or(0.0),
+                };
+                self.random = self.seed.to_bits();
+                previous
+            }
+            "tolower" | "toupper" => {
+                let text = self.text(values[0].clone());
+                return Ok(AwkValue::Str(if name == "tolower" {
+                    text.to_lowercase()
+                } else {
+                    text.to_uppercase()
+                }));
+            }
+            "system" => {
+                let command = self.text(values[0].clone());
+                self.flush_all()?;
+                awk_status(
+                    std::process::Command::new("sh")
+                        .arg("-c")
+                        .arg(command)
+                        .status(),
+                )
+            }
+            "close" => {
+                let name = self.text(values[0].clone());
+                self.close(&name)
+            }
+            _ => {
+                // fflush
+                match values.first() {
+                    None => {
+                        self.flush_all()?;
+                        0.0
+                    }
+                    Some(value) => {
+                        let name = self.text(value.clone());
+                        match self.outputs.get_mut(&name) {
+                            Some(AwkOutput::File(file)) => file.flush().map_or(-1.0, |_| 0.0),
+                            Some(AwkOutput::Pipe(_, stdin)) => stdin.flush().map_or(-1.0, |_| 0.0),
+                            None if name == "/dev/stdout" => {
+                                self.out.flush().map_err(awk_write_error)?;
+                                0.0
+                            }
+                            None => -1.0,
+                        }
+                    }
+                }
+            }
+        };
+        Ok(AwkValue::Number(result))
+    }
+
+    fn record_separator(&mut self) -> Result<AwkSeparator, AwkJump> {
+        let separator = self.special(AWK_RS);
+        Ok(match separator.len() {
+            0 => AwkSeparator::Paragraph,
+            1 => AwkSeparator::Byte(separator.as_bytes()[0]),
+            _ => AwkSeparator::Regex(self.regex(&separator)?),
+        })
+    }
+
+    fn open_next(&mut self) -> Result<bool, AwkJump> {
+        // The operands are read from ARGV when needed, so BEGIN can change them
+        let argv = self.array(AwkVar::Global(AWK_ARGV))?;
+        while (self.next_argument as f64) < self.special_number(AWK_ARGC) {
+            let argument = argv
+                .borrow()
+                .get(&self.next_argument.to_string())
+                .map(|value| value.string("%.6g"))
+                .unwrap_or_default();
+            self.next_argument += 1;
+            if argument.is_empty() {
+                continue;
+            }
+            if let Some((name, value)) = awk_assignment(&argument) {
+                self.assign_variable(name, value)?;
+                continue;
+            }
+            self.used_operand = true;
+            let input: Box<dyn BufRead> = if argument == "-" || argument == "/dev/stdin" {
+                Box::new(BufReader::new(io::stdin()))
+            } else {
+                match File::open(&argument) {
+                    Ok(file) => Box::new(BufReader::new(file)),
+                    Err(e) => {
+                        return Err(awk_error(format!(
+                            "cannot open \"{}\" ({})",
+                            argument,
+                            strerror(&e)
+                        )))
+                    }
+                }
+            };
+            self.set_special(AWK_FILENAME, AwkValue::Str(argument));
+            self.set_special(AWK_FNR, AwkValue::Number(0.0));
+            self.main = Some(AwkReader::new(input));
+            return Ok(true);
+        }
+        // Without file operands, the standard input is read
+        if !self.used_operand {
+            self.used_operand = true;
+            self.main = Some(AwkReader::new(Box::new(BufReader::new(io::stdin()))));
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn next_record(&mut self) -> Result<Option<String>, AwkJump> {
+        let separator = self.record_separator()?;
+        loop {
+            if let Some(reader) = self.main.as_mut() {
+                match reader.read(&separator) {
+                    Ok(Some(record)) => {
+                        let nr = self.special_number(AWK_NR) + 1.0;
+                        let fnr = self.special_number(AWK_FNR) + 1.0;
+                        self.set_special(AWK_NR, AwkValue::Number(nr));
+                        self.set_special(AWK_FNR, AwkValue::Number(fnr));
+                        return Ok(Some(record));
+                    }
+                    Ok(None) => self.main = None,
+                    Err(e) => return Err(awk_error(format!("read error ({})", strerror(&e)))),
+                }
+            }
+            if !self.open_next()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn assign_variable(&mut self, name: &str, value: String) -> Result<(), AwkJump> {
+        // Variables the program never uses don't need to be set
+        match self.names.get(name) {
+            Some(index) => self.set(
+                &AwkPlace::Var(AwkVar::Global(*index)),
+                AwkValue::StrNum(value),
+            ),
+            None => Ok(()),
+        }
+    }
+
+    fn getline(
+        &mut self,
+        source: &AwkSource,
+        target: Option<&AwkExpr>,
+    ) -> Result<AwkValue, AwkJump> {
+        let record = match source {
+            AwkSource::Main => match self.next_record()? {
+                Some(record) => record,
+                None => return Ok(AwkValue::Number(0.0)),
+            },
+            AwkSource::File(name) | AwkSource::Command(name) => {
+                let command = matches!(source, AwkSource::Command(_));
+                let value = self.eval(name)?;
+                let name = self.text(value);
+                if !self.inputs.contains_key(&name) {
+                    let input = if command {
+                        self.flush_all()?;
+                        let child = std::process::Command::new("sh")
+                            .arg("-c")
+                            .arg(&name)
+                            .stdout(std::process::Stdio::piped())
+                            .spawn();
+                        match child {
+                            Ok(mut child) => match child.stdout.take() {
+                                Some(stdout) => AwkInput {
+                                    reader: AwkReader::new(Box::new(BufReader::new(stdout))),
+                                    child: Some(child),
+                                },
+                                None => return Ok(AwkValue::Number(-1.0)),
+                            },
+                            Err(_) => return Ok(AwkValue::Number(-1.0)),
+                        }
+                    } else if name == "-" || name == "/dev/stdin" {
+                        AwkInput {
+                            reader: AwkReader::new(Box::new(BufReader::new(io::stdin()))),
+                            child: None,
+                        }
+                    } else {
+                        match File::open(&name) {
+                            Ok(file) => AwkInput {
+                                reader: AwkReader::new(Box::new(BufReader::new(file))),
+                                child: None,
+                            },
+                            Err(_) => return Ok(AwkValue::Number(-1.0)),
+                        }
+                    };
+                    self.inputs.insert(name.clone(), input);
+                }
+                let separator = self.record_separator()?;
+                let read = match self.inputs.get_mut(&name) {
+                    Some(input) => input.reader.read(&separator),
+                    None => Ok(None),
+                };
+                match read {
+                    Ok(Some(record)) => {
+                        if command {
+                            let nr = self.special_number(AWK_NR) + 1.0;
+                            self.set_special(AWK_NR, AwkValue::Number(nr));
+                        }
+                        record
+                    }
+                    Ok(None) => return Ok(AwkValue::Number(0.0)),
+                    Err(_) => return Ok(AwkValue::Number(-1.0)),
+                }
+            }
+        };
+        match target {
+            Some(target) => {
+                let place = self.place(target)?;
+                self.set(&place, AwkValue::StrNum(record))?;
+            }
+            None => self.set_record(record)?,
+        }
+        Ok(AwkValue::Number(1.0))
+    }
+
+    fn output(&mut self, redirect: &AwkRedirect, text: &str) -> Result<(), AwkJump> {
+        let (mode, target) = match redirect {
+            Some((mode, target)) => (*mode, target),
+            None => return self.out.write_all(text.as_bytes()).map_err(awk_write_error),
+        };
+        let value = self.eval(target)?;
+        let name = self.text(value);
+        match name.as_str() {
+            "/dev/stdout" | "-" if mode != "|" => {
+                return self.out.write_all(text.as_bytes()).map_err(awk_write_error)
+            }
+            "/dev/stderr" if mode != "|" => {
+                self.out.flush().map_err(awk_write_error)?;
+                return io::stderr()
+                    .write_all(text.as_bytes())
+                    .map_err(awk_write_error);
+            }
+            _ => (),
+        }
+        if !self.outputs.contains_key(&name) {
+            let output = if mode == "|" {
+                // The command's output goes after what was already printed
+                self.flush_all()?;
+                std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(&name)
+                    .stdin(std::process::Stdio::piped())
+                    .spawn()
+                    .and_then(|mut child| match child.stdin.take() {
+                        Some(stdin) => Ok(AwkOutput::Pipe(child, io::BufWriter::new(stdin))),
+                        None => Err(io::Error::other("no pipe")),
+                    })
+            } else {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .append(mode == ">>")
+                    .truncate(mode == ">")
+                    .open(&name)
+                    .map(|file| AwkOutput::File(io::BufWriter::new(file)))
+            };
+            let output = output
+                .map_err(|e| awk_error(format!("can't redirect to {} ({})", name, strerror(&e))))?;
+            self.outputs.insert(name.clone(), output);
+        }
+        let written = match self.outputs.get_mut(&name) {
+            Some(AwkOutput::File(file)) => file.write_all(text.as_bytes()),
+            Some(AwkOutput::Pipe(_, stdin)) => stdin.write_all(text.as_bytes()),
+            None => Ok(()),
+        };
+        written.map_err(awk_write_error)
+    }
+
+    fn execute_all(&mut self, statements: &[AwkStmt]) -> Result<AwkFlow, AwkJump> {
+        for statement in statements {
+            match self.execute(statement)? {
+                AwkFlow::Normal => (),
+                flow => return Ok(flow),
+            }
+        }
+        Ok(AwkFlow::Normal)
+    }
+
+    // Runs the body of a loop, 'Some' when the loop has to stop with this flow
+    fn loop_body(&mut self, body: &AwkStmt) -> Result<Option<AwkFlow>, AwkJump> {
+        Ok(match self.execute(body)? {
+            AwkFlow::Break => Some(AwkFlow::Normal),
+            AwkFlow::Return(value) => Some(AwkFlow::Return(value)),
+            AwkFlow::Normal | AwkFlow::Continue => None,
+        })
+    }
+
+    fn execute(&mut self, statement: &AwkStmt) -> Result<AwkFlow, AwkJump> {
+        match statement {
+            AwkStmt::Expr(expr) => {
+                self.eval(expr)?;
+            }
+            AwkStmt::Print(args, redirect) => {
+                let mut text = if args.is_empty() {
+                    self.record.clone()
+                } else {
+                    let separator = self.special(AWK_OFS);
+                    let mut text = String::new();
+                    for (index, arg) in args.iter().enumerate() {
+                        if index > 0 {
+                            text.push_str(&separator);
+                        }
+                        // Numbers are printed with OFMT instead of CONVFMT
+                        match self.eval(arg)? {
+                            AwkValue::Number(number) => {
+                                text.push_str(&awk_number_string(number, &self.special(AWK_OFMT)))
+                            }
+                            value => text.push_str(&self.text(value)),
+                        }
+                    }
+                    text
+                };
+                text.push_str(&self.special(AWK_ORS));
+                self.output(redirect, &text)?;
+            }
+            AwkStmt::Printf(args, redirect) => {
+                let mut values = Vec::new();
+                for arg in args {
+                    values.push(self.eval(arg)?);
+                }
+                let format = self.text(values[0].clone());
+                let text = awk_sprintf(&format, &values[1..], &self.special(AWK_CONVFMT))
+                    .map_err(awk_error)?;
+                self.output(redirect, &text)?;
+            }
+            AwkStmt::If(condition, then, other) => {
+                if self.eval(condition)?.truth() {
+                    return self.execute(then);
+                } else if let Some(other) = other {
+                    return self.execute(other);
+                }
+            }
+            AwkStmt::While(condition, body) => {
+                while self.eval(condition)?.truth() {
+                    if let Some(flow) = self.loop_body(body)? {
+                        return Ok(flow);
+                    }
+                }
+            }
+            AwkStmt::Do(body, condition) => loop {
+                if let Some(flow) = self.loop_body(body)? {
+                    return Ok(flow);
+                }
+                if !self.eval(condition)?.truth() {
+                    break;
+                }
+            },
+            AwkStmt::For(init, condition, step, body) => {
+                if let Some(init) = init {
+                    self.execute(init)?;
+                }
+                loop {
+                    if let Some(condition) = condition {
+                        if !self.eval(condition)?.truth() {
+                            break;
+                        }
+                    }
+                    if let Some(flow) = self.loop_body(body)? {
+                        return Ok(flow);
+                    }
+                    if let Some(step) = step {
+                        self.execute(step)?;
+                    }
+                }
+            }
+            AwkStmt::ForIn(target, var, body) => {
+                let array = self.array(*var)?;
+                let keys = awk_sorted_keys(&array.borrow());
+                for key in keys {
+                    // Elements deleted by the body aren't visited
+                    if !array.borrow().contains_key(&key) {
+                        continue;
+                    }
+                    let place = self.place(target)?;
+                    self.set(&place, AwkValue::StrNum(key))?;
+                    if let Some(flow) = self.loop_body(body)? {
+                        return Ok(flow);
+                    }
+                }
+            }
+            AwkStmt::Block(statements) => return self.execute_all(statements),
+            AwkStmt::Delete(var, subscripts) => {
+                let array = self.array(*var)?;
+                match subscripts {
+                    Some(subscripts) => {
+                        let key = self.subscript(subscripts)?;
+                        array.borrow_mut().remove(&key);
+                    }
+                    None => array.borrow_mut().clear(),
+                }
+            }
+            AwkStmt::Next => return Err(AwkJump::Next),
+            AwkStmt::NextFile => return Err(AwkJump::NextFile),
+            AwkStmt::Break => return Ok(AwkFlow::Break),
+            AwkStmt::Continue => return Ok(AwkFlow::Continue),
+            AwkStmt::Exit(code) => {
+                if let Some(code) = code {
+                    self.exit_code = self.eval(code)?.number() as i32;
+                }
+                return Err(AwkJump::Exit);
+            }
+            AwkStmt::Return(value) => {
+                let value = match value {
+                    Some(value) => self.eval(value)?,
+                    None => AwkValue::Uninit,
+                };
+                return Ok(AwkFlow::Return(value));
+            }
+        }
+        Ok(AwkFlow::Normal)
+    }
+
+    fn process_record(&mut self, items: &[AwkItem], ranges: &mut [bool]) -> Result<(), AwkJump> {
+        for (index, item) in items.iter().enumerate() {
+            let selected = match &item.pattern {
+                AwkPattern::Begin | AwkPattern::End => continue,
+                AwkPattern::All => true,
+                AwkPattern::Expr(expr) => self.eval(expr)?.truth(),
+                AwkPattern::Range(first, second) => {
+                    if ranges[index] || self.eval(first)?.truth() {
+                        ranges[index] = !self.eval(second)?.truth();
+                        true
+                    } else {
+                        false
+                    }
+                }
+            };
+            if !selected {
+                continue;
+            }
+            match &item.action {
+                Some(action) => {
+                    self.execute_all(action)?;
+                }
+                None => {
+                    let text = format!("{}{}", self.record, self.special(AWK_ORS));
+                    self.out
+                        .write_all(text.as_bytes())
+                        .map_err(awk_write_error)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn run(&mut self, items: &[AwkItem]) -> Result<(), AwkJump> {
+        let special = |jump: AwkJump, rule: &str| match jump {
+            AwkJump::Next | AwkJump::NextFile => awk_error(format!("next used in {} action", rule)),
+            jump => jump,
+        };
+        let mut exited = false;
+        for item in items
+            .iter()
+            .filter(|item| matches!(item.pattern, AwkPattern::Begin))
+        {
+            match self.execute_all(item.action.as_deref().unwrap_or_default()) {
+                Ok(_) => (),
+                Err(AwkJump::Exit) => {
+                    exited = true;
+                    break;
+                }
+                Err(jump) => return Err(special(jump, "BEGIN")),
+            }
+        }
+        // Without other rules than BEGIN, the input isn't read
+        if !exited
+            && items
+                .iter()
+                .any(|item| !matches!(item.pattern, AwkPattern::Begin))
+        {
+            let mut ranges = vec![false; items.len()];
+            while let Some(record) = self.next_record()? {
+                self.set_record(record)?;
+                match self.process_record(items, &mut ranges) {
+                    Ok(()) | Err(AwkJump::Next) => (),
+                    Err(AwkJump::NextFile) => self.main = None,
+                    Err(AwkJump::Exit) => break,
+                    Err(jump) => return Err(jump),
+                }
+            }
+        }
+        for item in items
+            .iter()
+            .filter(|item| matches!(item.pattern, AwkPattern::End))
+        {
+            match self.execute_all(item.action.as_deref().unwrap_or_default()) {
+                Ok(_) => (),
+                Err(AwkJump::Exit) => break,
+                Err(jump) => return Err(special(jump, "END")),
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), AwkJump> {
+        self.out.flush().map_err(awk_write_error)?;
+        let names: Vec<String> = self.outputs.keys().cloned().collect();
+        for name in names {
+            self.close(&name);
+        }
+        let names: Vec<String> = self.inputs.keys().cloned().collect();
+        for name in names {
+            self.close(&name);
+        }
+        Ok(())
+    }
+}
+
+fn awk_arithmetic(op: char, left: f64, right: f64) -> Result<f64, AwkJump> {
+    Ok(match op {
+        '+' => left + right,
+        '-' => left - right,
+        '*' => left * right,
+        '/' if right == 0.0 => return Err(awk_error(String::from("division by zero"))),
+        '/' => left / right,
+        '%' if right == 0.0 => return Err(awk_error(String::from("division by zero in %"))),
+        '%' => left % right,
+        _ => left.powf(right),
+    })
+}
+
+fn awk_regex_split(regex: &regex::bytes::Regex, text: &str) -> Vec<String> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    regex
+        .split(text.as_bytes())
+        .map(|field| String::from_utf8_lossy(field).into_owned())
+        .collect()
+}
+
+fn awk_sorted_keys(array: &HashMap<String, AwkValue>) -> Vec<String> {
+    // The order of 'for (key in array)' is unspecified; numbers in order, then strings
+    let mut keys: Vec<(Option<f64>, String)> = array
+        .keys()
+        .map(|key| match awk_number_prefix(key) {
+            (number, true) => (Some(number), key.clone()),
+            _ => (None, key.clone()),
+        })
+        .collect();
+    keys.sort_by(|a, b| match (a.0, b.0) {
+        (Some(x), Some(y)) => x.partial_cmp(&y).unwrap_or(std::cmp::Ordering::Equal),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => a.1.cmp(&b.1),
+    });
+    keys.into_iter().map(|(_, key)| key).collect()
+}
+
+fn awk(args: Vec<String>) -> Result<(), io::Error> {
+    // Deeply recursive awk functions need a bigger stack than the main thread has
+    std::thread::Builder::new()
+        .stack_size(1 << 30)
+        .spawn(move || awk_main(args))?
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("awk failed")))
+}
+
+fn awk_main(args: Vec<String>) -> Result<(), io::Error> {
+    let mut separator: Option<String> = None;
+    let mut assignments: Vec<String> = Vec::new();
+    let mut program_files: Vec<String> = Vec::new();
+    let mut operands: Vec<String> = Vec::new();
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message.to_string());
+
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--" => {
+                operands.extend(iter.by_ref().cloned());
+                break;
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                // The value can be attached, like '-F:' or '-vname=value'
+                let option = arg.chars().nth(1).unwrap_or('-');
+                if !matches!(option, 'F' | 'v' | 'f') {
+                    return Err(invalid("Can't use 'awk' with this option."));
+                }
+                let value = match &arg[2..] {
+                    "" => iter
+                        .next()
+                        .ok_or_else(|| invalid("Option requires a value."))?
+                        .clone(),
+                    rest => rest.to_string(),
+                };
+                match option {
+                    'F' => separator = Some(value),
+                    'v' => assignments.push(value),
+                    _ => program_files.push(value),
+                }
+            }
+            _ => {
+                operands.push(arg.clone());
+                operands.extend(iter.by_ref().cloned());
+                break;
+            }
+        }
+    }
+
+    // Without '-f', the first operand is the program
+    let program = if program_files.is_empty() {
+        if operands.is_empty() {
+            eprintln!("usage: awk [-F fs][-v var=value][prog | -f progfile][file ...]");
+            return Err(invalid("Missing program."));
+        }
+        operands.remove(0)
+    } else {
+        let mut program = String::new();
+        for name in &program_files {
+            let mut text = String::new();
+            let result = if name == "-" {
+                io::stdin().read_to_string(&mut text)
+            } else {
+                File::open(name).and_then(|mut file| file.read_to_string(&mut text))
+            };
+            if let Err(e) = result {
+                eprintln!("awk: can't open file {}: {}", name, strerror(&e));
+                return Err(e);
+            }
+            program.push_str(&text);
+            program.push('\n');
+        }
+        program
+    };
+
+    let mut functions = HashMap::new();
+    let mut parser = AwkParser {
+        tokens: Vec::new(),
+        position: 0,
+        globals: AWK_SPECIALS
+            .iter()
+            .enumerate()
+            .map(|(index, name)| (name.to_string(), index))
+            .collect(),
+        locals: None,
+    };
+    let parsed = awk_lex(&program).and_then(|tokens| {
+        parser.tokens = tokens;
+        parser.program(&mut functions)
+    });
+    let items = match parsed {
+        Ok(items) => items,
+        Err(message) => {
+            eprintln!("awk: {}", message);
+            return Err(invalid(&message));
+        }
+    };
+
+    let mut state = Awk {
+        globals: (0..parser.globals.len()).map(|_| AwkCell::Uninit).collect(),
+        names: parser.globals,
+        frames: Vec::new(),
+        functions,
+        record: String::new(),
+        fields: Vec::new(),
+        regexes: HashMap::new(),
+        outputs: HashMap::new(),
+        inputs: HashMap::new(),
+        main: None,
+        next_argument: 1,
+        used_operand: false,
+        out: io::BufWriter::new(io::stdout()),
+        seed: 0.0,
+        random: 0,
+        exit_code: 0,
+    };
+    let text = |text: &str| AwkValue::Str(text.to_string());
+    for (index, value) in [
+        (AWK_NR, AwkValue::Number(0.0)),
+        (AWK_NF, AwkValue::Number(0.0)),
+        (AWK_FNR, AwkValue::Number(0.0)),
+        (AWK_FS, text(" ")),
+        (AWK_OFS, text(" ")),
+        (AWK_ORS, text("\n")),
+        (AWK_RS, text("\n")),
+        (AWK_FILENAME, text("")),
+        (AWK_SUBSEP, text("\x1c")),
+        (AWK_RSTART, AwkValue::Number(0.0)),
+        (AWK_RLENGTH, AwkValue::Number(-1.0)),
+        (AWK_CONVFMT, text("%.6g")),
+        (AWK_OFMT, text("%.6g")),
+        (AWK_ARGC, AwkValue::Number(operands.len() as f64 + 1.0)),
+    ] {
+        state.set_special(index, value);
+    }
+    let environ: HashMap<String, AwkValue> = env::vars_os()
+        .map(|(name, value)| {
+            (
+                name.to_string_lossy().into_owned(),
+                AwkValue::StrNum(value.to_string_lossy().into_owned()),
+            )
+        })
+        .collect();
+    state.globals[AWK_ENVIRON] = AwkCell::Array(Rc::new(RefCell::new(environ)));
+    let argv: HashMap<String, AwkValue> = std::iter::once(String::from("awk"))
+        .chain(operands)
+        .enumerate()
+        .map(|(index, arg)| (index.to_string(), AwkValue::StrNum(arg)))
+        .collect();
+    state.globals[AWK_ARGV] = AwkCell::Array(Rc::new(RefCell::new(argv)));
+
+    let result = (|| {
+        if let Some(separator) = separator {
+            // '-Ft' means a tab
+            let separator = if separator == "t" {
+                String::from("\t")
+            } else {
+                awk_assignment(&format!("FS={}", separator))
+                    .map(|(_, value)| value)
+                    .unwrap_or(separator)
+            };
+            state.set_special(AWK_FS, AwkValue::Str(separator));
+        }
+        for assignment in &assignments {
+            match awk_assignment(assignment) {
+                Some((name, value)) => state.assign_variable(name, value)?,
+                None => return Err(awk_error(format!("invalid -v argument: {}", assignment))),
+            }
+        }
+        state.run(&items)?;
+        state.finish()
+    })();
+    match result {
+        Ok(()) | Err(AwkJump::Exit) => (),
+        Err(AwkJump::Error(message)) => {
+            let _ = state.finish();
+            if !message.is_empty() {
+                eprintln!("awk: {}", message);
+            }
+            return Err(io::Error::other(message));
+        }
+        Err(_) => (),
+    }
+    if state.exit_code != 0 {
+        std::process::exit(state.exit_code);
+    }
+    Ok(())
+}
+
+enum DiffFormat {
+    Normal,
+    // The number of context lines
+    Unified(usize),
+    Context(usize),
+}
+
+struct DiffOptions {
+    format: DiffFormat,
+    recursive: bool,
+    new_file: bool,
+    brief: bool,
+    identical: bool,
+    text: bool,
+    ignore_case: bool,
+    ignore_all_space: bool,
+    ignore_space_change: bool,
+    ignore_blank_lines: bool,
+    labels: Vec<String>,
+    // The options as given, repeated in the 'diff ...' lines of directory comparisons
+    switches: String,
+    zone: TimeZone,
+}
+
+// What was found so far: differences, and errors which make the exit status 2
+struct DiffStatus {
+    differ: bool,
+    trouble: bool,
+}
+
+// A group of changed lines: a[a..a_end] was replaced by b[b..b_end]
+struct DiffChange {
+    a: usize,
+    a_end: usize,
+    b: usize,
+    b_end: usize,
+    // Only blank lines changed, ignored with -B
+    ignore: bool,
+}
+
+fn diff(args: Vec<String>) -> Result<(), io::Error> {
+    let mut options = DiffOptions {
+        format: DiffFormat::Normal,
+        recursive: false,
+        new_file: false,
+        brief: false,
+        identical: false,
+        text: false,
+        ignore_case: false,
+        ignore_all_space: false,
+        ignore_space_change: false,
+        ignore_blank_lines: false,
+        labels: Vec::new(),
+        switches: String::new(),
+        zone: load_time_zone(),
+    };
+    let mut operands: Vec<String> = Vec::new();
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message.to_string());
+    let missing = |option: &str| {
+        match option.strip_prefix("--") {
+            Some(_) => eprintln!("diff: option '{}' requires an argument", option),
+            None => eprintln!("diff: option requires an argument -- '{}'", option),
+        }
+        invalid("Option requires a value.")
+    };
+    let lines = |value: &str| -> io::Result<usize> {
+        value.parse().map_err(|_| {
+            eprintln!("diff: invalid context length '{}'", value);
+            invalid("Invalid context length.")
+        })
+    };
+
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        if arg.starts_with('-') && arg.len() > 1 && arg != "--" {
+            options.switches.push(' ');
+            options.switches.push_str(arg);
+        }
+        match arg.as_str() {
+            "--" => {
+                operands.extend(iter.by_ref().cloned());
+                break;
+            }
+            "--unified" => options.format = DiffFormat::Unified(3),
+            "--context" => options.format = DiffFormat::Context(3),
+            "--recursive" => options.recursive = true,
+            "--new-file" => options.new_file = true,
+            "--brief" => options.brief = true,
+            "--report-identical-files" => options.identical = true,
+            "--text" => options.text = true,
+            "--ignore-case" => options.ignore_case = true,
+            "--ignore-all-space" => options.ignore_all_space = true,
+            "--ignore-space-change" => options.ignore_space_change = true,
+            "--ignore-blank-lines" => options.ignore_blank_lines = true,
+            "--label" => {
+                let value = iter.next().ok_or_else(|| missing(arg))?;
+                options.switches.push(' ');
+                options.switches.push_str(value);
+                options.labels.push(value.clone());
+            }
+            _ if arg.starts_with("--label=") => options.labels.push(arg[8..].to_string()),
+            _ if arg.starts_with("--unified=") => {
+                options.format = DiffFormat::Unified(lines(&arg[10..])?)
+            }
+            _ if arg.starts_with("--context=") => {
+                options.format = DiffFormat::Context(lines(&arg[10..])?)
+            }
+            _ if arg.starts_with("--") => {
+                eprintln!("diff: unrecognized option '{}'", arg);
+                return Err(invalid("Can't use 'diff' with this option."));
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                // Short options can be grouped, like '-ru' or '-U5'
+                for (index, c) in arg.char_indices().skip(1) {
+                    match c {
+                        'u' => options.format = DiffFormat::Unified(3),
+                        'c' => options.format = DiffFormat::Context(3),
+                        'r' => options.recursive = true,
+                        'N' => options.new_file = true,
+                        'q' => options.brief = true,
+                        's' => options.identical = true,
+                        'a' => options.text = true,
+                        'i' => options.ignore_case = true,
+                        'w' => options.ignore_all_space = true,
+                        'b' => options.ignore_space_change = true,
+                        'B' => options.ignore_blank_lines = true,
+                        'U' | 'C' | 'L' => {
+                            let value = match &arg[index + 1..] {
+                                "" => {
+                                    let value =
+                                        iter.next().ok_or_else(|| missing(&c.to_string()))?;
+                                    options.switches.push(' ');
+                                    options.switches.push_str(value);
+                                    value.clone()
+                                }
+                                rest => rest.to_string(),
+                            };
+                            match c {
+                                'U' => options.format = DiffFormat::Unified(lines(&value)?),
+                                'C' => options.format = DiffFormat::Context(lines(&value)?),
+                                _ => options.labels.push(value),
+                            }
+                            break;
+                        }
+                        _ => {
+                            eprintln!("diff: invalid option -- '{}'", c);
+                            return Err(invalid("Can't use 'diff' with this option."));
+                        }
+                    }
+                }
+            }
+            _ => operands.push(arg.clone()),
+        }
+    }
+    if operands.len() != 2 {
+        match operands.last() {
+            Some(last) if operands.len() > 2 => eprintln!("diff: extra operand '{}'", last),
+            Some(last) => eprintln!("diff: missing operand after '{}'", last),
+            None => eprintln!("diff: missing operand"),
+        }
+        return Err(invalid("Need two operands."));
+    }
+
+    let mut status = DiffStatus {
+        differ: false,
+        trouble: false,
+    };
+    let mut out = io::BufWriter::new(io::stdout());
+    let mut a = PathBuf::from(&operands[0]);
+    let mut b = PathBuf::from(&operands[1]);
+    // A file is compared with the file of the same name in a directory
+    let is_dir = |path: &Path| path != Path::new("-") && path.is_dir();
+    if is_dir(&a) && !is_dir(&b) && b != Path::new("-") {
+        if let Some(name) = b.file_name() {
+            a = a.join(name);
+        }
+    } else if is_dir(&b) && !is_dir(&a) && a != Path::new("-") {
+        if let Some(name) = a.file_name() {
+            b = b.join(name);
+        }
+    }
+    diff_paths(&a, &b, &options, &mut status, &mut out, true)?;
+    out.flush()?;
+    if status.trouble {
+        return Err(io::Error::other("Trouble comparing the files."));
+    }
+    if status.differ {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn diff_paths(
+    a: &Path,
+    b: &Path,
+    options: &DiffOptions,
+    status: &mut DiffStatus,
+    out: &mut dyn Write,
+    top: bool,
+) -> io::Result<()> {
+    let metadata = |path: &Path| {
+        if path == Path::new("-") {
+            return Ok(None);
+        }
+        fs::metadata(path).map(Some)
+    };
+    let (a_metadata, b_metadata) = match (metadata(a), metadata(b)) {
+        (Ok(a_metadata), Ok(b_metadata)) => (a_metadata, b_metadata),
+        (a_result, b_result) => {
+            // A missing file is empty with -N, if the other one exists
+            let missing = |result: &io::Result<Option<fs::Metadata>>| matches!(result, Err(e) if e.kind() == ErrorKind::NotFound);
+            let allowed = options.new_file && (a_result.is_ok() || b_result.is_ok());
+            for (path, result) in [(a, &a_result), (b, &b_result)] {
+                if let Err(e) = result {
+                    if !(allowed && missing(result)) {
+                        eprintln!("diff: {}: {}", path.display(), strerror(e));
+                        status.trouble = true;
+                    }
+                }
+            }
+            if !allowed || !(missing(&a_result) || missing(&b_result)) {
+                return Ok(());
+            }
+            let a_metadata = a_result.ok().flatten();
+            let b_metadata = b_result.ok().flatten();
+            let dir =
+                |metadata: &Option<fs::Metadata>| metadata.as_ref().is_some_and(|m| m.is_dir());
+            if dir(&a_metadata) || dir(&b_metadata) {
+                return diff_directories(a, b, options, status, out);
+            }
+            return diff_files(
+                a,
+                b,
+                a_metadata.as_ref(),
+                b_metadata.as_ref(),
+                options,
+                status,
+                out,
+                top,
+            );
+        }
+    };
+    let a_dir = a_metadata.as_ref().is_some_and(|m| m.is_dir());
+    let b_dir = b_metadata.as_ref().is_some_and(|m| m.is_dir());
+    match (a_dir, b_dir) {
+        (true, true) => diff_directories(a, b, options, status, out),
+        (false, false) => diff_files(
+            a,
+            b,
+            a_metadata.as_ref(),
+            b_metadata.as_ref(),
+            options,
+            status,
+            out,
+            top,
+        ),
+        _ => {
+            let kind = |metadata: &Option<fs::Metadata>| match metadata {
+                Some(metadata) => diff_file_type(metadata),
+                None => "fifo",
+            };
+            writeln!(
+                out,
+                "File {} is a {} while file {} is a {}",
+                a.display(),
+                kind(&a_metadata),
+                b.display(),
+                kind(&b_metadata)
+            )?;
+            status.differ = true;
+            Ok(())
+        }
+    }
+}
+
+fn diff_file_type(metadata: &fs::Metadata) -> &'static str {
+    let kind = metadata.file_type();
+    if kind.is_dir() {
+        "directory"
+    } else if kind.is_file() && metadata.len() == 0 {
+        "regular empty file"
+    } else if kind.is_file() {
+        "regular file"
+    } else {
+        use std::os::unix::fs::FileTypeExt;
+        if kind.is_fifo() {
+            "fifo"
+        } else if kind.is_char_device() {
+            "character special file"
+        } else if kind.is_block_device() {
+            "block special file"
+        } else if kind.is_socket() {
+            "socket"
+        } else {
+            "weird file"
+        }
+    }
+}
+
+fn diff_directories(
+    a: &Path,
+    b: &Path,
+    options: &DiffOptions,
+    status: &mut DiffStatus,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    // The names in both directories, sorted; a missing directory is empty with -N
+    let mut names: Vec<OsString> = Vec::new();
+    for dir in [a, b] {
+        match fs::read_dir(dir) {
+            Ok(entries) => {
+                for entry in entries {
+                    names.push(entry?.file_name());
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound && options.new_file => (),
+            Err(e) => {
+                eprintln!("diff: {}: {}", dir.display(), strerror(&e));
+                status.trouble = true;
+                return Ok(());
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    for name in names {
+        let a_path = a.join(&name);
+        let b_path = b.join(&name);
+        let a_metadata = fs::metadata(&a_path).ok();
+        let b_metadata = fs::metadata(&b_path).ok();
+        match (&a_metadata, &b_metadata) {
+            (Some(_), None) | (None, Some(_)) if !options.new_file => {
+                let dir = if a_metadata.is_some() { a } else { b };
+                writeln!(out, "Only in {}: {}", dir.display(), name.to_string_lossy())?;
+                status.differ = true;
+            }
+            (Some(a_metadata), Some(b_metadata))
+                if a_metadata.is_dir() && b_metadata.is_dir() && !options.recursive =>
+            {
+                writeln!(
+                    out,
+                    "Common subdirectories: {} and {}",
+                    a_path.display(),
+                    b_path.display()
+                )?;
+            }
+            _ => diff_paths(&a_path, &b_path, options, status, out, false)?,
+        }
+    }
+    Ok(())
+}
+
+fn diff_timestamp(time: (i64, i64), zone: &TimeZone, unified: bool) -> String {
+    // Like '2024-01-02 03:04:05.123456789 +0000' for unified diffs and
+    // 'Tue Jan  2 03:04:05 2024' for context diffs, in local time
+    let (seconds, nanoseconds) = time;
+    let offset = zone.offset_at(seconds);
+    let local = seconds + offset;
+    let days = local.div_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let time = local.rem_euclid(86400);
+    let clock = format!("{:02}:{:02}:{:02}", time / 3600, time / 60 % 60, time % 60);
+    if !unified {
+        const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        // The 1st of January 1970 was a Thursday
+        return format!(
+            "{} {} {:2} {} {}",
+            WEEKDAYS[(days + 4).rem_euclid(7) as usize],
+            MONTHS[month as usize - 1],
+            day,
+            clock,
+            year
+        );
+    }
+    format!(
+        "{:04}-{:02}-{:02} {}.{:09} {}{:02}{:02}",
+        year,
+        month,
+        day,
+        clock,
+        nanoseconds,
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 3600,
+        offset.abs() / 60 % 60
+    )
+}
+
+fn diff_key<'a>(line: &'a [u8], options: &DiffOptions) -> std::borrow::Cow<'a, [u8]> {
+    if !options.ignore_case && !options.ignore_all_space && !options.ignore_space_change {
+        return std::borrow::Cow::Borrowed(line);
+    }
+    let mut key = Vec::with_capacity(line.len());
+    let mut line = line;
+    // Ignoring white space also ignores a missing newline at the end
+    if options.ignore_all_space || options.ignore_space_change {
+        line = line.strip_suffix(b"\n").unwrap_or(line);
+    }
+    let space = |c: u8| matches!(c, b' ' | b'\t' | b'\r' | b'\x0b' | b'\x0c');
+    let mut in_space = false;
+    for &c in line {
+        if (options.ignore_all_space || options.ignore_space_change) && space(c) {
+            in_space = true;
+            continue;
+        }
+        // With -b, a run of white space is the same as a single space
+        if in_space && options.ignore_space_change && !options.ignore_all_space {
+            key.push(b' ');
+        }
+        in_space = false;
+        key.push(if options.ignore_case {
+            c.to_ascii_lowercase()
+        } else {
+            c
+        });
+    }
+    std::borrow::Cow::Owned(key)
+}
+
+// Where to split the lines in two, and whether each half must be compared exactly
+struct DiffSplit {
+    x: usize,
+    y: usize,
+    low_minimal: bool,
+    high_minimal: bool,
+}
+
+fn diff_bisect(a: &[usize], b: &[usize], minimal: bool, too_expensive: isize) -> DiffSplit {
+    // Find where the forward and backward searches of Myers' algorithm meet: a point of
+    // an optimal path splitting the problem in two, found in linear space. The diagonals
+    // are numbered by x - y, with 'm + 1' added for indexing.
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let index = |d: isize| (d + m + 1) as usize;
+    let mut forward = vec![0isize; (n + m + 3) as usize];
+    let mut backward = vec![0isize; (n + m + 3) as usize];
+    let (min, max) = (-m, n);
+    let (mut forward_min, mut forward_max) = (0, 0);
+    let (mut backward_min, mut backward_max) = (n - m, n - m);
+    // With an odd delta, the searches meet while going forward
+    let odd = (n - m) & 1 != 0;
+    forward[index(0)] = 0;
+    backward[index(n - m)] = n;
+    let split = |x: isize, y: isize, low_minimal: bool, high_minimal: bool| DiffSplit {
+        x: x as usize,
+        y: y as usize,
+        low_minimal,
+        high_minimal,
+    };
+    let mut cost = 0;
+    loop {
+        cost += 1;
+        if forward_min > min {
+            forward_min -= 1;
+            forward[index(forward_min - 1)] = -1;
+        } else {
+            forward_min += 1;
+        }
+        if forward_max < max {
+            forward_max += 1;
+            forward[index(forward_max + 1)] = -1;
+        } else {
+            forward_max -= 1;
+        }
+        let mut d = forward_max;
+        while d >= forward_min {
+            let (low, high) = (forward[index(d - 1)], forward[index(d + 1)]);
+            let mut x = if low < high { high } else { low + 1 };
+            let mut y = x - d;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[index(d)] = x;
+            if odd && backward_min <= d && d <= backward_max && backward[index(d)] <= x {
+                return split(x, y, true, true);
+            }
+            d -= 2;
+        }
+
+        if backward_min > min {
+            backward_min -= 1;
+            backward[index(backward_min - 1)] = isize::MAX;
+        } else {
+            backward_min += 1;
+        }
+        if backward_max < max {
+            backward_max += 1;
+            backward[index(backward_max + 1)] = isize::MAX;
+        } else {
+            backward_max -= 1;
+        }
+        let mut d = backward_max;
+        while d >= backward_min {
+            let (low, high) = (backward[index(d - 1)], backward[index(d + 1)]);
+            let mut x = if low < high { low } else { high - 1 };
+            let mut y = x - d;
+            while x > 0 && y > 0 && a[(x - 1) as usize] == b[(y - 1) as usize] {
+                x -= 1;
+                y -= 1;
+            }
+            backward[index(d)] = x;
+            if !odd && forward_min <= d && d <= forward_max && x <= forward[index(d)] {
+                return split(x, y, true, true);
+            }
+            d -= 2;
+        }
+
+        // Like GNU diff, give up when it takes too long and split at the furthest point
+        // reached, unless a minimal result is needed
+        if minimal || cost < too_expensive {
+            continue;
+        }
+        let (mut forward_best, mut forward_x) = (-1, 0);
+        let mut d = forward_max;
+        while d >= forward_min {
+            let mut x = forward[index(d)].min(n);
+            let mut y = x - d;
+            if y > m {
+                x = m + d;
+                y = m;
+            }
+            if x + y > forward_best {
+                forward_best = x + y;
+                forward_x = x;
+            }
+            d -= 2;
+        }
+        let (mut backward_best, mut backward_x) = (isize::MAX, 0);
+        let mut d = backward_max;
+        while d >= backward_min {
+            let mut x = backward[index(d)].max(0);
+            let mut y = x - d;
+            if y < 0 {
+                x = d;
+                y = 0;
+            }
+            if x + y < backward_best {
+                backward_best = x + y;
+                backward_x = x;
+            }
+            d -= 2;
+        }
+        return if n + m - backward_best < forward_best {
+            split(forward_x, forward_best - forward_x, true, false)
+        } else {
+            split(backward_x, backward_best - backward_x, false, true)
+        };
+    }
+}
+
+fn diff_compare(
+    a: &[usize],
+    b: &[usize],
+    changed_a: &mut [bool],
+    changed_b: &mut [bool],
+    minimal: bool,
+    too_expensive: isize,
+) {
+    // Skip the common prefix and suffix, then split the rest at a point of an optimal path
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (a, b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    let changed_a = &mut changed_a[prefix..prefix + a.len()];
+    let changed_b = &mut changed_b[prefix..prefix + b.len()];
+    if a.is_empty() || b.is_empty() {
+        changed_a.fill(true);
+        changed_b.fill(true);
+        return;
+    }
+    let split = diff_bisect(a, b, minimal, too_expensive);
+    let (a_low, a_high) = changed_a.split_at_mut(split.x);
+    let (b_low, b_high) = changed_b.split_at_mut(split.y);
+    diff_compare(
+        &a[..split.x],
+        &b[..split.y],
+        a_low,
+        b_low,
+        split.low_minimal,
+        too_expensive,
+    );
+    diff_compare(
+        &a[split.x..],
+        &b[split.y..],
+        a_high,
+        b_high,
+        split.high_minimal,
+        too_expensive,
+    );
+}
+
+fn diff_shift(ids: &[usize], changed: &mut [bool], other_changed: &[bool]) {
+    // Move the groups of changes to the same place as GNU diff: merged with the groups
+    // around them, as far down as possible, or next to a group of the other file.
+    // 'changed' and 'other_changed' have an unchanged line before and after the real ones.
+    let end = ids.len() + 1;
+    let id = |i: usize| ids[i - 1];
+    let mut i = 1;
+    let mut j = 1;
+    loop {
+        // Find the next group, and the corresponding point in the other file
+        while i < end && !changed[i] {
+            while other_changed[j] {
+                j += 1;
+            }
+            j += 1;
+            i += 1;
+        }
+        if i == end {
+            break;
+        }
+        let mut start = i;
+        while changed[i] {
+            i += 1;
+        }
+        while other_changed[j] {
+            j += 1;
+        }
+        let mut corresponding;
+        loop {
+            let length = i - start;
+            // Move the group up while the line before is the same as its last line
+            while start > 1 && id(start - 1) == id(i - 1) {
+                start -= 1;
+                changed[start] = true;
+                i -= 1;
+                changed[i] = false;
+                while changed[start - 1] {
+                    start -= 1;
+                }
+                j -= 1;
+                while other_changed[j] {
+                    j -= 1;
+                }
+            }
+            corresponding = if other_changed[j - 1] { i } else { end };
+            // Then down while the line after is the same as its first line
+            while i != end && id(start) == id(i) {
+                changed[start] = false;
+                start += 1;
+                changed[i] = true;
+                i += 1;
+                while changed[i] {
+                    i += 1;
+                }
+                j += 1;
+                while other_changed[j] {
+                    j += 1;
+                    corresponding = i;
+                }
+            }
+            if length == i - start {
+                break;
+            }
+        }
+        // Move it back next to a group of the other file if possible
+        while corresponding < i {
+            start -= 1;
+            changed[start] = true;
+            i -= 1;
+            changed[i] = false;
+            j -= 1;
+            while other_changed[j] {
+                j -= 1;
+            }
+        }
+    }
+}
+
+fn diff_discard(ids: &[usize], counts: &[usize]) -> Vec<bool> {
+    // Like GNU diff, lines matching nothing in the other file are changed without
+    // comparing them, and so are lines matching too many, when they are among the first
+    // ones. 'counts' has how many times each line is in the other file.
+    const KEEP: u8 = 0;
+    const DISCARD: u8 = 1;
+    const PROVISIONAL: u8 = 2;
+    let end = ids.len();
+    let mut many = 5;
+    let mut size = end / 64;
+    loop {
+        size >>= 2;
+        if size == 0 {
+            break;
+        }
+        many *= 2;
+    }
+    let mut discards: Vec<u8> = ids
+        .iter()
+        .map(|&id| match counts.get(id).copied().unwrap_or(0) {
+            0 => DISCARD,
+            count if count > many => PROVISIONAL,
+            _ => KEEP,
+        })
+        .collect();
+
+    // The provisional ones are only discarded in the middle of a run of discarded lines
+    let mut i = 0;
+    while i < end {
+        if discards[i] == PROVISIONAL {
+            discards[i] = KEEP;
+        } else if discards[i] != KEEP {
+            let mut j = i;
+            let mut provisional = 0;
+            while j < end && discards[j] != KEEP {
+                if discards[j] == PROVISIONAL {
+                    provisional += 1;
+                }
+                j += 1;
+            }
+            while j > i && discards[j - 1] == PROVISIONAL {
+                j -= 1;
+                discards[j] = KEEP;
+                provisional -= 1;
+            }
+            let length = j - i;
+            if provisional * 4 > length {
+                for discard in &mut discards[i..j] {
+                    if *discard == PROVISIONAL {
+                        *discard = KEEP;
+                    }
+                }
+            } else {
+                // Subruns of at least about the square root of length / 4 provisional
+                // lines are kept
+                let mut minimum = 1;
+                let mut size = length >> 2;
+                loop {
+                    size >>= 2;
+                    if size == 0 {
+                        break;
+                    }
+                    minimum <<= 1;
+                }
+                minimum += 1;
+                let mut consecutive = 0;
+                let mut k = 0;
+                while k < length {
+                    if discards[i + k] != PROVISIONAL {
+                        consecutive = 0;
+                    } else {
+                        consecutive += 1;
+                        if consecutive == minimum {
+                            k -= consecutive;
+                        } else if consecutive > minimum {
+                            discards[i + k] = KEEP;
+                        }
+                    }
+                    k += 1;
+                }
+                // Provisional lines are also kept near both ends of the run, until 3
+                // discarded lines in a row or a discarded line 8 lines in
+                for backward in [false, true] {
+                    let mut consecutive = 0;
+                    for k in 0..length {
+                        let index = if backward { i + length - 1 - k } else { i + k };
+                        if k >= 8 && discards[index] == DISCARD {
+                            break;
+                        }
+                        match discards[index] {
+                            PROVISIONAL => {
+                                consecutive = 0;
+                                discards[index] = KEEP;
+                            }
+                            KEEP => consecutive = 0,
+                            _ => consecutive += 1,
+                        }
+                        if consecutive == 3 {
+                            break;
+                        }
+                    }
+                }
+                i += length - 1;
+            }
+        }
+        i += 1;
+    }
+    discards.iter().map(|&discard| discard != KEEP).collect()
+}
+
+fn diff_changes<'a>(a: &[&'a [u8]], b: &[&'a [u8]], options: &DiffOptions) -> Vec<DiffChange> {
+    // The identical lines at the start and the end are left out, like GNU diff does, so
+    // the changes can't be moved into them. The ones which may be printed as context are
+    // kept.
+    let context = match options.format {
+        DiffFormat::Normal => 0,
+        DiffFormat::Unified(context) | DiffFormat::Context(context) => context,
+    };
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count()
+        .saturating_sub(context);
+    let prefix = prefix.saturating_sub(context);
+    let (a_middle, b_middle) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    // Number the distinct lines (as compared) and find the changed ones
+    let mut numbers: HashMap<std::borrow::Cow<'a, [u8]>, usize> = HashMap::new();
+    let mut id = |line: &&'a [u8]| {
+        let next = numbers.len();
+        *numbers.entry(diff_key(line, options)).or_insert(next)
+    };
+    let a_ids: Vec<usize> = a_middle.iter().map(&mut id).collect();
+    let b_ids: Vec<usize> = b_middle.iter().map(&mut id).collect();
+    // The lines left after discarding some, and where they are in the file
+    let mut counts = [vec![0; numbers.len()], vec![0; numbers.len()]];
+    for (index, ids) in [&a_ids, &b_ids].into_iter().enumerate() {
+        for &id in ids {
+            counts[index][id] += 1;
+        }
+    }
+    let a_discarded = diff_discard(&a_ids, &counts[1]);
+    let b_discarded = diff_discard(&b_ids, &counts[0]);
+    let kept = |ids: &[usize], discarded: &[bool]| -> (Vec<usize>, Vec<usize>) {
+        (0..ids.len())
+            .filter(|&i| !discarded[i])
+            .map(|i| (ids[i], i))
+            .unzip()
+    };
+    let (a_kept, a_indexes) = kept(&a_ids, &a_discarded);
+    let (b_kept, b_indexes) = kept(&b_ids, &b_discarded);
+    let mut a_kept_changed = vec![false; a_kept.len()];
+    let mut b_kept_changed = vec![false; b_kept.len()];
+    // About the square root of the size, at least 4096
+    let mut too_expensive: isize = 1;
+    let mut diagonals = a_kept.len() + b_kept.len() + 3;
+    while diagonals != 0 {
+        too_expensive <<= 1;
+        diagonals >>= 2;
+    }
+    diff_compare(
+        &a_kept,
+        &b_kept,
+        &mut a_kept_changed,
+        &mut b_kept_changed,
+        false,
+        too_expensive.max(4096),
+    );
+
+    // With an unchanged line before and after
+    let mut changed_a = vec![false; a.len() + 2];
+    let mut changed_b = vec![false; b.len() + 2];
+    for (i, &discarded) in a_discarded.iter().enumerate() {
+        changed_a[prefix + i + 1] = discarded;
+    }
+    for (i, &discarded) in b_discarded.iter().enumerate() {
+        changed_b[prefix + i + 1] = discarded;
+    }
+    for (k, &index) in a_indexes.iter().enumerate() {
+        changed_a[prefix + index + 1] = a_kept_changed[k];
+    }
+    for (k, &index) in b_indexes.iter().enumerate() {
+        changed_b[prefix + index + 1] = b_kept_changed[k];
+    }
+    let a_range = prefix..prefix + a_ids.len() + 2;
+    let b_range = prefix..prefix + b_ids.len() + 2;
+    diff_shift(
+        &a_ids,
+        &mut changed_a[a_range.clone()],
+        &changed_b[b_range.clone()],
+    );
+    diff_shift(&b_ids, &mut changed_b[b_range], &changed_a[a_range]);
+
+    let blank = |line: &[u8]| line.is_empty() || line == b"\n";
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if !changed_a[i + 1] && !changed_b[j + 1] {
+            i += 1;
+            j += 1;
+            continue;
+        }
+        let (a_start, b_start) = (i, j);
+        while i < a.len() && changed_a[i + 1] {
+            i += 1;
+        }
+        while j < b.len() && changed_b[j + 1] {
+            j += 1;
+        }
+        let ignore = options.ignore_blank_lines
+            && a[a_start..i]
+                .iter()
+                .chain(&b[b_start..j])
+                .all(|line| blank(line));
+        changes.push(DiffChange {
+            a: a_start,
+            a_end: i,
+            b: b_start,
+            b_end: j,
+            ignore,
+        });
+    }
+    changes
+}
+
+fn diff_line(out: &mut dyn Write, prefix: &str, line: &[u8]) -> io::Result<()> {
+    out.write_all(prefix.as_bytes())?;
+    out.write_all(line)?;
+    if !line.ends_with(b"\n") {
+        out.write_all(b"\n\\ No newline at end of file\n")?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn diff_files(
+    a_path: &Path,
+    b_path: &Path,
+    a_metadata: Option<&fs::Metadata>,
+    b_metadata: Option<&fs::Metadata>,
+    options: &DiffOptions,
+    status: &mut DiffStatus,
+    out: &mut dyn Write,
+    top: bool,
+) -> io::Result<()> {
+    // Missing files (with -N) are empty, '-' is the standard input
+    let read = |path: &Path, metadata: Option<&fs::Metadata>| -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        if path == Path::new("-") {
+            io::stdin().read_to_end(&mut data)?;
+        } else if metadata.is_some() {
+            File::open(path)?.read_to_end(&mut data)?;
+        }
+        Ok(data)
+    };
+    let mut contents = Vec::new();
+    for (path, metadata) in [(a_path, a_metadata), (b_path, b_metadata)] {
+        match read(path, metadata) {
+            Ok(data) => contents.push(data),
+            Err(e) => {
+                eprintln!("diff: {}: {}", path.display(), strerror(&e));
+                status.trouble = true;
+                return Ok(());
+            }
+        }
+    }
+    let (a_data, b_data) = (&contents[0], &contents[1]);
+    let identical = |out: &mut dyn Write| -> io::Result<()> {
+        if options.identical {
+            writeln!(
+                out,
+                "Files {} and {} are identical",
+                a_path.display(),
+                b_path.display()
+            )?;
+        }
+        Ok(())
+    };
+    if a_data == b_data {
+        return identical(out);
+    }
+    // A NUL byte near the start makes it a binary file
+    let binary = |data: &[u8]| data[..data.len().min(32768)].contains(&0);
+    if options.brief || (!options.text && (binary(a_data) || binary(b_data))) {
+        let what = if options.brief {
+            "Files"
+        } else {
+            "Binary files"
+        };
+        if !options.brief || !diff_changes_all_ignored(a_data, b_data, options) {
+            writeln!(
+                out,
+                "{} {} and {} differ",
+                what,
+                a_path.display(),
+                b_path.display()
+            )?;
+            status.differ = true;
+        }
+        return Ok(());
+    }
+    let a_lines: Vec<&[u8]> = a_data.split_inclusive(|&c| c == b'\n').collect();
+    let b_lines: Vec<&[u8]> = b_data.split_inclusive(|&c| c == b'\n').collect();
+    let changes = diff_changes(&a_lines, &b_lines, options);
+    if changes.iter().all(|change| change.ignore) {
+        return identical(out);
+    }
+    status.differ = true;
+    if !top {
+        writeln!(
+            out,
+            "diff{} {} {}",
+            options.switches,
+            a_path.display(),
+            b_path.display()
+        )?;
+    }
+
+    let context = match options.format {
+        DiffFormat::Normal => {
+            for change in changes.iter().filter(|change| !change.ignore) {
+                diff_normal(out, change, &a_lines, &b_lines)?;
+            }
+            return Ok(());
+        }
+        DiffFormat::Unified(context) | DiffFormat::Context(context) => context,
+    };
+    let unified = matches!(options.format, DiffFormat::Unified(_));
+    // The standard input has the current time, a missing file the epoch
+    let header = |index: usize, path: &Path, metadata: Option<&fs::Metadata>| {
+        if let Some(label) = options.labels.get(index) {
+            return label.clone();
+        }
+        let time = match metadata {
+            _ if path == Path::new("-") => now_timestamp(),
+            Some(metadata) => (metadata.mtime(), metadata.mtime_nsec()),
+            None => (0, 0),
+        };
+        format!(
+            "{}\t{}",
+            path.display(),
+            diff_timestamp(time, &options.zone, unified)
+        )
+    };
+    let (first, second) = if unified {
+        ("---", "+++")
+    } else {
+        ("***", "---")
+    };
+    writeln!(out, "{} {}", first, header(0, a_path, a_metadata))?;
+    writeln!(out, "{} {}", second, header(1, b_path, b_metadata))?;
+
+    // Changes closer than twice the context are in the same hunk, ignored ones only
+    // when closer than the context
+    let mut index = 0;
+    while index < changes.len() {
+        let mut last = index;
+        while let Some(next) = changes.get(last + 1) {
+            let limit = if next.ignore {
+                context
+            } else {
+                2 * context + 1
+            };
+            if next.a - changes[last].a_end >= limit {
+                break;
+            }
+            last += 1;
+        }
+        let hunk = &changes[index..=last];
+        index = last + 1;
+        if hunk.iter().all(|change| change.ignore) {
+            continue;
+        }
+        if unified {
+            diff_unified(out, hunk, &a_lines, &b_lines, context)?;
+        } else {
+            diff_context(out, hunk, &a_lines, &b_lines, context)?;
+        }
+    }
+    Ok(())
+}
+
+fn diff_changes_all_ignored(a: &[u8], b: &[u8], options: &DiffOptions) -> bool {
+    // Only the options changing how lines compare can make different files the same
+    if !options.ignore_case
+        && !options.ignore_all_space
+        && !options.ignore_space_change
+        && !options.ignore_blank_lines
+    {
+        return false;
+    }
+    let a_lines: Vec<&[u8]> = a.split_inclusive(|&c| c == b'\n').collect();
+    let b_lines: Vec<&[u8]> = b.split_inclusive(|&c| c == b'\n').collect();
+    diff_changes(&a_lines, &b_lines, options)
+        .iter()
+        .all(|change| change.ignore)
+}
+
+fn diff_normal(
+    out: &mut dyn Write,
+    change: &DiffChange,
+    a: &[&[u8]],
+    b: &[&[u8]],
+) -> io::Result<()> {
+    // Like '3,4c3', with the line before for an empty range
+    let range = |start: usize, end: usize| match end - start {
+        0 => start.to_string(),
+        1 => end.to_string(),
+        _ => format!("{},{}", start + 1, end),
+    };
+    let kind = if change.a == change.a_end {
+        'a'
+    } else if change.b == change.b_end {
+        'd'
+    } else {
+        'c'
+    };
+    writeln!(
+        out,
+        "{}{}{}",
+        range(change.a, change.a_end),
+        kind,
+        range(change.b, change.b_end)
+    )?;
+    for line in &a[change.a..change.a_end] {
+        diff_line(out, "< ", line)?;
+    }
+    if kind == 'c' {
+        writeln!(out, "---")?;
+    }
+    for line in &b[change.b..change.b_end] {
+        diff_line(out, "> ", line)?;
+    }
+    Ok(())
+}
+
+// The lines of a hunk: the changes with their context around
+fn diff_hunk_range(
+    hunk: &[DiffChange],
+    a_length: usize,
+    context: usize,
+) -> (usize, usize, usize, usize) {
+    let (first, last) = (&hunk[0], &hunk[hunk.len() - 1]);
+    let a_start = first.a.saturating_sub(context);
+    let a_end = (last.a_end + context).min(a_length);
+    let b_start = first.b - (first.a - a_start);
+    let b_end = last.b_end + (a_end - last.a_end);
+    (a_start, a_end, b_start, b_end)
+}
+
+fn diff_unified(
+    out: &mut dyn Write,
+    hunk: &[DiffChange],
+    a: &[&[u8]],
+    b: &[&[u8]],
+    context: usize,
+) -> io::Result<()> {
+    let (a_start, a_end, b_start, b_end) = diff_hunk_range(hunk, a.len(), context);
+    // Like '-3,4', without the length when it is 1
+    let range = |start: usize, end: usize| match end - start {
+        0 => format!("{},0", start),
+        1 => (start + 1).to_string(),
+        length => format!("{},{}", start + 1, length),
+    };
+    writeln!(
+        out,
+        "@@ -{} +{} @@",
+        range(a_start, a_end),
+        range(b_start, b_end)
+    )?;
+    let mut position = a_start;
+    for change in hunk {
+        for line in &a[position..change.a] {
+            diff_line(out, " ", line)?;
+        }
+        for line in &a[change.a..change.a_end] {
+            diff_line(out, "-", line)?;
+        }
+        for line in &b[change.b..change.b_end] {
+            diff_line(out, "+", line)?;
+        }
+        position = change.a_end;
+    }
+    for line in &a[position..a_end] {
+        diff_line(out, " ", line)?;
+    }
+    Ok(())
+}
+
+fn diff_context(
+    out: &mut dyn Write,
+    hunk: &[DiffChange],
+    a: &[&[u8]],
+    b: &[&[u8]],
+    context: usize,
+) -> io::Result<()> {
+    let (a_start, a_end, b_start, b_end) = diff_hunk_range(hunk, a.len(), context);
+    // Like '3,6', or the line before for an empty range
+    let range = |start: usize, end: usize| {
+        if end > start + 1 {
+            format!("{},{}", start + 1, end)
+        } else {
+            end.to_string()
+        }
+    };
+    writeln!(out, "***************")?;
+    writeln!(out, "*** {} ****", range(a_start, a_end))?;
+    // A side is only printed when it has changed lines
+    if hunk.iter().any(|change| change.a < change.a_end) {
+        let mut position = a_start;
+        for change in hunk {
+            for line in &a[position..change.a] {
+                diff_line(out, "  ", line)?;
+            }
+            let prefix = if change.b < change.b_end { "! " } else { "- " };
+            for line in &a[change.a..change.a_end] {
+                diff_line(out, prefix, line)?;
+            }
+            position = change.a_end;
+        }
+        for line in &a[position..a_end] {
+            diff_line(out, "  ", line)?;
+        }
+    }
+    writeln!(out, "--- {} ----", range(b_start, b_end))?;
+    if hunk.iter().any(|change| change.b < change.b_end) {
+        let mut position = b_start;
+        for change in hunk {
+            for line in &b[position..change.b] {
+                diff_line(out, "  ", line)?;
+            }
+            let prefix = if change.a < change.a_end { "! " } else { "+ " };
+            for line in &b[change.b..change.b_end] {
+                diff_line(out, prefix, line)?;
+            }
+            position = change.b_end;
+        }
+        for line in &b[position..b_end] {
+            diff_line(out, "  ", line)?;
+        }
+    }
+    Ok(())
+}
+
+// A hunk, with its lines marked ' ', '-' or '+'
+struct PatchHunk {
+    // The first line of each side, or the line after for an empty side
+    old_first: usize,
+    new_first: usize,
+    lines: Vec<(u8, Vec<u8>)>,
+    // The text after the ranges in the header, like a function name
+    function: String,
+    // The line of the patch where it starts
+    line: usize,
+}
+
+impl PatchHunk {
+    fn reverse(&mut self) {
+        std::mem::swap(&mut self.old_first, &mut self.new_first);
+        for (kind, _) in &mut self.lines {
+            *kind = match *kind {
+                b'-' => b'+',
+                b'+' => b'-',
+                kind => kind,
+            };
+        }
+        // The removed lines of a change are first
+        let mut start = 0;
+        while start < self.lines.len() {
+            let length = self.lines[start..]
+                .iter()
+                .take_while(|(kind, _)| *kind != b' ')
+                .count();
+            self.lines[start..start + length].sort_by_key(|(kind, _)| *kind == b'+');
+            start += length.max(1);
+        }
+    }
+}
+
+// The changes to one file
+struct PatchFile {
+    names: [String; 2],
+    // The file doesn't exist on this side: /dev/null, or the time is the epoch
+    missing: [bool; 2],
+    // The name and time of each side, for the rejects
+    headers: [Vec<u8>; 2],
+    context: bool,
+    // The text before the hunks, shown when the file is not found
+    leading: Vec<u8>,
+    hunks: Vec<PatchHunk>,
+}
+
+impl PatchFile {
+    fn reverse(&mut self) {
+        self.names.swap(0, 1);
+        self.missing.swap(0, 1);
+        self.headers.swap(0, 1);
+        for hunk in &mut self.hunks {
+            hunk.reverse();
+        }
+    }
+}
+
+struct PatchOptions {
+    strip: Option<usize>,
+    reverse: bool,
+    forward: bool,
+    force: bool,
+    batch: bool,
+    dry_run: bool,
+    backup: bool,
+    silent: bool,
+    fuzz: usize,
+    output: Option<String>,
+}
+
+fn patch(args: Vec<String>) -> Result<(), io::Error> {
+    let mut options = PatchOptions {
+        strip: None,
+        reverse: false,
+        forward: false,
+        force: false,
+        batch: false,
+        dry_run: false,
+        backup: false,
+        silent: false,
+        fuzz: 2,
+        output: None,
+    };
+    let mut input: Option<String> = None;
+    let mut operands: Vec<String> = Vec::new();
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message.to_string());
+    let number = |value: &str| -> io::Result<usize> {
+        value.parse().map_err(|_| {
+            eprintln!("patch: **** invalid number '{}'", value);
+            invalid("Invalid number.")
+        })
+    };
+
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--" => {
+                operands.extend(iter.by_ref().cloned());
+                break;
+            }
+            "--reverse" => options.reverse = true,
+            "--forward" => options.forward = true,
+            "--force" => options.force = true,
+            "--batch" => options.batch = true,
+            "--dry-run" => options.dry_run = true,
+            "--backup" => options.backup = true,
+            "--silent" | "--quiet" => options.silent = true,
+            _ if arg.starts_with("--strip=") => options.strip = Some(number(&arg[8..])?),
+            _ if arg.starts_with("--fuzz=") => options.fuzz = number(&arg[7..])?,
+            _ if arg.starts_with("--input=") => input = Some(arg[8..].to_string()),
+            _ if arg.starts_with("--output=") => options.output = Some(arg[9..].to_string()),
+            _ if arg.starts_with("--") => {
+                return Err(invalid("Can't use 'patch' with this option."))
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                // Short options can be grouped, like '-Rp1'
+                for (index, c) in arg.char_indices().skip(1) {
+                    match c {
+                        'R' => options.reverse = true,
+                        'N' => options.forward = true,
+                        'f' => options.force = true,
+                        't' => options.batch = true,
+                        'b' => options.backup = true,
+                        's' => options.silent = true,
+                        'p' | 'F' | 'i' | 'o' => {
+                            let value = match &arg[index + 1..] {
+                                "" => iter
+                                    .next()
+                                    .ok_or_else(|| invalid("Option requires a value."))?
+                                    .clone(),
+                                rest => rest.to_string(),
+                            };
+                            match c {
+                                'p' => options.strip = Some(number(&value)?),
+                                'F' => options.fuzz = number(&value)?,
+                                'i' => input = Some(value),
+                                _ => options.output = Some(value),
+                            }
+                            break;
+                        }
+                        _ => return Err(invalid("Can't use 'patch' with this option.")),
+                    }
+                }
+            }
+            _ => operands.push(arg.clone()),
+        }
+    }
+    // The operands are the file to patch and the patch
+    if operands.len() > 2 {
+        eprintln!("patch: **** Can't handle more than 2 operands");
+        return Err(invalid("Too many operands."));
+    }
+    if input.is_none() && operands.len() == 2 {
+        input = operands.pop();
+    }
+    let target = operands.pop();
+
+    let mut data = Vec::new();
+    let read = match input.as_deref() {
+        None | Some("-") => io::stdin().read_to_end(&mut data),
+        Some(name) => File::open(name).and_then(|mut file| file.read_to_end(&mut data)),
+    };
+    if let Err(e) = read {
+        eprintln!(
+            "patch: **** Can't open patch file {} : {}",
+            input.as_deref().unwrap_or("-"),
+            strerror(&e)
+        );
+        return Err(e);
+    }
+    let files = match patch_parse(&data) {
+        Ok(files) => files,
+        Err(message) => {
+            eprintln!("patch: **** {}", message);
+            return Err(io::Error::other(message));
+        }
+    };
+    // Hunks without names are only of use when the file is given
+    let nameless = |file: &PatchFile| file.names.iter().all(|name| name.is_empty());
+    if files.is_empty() || (target.is_none() && files.iter().all(nameless)) {
+        eprintln!("patch: **** Only garbage was found in the patch input.");
+        return Err(io::Error::other(
+            "Only garbage was found in the patch input.",
+        ));
+    }
+
+    let mut failed = false;
+    let mut backups: Vec<PathBuf> = Vec::new();
+    for mut file in files {
+        if options.reverse {
+            file.reverse();
+        }
+        if !patch_file(&mut file, target.as_deref(), &options, &mut backups)? {
+            failed = true;
+        }
+    }
+    io::stdout().flush()?;
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn patch_parse(data: &[u8]) -> Result<Vec<PatchFile>, String> {
+    let lines: Vec<&[u8]> = data.split_inclusive(|&c| c == b'\n').collect();
+    let mut files = Vec::new();
+    let mut leading = 0;
+    let mut i = 0;
+    while i < lines.len() {
+        let next =
+            |k: usize, prefix: &[u8]| lines.get(i + k).is_some_and(|l| l.starts_with(prefix));
+        let unified = next(0, b"--- ") && next(1, b"+++ ") && next(2, b"@@ ");
+        let context = next(0, b"*** ") && next(1, b"--- ") && next(2, b"***************");
+        if !unified && !context && !next(0, b"@@ ") {
+            i += 1;
+            continue;
+        }
+        let mut file = PatchFile {
+            names: [String::new(), String::new()],
+            missing: [false, false],
+            headers: [Vec::new(), Vec::new()],
+            context,
+            leading: Vec::new(),
+            hunks: Vec::new(),
+        };
+        if unified || context {
+            for side in 0..2 {
+                let (name, missing) = patch_header_name(&lines[i + side][4..]);
+                file.names[side] = name;
+                file.missing[side] = missing;
+                file.headers[side] = lines[i + side][4..].to_vec();
+            }
+            i += 2;
+        }
+        file.leading = lines[leading..i].concat();
+        while i < lines.len() {
+            let hunk = if context && lines[i].starts_with(b"***************") {
+                patch_context_hunk(&lines, &mut i)?
+            } else if !context && lines[i].starts_with(b"@@ ") {
+                patch_unified_hunk(&lines, &mut i)?
+            } else {
+                break;
+            };
+            file.hunks.push(hunk);
+        }
+        leading = i;
+        files.push(file);
+    }
+    Ok(files)
+}
+
+fn patch_header_name(rest: &[u8]) -> (String, bool) {
+    // The name is followed by a TAB and the time, which is the epoch for a missing file
+    let text = String::from_utf8_lossy(rest);
+    let text = text.trim_end_matches(['\n', '\r']);
+    let (name, time) = match text.split_once('\t') {
+        Some((name, time)) => (name, time.trim()),
+        None => (text.trim_end(), ""),
+    };
+    let epoch = time.starts_with("1970-01-01 ")
+        || time.starts_with("1969-12-31 ")
+        || (time.ends_with(" 1970") && time.contains("Jan  1 "))
+        || (time.ends_with(" 1969") && time.contains("Dec 31 "));
+    (name.to_string(), name == "/dev/null" || epoch)
+}
+
+fn patch_malformed(lines: &[&[u8]], index: usize) -> String {
+    let line = match lines.get(index) {
+        Some(line) => line,
+        None => return String::from("Only garbage was found in the patch input."),
+    };
+    format!(
+        "malformed patch at line {}: {}",
+        index + 1,
+        String::from_utf8_lossy(line).trim_end_matches('\n')
+    )
+}
+
+// A range like '3,4' in a hunk header, with the length of the range
+fn patch_range(text: &str, context: bool) -> Option<(usize, usize)> {
+    match text.split_once(',') {
+        Some((first, second)) => {
+            let first: usize = first.parse().ok()?;
+            let second: usize = second.parse().ok()?;
+            // Context diffs have the last line, unified ones the length
+            if context {
+                Some((first, (second + 1).checked_sub(first)?))
+            } else {
+                Some((first, second))
+            }
+        }
+        None => {
+            let first: usize = text.parse().ok()?;
+            Some((first, usize::from(!context || first > 0)))
+        }
+    }
+}
+
+fn patch_unified_hunk(lines: &[&[u8]], i: &mut usize) -> Result<PatchHunk, String> {
+    // '@@ -1,3 +1,4 @@', then the lines
+    let start = *i;
+    let header = String::from_utf8_lossy(lines[start]);
+    let mut words = header.split_whitespace().skip(1);
+    let (old, new) = match (words.next(), words.next()) {
+        (Some(old), Some(new)) if old.starts_with('-') && new.starts_with('+') => {
+            (patch_range(&old[1..], false), patch_range(&new[1..], false))
+        }
+        _ => (None, None),
+    };
+    let ((old_start, old_length), (new_start, new_length)) = match (old, new) {
+        (Some(old), Some(new)) => (old, new),
+        _ => return Err(patch_malformed(lines, start)),
+    };
+    let function = header
+        .trim_end_matches('\n')
+        .split("@@")
+        .nth(2)
+        .unwrap_or("")
+        .to_string();
+    let mut hunk = PatchHunk {
+        old_first: if old_length == 0 {
+            old_start + 1
+        } else {
+            old_start
+        },
+        new_first: if new_length == 0 {
+            new_start + 1
+        } else {
+            new_start
+        },
+        lines: Vec::new(),
+        function,
+        line: start + 1,
+    };
+    *i += 1;
+    let (mut old, mut new) = (0, 0);
+    loop {
+        // A missing newline at the end of the previous line
+        if lines.get(*i).is_some_and(|line| line.starts_with(b"\\")) {
+            if let Some((_, text)) = hunk.lines.last_mut() {
+                if text.ends_with(b"\n") {
+                    text.pop();
+                }
+            }
+            *i += 1;
+            continue;
+        }
+        if old == old_length && new == new_length {
+            break;
+        }
+        let line = match lines.get(*i) {
+            Some(line) => *line,
+            None => return Err(patch_malformed(lines, *i)),
+        };
+        // An empty line is a context line which lost its space
+        let (kind, text) = match line[0] {
+            b' ' | b'-' | b'+' => (line[0], &line[1..]),
+            b'\n' => (b' ', line),
+            _ => return Err(patch_malformed(lines, *i)),
+        };
+        if kind != b'+' {
+            old += 1;
+        }
+        if kind != b'-' {
+            new += 1;
+        }
+        if old > old_length || new > new_length {
+            return Err(patch_malformed(lines, *i));
+        }
+        hunk.lines.push((kind, text.to_vec()));
+        *i += 1;
+    }
+    Ok(hunk)
+}
+
+fn patch_context_hunk(lines: &[&[u8]], i: &mut usize) -> Result<PatchHunk, String> {
+    // '***************', '*** 1,3 ****' and the old lines, '--- 1,4 ----' and the new lines
+    let start = *i;
+    let function = String::from_utf8_lossy(&lines[start][15..])
+        .trim_end_matches('\n')
+        .to_string();
+    let range = |line: &[u8], prefix: &str, suffix: &str| {
+        let text = String::from_utf8_lossy(line);
+        let text = text
+            .trim_end()
+            .strip_prefix(prefix)?
+            .strip_suffix(suffix)?
+            .to_string();
+        patch_range(&text, true)
+    };
+    *i += 1;
+    let (old_start, _) = lines
+        .get(*i)
+        .and_then(|line| range(line, "*** ", " ****"))
+        .ok_or_else(|| patch_malformed(lines, *i))?;
+    *i += 1;
+    // Each side has '  ' for context, '- ' or '+ ' and '! ' for changed lines
+    let mut sides: [Vec<(u8, Vec<u8>)>; 2] = [Vec::new(), Vec::new()];
+    let mut new_start = 0;
+    for side in 0..2 {
+        let changed = if side == 0 { b"- " } else { b"+ " };
+        let mut limit = usize::MAX;
+        while let Some(line) = lines.get(*i) {
+            if side == 0 {
+                if let Some((first, length)) = range(line, "--- ", " ----") {
+                    new_start = first;
+                    limit = length;
+                    *i += 1;
+                    break;
+                }
+            } else if sides[1].len() == limit && !line.starts_with(b"\\") {
+                break;
+            }
+            if line.starts_with(b"\\") {
+                if let Some((_, text)) = sides[side].last_mut() {
+                    if text.ends_with(b"\n") {
+                        text.pop();
+                    }
+                }
+            } else if line.starts_with(b"  ") {
+                sides[side].push((b' ', line[2..].to_vec()));
+            } else if line.starts_with(changed) || line.starts_with(b"! ") {
+                sides[side].push((if side == 0 { b'-' } else { b'+' }, line[2..].to_vec()));
+            } else if side == 0 {
+                return Err(patch_malformed(lines, *i));
+            } else {
+                break;
+            }
+            *i += 1;
+        }
+        if side == 0 && limit == usize::MAX {
+            return Err(patch_malformed(lines, *i));
+        }
+    }
+    // A side without changes is left out, its lines are the context of the other one
+    let [mut old, mut new] = sides;
+    if old.is_empty() {
+        old = new
+            .iter()
+            .filter(|(kind, _)| *kind == b' ')
+            .cloned()
+            .collect();
+    } else if new.is_empty() {
+        new = old
+            .iter()
+            .filter(|(kind, _)| *kind == b' ')
+            .cloned()
+            .collect();
+    }
+    let mut merged = Vec::new();
+    let (mut old, mut new) = (old.into_iter().peekable(), new.into_iter().peekable());
+    loop {
+        if let Some(line) = old.next_if(|(kind, _)| *kind != b' ') {
+            merged.push(line);
+        } else if let Some(line) = new.next_if(|(kind, _)| *kind != b' ') {
+            merged.push(line);
+        } else {
+            match (old.next(), new.next()) {
+                (Some(line), Some(_)) => merged.push(line),
+                (None, None) => break,
+                _ => return Err(patch_malformed(lines, start)),
+            }
+        }
+    }
+    let old_length = merged.iter().filter(|(kind, _)| *kind != b'+').count();
+    let new_length = merged.iter().filter(|(kind, _)| *kind != b'-').count();
+    Ok(PatchHunk {
+        old_first: if old_length == 0 {
+            old_start + 1
+        } else {
+            old_start
+        },
+        new_first: if new_length == 0 {
+            new_start + 1
+        } else {
+            new_start
+        },
+        lines: merged,
+        function,
+        line: start + 1,
+    })
+}
+
+fn patch_rejects(file: &PatchFile, strip: Option<usize>, rejects: &[(usize, isize)]) -> Vec<u8> {
+    // The rejected hunks in the format of the patch, as they would be applied now
+    let mut text = Vec::new();
+    let line = |text: &mut Vec<u8>, prefix: &str, line: &[u8]| {
+        text.extend_from_slice(prefix.as_bytes());
+        text.extend_from_slice(line);
+        if !line.ends_with(b"\n") {
+            text.extend_from_slice(b"\n\\ No newline at end of file\n");
+        }
+    };
+    let (first, second) = if file.context {
+        ("*** ", "--- ")
+    } else {
+        ("--- ", "+++ ")
+    };
+    // The names in the headers are stripped like the ones of the files
+    for (prefix, header) in [(first, &file.headers[0]), (second, &file.headers[1])] {
+        if header.is_empty() {
+            continue;
+        }
+        let end = header
+            .iter()
+            .position(|&c| c == b'\t' || c == b'\n')
+            .unwrap_or(header.len());
+        let name = String::from_utf8_lossy(&header[..end]);
+        match patch_strip(&name, strip) {
+            Some(name) if name != "/dev/null" => {
+                let mut stripped = name.into_bytes();
+                stripped.extend_from_slice(&header[end..]);
+                line(&mut text, prefix, &stripped);
+            }
+            _ => line(&mut text, prefix, header),
+        }
+    }
+    for &(index, offset) in rejects {
+        let hunk = &file.hunks[index];
+        let old_first = (hunk.old_first as isize + offset) as usize;
+        let new_first = (hunk.new_first as isize + offset) as usize;
+        let old_length = hunk.lines.iter().filter(|(kind, _)| *kind != b'+').count();
+        let new_length = hunk.lines.iter().filter(|(kind, _)| *kind != b'-').count();
+        if !file.context {
+            let range = |first: usize, length: usize| match length {
+                0 => format!("{},0", first - 1),
+                1 => first.to_string(),
+                _ => format!("{},{}", first, length),
+            };
+            text.extend_from_slice(
+                format!(
+                    "@@ -{} +{} @@{}\n",
+                    range(old_first, old_length),
+                    range(new_first, new_length),
+                    hunk.function
+                )
+                .as_bytes(),
+            );
+            for (kind, content) in &hunk.lines {
+                line(&mut text, &(*kind as char).to_string(), content);
+            }
+            continue;
+        }
+        // Both sides are written, with '!' for the lines of a change both adding and removing
+        let range = |first: usize, length: usize| match length {
+            0 => String::from("0"),
+            1 => first.to_string(),
+            _ => format!("{},{}", first, first + length - 1),
+        };
+        let mut changed = vec![false; hunk.lines.len()];
+        let mut start = 0;
+        while start < hunk.lines.len() {
+            let group = &hunk.lines[start..];
+            let length = group.iter().take_while(|(kind, _)| *kind != b' ').count();
+            let kinds = |kind: u8| group[..length].iter().any(|line| line.0 == kind);
+            changed[start..start + length].fill(kinds(b'-') && kinds(b'+'));
+            start += length.max(1);
+        }
+        text.extend_from_slice(format!("***************{}\n", hunk.function).as_bytes());
+        for (header, skipped, marker) in [
+            (
+                format!("*** {} ****\n", range(old_first, old_length)),
+                b'+',
+                "- ",
+            ),
+            (
+                format!("--- {} ----\n", range(new_first, new_length)),
+                b'-',
+                "+ ",
+            ),
+        ] {
+            text.extend_from_slice(header.as_bytes());
+            for (index, (kind, content)) in hunk.lines.iter().enumerate() {
+                let prefix = match *kind {
+                    kind if kind == skipped => continue,
+                    b' ' => "  ",
+                    _ if changed[index] => "! ",
+                    _ => marker,
+                };
+                line(&mut text, prefix, content);
+            }
+        }
+    }
+    text
+}
+
+fn patch_locate(
+    input: &[Vec<u8>],
+    hunk: &PatchHunk,
+    fuzz: usize,
+    offset: &mut isize,
+    frozen: usize,
+) -> Option<usize> {
+    // Look for the old lines of the hunk around where they should be, like GNU patch:
+    // the closest place after the lines already patched, ignoring up to 'fuzz' context
+    // lines at the start and at the end. A hunk with less context at one end must be at
+    // that end of the file.
+    let pattern: Vec<&[u8]> = hunk
+        .lines
+        .iter()
+        .filter(|(kind, _)| *kind != b'+')
+        .map(|(_, text)| text.as_slice())
+        .collect();
+    let lines = pattern.len() as isize;
+    let input_lines = input.len() as isize;
+    let prefix_context = hunk
+        .lines
+        .iter()
+        .take_while(|(kind, _)| *kind == b' ')
+        .count() as isize;
+    let suffix_context = hunk
+        .lines
+        .iter()
+        .rev()
+        .take_while(|(kind, _)| *kind == b' ')
+        .count() as isize;
+    let context = prefix_context.max(suffix_context);
+    let fuzz = fuzz as isize;
+    let mut prefix_fuzz = fuzz + prefix_context - context;
+    let suffix_fuzz = fuzz + suffix_context - context;
+    let first_guess = hunk.old_first as isize + *offset;
+    let frozen = frozen as isize;
+    let max_where = input_lines - (lines - suffix_fuzz) + 1;
+    let min_where = frozen + 1 - (prefix_context - prefix_fuzz);
+    let max_positive = max_where - first_guess;
+    let mut max_negative = first_guess - min_where;
+    let max_offset = max_positive.max(max_negative);
+    if lines == 0 {
+        return usize::try_from(first_guess).ok();
+    }
+    if first_guess <= max_negative {
+        max_negative = first_guess - 1;
+    }
+    let matches = |base: isize, prefix_fuzz: isize, suffix_fuzz: isize| {
+        (prefix_fuzz..lines - suffix_fuzz.max(0)).all(|k| {
+            let line = base + k - 1;
+            line >= 0 && line < input_lines && input[line as usize] == pattern[k as usize]
+        })
+    };
+    let mut found = |delta: isize| {
+        *offset += delta;
+        Some((first_guess + delta) as usize)
+    };
+    if prefix_fuzz < 0 && hunk.old_first <= 1 {
+        // Only at the start of the file, or only the whole file
+        if suffix_fuzz < 0 && (lines != input_lines || prefix_context < frozen) {
+            return None;
+        }
+        let delta = 1 - first_guess;
+        if frozen <= prefix_context && delta <= max_positive && matches(1, 0, suffix_fuzz) {
+            return found(delta);
+        }
+        return None;
+    } else if prefix_fuzz < 0 {
+        prefix_fuzz = 0;
+    }
+    if suffix_fuzz < 0 {
+        // Only at the end of the file
+        let delta = first_guess - (input_lines - lines + 1);
+        if delta <= max_negative && matches(first_guess - delta, prefix_fuzz, 0) {
+            return found(-delta);
+        }
+        return None;
+    }
+    for delta in 0..=max_offset {
+        if delta <= max_positive && matches(first_guess + delta, prefix_fuzz, suffix_fuzz) {
+            return found(delta);
+        }
+        if delta > 0
+            && delta <= max_negative
+            && matches(first_guess - delta, prefix_fuzz, suffix_fuzz)
+        {
+            return found(-delta);
+        }
+    }
+    None
+}
+
+fn patch_strip(name: &str, strip: Option<usize>) -> Option<String> {
+    // Without -p, the base name
+    let Some(count) = strip else {
+        return Path::new(name)
+            .file_name()
+            .map(|base| base.to_string_lossy().to_string());
+    };
+    let mut name = name;
+    for _ in 0..count {
+        let slash = name.find('/')?;
+        name = name[slash..].trim_start_matches('/');
+    }
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+// Whether to go on after finding a reversed patch, and reversing it
+fn patch_reversed(options: &PatchOptions, message: &str) -> (bool, bool) {
+    if options.silent && options.force && !options.forward {
+        return (true, false);
+    }
+    print!("{}", message);
+    if options.forward {
+        println!("  Skipping patch.");
+        (false, false)
+    } else if options.force {
+        if !options.silent {
+            println!("  Applying it anyway.");
+        }
+        (true, false)
+    } else if options.batch {
+        println!(
+            "  {}",
+            if options.reverse {
+                "Ignoring -R."
+            } else {
+                "Assuming -R."
+            }
+        );
+        (true, true)
+    } else {
+        // Nobody is asked, the answers are the defaults
+        println!(
+            "  {} [n] ",
+            if options.reverse {
+                "Ignore -R?"
+            } else {
+                "Assume -R?"
+            }
+        );
+        println!("Apply anyway? [n] ");
+        if !options.silent {
+            println!("Skipping patch.");
+        }
+        (false, false)
+    }
+}
+
+fn patch_file(
+    file: &mut PatchFile,
+    target: Option<&str>,
+    options: &PatchOptions,
+    backups: &mut Vec<PathBuf>,
+) -> io::Result<bool> {
+    let total = file.hunks.len();
+    let plural = if total == 1 { "" } else { "s" };
+    let ignored = || {
+        println!("{} out of {} hunk{} ignored", total, total, plural);
+        Ok(false)
+    };
+
+    // The file to patch: the operand, or the existing one with the shortest name
+    let name = match target {
+        Some(target) => target.to_string(),
+        None => {
+            let candidates: Vec<String> = file
+                .names
+                .iter()
+                .filter(|name| !name.is_empty() && *name != "/dev/null")
+                .filter_map(|name| patch_strip(name, options.strip))
+                .collect();
+            let key = |name: &&String| {
+                let path = Path::new(name.as_str());
+                let base = path.file_name().map_or(0, |base| base.len());
+                (path.components().count(), base, name.len())
+            };
+            let existing = candidates.iter().filter(|name| Path::new(name).exists());
+            // Ties go to the old name as written
+            let existing = if options.reverse {
+                existing.rev().min_by_key(key)
+            } else {
+                existing.min_by_key(key)
+            };
+            // A file is created where the fewest directories are missing, and the ties go to
+            // the old name as written, while deletions go to the new one
+            let missing_key = |name: &&String| {
+                let missing = Path::new(name.as_str())
+                    .ancestors()
+                    .skip(1)
+                    .filter(|dir| !dir.as_os_str().is_empty() && !dir.is_dir())
+                    .count();
+                (if file.missing[0] { missing } else { 0 }, key(name))
+            };
+            let missing = if file.missing[0] == options.reverse {
+                candidates.iter().rev().min_by_key(missing_key)
+            } else {
+                candidates.iter().min_by_key(missing_key)
+            };
+            let missing = missing.filter(|_| file.missing[0] || file.missing[1]);
+            match existing.or(missing) {
+                Some(name) => name.clone(),
+                None => {
+                    if !options.silent {
+                        let line = file.hunks.first().map_or(0, |hunk| hunk.line);
+                        println!("can't find file to patch at input line {}", line);
+                        if options.strip.is_some() {
+                            println!("Perhaps you used the wrong -p or --strip option?");
+                        } else {
+                            println!("Perhaps you should have used the -p or --strip option?");
+                        }
+                    }
+                    println!("The text leading up to this was:");
+                    println!("--------------------------");
+                    for line in file.leading.split_inclusive(|&c| c == b'\n') {
+                        print!("|{}", String::from_utf8_lossy(line));
+                        if !line.ends_with(b"\n") {
+                            println!();
+                        }
+                    }
+                    println!("--------------------------");
+                    if options.force || options.batch {
+                        println!("No file to patch.  Skipping patch.");
+                    } else {
+                        // Nobody is asked, the answers are the defaults
+                        println!("File to patch: ");
+                        println!("Skip this patch? [y] ");
+                        if !options.silent {
+                            println!("Skipping patch.");
+                        }
+                    }
+                    return ignored();
+                }
+            }
+        }
+    };
+    let path = Path::new(&name);
+
+    let mut data = Vec::new();
+    let exists = match File::open(path) {
+        Ok(mut input) => {
+            input.read_to_end(&mut data)?;
+            true
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => false,
+        Err(e) => {
+            eprintln!("patch: **** Can't open file {} : {}", name, strerror(&e));
+            return Ok(false);
+        }
+    };
+    // A patch creating a file which exists, or deleting one which doesn't, is reversed
+    let mut conflict = false;
+    if (file.missing[0] && exists && !data.is_empty()) || (file.missing[1] && !exists) {
+        let message = format!(
+            "The next patch{} would {} the file {},\nwhich {}!",
+            if options.reverse {
+                ", when reversed,"
+            } else {
+                ""
+            },
+            if exists { "create" } else { "delete" },
+            name,
+            if exists {
+                "already exists"
+            } else {
+                "does not exist"
+            }
+        );
+        match patch_reversed(options, &message) {
+            (false, _) => return ignored(),
+            (true, true) => file.reverse(),
+            (true, false) => conflict = true,
+        }
+    }
+
+    if !options.silent {
+        match &options.output {
+            Some(output) => println!("patching file {} (read from {})", output, name),
+            None if options.dry_run => println!("checking file {}", name),
+            None => println!("patching file {}", name),
+        }
+    }
+    let input: Vec<Vec<u8>> = data
+        .split_inclusive(|&c| c == b'\n')
+        .map(|line| line.to_vec())
+        .collect();
+    let mut output: Vec<u8> = Vec::new();
+    let mut offset: isize = 0;
+    let mut frozen = 0;
+    // How many lines the applied hunks added, to guess where the rejected ones go
+    let mut out_offset: isize = 0;
+    let mut rejects: Vec<(usize, isize)> = Vec::new();
+    let mut skip = false;
+    let mut mismatch = false;
+    for index in 0..total {
+        let mut found = None;
+        let mut fuzz = 0;
+        // At most all the context lines at the start or at the end can be ignored
+        let lines = &file.hunks[index].lines;
+        let context = lines
+            .iter()
+            .take_while(|(kind, _)| *kind == b' ')
+            .count()
+            .max(
+                lines
+                    .iter()
+                    .rev()
+                    .take_while(|(kind, _)| *kind == b' ')
+                    .count(),
+            );
+        while !conflict && fuzz <= options.fuzz.min(context) {
+            found = patch_locate(&input, &file.hunks[index], fuzz, &mut offset, frozen);
+            if found.is_some() {
+                break;
+            }
+            mismatch = true;
+            // The first hunk may match reversed, then the whole patch is probably reversed
+            if index == 0 && !options.force {
+                let mut reversed = PatchHunk {
+                    lines: file.hunks[0].lines.clone(),
+                    function: String::new(),
+                    ..file.hunks[0]
+                };
+                reversed.reverse();
+                let mut reversed_offset = offset;
+                if let Some(place) =
+                    patch_locate(&input, &reversed, fuzz, &mut reversed_offset, frozen)
+                {
+                    let message = if options.reverse {
+                        "Unreversed patch detected!"
+                    } else {
+                        "Reversed (or previously applied) patch detected!"
+                    };
+                    match patch_reversed(options, message) {
+                        (false, _) => skip = true,
+                        (true, true) => {
+                            file.reverse();
+                            offset = reversed_offset;
+                            found = Some(place);
+                        }
+                        (true, false) => (),
+                    }
+                    if found.is_some() || skip {
+                        break;
+                    }
+                }
+            }
+            fuzz += 1;
+        }
+        if skip {
+            rejects.extend((index..total).map(|index| (index, out_offset)));
+            break;
+        }
+        let hunk = &file.hunks[index];
+        let Some(place) = found else {
+            if !options.silent {
+                println!(
+                    "Hunk #{} FAILED at {}.",
+                    index + 1,
+                    hunk.old_first as isize + out_offset
+                );
+            }
+            rejects.push((index, out_offset));
+            mismatch = true;
+            continue;
+        };
+        if fuzz > 0 || offset != 0 {
+            mismatch = true;
+            if !options.silent {
+                let mut message = format!(
+                    "Hunk #{} succeeded at {}",
+                    index + 1,
+                    place as isize - hunk.old_first as isize + hunk.new_first as isize
+                );
+                if fuzz > 0 {
+                    message.push_str(&format!(" with fuzz {}", fuzz));
+                }
+                if offset != 0 {
+                    message.push_str(&format!(
+                        " (offset {} line{})",
+                        offset,
+                        if offset == 1 { "" } else { "s" }
+                    ));
+                }
+                println!("{}.", message);
+            }
+        }
+        // Copy the lines up to the hunk, then the context lines from the file and the new ones
+        let mut position = place - 1;
+        for line in input.iter().take(position).skip(frozen) {
+            output.extend_from_slice(line);
+        }
+        for (kind, text) in &hunk.lines {
+            match kind {
+                b'+' => {
+                    output.extend_from_slice(text);
+                    out_offset += 1;
+                }
+                _ => {
+                    if *kind == b'-' {
+                        out_offset -= 1;
+                    }
+                    if *kind == b' ' && position >= frozen && position < input.len() {
+                        output.extend_from_slice(&input[position]);
+                    }
+                    position += 1;
+                }
+            }
+        }
+        frozen = frozen.max(position.min(input.len()));
+    }
+    for line in input.iter().skip(frozen) {
+        output.extend_from_slice(line);
+    }
+
+    let reject_name = format!("{}.rej", options.output.as_deref().unwrap_or(&name));
+    if !rejects.is_empty() {
+        print!(
+            "{} out of {} hunk{} {}",
+            rejects.len(),
+            total,
+            plural,
+            if skip { "ignored" } else { "FAILED" }
+        );
+        if options.dry_run {
+            println!();
+        } else {
+            println!(" -- saving rejects to file {}", reject_name);
+        }
+    }
+    if options.dry_run {
+        return Ok(rejects.is_empty());
+    }
+    if !rejects.is_empty() {
+        if let Some(dir) = Path::new(&reject_name).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&reject_name, patch_rejects(file, options.strip, &rejects))?;
+    }
+    // The output file is created even when the patch is skipped, but stays empty
+    if let Some(output_name) = &options.output {
+        fs::write(output_name, if skip { &[][..] } else { &output })?;
+        return Ok(rejects.is_empty());
+    }
+    if skip {
+        return Ok(false);
+    }
+    // The original is kept with -b, or when the patch didn't apply exactly
+    let backup = PathBuf::from(format!("{}.orig", name));
+    if (options.backup || mismatch) && !backups.contains(&backup) {
+        if let Some(dir) = backup.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        if exists {
+            fs::copy(path, &backup)?;
+        } else {
+            File::create(&backup)?;
+        }
+        backups.push(backup);
+    }
+    if file.missing[1] && output.is_empty() {
+        if exists {
+            fs::remove_file(path)?;
+            // Like the file, the directories which are left empty are removed
+            for dir in path.ancestors().skip(1) {
+                if dir.as_os_str().is_empty() || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+        return Ok(rejects.is_empty());
+    }
+    // Write a temporary file next to the original and rename it over it
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(dir)?;
+    let (mut temp_output, temp) = temp_file(dir, "patch")?;
+    let result = (|| {
+        if exists {
+            temp_output.set_permissions(fs::metadata(path)?.permissions())?;
+        } else {
+            // New files get the usual mode, not the private one of temporary files
+            let mask = unsafe { libc::umask(0) };
+            unsafe { libc::umask(mask) };
+            temp_output.set_permissions(fs::Permissions::from_mode(0o666 & !mask))?;
+        }
+        temp_output.write_all(&output)?;
+        fs::rename(&temp, path)
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        eprintln!("patch: **** Can't write {} : {}", name, strerror(&e));
+        return Ok(false);
+    }
+    Ok(rejects.is_empty())
+}
+
+// What 'walk_tree' comes across on its way
+enum WalkEvent<'a> {
+    // An entry, before the ones inside it when it's a directory (which are skipped on false)
+    Enter(&'a Path, &'a fs::Metadata, usize),
+    // A directory, after the entries inside it
+    Leave(&'a Path, &'a fs::Metadata, usize),
+    // An entry which can't be examined, or a directory which can't be read
+    Error(&'a Path, io::Error),
+    // A directory which is also one of its ancestors (through symbolic links)
+    Loop(&'a Path, &'a Path),
+}
+
+fn walk_tree(
+    root: &Path,
+    follow: bool,
+    follow_root: bool,
+    visit: &mut dyn FnMut(WalkEvent) -> bool,
+) {
+    // Walk the tree depth-first, with the symbolic links followed everywhere or only at the root
+    let mut ancestors = Vec::new();
+    walk_entry(
+        root,
+        0,
+        follow || follow_root,
+        follow,
+        &mut ancestors,
+        visit,
+    );
+}
+
+fn walk_entry(
+    path: &Path,
+    depth: usize,
+    follow_this: bool,
+    follow: bool,
+    ancestors: &mut Vec<(u64, u64, PathBuf)>,
+    visit: &mut dyn FnMut(WalkEvent) -> bool,
+) {
+    // A followed link which points to nothing is the link itself
+    let metadata = if follow_this {
+        fs::metadata(path).or_else(|e| match e.kind() {
+            ErrorKind::NotFound => fs::symlink_metadata(path),
+            _ => Err(e),
+        })
+    } else {
+        fs::symlink_metadata(path)
+    };
+    let metadata = match metadata {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            visit(WalkEvent::Error(path, e));
+            return;
+        }
+    };
+    if metadata.is_dir() {
+        let same =
+            |(dev, ino, _): &&(u64, u64, PathBuf)| *dev == metadata.dev() && *ino == metadata.ino();
+        if let Some((_, _, ancestor)) = ancestors.iter().find(same) {
+            visit(WalkEvent::Loop(path, ancestor));
+            return;
+        }
+    }
+    if !visit(WalkEvent::Enter(path, &metadata, depth)) || !metadata.is_dir() {
+        return;
+    }
+    match fs::read_dir(path) {
+        Ok(entries) => {
+            ancestors.push((metadata.dev(), metadata.ino(), path.to_path_buf()));
+            for entry in entries {
+                match entry {
+                    Ok(entry) => {
+                        let child = path.join(entry.file_name());
+                        walk_entry(&child, depth + 1, follow, follow, ancestors, visit);
+                    }
+                    Err(e) => {
+                        visit(WalkEvent::Error(path, e));
+                    }
+                }
+            }
+            ancestors.pop();
+        }
+        Err(e) => {
+            visit(WalkEvent::Error(path, e));
+        }
+    }
+    visit(WalkEvent::Leave(path, &metadata, depth));
+}
+
+// A number in tests like '-links +2' (more than 2), '-links -2' (less than 2) or '-links 2'
+#[derive(Clone, Copy)]
+enum FindCompare {
+    Less(i64),
+    Equal(i64),
+    Greater(i64),
+}
+
+impl FindCompare {
+    fn matches(self, value: i64) -> bool {
+        match self {
+            FindCompare::Less(n) => value < n,
+            FindCompare::Equal(n) => value == n,
+            FindCompare::Greater(n) => value > n,
+        }
+    }
+}
+
+enum FindExpr {
+    Not(Box<FindExpr>),
+    And(Box<FindExpr>, Box<FindExpr>),
+    Or(Box<FindExpr>, Box<FindExpr>),
+    // 'a , b' evaluates both and gives the result of b
+    List(Box<FindExpr>, Box<FindExpr>),
+    True,
+    False,
+    // The pattern for the base name or the whole path, with the flags of fnmatch
+    Name(CString, libc::c_int),
+    Path(CString, libc::c_int),
+    Regex(regex::bytes::Regex),
+    Type(Vec<u32>),
+    // The size in units of the given number of bytes, rounded up
+    Size(FindCompare, u64),
+    // The kind of time ('a', 'c' or 'm'), and the age in units of the given number of seconds
+    Time(u8, FindCompare, i64),
+    Newer(u8, (i64, i64)),
+    // Exactly the mode ('='), at least its bits ('-') or any of its bits ('/')
+    Perm(u8, u32),
+    User(u32),
+    Group(u32),
+    Empty,
+    Links(FindCompare),
+    Inum(FindCompare),
+    Print(u8),
+    Printf(Vec<u8>),
+    Ls,
+    Delete,
+    Prune,
+    // The command, and for '-exec ... {} +' the index of the files gathered for it
+    Exec(Vec<String>, Option<usize>),
+}
+
+struct FindOptions {
+    // 'P' never follows symbolic links, 'H' only the starting points and 'L' all of them
+    follow: u8,
+    max_depth: usize,
+    min_depth: usize,
+    depth_first: bool,
+    xdev: bool,
+}
+
+struct FindParser<'a> {
+    args: &'a [String],
+    position: usize,
+    options: FindOptions,
+    regex_type: String,
+    // Without an action, the matching files are printed
+    action: bool,
+    // The commands of '-exec ... {} +'
+    batches: Vec<Vec<String>>,
+}
+
+impl FindParser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.args.get(self.position).map(|arg| arg.as_str())
+    }
+
+    fn value(&mut self, test: &str) -> Result<String, String> {
+        match self.args.get(self.position) {
+            Some(value) => {
+                self.position += 1;
+                Ok(value.clone())
+            }
+            None => Err(format!("missing argument to `{}'", test)),
+        }
+    }
+
+    fn list(&mut self) -> Result<FindExpr, String> {
+        let mut left = self.or()?;
+        while self.peek() == Some(",") {
+            self.position += 1;
+            self.operand(",")?;
+            let right = self.or()?;
+            left = FindExpr::List(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn or(&mut self) -> Result<FindExpr, String> {
+        let mut left = self.and()?;
+        while let Some(operator @ ("-o" | "-or")) = self.peek() {
+            let operator = operator.to_string();
+            self.position += 1;
+            self.operand(&operator)?;
+            let right = self.and()?;
+            left = FindExpr::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<FindExpr, String> {
+        let mut left = self.not()?;
+        loop {
+            // Two tests in a row are joined by an implicit '-a'
+            match self.peek() {
+                None | Some(")" | "," | "-o" | "-or") => break,
+                Some(operator @ ("-a" | "-and")) => {
+                    let operator = operator.to_string();
+                    self.position += 1;
+                    self.operand(&operator)?;
+                }
+                _ => (),
+            }
+            let right = self.not()?;
+            left = FindExpr::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<FindExpr, String> {
+        match self.peek() {
+            Some(operator @ ("!" | "-not")) => {
+                let operator = operator.to_string();
+                self.position += 1;
+                self.operand(&operator)?;
+                Ok(FindExpr::Not(Box::new(self.not()?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn operand(&self, operator: &str) -> Result<(), String> {
+        // An operator needs something after it
+        match self.peek() {
+            None => Err(format!("expected an expression after '{}'", operator)),
+            Some(")") => Err(format!(
+                "expected an expression between '{}' and ')'",
+                operator
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn number(&self, value: &str, test: &str) -> Result<FindCompare, String> {
+        let invalid = || format!("invalid argument `{}' to `{}'", value, test);
+        let (make, digits): (fn(i64) -> FindCompare, &str) = match value.as_bytes().first() {
+            Some(b'+') => (FindCompare::Greater, &value[1..]),
+            Some(b'-') => (FindCompare::Less, &value[1..]),
+            _ => (FindCompare::Equal, value),
+        };
+        if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        digits.parse().map(make).map_err(|_| invalid())
+    }
+
+    fn depth(&mut self, option: &str) -> Result<usize, String> {
+        let value = self.value(option)?;
+        match value.parse() {
+            Ok(depth) if value.bytes().all(|c| c.is_ascii_digit()) => Ok(depth),
+            _ => Err(format!(
+                "Expected a positive decimal integer argument to {}, but got '{}'",
+                option, value
+            )),
+        }
+    }
+
+    fn regex(&self, pattern: &str, ignore_case: bool) -> Result<regex::bytes::Regex, String> {
+        // The whole path must match, and the default syntax is the one of Emacs, where '+' and
+        // '?' are operators but the groups and the alternatives are written like '\(a\|b\)'
+        let extended = !matches!(self.regex_type.as_str(), "emacs" | "posix-basic");
+        let mut anchored = if extended {
+            String::from("^(")
+        } else {
+            String::from("^\\(")
+        };
+        if self.regex_type == "emacs" {
+            let mut chars = pattern.chars().peekable();
+            let mut bracket = false;
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' if !bracket => match chars.next() {
+                        Some(next @ ('+' | '?')) => anchored.push(next),
+                        Some(next) => {
+                            anchored.push('\\');
+                            anchored.push(next);
+                        }
+                        None => anchored.push('\\'),
+                    },
+                    '+' | '?' if !bracket => {
+                        anchored.push('\\');
+                        anchored.push(c);
+                    }
+                    '[' if !bracket => {
+                        // A ']' right after the opening one is in the set
+                        bracket = true;
+                        anchored.push(c);
+                        if let Some(negation) = chars.next_if_eq(&'^') {
+                            anchored.push(negation);
+                        }
+                        if let Some(close) = chars.next_if_eq(&']') {
+                            anchored.push(close);
+                        }
+                    }
+                    ']' if bracket => {
+                        bracket = false;
+                        anchored.push(c);
+                    }
+                    _ => anchored.push(c),
+                }
+            }
+        } else {
+            anchored.push_str(pattern);
+        }
+        anchored.push_str(if extended { ")$" } else { "\\)$" });
+        posix_regex(
+            anchored.as_bytes(),
+            extended,
+            if ignore_case { "i" } else { "" },
+        )
+    }
+
+    fn primary(&mut self) -> Result<FindExpr, String> {
+        let Some(arg) = self.peek().map(|arg| arg.to_string()) else {
+            return Err(String::from("invalid expression"));
+        };
+        self.position += 1;
+        let test = arg.as_str();
+        Ok(match test {
+            "(" => {
+                if self.peek() == Some(")") {
+                    return Err(String::from(
+                        "invalid expression; empty parentheses are not allowed.",
+                    ));
+                }
+                let expr = self.list()?;
+                if self.peek() != Some(")") {
+                    return Err(String::from("invalid expression; I was expecting to find a ')' somewhere but did not see one."));
+                }
+                self.position += 1;
+                expr
+            }
+            ")" => return Err(String::from("you have too many ')'")),
+            "-o" | "-or" | "-a" | "-and" | "," => {
+                return Err(format!(
+                "invalid expression; you have used a binary operator '{}' with nothing before it.",
+                test
+            ))
+            }
+            "-true" => FindExpr::True,
+            "-false" => FindExpr::False,
+            "-name" | "-iname" | "-path" | "-ipath" | "-wholename" | "-iwholename" => {
+                let pattern = self.value(test)?;
+                let pattern = CString::new(pattern).map_err(|e| e.to_string())?;
+                let flags = if test.starts_with("-i") {
+                    libc::FNM_CASEFOLD
+                } else {
+                    0
+                };
+                if test.ends_with("name") && !test.ends_with("wholename") {
+                    FindExpr::Name(pattern, flags)
+                } else {
+                    FindExpr::Path(pattern, flags)
+                }
+            }
+            "-regex" | "-iregex" => {
+                let pattern = self.value(test)?;
+                FindExpr::Regex(self.regex(&pattern, test == "-iregex")?)
+            }
+            "-regextype" => {
+                let kind = self.value(test)?;
+                self.regex_type = match kind.as_str() {
+                    "emacs" | "findutils-default" => String::from("emacs"),
+                    "posix-basic" | "grep" | "ed" | "sed" | "posix-minimal-basic" => {
+                        String::from("posix-basic")
+                    }
+                    "posix-extended" | "posix-egrep" | "egrep" | "awk" | "posix-awk"
+                    | "gnu-awk" => String::from("posix-extended"),
+                    _ => {
+                        return Err(format!("Unknown regular expression type '{}'; valid types are 'findutils-default', 'ed', 'emacs', 'gnu-awk', 'grep', 'posix-awk', 'awk', 'posix-basic', 'posix-egrep', 'egrep', 'posix-extended', 'posix-minimal-basic', 'sed'.", kind))
+                    }
+                };
+                FindExpr::True
+            }
+            "-type" => {
+                let types = self.value(test)?;
+                let mut modes = Vec::new();
+                for letter in types.split(',') {
+                    modes.push(match letter {
+                        "b" => libc::S_IFBLK,
+                        "c" => libc::S_IFCHR,
+                        "d" => libc::S_IFDIR,
+                        "p" => libc::S_IFIFO,
+                        "f" => libc::S_IFREG,
+                        "l" => libc::S_IFLNK,
+                        "s" => libc::S_IFSOCK,
+                        _ => return Err(format!("Unknown argument to -type: {}", letter)),
+                    });
+                }
+                FindExpr::Type(modes)
+            }
+            "-size" => {
+                let value = self.value(test)?;
+                // The unit is after the number, 512-byte blocks by default
+                let (number, unit) = match value.char_indices().last() {
+                    Some((index, c)) if c.is_ascii_alphabetic() => (&value[..index], c),
+                    _ => (value.as_str(), 'b'),
+                };
+                let unit = match unit {
+                    'c' => 1,
+                    'w' => 2,
+                    'b' => 512,
+                    'k' => 1024,
+                    'M' => 1024 * 1024,
+                    'G' => 1024 * 1024 * 1024,
+                    _ => return Err(format!("invalid -size type `{}'", unit)),
+                };
+                FindExpr::Size(self.number(number, test)?, unit)
+            }
+            "-mtime" | "-atime" | "-ctime" | "-mmin" | "-amin" | "-cmin" => {
+                let value = self.value(test)?;
+                let unit = if test.ends_with("min") { 60 } else { 86400 };
+                FindExpr::Time(test.as_bytes()[1], self.number(&value, test)?, unit)
+            }
+            "-newer" | "-anewer" | "-cnewer" => {
+                let name = self.value(test)?;
+                let metadata = if self.options.follow == b'P' {
+                    fs::symlink_metadata(&name)
+                } else {
+                    fs::metadata(&name)
+                };
+                let metadata = metadata.map_err(|e| format!("'{}': {}", name, strerror(&e)))?;
+                FindExpr::Newer(
+                    if test == "-newer" {
+                        b'm'
+                    } else {
+                        test.as_bytes()[1]
+                    },
+                    (metadata.mtime(), metadata.mtime_nsec()),
+                )
+            }
+            "-perm" => {
+                let value = self.value(test)?;
+                let (how, mode) = match value.as_bytes().first() {
+                    Some(&how @ (b'-' | b'/')) => (how, &value[1..]),
+                    _ => (b'=', value.as_str()),
+                };
+                // A symbolic mode is applied to nothing, without the umask
+                let mode: String = mode
+                    .split(',')
+                    .map(|clause| match clause.as_bytes().first() {
+                        Some(b'+' | b'-' | b'=') => format!("a{}", clause),
+                        _ => clause.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",");
+                match new_mode(&mode, 0, false) {
+                    Ok(mode) => FindExpr::Perm(how, mode),
+                    Err(_) => return Err(format!("invalid mode '{}'", value)),
+                }
+            }
+            "-user" => {
+                let name = self.value(test)?;
+                let user = CString::new(name.clone()).map_err(|e| e.to_string())?;
+                let entry = unsafe { libc::getpwnam(user.as_ptr()) };
+                if !entry.is_null() {
+                    FindExpr::User(unsafe { (*entry).pw_uid })
+                } else {
+                    match name.parse() {
+                        Ok(uid) if name.bytes().all(|c| c.is_ascii_digit()) => FindExpr::User(uid),
+                        _ => return Err(format!("'{}' is not the name of a known user", name)),
+                    }
+                }
+            }
+            "-group" => {
+                let name = self.value(test)?;
+                let group = CString::new(name.clone()).map_err(|e| e.to_string())?;
+                let entry = unsafe { libc::getgrnam(group.as_ptr()) };
+                if !entry.is_null() {
+                    FindExpr::Group(unsafe { (*entry).gr_gid })
+                } else {
+                    match name.parse() {
+                        Ok(gid) if name.bytes().all(|c| c.is_ascii_digit()) => FindExpr::Group(gid),
+                        _ => {
+                            return Err(format!("'{}' is not the name of an existing group", name))
+                        }
+                    }
+                }
+            }
+            "-empty" => FindExpr::Empty,
+            "-links" => {
+                let value = self.value(test)?;
+                FindExpr::Links(self.number(&value, test)?)
+            }
+            "-inum" => {
+                let value = self.value(test)?;
+                FindExpr::Inum(self.number(&value, test)?)
+            }
+            // The options apply to the whole expression, wherever they are
+            "-maxdepth" => {
+                self.options.max_depth = self.depth(test)?;
+                FindExpr::True
+            }
+            "-mindepth" => {
+                self.options.min_depth = self.depth(test)?;
+                FindExpr::True
+            }
+            "-depth" | "-d" => {
+                self.options.depth_first = true;
+                FindExpr::True
+            }
+            "-xdev" | "-mount" => {
+                self.options.xdev = true;
+                FindExpr::True
+            }
+            "-noleaf" => FindExpr::True,
+            "-print" | "-print0" => {
+                self.action = true;
+                FindExpr::Print(if test == "-print" { b'\n' } else { b'\0' })
+            }
+            "-printf" => {
+                self.action = true;
+                FindExpr::Printf(self.value(test)?.into_bytes())
+            }
+            "-ls" => {
+                self.action = true;
+                FindExpr::Ls
+            }
+            "-delete" => {
+                // The entries of a directory are deleted before it
+                self.action = true;
+                self.options.depth_first = true;
+                FindExpr::Delete
+            }
+            "-prune" => FindExpr::Prune,
+            "-exec" => {
+                self.action = true;
+                let mut command = Vec::new();
+                let mut batch = false;
+                loop {
+                    let Some(arg) = self.peek().map(|arg| arg.to_string()) else {
+                        return Err(format!("missing argument to `{}'", test));
+                    };
+                    self.position += 1;
+                    if arg == ";" {
+                        break;
+                    }
+                    // With '+', the files are gathered in place of the '{}' just before it
+                    if arg == "+" && command.last().is_some_and(|last| last == "{}") {
+                        command.pop();
+                        batch = true;
+                        break;
+                    }
+                    command.push(arg);
+                }
+                if command.is_empty() {
+                    return Err(format!("missing argument to `{}'", test));
+                }
+                let batch = batch.then(|| {
+                    self.batches.push(command.clone());
+                    self.batches.len() - 1
+                });
+                FindExpr::Exec(command, batch)
+            }
+            _ if test.starts_with('-') => return Err(format!("unknown predicate `{}'", test)),
+            _ => return Err(format!("paths must precede expression: `{}'", test)),
+        })
+    }
+}
+
+// An entry found by 'find', with the starting point it was found from
+struct FindEntry<'a> {
+    path: &'a Path,
+    root: &'a Path,
+    depth: usize,
+    metadata: &'a fs::Metadata,
+}
+
+struct FindState {
+    out: io::BufWriter<io::Stdout>,
+    now: (i64, i64),
+    zone: TimeZone,
+    // The commands of '-exec ... {} +', with the files gathered for them and their length
+    batches: Vec<(Vec<OsString>, Vec<OsString>, usize)>,
+    users: HashMap<u32, String>,
+    groups: HashMap<u32, String>,
+    prune: bool,
+    failed: bool,
+}
+
+impl FindState {
+    fn user(&mut self, uid: u32) -> String {
+        // The name of the user, or the number when there is none
+        self.users
+            .entry(uid)
+            .or_insert_with(|| {
+                let entry = unsafe { libc::getpwuid(uid) };
+                if entry.is_null() {
+                    uid.to_string()
+                } else {
+                    unsafe { CStr::from_ptr((*entry).pw_name) }
+                        .to_string_lossy()
+                        .to_string()
+                }
+            })
+            .clone()
+    }
+
+    fn group(&mut self, gid: u32) -> String {
+        self.groups
+            .entry(gid)
+            .or_insert_with(|| {
+                let entry = unsafe { libc::getgrgid(gid) };
+                if entry.is_null() {
+                    gid.to_string()
+                } else {
+                    unsafe { CStr::from_ptr((*entry).gr_name) }
+                        .to_string_lossy()
+                        .to_string()
+                }
+            })
+            .clone()
+    }
+
+    fn run(&mut self, arguments: &[OsString]) -> bool {
+        // The output so far comes before the one of the command
+        let _ = self.out.flush();
+        match std::process::Command::new(&arguments[0])
+            .args(&arguments[1..])
+            .status()
+        {
+            Ok(status) => status.success(),
+            Err(e) => {
+                eprintln!(
+                    "find: '{}': {}",
+                    arguments[0].to_string_lossy(),
+                    strerror(&e)
+                );
+                false
+            }
+        }
+    }
+
+    fn run_batch(&mut self, index: usize) {
+        let (command, files, length) = &mut self.batches[index];
+        if files.is_empty() {
+            return;
+        }
+        let mut arguments = command.clone();
+        arguments.append(files);
+        *length = 0;
+        // The exit status of find tells when a command failed
+        if !self.run(&arguments) {
+            self.failed = true;
+        }
+    }
+}
+
+fn find_base_name(path: &Path) -> &[u8] {
+    // The last component, where '/' is the root and 'a/' is 'a'
+    let bytes = path.as_os_str().as_bytes();
+    let end = bytes.len() - bytes.iter().rev().take_while(|&&c| c == b'/').count();
+    if end == 0 {
+        return &bytes[..bytes.len().min(1)];
+    }
+    let start = bytes[..end]
+        .iter()
+        .rposition(|&c| c == b'/')
+        .map_or(0, |i| i + 1);
+    &bytes[start..end]
+}
+
+fn find_fnmatch(pattern: &CStr, name: &[u8], flags: libc::c_int) -> bool {
+    match CString::new(name) {
+        Ok(name) => unsafe { libc::fnmatch(pattern.as_ptr(), name.as_ptr(), flags) == 0 },
+        Err(_) => false,
+    }
+}
+
+fn find_time(metadata: &fs::Metadata, kind: u8) -> (i64, i64) {
+    match kind {
+        b'a' => (metadata.atime(), metadata.atime_nsec()),
+        b'c' => (metadata.ctime(), metadata.ctime_nsec()),
+        _ => (metadata.mtime(), metadata.mtime_nsec()),
+    }
+}
+
+fn find_type(mode: u32, letter: char) -> char {
+    // The type like '-type' names it, or like 'ls -l' shows it when 'letter' is '-'
+    match mode & libc::S_IFMT {
+        libc::S_IFDIR => 'd',
+        libc::S_IFLNK => 'l',
+        libc::S_IFIFO => 'p',
+        libc::S_IFSOCK => 's',
+        libc::S_IFCHR => 'c',
+        libc::S_IFBLK => 'b',
+        _ => letter,
+    }
+}
+
+fn find_eval(expr: &FindExpr, entry: &FindEntry, state: &mut FindState) -> bool {
+    let metadata = entry.metadata;
+    match expr {
+        FindExpr::Not(expr) => !find_eval(expr, entry, state),
+        FindExpr::And(left, right) => {
+            find_eval(left, entry, state) && find_eval(right, entry, state)
+        }
+        FindExpr::Or(left, right) => {
+            find_eval(left, entry, state) || find_eval(right, entry, state)
+        }
+        FindExpr::List(left, right) => {
+            find_eval(left, entry, state);
+            find_eval(right, entry, state)
+        }
+        FindExpr::True => true,
+        FindExpr::False => false,
+        FindExpr::Name(pattern, flags) => find_fnmatch(pattern, find_base_name(entry.path), *flags),
+        FindExpr::Path(pattern, flags) => {
+            find_fnmatch(pattern, entry.path.as_os_str().as_bytes(), *flags)
+        }
+        FindExpr::Regex(regex) => regex.is_match(entry.path.as_os_str().as_bytes()),
+        FindExpr::Type(modes) => modes.contains(&(metadata.mode() & libc::S_IFMT)),
+        FindExpr::Size(compare, unit) => compare.matches(metadata.size().div_ceil(*unit) as i64),
+        FindExpr::Time(kind, compare, unit) => {
+            let (seconds, nanoseconds) = find_time(metadata, *kind);
+            let age = (state.now.0 - seconds) as i128 * 1_000_000_000
+                + (state.now.1 - nanoseconds) as i128;
+            compare.matches(age.div_euclid(*unit as i128 * 1_000_000_000) as i64)
+        }
+        FindExpr::Newer(kind, time) => find_time(metadata, *kind) > *time,
+        FindExpr::Perm(how, mode) => {
+            let bits = metadata.mode() & 0o7777;
+            match how {
+                b'-' => bits & mode == *mode,
+                b'/' => *mode == 0 || bits & mode != 0,
+                _ => bits == *mode,
+            }
+        }
+        FindExpr::User(uid) => metadata.uid() == *uid,
+        FindExpr::Group(gid) => metadata.gid() == *gid,
+        FindExpr::Empty => {
+            if metadata.is_dir() {
+                fs::read_dir(entry.path).is_ok_and(|mut entries| entries.next().is_none())
+            } else {
+                metadata.is_file() && metadata.size() == 0
+            }
+        }
+        FindExpr::Links(compare) => compare.matches(metadata.nlink() as i64),
+        FindExpr::Inum(compare) => compare.matches(metadata.ino() as i64),
+        FindExpr::Print(terminator) => {
+            let _ = state.out.write_all(entry.path.as_os_str().as_bytes());
+            let _ = state.out.write_all(&[*terminator]);
+            true
+        }
+        FindExpr::Printf(format) => {
+            let text = find_printf(format, entry, state);
+            let _ = state.out.write_all(&text);
+            true
+        }
+        FindExpr::Ls => {
+            let text = find_ls(entry, state);
+            let _ = state.out.write_all(&text);
+            true
+        }
+        FindExpr::Delete => {
+            // The starting point '.' is never deleted
+            if entry.path == Path::new(".") {
+                return true;
+            }
+            let is_dir = fs::symlink_metadata(entry.path).is_ok_and(|m| m.is_dir());
+            let result = if is_dir {
+                fs::remove_dir(entry.path)
+            } else {
+                fs::remove_file(entry.path)
+            };
+            match result {
+                Ok(()) => true,
+                Err(e) => {
+                    eprintln!(
+                        "find: cannot delete '{}': {}",
+                        entry.path.display(),
+                        strerror(&e)
+                    );
+                    state.failed = true;
+                    false
+                }
+            }
+        }
+        FindExpr::Prune => {
+            state.prune = true;
+            true
+        }
+        FindExpr::Exec(_, Some(index)) => {
+            // The files are gathered, and the command runs when there are enough of them
+            let path = entry.path.as_os_str().to_os_string();
+            let batch = &mut state.batches[*index];
+            batch.2 += path.len() + 1;
+            batch.1.push(path);
+            if batch.2 >= 128 * 1024 {
+                state.run_batch(*index);
+            }
+            true
+        }
+        FindExpr::Exec(command, None) => {
+            // Every '{}' in the arguments is replaced by the file
+            let path = entry.path.as_os_str().as_bytes();
+            let arguments: Vec<OsString> = command
+                .iter()
+                .map(|arg| {
+                    let mut replaced = Vec::new();
+                    let mut rest = arg.as_bytes();
+                    while let Some(index) = rest.windows(2).position(|pair| pair == b"{}") {
+                        replaced.extend_from_slice(&rest[..index]);
+                        replaced.extend_from_slice(path);
+                        rest = &rest[index + 2..];
+                    }
+                    replaced.extend_from_slice(rest);
+                    OsStr::from_bytes(&replaced).to_os_string()
+                })
+                .collect();
+            state.run(&arguments)
+        }
+    }
+}
+
+fn find_strftime(time: (i64, i64), format: u8, zone: &TimeZone) -> Option<String> {
+    // A part of the time like '%TY' (the year) shows it, with the nanoseconds in the seconds
+    const WEEKDAYS: [&str; 7] = [
+        "Sunday",
+        "Monday",
+        "Tuesday",
+        "Wednesday",
+        "Thursday",
+        "Friday",
+        "Saturday",
+    ];
+    const MONTHS: [&str; 12] = [
+        "January",
+        "February",
+        "March",
+        "April",
+        "May",
+        "June",
+        "July",
+        "August",
+        "September",
+        "October",
+        "November",
+        "December",
+    ];
+    let (seconds, nanoseconds) = time;
+    let offset = zone.offset_at(seconds);
+    let local = seconds + offset;
+    let days = local.div_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let clock = local.rem_euclid(86400);
+    let (hour, minute, second) = (clock / 3600, clock / 60 % 60, clock % 60);
+    // The 1st of January 1970 was a Thursday
+    let weekday = (days + 4).rem_euclid(7) as usize;
+    let month_name = MONTHS[month as usize - 1];
+    let hour12 = if hour % 12 == 0 { 12 } else { hour % 12 };
+    let fraction = format!("{:09}0", nanoseconds);
+    Some(match format {
+        b'@' => format!("{}.{}", seconds, fraction),
+        b'a' => WEEKDAYS[weekday][..3].to_string(),
+        b'A' => WEEKDAYS[weekday].to_string(),
+        b'b' | b'h' => month_name[..3].to_string(),
+        b'B' => month_name.to_string(),
+        b'c' => format!(
+            "{} {} {:2} {:02}:{:02}:{:02} {}",
+            &WEEKDAYS[weekday][..3],
+            &month_name[..3],
+            day,
+            hour,
+            minute,
+            second,
+            year
+        ),
+        b'd' => format!("{:02}", day),
+        b'D' | b'x' => format!("{:02}/{:02}/{:02}", month, day, year.rem_euclid(100)),
+        b'e' => format!("{:2}", day),
+        b'F' => format!("{:04}-{:02}-{:02}", year, month, day),
+        b'H' => format!("{:02}", hour),
+        b'I' => format!("{:02}", hour12),
+        b'j' => format!("{:03}", days - days_from_civil(year, 1, 1) + 1),
+        b'k' => format!("{:2}", hour),
+        b'l' => format!("{:2}", hour12),
+        b'm' => format!("{:02}", month),
+        b'M' => format!("{:02}", minute),
+        b'p' => String::from(if hour < 12 { "AM" } else { "PM" }),
+        b'r' => format!(
+            "{:02}:{:02}:{:02} {}",
+            hour12,
+            minute,
+            second,
+            if hour < 12 { "AM" } else { "PM" }
+        ),
+        b's' => seconds.to_string(),
+        b'S' => format!("{:02}.{}", second, fraction),
+        b'T' | b'X' => format!("{:02}:{:02}:{:02}.{}", hour, minute, second, fraction),
+        b'u' => (if weekday == 0 { 7 } else { weekday }).to_string(),
+        b'w' => weekday.to_string(),
+        b'y' => format!("{:02}", year.rem_euclid(100)),
+        b'Y' => year.to_string(),
+        b'z' => format!(
+            "{}{:02}{:02}",
+            if offset < 0 { '-' } else { '+' },
+            offset.abs() / 3600,
+            offset.abs() / 60 % 60
+        ),
+        b'+' => format!(
+            "{:04}-{:02}-{:02}+{:02}:{:02}:{:02}.{}",
+            year, month, day, hour, minute, second, fraction
+        ),
+        // Like ctime, with the nanoseconds
+        b't' => format!(
+            "{} {} {:2} {:02}:{:02}:{:02}.{} {}",
+            &WEEKDAYS[weekday][..3],
+            &month_name[..3],
+            day,
+            hour,
+            minute,
+            second,
+            fraction,
+            year
+        ),
+        _ => return None,
+    })
+}
+
+fn find_printf(format: &[u8], entry: &FindEntry, state: &mut FindState) -> Vec<u8> {
+    let metadata = entry.metadata;
+    let mut out = Vec::new();
+    let mut index = 0;
+    while index < format.len() {
+        match format[index] {
+            b'\\' => {
+                index += 1;
+                match format.get(index) {
+                    Some(b'a') => out.push(0x07),
+                    Some(b'b') => out.push(0x08),
+                    // Nothing more is printed
+                    Some(b'c') => return out,
+                    Some(b'f') => out.push(0x0c),
+                    Some(b'n') => out.push(b'\n'),
+                    Some(b'r') => out.push(b'\r'),
+                    Some(b't') => out.push(b'\t'),
+                    Some(b'v') => out.push(0x0b),
+                    Some(b'\\') => out.push(b'\\'),
+                    Some(b'0'..=b'7') => {
+                        let digits = format[index..]
+                            .iter()
+                            .take(3)
+                            .take_while(|c| (b'0'..=b'7').contains(c))
+                            .count();
+                        let text = String::from_utf8_lossy(&format[index..index + digits]);
+                        out.push(u32::from_str_radix(&text, 8).unwrap_or(0) as u8);
+                        index += digits - 1;
+                    }
+                    Some(&other) => {
+                        out.push(b'\\');
+                        out.push(other);
+                    }
+                    None => out.push(b'\\'),
+                }
+            }
+            b'%' => {
+                // Flags, width and precision come before the directive, like '%-10p'
+                let start = index;
+                index += 1;
+                let flags_start = index;
+                while index < format.len() && b"-+ #0".contains(&format[index]) {
+                    index += 1;
+                }
+                let flags = &format[flags_start..index];
+                let number = |index: &mut usize| {
+                    let digits_start = *index;
+                    while *index < format.len() && format[*index].is_ascii_digit() {
+                        *index += 1;
+                    }
+                    String::from_utf8_lossy(&format[digits_start..*index])
+                        .parse::<usize>()
+                        .ok()
+                };
+                let width = number(&mut index);
+                let precision = if format.get(index) == Some(&b'.') {
+                    index += 1;
+                    Some(number(&mut index).unwrap_or(0))
+                } else {
+                    None
+                };
+                let Some(&directive) = format.get(index) else {
+                    out.extend_from_slice(&format[start..]);
+                    break;
+                };
+                let path = entry.path.as_os_str().as_bytes();
+                let mode = metadata.mode();
+                // The depth and the mode are numbers, the others are strings
+                let numeric = directive == b'd' || directive == b'm';
+                let value: Vec<u8> = match directive {
+                    b'%' => b"%".to_vec(),
+                    b'p' => path.to_vec(),
+                    b'f' => find_base_name(entry.path).to_vec(),
+                    b'h' => {
+                        // The directory, '.' for the names without one
+                        let base = find_base_name(entry.path);
+                        let trimmed = &path
+                            [..path.len() - path.iter().rev().take_while(|&&c| c == b'/').count()];
+                        let dir = &trimmed[..trimmed.len() - base.len().min(trimmed.len())];
+                        match dir.len() {
+                            0 if trimmed.is_empty() => Vec::new(),
+                            0 => b".".to_vec(),
+                            1 => dir.to_vec(),
+                            length => dir[..length - 1].to_vec(),
+                        }
+                    }
+                    b'P' => {
+                        let root = entry.root.as_os_str().as_bytes();
+                        let rest = path.strip_prefix(root).unwrap_or(path);
+                        rest.strip_prefix(b"/").unwrap_or(rest).to_vec()
+                    }
+                    b'H' => entry.root.as_os_str().as_bytes().to_vec(),
+                    b'd' => entry.depth.to_string().into_bytes(),
+                    b's' => metadata.size().to_string().into_bytes(),
+                    b'm' => {
+                        let alternate = if flags.contains(&b'#') { "0" } else { "" };
+                        format!("{}{:o}", alternate, mode & 0o7777).into_bytes()
+                    }
+                    b'M' => format!("{}{}", find_type(mode, '-'), mode_string(mode)).into_bytes(),
+                    b'n' => metadata.nlink().to_string().into_bytes(),
+                    b'k' => metadata.blocks().div_ceil(2).to_string().into_bytes(),
+                    b'b' => metadata.blocks().to_string().into_bytes(),
+                    b'i' => metadata.ino().to_string().into_bytes(),
+                    b'D' => metadata.dev().to_string().into_bytes(),
+                    b'u' => state.user(metadata.uid()).into_bytes(),
+                    b'g' => state.group(metadata.gid()).into_bytes(),
+                    b'U' => metadata.uid().to_string().into_bytes(),
+                    b'G' => metadata.gid().to_string().into_bytes(),
+                    b'l' => match fs::read_link(entry.path) {
+                        Ok(target) if metadata.file_type().is_symlink() => {
+                            target.as_os_str().as_bytes().to_vec()
+                        }
+                        _ => Vec::new(),
+                    },
+                    b'y' => find_type(mode, 'f').to_string().into_bytes(),
+                    b'Y' => {
+                        // The type of what a link points to: 'N' for nothing, 'L' for a loop
+                        let letter = if metadata.file_type().is_symlink() {
+                            match fs::metadata(entry.path) {
+                                Ok(target) => find_type(target.mode(), 'f'),
+                                Err(e) if e.raw_os_error() == Some(libc::ELOOP) => 'L',
+                                Err(e) if e.kind() == ErrorKind::NotFound => 'N',
+                                Err(_) => '?',
+                            }
+                        } else {
+                            find_type(mode, 'f')
+                        };
+                        letter.to_string().into_bytes()
+                    }
+                    b'a' | b'c' | b't' => {
+                        let kind = if directive == b't' { b'm' } else { directive };
+                        find_strftime(find_time(metadata, kind), b't', &state.zone)
+                            .unwrap_or_default()
+                            .into_bytes()
+                    }
+                    b'A' | b'C' | b'T' => {
+                        index += 1;
+                        let time = find_time(metadata, directive.to_ascii_lowercase());
+                        let part = format.get(index).copied().unwrap_or(0);
+                        match find_strftime(time, part, &state.zone) {
+                            Some(text) => text.into_bytes(),
+                            None => format[start..(index + 1).min(format.len())].to_vec(),
+                        }
+                    }
+                    // Unknown directives are printed as they are
+                    _ => format[start..=index].to_vec(),
+                };
+                let mut value = value;
+                if let Some(precision) = precision.filter(|_| !numeric) {
+                    value.truncate(precision);
+                }
+                let padding = width.unwrap_or(0).saturating_sub(value.len());
+                if flags.contains(&b'-') {
+                    out.extend_from_slice(&value);
+                    out.resize(out.len() + padding, b' ');
+                } else {
+                    let fill = if numeric && flags.contains(&b'0') {
+                        b'0'
+                    } else {
+                        b' '
+                    };
+                    out.resize(out.len() + padding, fill);
+                    out.extend_from_slice(&value);
+                }
+            }
+            c => out.push(c),
+        }
+        index += 1;
+    }
+    out
+}
+
+fn find_ls(entry: &FindEntry, state: &mut FindState) -> Vec<u8> {
+    // Like 'ls -dils', with the sizes in blocks of 1024 bytes
+    let metadata = entry.metadata;
+    let mode = metadata.mode();
+    let size = match mode & libc::S_IFMT {
+        libc::S_IFCHR | libc::S_IFBLK => format!(
+            "{:3}, {:3}",
+            libc::major(metadata.rdev()),
+            libc::minor(metadata.rdev())
+        ),
+        _ => metadata.size().to_string(),
+    };
+    // The times more than six months old (or in the future) show the year
+    let time = metadata.mtime();
+    let recent = time > state.now.0 - 31556952 / 2 && time <= state.now.0;
+    let date = if recent {
+        format!(
+            "{} {} {}:{}",
+            find_strftime((time, 0), b'b', &state.zone).unwrap_or_default(),
+            find_strftime((time, 0), b'e', &state.zone).unwrap_or_default(),
+            find_strftime((time, 0), b'H', &state.zone).unwrap_or_default(),
+            find_strftime((time, 0), b'M', &state.zone).unwrap_or_default()
+        )
+    } else {
+        format!(
+            "{} {}  {}",
+            find_strftime((time, 0), b'b', &state.zone).unwrap_or_default(),
+            find_strftime((time, 0), b'e', &state.zone).unwrap_or_default(),
+            find_strftime((time, 0), b'Y', &state.zone).unwrap_or_default()
+        )
+    };
+    let user = state.user(metadata.uid());
+    let group = state.group(metadata.gid());
+    let mut line = format!(
+        "{:9} {:6} {}{} {:3} {:<8} {:<8} {:>8} {} ",
+        metadata.ino(),
+        metadata.blocks().div_ceil(2),
+        find_type(mode, '-'),
+        mode_string(mode),
+        metadata.nlink(),
+        user,
+        group,
+        size,
+        date
+    )
+    .into_bytes();
+    line.extend_from_slice(entry.path.as_os_str().as_bytes());
+    if metadata.file_type().is_symlink() {
+        if let Ok(target) = fs::read_link(entry.path) {
+            line.extend_from_slice(b" -> ");
+            line.extend_from_slice(target.as_os_str().as_bytes());
+        }
+    }
+    line.push(b'\n');
+    line
+}
+
+fn find(args: Vec<String>) -> Result<(), io::Error> {
+    let invalid = |message: String| {
+        eprintln!("find: {}", message);
+        io::Error::new(io::ErrorKind::InvalidInput, message)
+    };
+    let mut options = FindOptions {
+        follow: b'P',
+        max_depth: usize::MAX,
+        min_depth: 0,
+        depth_first: false,
+        xdev: false,
+    };
+    // The options about symbolic links come first, then the starting points
+    let mut position = 2;
+    while let Some(arg @ ("-H" | "-L" | "-P")) = args.get(position).map(|arg| arg.as_str()) {
+        options.follow = arg.as_bytes()[1];
+        position += 1;
+    }
+    let mut roots: Vec<String> = Vec::new();
+    while let Some(arg) = args.get(position) {
+        if (arg.starts_with('-') && arg.len() > 1) || arg == "(" || arg == "!" {
+            break;
+        }
+        roots.push(arg.clone());
+        position += 1;
+    }
+    if roots.is_empty() {
+        roots.push(String::from("."));
+    }
+
+    let mut parser = FindParser {
+        args: &args[position..],
+        position: 0,
+        options,
+        regex_type: String::from("emacs"),
+        action: false,
+        batches: Vec::new(),
+    };
+    let expr = if parser.args.is_empty() {
+        FindExpr::True
+    } else {
+        let expr = parser.list().map_err(invalid)?;
+        if parser.position < parser.args.len() {
+            return Err(invalid(match parser.args[parser.position].as_str() {
+                ")" => String::from("you have too many ')'"),
+                other => format!("paths must precede expression: `{}'", other),
+            }));
+        }
+        expr
+    };
+    // Without an action, the matching files are printed
+    let expr = if parser.action {
+        expr
+    } else {
+        FindExpr::And(Box::new(expr), Box::new(FindExpr::Print(b'\n')))
+    };
+    let options = parser.options;
+
+    let mut state = FindState {
+        out: io::BufWriter::new(io::stdout()),
+        now: now_timestamp(),
+        zone: load_time_zone(),
+        batches: parser
+            .batches
+            .into_iter()
+            .map(|command| {
+                (
+                    command.into_iter().map(OsString::from).collect(),
+                    Vec::new(),
+                    0,
+                )
+            })
+            .collect(),
+        users: HashMap::new(),
+        groups: HashMap::new(),
+        prune: false,
+        failed: false,
+    };
+    for root in &roots {
+        let root = Path::new(root);
+        let mut root_dev = None;
+        walk_tree(
+            root,
+            options.follow == b'L',
+            options.follow != b'P',
+            &mut |event| match event {
+                WalkEvent::Enter(path, metadata, depth) => {
+                    let dev = *root_dev.get_or_insert(metadata.dev());
+                    let descend = metadata.is_dir()
+                        && depth < options.max_depth
+                        && !(options.xdev && metadata.dev() != dev);
+                    // With -depth, a directory comes after its entries
+                    if options.depth_first && descend {
+                        return true;
+                    }
+                    state.prune = false;
+                    if depth >= options.min_depth {
+                        let entry = FindEntry {
+                            path,
+                            root,
+                            depth,
+                            metadata,
+                        };
+                        find_eval(&expr, &entry, &mut state);
+                    }
+                    descend && !state.prune
+                }
+                WalkEvent::Leave(path, metadata, depth) => {
+                    if options.depth_first && depth >= options.min_depth {
+                        let entry = FindEntry {
+                            path,
+                            root,
+                            depth,
+                            metadata,
+                        };
+                        find_eval(&expr, &entry, &mut state);
+                    }
+                    true
+                }
+                WalkEvent::Error(path, e) => {
+                    let _ = state.out.flush();
+                    eprintln!("find: '{}': {}", path.display(), strerror(&e));
+                    state.failed = true;
+                    false
+                }
+                WalkEvent::Loop(path, ancestor) => {
+                    let _ = state.out.flush();
+                    eprintln!(
+                        "find: File system loop detected; '{}' is part of the same file system loop as '{}'.",
+                        path.display(),
+                        ancestor.display()
+                    );
+                    state.failed = true;
+                    false
+                }
+            },
+        );
+    }
+    // The files gathered for '-exec ... {} +' which are left
+    for index in 0..state.batches.len() {
+        state.run_batch(index);
+    }
+    state.out.flush()?;
+    if state.failed {
+        return Err(io::Error::other("Some files couldn't be processed."));
+    }
+    Ok(())
+}
+
+fn mkdir(args: Vec<String>) -> Result<(), io::Error> {
+    let mut parents = false;
+    let mut verbose = false;
+    let mut mode: Option<String> = None;
+    let mut operands: Vec<&String> = Vec::new();
+    // Separate the options from the directories
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        if arg == "-p" || arg == "--parents" {
+            parents = true;
+        } else if arg == "-v" || arg == "--verbose" {
+            verbose = true;
+        } else if arg == "--mode" {
+            // The mode is given in the next argument
+            match iter.next() {
+                Some(m) => mode = Some(m.clone()),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Option '-m' requires a mode.",
+                    ))
+                }
+            }
+        } else if let Some(m) = arg.strip_prefix("--mode=") {
+            mode = Some(m.to_string());
+        } else if arg.starts_with('-') && arg.len() > 1 {
+            // Short options can be grouped, like '-pv' or '-pm 755'
+            for (index, option) in arg.char_indices().skip(1) {
+                match option {
+                    'p' => parents = true,
+                    'v' => verbose = true,
+                    'm' => {
+                        // The mode is the rest of the argument or the next argument
+                        let rest = &arg[index + 1..];
+                        if !rest.is_empty() {
+                            mode = Some(rest.to_string());
+                        } else {
+                            match iter.next() {
+                                Some(m) => mode = Some(m.clone()),
+                                None => {
+                                    return Err(io::Error::new(
+                                        io::ErrorKind::InvalidInput,
+                                        "Option '-m' requires a mode.",
+                                    ))
+                                }
+                            }
+                        }
+                        break;
+                    }
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "Can't use 'mkdir' with this option.",
+                        ))
+                    }
+                }
+            }
+        } else {
+            operands.push(arg);
+        }
+    }
+    if operands.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use 'mkdir' on nothing.",
+        ));
+    }
+
+    let umask = current_umask();
+    // The mode can be octal or symbolic (applied to 'a=rwx')
+    let mode = match mode {
+        Some(m) if !m.is_empty() && m.chars().all(|c| c.is_digit(8)) => {
+            match u32::from_str_radix(&m, 8) {
+                Ok(octal) if octal <= 0o7777 => Some(octal),
+                _ => {
+                    eprintln!("mkdir: invalid mode '{}'", m);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid mode."));
+                }
+            }
+        }
+        Some(m) => match symbolic_to_octal(&m, 0o777, true) {
+            Ok(octal) => Some(octal),
+            Err(e) => {
+                eprintln!("mkdir: invalid mode '{}'", m);
+                return Err(e);
+            }
+        },
+        None => None,
+    };
+
+    // Create every directory, report failures and continue with the next one
+    let mut failed = false;
+    for arg in operands {
+        if let Err(e) = create_directory(Path::new(arg), parents, verbose, mode, umask) {
+            eprintln!("mkdir: cannot create directory '{}': {}", arg, strerror(&e));
+            failed = true;
+        }
+    }
+    if failed {
+        return Err(io::Error::other("Some directories couldn't be created."));
+    }
+    Ok(())
+}
+
+fn current_umask() -> u32 {
+    // The umask can only be read by changing it, so set it back right away
+    let umask = unsafe { libc::umask(0) };
+    unsafe { libc::umask(umask) };
+    umask as u32
+}
+
+fn create_directory(
+    path: &Path,
+    parents: bool,
+    verbose: bool,
+    mode: Option<u32>,
+    umask: u32,
+) -> io::Result<()> {
+    if parents {
+        // With '-p', an existing directory is not an error
+        if path.is_dir() {
+            return Ok(());
+        }
+        // Create the missing parents first, from the top down
+        let mut missing = Vec::new();
+        for ancestor in path.ancestors().skip(1) {
+            if ancestor.as_os_str().is_empty() || ancestor.is_dir() {
+                break;
+            }
+            missing.push(ancestor);
+        }
+        for parent in missing.into_iter().rev() {
+            match fs::create_dir(parent) {
+                Ok(()) => {
+                    // Like coreutils, the parents always stay writable and searchable by the user
+                    if umask & 0o300 != 0 {
+                        let parent_mode = (0o777 & !umask) | 0o300;
+                        fs::set_permissions(parent, fs::Permissions::from_mode(parent_mode))?;
+                    }
+                    if verbose {
+                        println!("mkdir: created directory '{}'", parent.display());
+                    }
+                }
+                // Another process may have created it in the meantime
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && parent.is_dir() => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    match fs::create_dir(path) {
+        Err(e) if parents && e.kind() == io::ErrorKind::AlreadyExists && path.is_dir() => {
+            return Ok(())
+        }
+        result => result?,
+    }
+    if let Some(mode) = mode {
+        // Set the exact mode, which is not affected by the umask
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    if verbose {
+        println!("mkdir: created directory '{}'", path.display());
+    }
+    Ok(())
+}
+
+fn mv(args: Vec<String>) -> Result<(), io::Error> {
+    if args.len() == 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use 'mv' on nothing.",
+        ));
+    }
+    let mut options = MoveOptions {
+        overwrite: Overwrite::Force,
+        update: false,
+        verbose: false,
+        backup: BackupMode::None,
+        suffix: backup_suffix(),
+        exchange: false,
+        no_replace: false,
+    };
+    let mut backup_control: Option<String> = None;
+    let mut make_backups = false;
+    let mut target_directory: Option<String> = None;
+    let mut no_target_directory = false;
+    let mut operands: Vec<&String> = Vec::new();
+    // Separate the options from the sources and destination
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        if let Some(long) = arg.strip_prefix("--").filter(|long| !long.is_empty()) {
+            match long {
+                "interactive" => options.overwrite = Overwrite::Interactive,
+                "no-clobber" => options.overwrite = Overwrite::NoClobber,
+                "force" => options.overwrite = Overwrite::Force,
+                "update" => options.update = true,
+                "verbose" => options.verbose = true,
+                "backup" => make_backups = true,
+                "no-target-directory" => no_target_directory = true,
+                "exchange" => options.exchange = true,
+                "no-replace" => options.no_replace = true,
+                "target-directory" | "suffix" => {
+                    // The value is given in the next argument
+                    let value = match iter.next() {
+                        Some(value) => value.clone(),
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "Option requires an argument.",
+                            ))
+                        }
+                    };
+                    if long == "suffix" {
+                        make_backups = true;
+                        options.suffix = value;
+                    } else {
+                        target_directory = Some(value);
+                    }
+                }
+                _ => {
+                    if let Some(control) = long.strip_prefix("backup=") {
+                        make_backups = true;
+                        backup_control = Some(control.to_string());
+                    } else if let Some(suffix) = long.strip_prefix("suffix=") {
+                        make_backups = true;
+                        options.suffix = suffix.to_string();
+                    } else if let Some(dir) = long.strip_prefix("target-directory=") {
+                        target_directory = Some(dir.to_string());
+                    } else {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "Can't use 'mv' with this option.",
+                        ));
+                    }
+                }
+            }
+        } else if arg.starts_with('-') && arg.len() > 1 {
+            // Short options can be grouped, like '-fv'
+            for (index, option) in arg.char_indices().skip(1) {
+                match option {
+                    'i' => options.overwrite = Overwrite::Interactive,
+                    'n' => options.overwrite = Overwrite::NoClobber,
+                    'f' => options.overwrite = Overwrite::Force,
+                    'u' => options.update = true,
+                    'v' => options.verbose = true,
+                    'b' => make_backups = true,
+                    'T' => no_target_directory = true,
+                    't' | 'S' => {
+                        // The value is the rest of the argument or the next argument
+                        let rest = &arg[index + 1..];
+                        let value = if !rest.is_empty() {
+                            rest.to_string()
+                        } else {
+                            match iter.next() {
+                                Some(value) => value.clone(),
+                                None => {
+                                    return Err(io::Error::new(
+                                        io::ErrorKind::InvalidInput,
+                                        "Option requires an argument.",
+                                    ))
+                                }
+                            }
+                        };
+                        if option == 'S' {
+                            make_backups = true;
+                            options.suffix = value;
+                        } else {
+                            target_directory = Some(value);
+                        }
+                        break;
+                    }
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "Can't use 'mv' with this option.",
+                        ))
+                    }
+                }
+            }
+        } else {
+            operands.push(arg);
+        }
+    }
+    if make_backups {
+        options.backup = backup_mode(backup_control.as_deref(), "mv")?;
+    }
+
+    if options.exchange && options.no_replace {
+        eprintln!("mv: cannot combine --exchange and --no-replace");
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use '--exchange' and '--no-replace' together.",
+        ));
+    }
+    if options.exchange && target_directory.is_none() && operands.len() == 2 {
+        // Swapping two paths never moves one inside the other
+        no_target_directory = true;
+    }
+
+    // Build the list of (source, destination) pairs
+    let mut moves: Vec<(PathBuf, PathBuf)> = Vec::new();
+    if let Some(dir) = target_directory {
+        // With '-t', every operand is a source moved into the directory
+        if no_target_directory || operands.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't use 'mv' like this.",
+            ));
+        }
+        let dir = PathBuf::from(dir);
+        if !dir.is_dir() {
+            eprintln!(
+                "mv: target directory '{}' is not a directory",
+                dir.display()
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Target is not a directory.",
+            ));
+        }
+        for source in operands {
+            moves.push((PathBuf::from(source), into_directory(source, &dir)?));
+        }
+    } else if operands.len() < 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use 'mv' like this.",
+        ));
+    } else if no_target_directory {
+        // With '-T', the destination is always treated as a plain file name
+        if operands.len() > 2 {
+            eprintln!("mv: extra operand '{}'", operands[2]);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Too many operands for '-T'.",
+            ));
+        }
+        moves.push((PathBuf::from(operands[0]), PathBuf::from(operands[1])));
+    } else {
+        // The last operand is the destination
+        let dest = PathBuf::from(operands[operands.len() - 1]);
+        if dest.is_dir() {
+            // Sources are moved inside the destination directory
+            for source in &operands[..operands.len() - 1] {
+                moves.push((PathBuf::from(source), into_directory(source, &dest)?));
+            }
+        } else if operands.len() > 2 {
+            eprintln!("mv: target '{}' is not a directory", dest.display());
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Target is not a directory.",
+            ));
+        } else {
+            // A single source is renamed to the destination
+            moves.push((PathBuf::from(operands[0]), dest));
+        }
+    }
+
+    // Move every source, report failures and continue with the next one
+    let mut failed = false;
+    for (src, dest) in moves {
+        match move_one(&src, &dest, &options) {
+            Ok(true) => (),
+            Ok(false) => failed = true,
+            Err(e) => {
+                eprintln!(
+                    "mv: cannot move '{}' to '{}': {}",
+                    src.display(),
+                    dest.display(),
+                    strerror(&e)
+                );
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        return Err(io::Error::other("Some files couldn't be moved."));
+    }
+    Ok(())
+}
+
+// How 'mv' treats destinations that already exist
+enum Overwrite {
+    Force,
+    Interactive,
+    NoClobber,
+}
+
+struct MoveOptions {
+    overwrite: Overwrite,
+    // Move only when the source is newer than the destination
+    update: bool,
+    // Print every rename
+    verbose: bool,
+    backup: BackupMode,
+    suffix: String,
+    // Atomically swap the source and the destination
+    exchange: bool,
+    // Fail atomically if the destination exists
+    no_replace: bool,
+}
+
+fn move_one(src: &Path, dest: &Path, options: &MoveOptions) -> io::Result<bool> {
+    // Failures reported here return 'false', other errors are left to the caller
+    let src_metadata = match fs::symlink_metadata(src) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("mv: cannot stat '{}': {}", src.display(), strerror(&e));
+            return Ok(false);
+        }
+    };
+    if is_subdirectory(src, dest) {
+        // A directory can't be moved inside itself
+        eprintln!(
+            "mv: cannot move '{}' to a subdirectory of itself, '{}'",
+            src.display(),
+            dest.display()
+        );
+        return Ok(false);
+    }
+
+    if options.exchange {
+        // Both paths must exist and are swapped in a single step
+        if let Err(e) = rename_with_flags(src, dest, libc::RENAME_EXCHANGE) {
+            eprintln!(
+                "mv: cannot exchange '{}' and '{}': {}",
+                src.display(),
+                dest.display(),
+                strerror(&e)
+            );
+            return Ok(false);
+        }
+        if options.verbose {
+            println!("exchanged '{}' <-> '{}'", src.display(), dest.display());
+        }
+        return Ok(true);
+    }
+
+    let mut backup = None;
+    // With '--no-replace', the rename itself refuses existing destinations
+    let dest_metadata = match options.no_replace {
+        true => None,
+        false => fs::symlink_metadata(dest).ok(),
+    };
+    if let Some(dest_metadata) = dest_metadata {
+        if src_metadata.dev() == dest_metadata.dev() && src_metadata.ino() == dest_metadata.ino() {
+            eprintln!(
+                "mv: '{}' and '{}' are the same file",
+                src.display(),
+                dest.display()
+            );
+            return Ok(false);
+        }
+        // Directories and other files can't replace each other
+        if src_metadata.is_dir() && !dest_metadata.is_dir() {
+            eprintln!(
+                "mv: cannot overwrite non-directory '{}' with directory '{}'",
+                dest.display(),
+                src.display()
+            );
+            return Ok(false);
+        }
+        if !src_metadata.is_dir() && dest_metadata.is_dir() {
+            eprintln!(
+                "mv: cannot overwrite directory '{}' with non-directory",
+                dest.display()
+            );
+            return Ok(false);
+        }
+        if options.update && dest_metadata.modified()? >= src_metadata.modified()? {
+            // With '-u', keep destinations that are not older than the source
+            return Ok(true);
+        }
+        match options.overwrite {
+            // Never replace an existing destination with '-n'
+            Overwrite::NoClobber => return Ok(true),
+            Overwrite::Interactive => {
+                if !confirm(&format!("mv: overwrite '{}'? ", dest.display())) {
+                    return Ok(true);
+                }
+            }
+            Overwrite::Force => (),
+        }
+        backup = make_backup(dest, &options.backup, &options.suffix)?;
+    }
+
+    move_path(src, dest, options.no_replace)?;
+    if options.verbose {
+        match backup {
+            Some(backup) => println!(
+                "renamed '{}' -> '{}' (backup: '{}')",
+                src.display(),
+                dest.display(),
+                backup.display()
+            ),
+            None => println!("renamed '{}' -> '{}'", src.display(), dest.display()),
+        }
+    }
+    Ok(true)
+}
+
+fn confirm(prompt: &str) -> bool {
+    // Ask the question on stderr and accept answers starting with 'y'
+    eprint!("{}", prompt);
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    answer.trim_start().starts_with(['y', 'Y'])
+}
+
+// How backups of replaced files are named
+enum BackupMode {
+    None,
+    // Always add the suffix, like 'file~'
+    Simple,
+    // Always number the backups, like 'file.~1~'
+    Numbered,
+    // Number the backups only if numbered backups already exist
+    Existing,
+}
+
+fn backup_suffix() -> String {
+    // The suffix can be changed with the SIMPLE_BACKUP_SUFFIX variable
+    match env::var("SIMPLE_BACKUP_SUFFIX") {
+        Ok(suffix) if !suffix.is_empty() && !suffix.contains('/') => suffix,
+        _ => String::from("~"),
+    }
+}
+
+fn backup_mode(control: Option<&str>, command: &str) -> io::Result<BackupMode> {
+    // Without an explicit value, use the VERSION_CONTROL variable
+    let control = match control {
+        Some(c) => c.to_string(),
+        None => env::var("VERSION_CONTROL").unwrap_or_default(),
+    };
+    match control.as_str() {
+        "none" | "off" => Ok(BackupMode::None),
+        "simple" | "never" => Ok(BackupMode::Simple),
+        "numbered" | "t" => Ok(BackupMode::Numbered),
+        "existing" | "nil" | "" => Ok(BackupMode::Existing),
+        _ => {
+            eprintln!(
+                "{}: invalid argument '{}' for '--backup'\nValid arguments are:\n  - 'none', 'off'\n  - 'simple', 'never'\n  - 'existing', 'nil'\n  - 'numbered', 't'",
+                command, control
+            );
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid backup type.",
+            ))
+        }
+    }
+}
+
+fn make_backup(dest: &Path, mode: &BackupMode, suffix: &str) -> io::Result<Option<PathBuf>> {
+    let file_name = match dest.file_name() {
+        Some(f) => f.to_string_lossy().to_string(),
+        None => return Ok(None),
+    };
+    // Find the highest number used by the existing numbered backups
+    let parent = match dest.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let prefix = format!("{}.~", file_name);
+    let mut highest = 0;
+    if let Ok(entries) = fs::read_dir(parent) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(number) = name
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix('~'))
+                .and_then(|number| number.parse::<u32>().ok())
+            {
+                highest = highest.max(number);
+            }
+        }
+    }
+    let backup_name = match mode {
+        BackupMode::None => return Ok(None),
+        BackupMode::Simple => format!("{}{}", file_name, suffix),
+        BackupMode::Numbered => format!("{}{}~", prefix, highest + 1),
+        BackupMode::Existing if highest > 0 => format!("{}{}~", prefix, highest + 1),
+        BackupMode::Existing => format!("{}{}", file_name, suffix),
+    };
+    // Rename the destination to its backup name
+    let backup = dest.with_file_name(backup_name);
+    fs::rename(dest, &backup)?;
+    Ok(Some(backup))
+}
+
+fn into_directory(source: &str, dir: &Path) -> io::Result<PathBuf> {
+    // Add the source name to the directory path
+    match Path::new(source).file_name() {
+        Some(f) => Ok(dir.join(f)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Invalid source file path.",
+        )),
+    }
+}
+
+fn is_subdirectory(src: &Path, dest: &Path) -> bool {
+    // Check if the destination lies inside the source directory
+    if !src.is_dir() {
+        return false;
+    }
+    let parent = match dest.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    match (fs::canonicalize(src), fs::canonicalize(parent)) {
+        (Ok(src_full), Ok(dest_parent)) => dest_parent.starts_with(src_full),
+        _ => false,
+    }
+}
+
+fn path_to_cstring(path: &Path) -> io::Result<CString> {
+    // Convert a path for the C library calls
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn rename_with_flags(src: &Path, dest: &Path, flags: libc::c_uint) -> io::Result<()> {
+    // Use renameat2 to make the existence check part of the rename itself
+    let src_name = path_to_cstring(src)?;
+    let dest_name = path_to_cstring(dest)?;
+    let result = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            src_name.as_ptr(),
+            libc::AT_FDCWD,
+            dest_name.as_ptr(),
+            flags,
+        )
+    };
+    if result == -1 {
+        let e = io::Error::last_os_error();
+        // EINVAL also means a plain rename is invalid, like a directory moved inside itself,
+        // or a missing side with '--exchange'
+        let valid = || {
+            !is_subdirectory(src, dest)
+                && (flags & libc::RENAME_EXCHANGE == 0 || fs::symlink_metadata(dest).is_ok())
+        };
+        return match e.raw_os_error() {
+            // Old kernels and some file systems (like NFS) don't know these flags
+            Some(errno @ (libc::ENOSYS | libc::EINVAL))
+                if errno == libc::ENOSYS || (flags != 0 && valid()) =>
+            {
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "atomic rename flags are not supported by this file system",
+                ))
+            }
+            _ => Err(e),
+        };
+    }
+    Ok(())
+}
+
+fn move_path(src: &Path, dest: &Path, no_replace: bool) -> io::Result<()> {
+    let result = if no_replace {
+        rename_with_flags(src, dest, libc::RENAME_NOREPLACE)
+    } else {
+        fs::rename(src, dest)
+    };
+    match result {
+        // Renaming fails between file systems, so copy the source and remove it
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            if no_replace && fs::symlink_metadata(dest).is_ok() {
+                // The copy can't be atomic, so check the destination first
+                return Err(io::Error::from_raw_os_error(libc::EEXIST));
+            }
+            // Like a rename, a directory can only replace an empty directory
+            let is_dir = |path: &Path| fs::symlink_metadata(path).is_ok_and(|m| m.is_dir());
+            if is_dir(src) && is_dir(dest) && fs::read_dir(dest)?.next().is_some() {
+                return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY));
+            }
+            copy_preserve(src, dest)?;
+            if fs::symlink_metadata(src)?.is_dir() {
+                fs::remove_dir_all(src)
+            } else {
+                fs::remove_file(src)
+            }
+        }
+        result => result,
+    }
+}
+
+fn copy_preserve(src: &Path, dest: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(src)?;
+    if metadata.file_type().is_symlink() {
+        // Copy the link itself, not the file it points to
+        if fs::symlink_metadata(dest).is_ok() {
+            fs::remove_file(dest)?;
+        }
+        other_fs::symlink(fs::read_link(src)?, dest)?;
+    } else if metadata.is_dir() {
+        // Recreate the directory and copy its entries
+        if !dest.is_dir() {
+            fs::create_dir(dest)?;
+        }
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_preserve(&entry.path(), &dest.join(entry.file_name()))?;
+        }
+    } else if metadata.is_file() {
+        // Copy the file contents and permissions
+        fs::copy(src, dest)?;
+    } else {
+        // Fifos and devices are made again, reading them would block or never end
+        let name = path_to_cstring(dest)?;
+        let mode = metadata.mode() as libc::mode_t;
+        if unsafe { libc::mknod(name.as_ptr(), mode, metadata.rdev() as libc::dev_t) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if !metadata.file_type().is_symlink() {
+        // Keep the timestamps of the source, set through the path since the copy may not be
+        // readable, then its permissions
+        let times = [
+            libc::timespec {
+                tv_sec: metadata.atime(),
+                tv_nsec: metadata.atime_nsec(),
+            },
+            libc::timespec {
+                tv_sec: metadata.mtime(),
+                tv_nsec: metadata.mtime_nsec(),
+            },
+        ];
+        let name = path_to_cstring(dest)?;
+        if unsafe { libc::utimensat(libc::AT_FDCWD, name.as_ptr(), times.as_ptr(), 0) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        fs::set_permissions(dest, metadata.permissions())?;
+    }
+    // Keep the owner too, if we are allowed to (only root can give files away)
+    let _ = other_fs::lchown(dest, Some(metadata.uid()), Some(metadata.gid()));
+    Ok(())
+}
+
+struct LinkOptions {
+    symbolic: bool,
+    // Replace existing destinations
+    force: bool,
+    // Ask before replacing existing destinations
+    interactive: bool,
+    // Make symbolic links relative to the link location
+    relative: bool,
+    verbose: bool,
+    backup: BackupMode,
+    suffix: String,
+}
+
+fn ln(args: Vec<String>) -> Result<(), io::Error> {
+    if args.len() == 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use 'ln' on nothing.",
+        ));
+    }
+    let mut options = LinkOptions {
+        symbolic: false,
+        force: false,
+        interactive: false,
+        relative: false,
+        verbose: false,
+        backup: BackupMode::None,
+        suffix: backup_suffix(),
+    };
+    let mut backup_control: Option<String> = None;
+    let mut make_backups = false;
+    let mut no_dereference = false;
+    let mut target_directory: Option<String> = None;
+    let mut no_target_directory = false;
+    let mut operands: Vec<&String> = Vec::new();
+    // Separate the options from the sources and link names
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        if let Some(long) = arg.strip_prefix("--").filter(|long| !long.is_empty()) {
+            match long {
+                "symbolic" => options.symbolic = true,
+                "force" => {
+                    options.force = true;
+                    options.interactive = false;
+                }
+                "interactive" => {
+                    options.interactive = true;
+                    options.force = false;
+                }
+                "relative" => options.relative = true,
+                "verbose" => options.verbose = true,
+                "no-dereference" => no_dereference = true,
+                "backup" => make_backups = true,
+                "no-target-directory" => no_target_directory = true,
+                "target-directory" | "suffix" => {
+                    // The value is given in the next argument
+                    let value = match iter.next() {
+                        Some(value) => value.clone(),
+                        None => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "Option requires an argument.",
+                            ))
+                        }
+                    };
+                    if long == "suffix" {
+                        make_backups = true;
+                        options.suffix = value;
+                    } else {
+                        target_directory = Some(value);
+                    }
+                }
+                _ => {
+                    if let Some(control) = long.strip_prefix("backup=") {
+                        make_backups = true;
+                        backup_control = Some(control.to_string());
+                    } else if let Some(suffix) = long.strip_prefix("suffix=") {
+                        make_backups = true;
+                        options.suffix = suffix.to_string();
+                    } else if let Some(dir) = long.strip_prefix("target-directory=") {
+                        target_directory = Some(dir.to_string());
+                    } else {
+                        // If any other option is provided, return error
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "Can't use this option on 'ln'.",
+                        ));
+                    }
+                }
+            }
+        } else if arg.starts_with('-') && arg.len() > 1 {
+            // Short options can be grouped, like '-sfv'
+            for (index, option) in arg.char_indices().skip(1) {
+                match option {
+                    's' => options.symbolic = true,
+                    'f' => {
+                        options.force = true;
+                        options.interactive = false;
+                    }
+                    'i' => {
+                        options.interactive = true;
+                        options.force = false;
+                    }
+                    'r' => options.relative = true,
+                    'v' => options.verbose = true,
+                    'n' => no_dereference = true,
+                    'b' => make_backups = true,
+                    'T' => no_target_directory = true,
+                    't' | 'S' => {
+                        // The value is the rest of the argument or the next argument
+                        let rest = &arg[index + 1..];
+                        let value = if !rest.is_empty() {
+                            rest.to_string()
+                        } else {
+                            match iter.next() {
+                                Some(value) => value.clone(),
+                                None => {
+                                    return Err(io::Error::new(
+                                        io::ErrorKind::InvalidInput,
+                                        "Option requires an argument.",
+                                    ))
+                                }
+                            }
+                        };
+                        if option == 'S' {
+                            make_backups = true;
+                            options.suffix = value;
+                        } else {
+                            target_directory = Some(value);
+                        }
+                        break;
+                    }
+                    _ => {
+                        // If any other option is provided, return error
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "Can't use this option on 'ln'.",
+                        ));
+                    }
+                }
+            }
+        } else {
+            operands.push(arg);
+        }
+    }
+    if make_backups {
+        options.backup = backup_mode(backup_control.as_deref(), "ln")?;
+    }
+    if options.relative && !options.symbolic {
+        eprintln!("ln: cannot do --relative without --symbolic");
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use '-r' without '-s'.",
+        ));
+    }
+    if operands.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use 'ln' like this.",
+        ));
+    }
+
+    // Build the list of (target, link name) pairs
+    let mut links: Vec<(&String, PathBuf)> = Vec::new();
+    let last = operands[operands.len() - 1];
+    // With '-n', a symbolic link to a directory is treated like a file
+    let last_is_dir = if no_dereference {
+        fs::symlink_metadata(last)
+            .map(|m| m.is_dir())
+            .unwrap_or(false)
+    } else {
+        Path::new(last).is_dir()
+    };
+    if let Some(dir) = target_directory {
+        // With '-t', every operand is linked inside the directory
+        let dir = PathBuf::from(dir);
+        if no_target_directory || !dir.is_dir() {
+            eprintln!("ln: target '{}' is not a directory", dir.display());
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Target is not a directory.",
+            ));
+        }
+        for source in operands {
+            links.push((source, into_directory(source, &dir)?));
+        }
+    } else if operands.len() == 1 {
+        // A single operand is linked in the current directory
+        links.push((operands[0], into_directory(operands[0], Path::new(""))?));
+    } else if no_target_directory {
+        if operands.len() > 2 {
+            eprintln!("ln: extra operand '{}'", operands[2]);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Too many operands for '-T'.",
+            ));
+        }
+        links.push((operands[0], PathBuf::from(operands[1])));
+    } else if last_is_dir {
+        // The links are made inside the destination directory
+        for source in &operands[..operands.len() - 1] {
+            links.push((source, into_directory(source, Path::new(last))?));
+        }
+    } else if operands.len() > 2 {
+        eprintln!("ln: target '{}' is not a directory", last);
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Target is not a directory.",
+        ));
+    } else {
+        links.push((operands[0], PathBuf::from(last)));
+    }
+
+    // Make every link, report failures and continue with the next one
+    let mut failed = false;
+    for (target, link) in links {
+        if make_link(Path::new(target), &link, &options).is_err() {
+            failed = true;
+        }
+    }
+    if failed {
+        return Err(io::Error::other("Some links couldn't be made."));
+    }
+    Ok(())
+}
+
+fn make_link(target: &Path, link: &Path, options: &LinkOptions) -> Result<(), ()> {
+    let kind = if options.symbolic {
+        "symbolic link"
+    } else {
+        "hard link"
+    };
+    let report = |e: io::Error| -> Result<(), ()> {
+        if options.symbolic {
+            eprintln!(
+                "ln: failed to create {} '{}': {}",
+                kind,
+                link.display(),
+                strerror(&e)
+            );
+        } else {
+            eprintln!(
+                "ln: failed to create {} '{}' => '{}': {}",
+                kind,
+                link.display(),
+                target.display(),
+                strerror(&e)
+            );
+        }
+        Err(())
+    };
+
+    // With '-r', point the link to the target relative to the link directory
+    let target_path = if options.relative {
+        let absolute_target = match fs::canonicalize(target) {
+            Ok(path) => path,
+            Err(_) => match std::path::absolute(target) {
+                Ok(path) => normalize_path(&path),
+                Err(e) => return report(e),
+            },
+        };
+        let link_dir = match link.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        match fs::canonicalize(link_dir) {
+            Ok(dir) => relative_path(&absolute_target, &dir),
+            Err(e) => return report(e),
+        }
+    } else {
+        target.to_path_buf()
+    };
+
+    let mut backup = None;
+    if let Ok(link_metadata) = fs::symlink_metadata(link) {
+        if link_metadata.is_dir() {
+            eprintln!("ln: '{}': cannot overwrite directory", link.display());
+            return Err(());
+        }
+        if !options.symbolic {
+            if let Ok(target_metadata) = fs::metadata(target) {
+                if target_metadata.dev() == link_metadata.dev()
+                    && target_metadata.ino() == link_metadata.ino()
+                {
+                    eprintln!(
+                        "ln: '{}' and '{}' are the same file",
+                        target.display(),
+                        link.display()
+                    );
+                    return Err(());
+                }
+            }
+        }
+        if options.interactive && !confirm(&format!("ln: replace '{}'? ", link.display())) {
+            return Ok(());
+        }
+        // Keep the replaced file with '-b', or remove it with '-f'
+        match make_backup(link, &options.backup, &options.suffix) {
+            Ok(Some(path)) => backup = Some(path),
+            Ok(None) => {
+                if options.force || options.interactive {
+                    if let Err(e) = fs::remove_file(link) {
+                        return report(e);
+                    }
+                }
+            }
+            Err(e) => return report(e),
+        }
+    }
+
+    let result = if options.symbolic {
+        other_fs::symlink(&target_path, link)
+    } else {
+        fs::hard_link(target, link)
+    };
+    if let Err(e) = result {
+        return report(e);
+    }
+    if options.verbose {
+        let arrow = if options.symbolic { "->" } else { "=>" };
+        match backup {
+            Some(backup) => println!(
+                "'{}' {} '{}' (backup: '{}')",
+                link.display(),
+                arrow,
+                target_path.display(),
+                backup.display()
+            ),
+            None => println!("'{}' {} '{}'", link.display(), arrow, target_path.display()),
+        }
+    }
+    Ok(())
+}
+
+fn normalize_path(path: &Path) -> PathBuf {
+    // Remove '.' and '..' components without looking at the file system
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => (),
+            std::path::Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+fn relative_path(target: &Path, base: &Path) -> PathBuf {
+    // Both paths are absolute, so skip their common part and go up from the rest of the base
+    let target_components: Vec<_> = target.components().collect();
+    let base_components: Vec<_> = base.components().collect();
+    let common = target_components
+        .iter()
+        .zip(base_components.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in common..base_components.len() {
+        relative.push("..");
+    }
+    for component in &target_components[common..] {
+        relative.push(component);
+    }
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    relative
+}
+
+fn rmdir(args: Vec<String>) -> Result<(), io::Error> {
+    let mut parents = false;
+    let mut ignore_non_empty = false;
+    let mut verbose = false;
+    let mut operands: Vec<&String> = Vec::new();
+    // Separate the options from the directories
+    for arg in args.iter().skip(2) {
+        match arg.as_str() {
+            "--parents" => parents = true,
+            "--ignore-fail-on-non-empty" => ignore_non_empty = true,
+            "--verbose" => verbose = true,
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                // Short options can be grouped, like '-pv'
+                for option in arg.chars().skip(1) {
+                    match option {
+                        'p' => parents = true,
+                        'v' => verbose = true,
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "Can't use 'rmdir' with this option.",
+                            ))
+                        }
+                    }
+                }
+            }
+            _ => operands.push(arg),
+        }
+    }
+    if operands.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use 'rmdir' on nothing.",
+        ));
+    }
+
+    // Remove every directory, report failures and continue with the next one
+    // The first failure is returned, with the kind of the system error
+    let mut failure: Option<io::Error> = None;
+    for arg in operands {
+        let trimmed = arg.trim_end_matches('/');
+        // A path made of slashes only is the root, kept to report its failure
+        let path = match trimmed.is_empty() && !arg.is_empty() {
+            true => Path::new("/"),
+            false => Path::new(trimmed),
+        };
+        // With '-p', the parents in the path are removed too, from the bottom up
+        let chain: Vec<&Path> = if parents {
+            std::iter::once(path)
+                .chain(
+                    path.ancestors()
+                        .skip(1)
+                        .take_while(|p| !p.as_os_str().is_empty() && p != &Path::new("/")),
+                )
+                .collect()
+        } else {
+            vec![Path::new(arg)]
+        };
+        for dir in chain {
+            if verbose {
+                println!("rmdir: removing directory, '{}'", dir.display());
+            }
+            if let Err(e) = remove_directory(dir) {
+                // A non-empty directory can be ignored with '--ignore-fail-on-non-empty'
+                if !(ignore_non_empty && e.kind() == io::ErrorKind::DirectoryNotEmpty) {
+                    eprintln!(
+                        "rmdir: failed to remove '{}': {}",
+                        dir.display(),
+                        strerror(&e)
+                    );
+                    failure.get_or_insert(e);
+                }
+                break;
+            }
+        }
+    }
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn remove_directory(dir: &Path) -> io::Result<()> {
+    // Handle case when rmdir is used on files (and symbolic links to directories)
+    if !fs::symlink_metadata(dir)?.is_dir() {
+        return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+    }
+    fs::remove_dir(dir)
+}
+
+// When 'rm' asks before removing
+enum Prompt {
+    Never,
+    // Once, before removing more than three files or removing recursively ('-I')
+    Once,
+    // Before every removal ('-i')
+    Always,
+}
+
+struct RemoveOptions {
+    force: bool,
+    prompt: Prompt,
+    recursive: bool,
+    // Remove empty directories
+    dir: bool,
+    verbose: bool,
+    preserve_root: bool,
+    // Don't remove directories on other file systems than their argument
+    one_file_system: bool,
+}
+
+fn rm(args: Vec<String>) -> Result<(), io::Error> {
+    let mut options = RemoveOptions {
+        force: false,
+        prompt: Prompt::Never,
+        recursive: false,
+        dir: false,
+        verbose: false,
+        preserve_root: true,
+        one_file_system: false,
+    };
+    let mut operands: Vec<&String> = Vec::new();
+    let mut only_operands = false;
+    // Separate the options from the files and directories
+    for arg in args.iter().skip(2) {
+        if only_operands || arg == "-" || !arg.starts_with('-') {
+            operands.push(arg);
+        } else if arg == "--" {
+            // Everything after '--' is a file name
+            only_operands = true;
+        } else if let Some(long) = arg.strip_prefix("--") {
+            match long {
+                "force" => {
+                    options.force = true;
+                    options.prompt = Prompt::Never;
+                }
+                "interactive" | "interactive=always" => {
+                    options.force = false;
+                    options.prompt = Prompt::Always;
+                }
+                "interactive=once" => {
+                    options.force = false;
+                    options.prompt = Prompt::Once;
+                }
+                "interactive=never" => options.prompt = Prompt::Never,
+                "recursive" => options.recursive = true,
+                "dir" => options.dir = true,
+                "verbose" => options.verbose = true,
+                "preserve-root" => options.preserve_root = true,
+                "no-preserve-root" => options.preserve_root = false,
+                "one-file-system" => options.one_file_system = true,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Can't use 'rm' with this option.",
+                    ))
+                }
+            }
+        } else {
+            // Short options can be grouped, like '-rf'
+            for option in arg.chars().skip(1) {
+                match option {
+                    'f' => {
+                        options.force = true;
+                        options.prompt = Prompt::Never;
+                    }
+                    'i' => {
+                        options.force = false;
+                        options.prompt = Prompt::Always;
+                    }
+                    'I' => {
+                        options.force = false;
+                        options.prompt = Prompt::Once;
+                    }
+                    'r' | 'R' => options.recursive = true,
+                    'd' => options.dir = true,
+                    'v' => options.verbose = true,
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "Can't use 'rm' with this option.",
+                        ))
+                    }
+                }
+            }
+        }
+    }
+    if operands.is_empty() {
+        // 'rm -f' with nothing to remove is not an error
+        if options.force {
+            return Ok(());
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use 'rm' on nothing.",
+        ));
+    }
+
+    if let Prompt::Once = options.prompt {
+        // Ask a single question before a dangerous removal
+        if options.recursive || operands.len() > 3 {
+            let plural = if operands.len() == 1 { "" } else { "s" };
+            let recursively = if options.recursive {
+                " recursively"
+            } else {
+                ""
+            };
+            if !confirm(&format!(
+                "rm: remove {} argument{}{}? ",
+                operands.len(),
+                plural,
+                recursively
+            )) {
+                return Ok(());
+            }
+        }
+    }
+
+    // Remove every argument, report failures and continue with the next one
+    let mut failed = false;
+    for arg in operands {
+        let path = Path::new(arg);
+        // Refuse to remove '.' and '..' (also as the last component of a path)
+        let last = arg.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+        if last == "." || last == ".." {
+            eprintln!(
+                "rm: refusing to remove '.' or '..' directory: skipping '{}'",
+                arg
+            );
+            failed = true;
+            continue;
+        }
+        if options.recursive && options.preserve_root {
+            if let Ok(full) = fs::canonicalize(path) {
+                if full == Path::new("/") {
+                    if arg == "/" {
+                        eprintln!("rm: it is dangerous to operate recursively on '/'");
+                    } else {
+                        eprintln!(
+                            "rm: it is dangerous to operate recursively on '{}' (same as '/')",
+                            arg
+                        );
+                    }
+                    eprintln!("rm: use --no-preserve-root to override this failsafe");
+                    failed = true;
+                    continue;
+                }
+            }
+        }
+        if remove_path(path, &options).is_err() {
+            failed = true;
+        }
+    }
+    if failed {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Some files couldn't be removed.",
+        ));
+    }
+    Ok(())
+}
+
+fn file_type_name(mode: u32, size: i64) -> &'static str {
+    // Describe the file in the questions of 'rm -i'
+    match mode & libc::S_IFMT {
+        libc::S_IFLNK => "symbolic link",
+        libc::S_IFDIR => "directory",
+        libc::S_IFREG if size == 0 => "regular empty file",
+        libc::S_IFREG => "regular file",
+        libc::S_IFIFO => "fifo",
+        libc::S_IFSOCK => "socket",
+        libc::S_IFCHR => "character special file",
+        libc::S_IFBLK => "block special file",
+        _ => "file",
+    }
+}
+
+fn stat_at(dir: RawFd, name: &CStr) -> io::Result<libc::stat> {
+    // Get the metadata of an entry without following symbolic links
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::fstatat(dir, name.as_ptr(), &mut stat, libc::AT_SYMLINK_NOFOLLOW) };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat)
+}
+
+fn open_dir_at(dir: RawFd, name: &CStr) -> io::Result<OwnedFd> {
+    // Open a directory relative to its parent, refusing symbolic links
+    let fd = unsafe {
+        libc::openat(
+            dir,
+            name.as_ptr(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+        )
+    };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn read_dir_names(dir: &OwnedFd) -> io::Result<Vec<CString>> {
+    // Read the entry names through a copy of the descriptor (closedir closes it)
+    let fd = unsafe { libc::dup(dir.as_raw_fd()) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let stream = unsafe { libc::fdopendir(fd) };
+    if stream.is_null() {
+        let e = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+    let mut names = Vec::new();
+    loop {
+        let entry = unsafe { libc::readdir(stream) };
+        if entry.is_null() {
+            break;
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        if name.to_bytes() != b"." && name.to_bytes() != b".." {
+            names.push(name.to_owned());
+        }
+    }
+    unsafe { libc::closedir(stream) };
+    Ok(names)
+}
+
+fn unlink_at(dir: RawFd, name: &CStr, is_dir: bool) -> io::Result<()> {
+    // Remove an entry relative to its parent directory
+    let flags = if is_dir { libc::AT_REMOVEDIR } else { 0 };
+    if unsafe { libc::unlinkat(dir, name.as_ptr(), flags) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn remove_path(path: &Path, options: &RemoveOptions) -> Result<bool, ()> {
+    let name = match path_to_cstring(path) {
+        Ok(name) => name,
+        Err(e) => {
+            eprintln!("rm: cannot remove '{}': {}", path.display(), strerror(&e));
+            return Err(());
+        }
+    };
+    // The arguments are relative to the current directory
+    remove_at(libc::AT_FDCWD, &name, path, options, None)
+}
+
+// Returns Ok(true) if the entry was removed, Ok(false) if the user kept it,
+// and an error (already reported) if the removal failed.
+// Every entry is reached through the descriptor of its parent directory, so
+// a directory replaced by a symbolic link during the walk is never followed.
+fn remove_at(
+    parent: RawFd,
+    name: &CStr,
+    path: &Path,
+    options: &RemoveOptions,
+    device: Option<u64>,
+) -> Result<bool, ()> {
+    let report = |e: io::Error| {
+        eprintln!("rm: cannot remove '{}': {}", path.display(), strerror(&e));
+    };
+    let stat = match stat_at(parent, name) {
+        Ok(stat) => stat,
+        Err(e) => {
+            // With '-f', missing files are ignored
+            if options.force && e.kind() == io::ErrorKind::NotFound {
+                return Ok(true);
+            }
+            report(e);
+            return Err(());
+        }
+    };
+    let ask = |question: &str| match options.prompt {
+        Prompt::Always => confirm(&format!("rm: {} '{}'? ", question, path.display())),
+        _ => true,
+    };
+    let remove = |is_dir: bool| match unlink_at(parent, name, is_dir) {
+        Ok(()) => {
+            if options.verbose {
+                if is_dir {
+                    println!("removed directory '{}'", path.display());
+                } else {
+                    println!("removed '{}'", path.display());
+                }
+            }
+            Ok(true)
+        }
+        Err(e) => {
+            if options.force && e.kind() == io::ErrorKind::NotFound {
+                return Ok(true);
+            }
+            report(e);
+            Err(())
+        }
+    };
+
+    if stat.st_mode & libc::S_IFMT != libc::S_IFDIR {
+        if !ask(&format!(
+            "remove {}",
+            file_type_name(stat.st_mode, stat.st_size)
+        )) {
+            return Ok(false);
+        }
+        return remove(false);
+    }
+    if !options.recursive {
+        // Without '-r', only empty directories can be removed with '-d'
+        if !options.dir {
+            eprintln!("rm: cannot remove '{}': Is a directory", path.display());
+            return Err(());
+        }
+        if !ask("remove directory") {
+            return Ok(false);
+        }
+        return remove(true);
+    }
+    // The arguments decide the file system with '--one-file-system'
+    let device = device.unwrap_or(stat.st_dev);
+    if options.one_file_system && stat.st_dev != device {
+        eprintln!(
+            "rm: skipping '{}', since it's on a different device",
+            path.display()
+        );
+        return Err(());
+    }
+
+    // Open the directory and check it is still the one we looked at
+    let dir = match open_dir_at(parent, name) {
+        Ok(dir) => dir,
+        Err(e) => {
+            // A directory we can't read may still be removed if it is empty
+            if e.kind() == io::ErrorKind::PermissionDenied
+                && ask("remove directory")
+                && unlink_at(parent, name, true).is_ok()
+            {
+                if options.verbose {
+                    println!("removed directory '{}'", path.display());
+                }
+                return Ok(true);
+            }
+            report(e);
+            return Err(());
+        }
+    };
+    let mut opened: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(dir.as_raw_fd(), &mut opened) } == 0
+        && (opened.st_dev != stat.st_dev || opened.st_ino != stat.st_ino)
+    {
+        eprintln!(
+            "rm: cannot remove '{}': directory was replaced during removal",
+            path.display()
+        );
+        return Err(());
+    }
+    let names = match read_dir_names(&dir) {
+        Ok(names) => names,
+        Err(e) => {
+            report(e);
+            return Err(());
+        }
+    };
+    if !names.is_empty() && !ask("descend into directory") {
+        return Ok(false);
+    }
+
+    // Remove the entries first, continue past failures and keep the directory if any entry is left
+    let mut result = Ok(true);
+    for entry in names {
+        let entry_path = path.join(OsStr::from_bytes(entry.to_bytes()));
+        match remove_at(dir.as_raw_fd(), &entry, &entry_path, options, Some(device)) {
+            Ok(true) => (),
+            Ok(false) => {
+                if result.is_ok() {
+                    result = Ok(false);
+                }
+            }
+            Err(()) => result = Err(()),
+        }
+    }
+    drop(dir);
+    if result != Ok(true) {
+        return result;
+    }
+    if !ask("remove directory") {
+        return Ok(false);
+    }
+    remove(true)
+}
+
+// Options shared by 'cp' and the recursive copy
+struct CopyOptions {
+    recursive: bool,
+    // Hard link files instead of copying them
+    link: bool,
+    // Make symbolic links to the source files instead of copying them
+    symbolic: bool,
+    // Copy only when the source is newer than the destination
+    update: bool,
+    // Remove destinations that can't be opened or linked over
+    force: bool,
+}
 
 fn cp(args: Vec<String>) -> Result<(), io::Error> {
     if args.len() == 2 {
@@ -271,16 +12939,110 @@ fn cp(args: Vec<String>) -> Result<(), io::Error> {
             io::ErrorKind::InvalidInput,
             "Can't use 'cp' with no arguments.",
         ));
-    } else if args[2] == "-r" || args[2] == "-R" || args[2] == "--recursive" {
-        let src = Path::new(&args[3]);
-        let dest = PathBuf::new().join(&args[4]);
+    }
+    let mut options = CopyOptions {
+        recursive: false,
+        link: false,
+        symbolic: false,
+        update: false,
+        force: false,
+    };
+    let mut operands: Vec<&String> = Vec::new();
+    // Separate the options from the sources and destination
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            // Everything after '--' is a file name
+            "--" => {
+                operands.extend(iter.by_ref());
+                break;
+            }
+            "--recursive" => options.recursive = true,
+            "--link" => options.link = true,
+            "--symbolic-link" => options.symbolic = true,
+            "--update" => options.update = true,
+            "--force" => options.force = true,
+            _ if arg.starts_with("--") => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Can't use 'cp' with this option.",
+                ))
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                // Short options can be grouped, like '-rlu'
+                for option in arg.chars().skip(1) {
+                    match option {
+                        'r' | 'R' => options.recursive = true,
+                        'l' => options.link = true,
+                        's' => options.symbolic = true,
+                        'u' => options.update = true,
+                        'f' => options.force = true,
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "Can't use 'cp' with this option.",
+                            ))
+                        }
+                    }
+                }
+            }
+            _ => operands.push(arg),
+        }
+    }
+    if operands.len() < 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use 'cp' like this.",
+        ));
+    }
+    if options.link && options.symbolic {
+        eprintln!("cp: cannot make both hard and symbolic links");
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use '-l' and '-s' together.",
+        ));
+    }
 
-        if src.is_dir() {
-            // If source is a directory, perform a recursive copy
-            copy_r(src, &dest)?;
-        } else {
-            // If source is a file, perform a regular file copy
-            let file_name = match src.file_name() {
+    // The last operand is the destination
+    let dest = PathBuf::from(operands[operands.len() - 1]);
+    if operands.len() > 2 && !dest.is_dir() {
+        eprintln!("cp: target '{}' is not a directory", dest.display());
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Target is not a directory.",
+        ));
+    }
+    for source in &operands[..operands.len() - 1] {
+        let src = Path::new(source);
+        if src.is_dir() && !options.recursive {
+            // Directories can only be copied with '-r'
+            eprintln!(
+                "cp: -r not specified; omitting directory '{}'",
+                src.display()
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't copy a directory without '-r'.",
+            ));
+        }
+        copy_r(src, &dest, &options)?;
+    }
+    Ok(())
+}
+
+fn copy_r(source: &Path, destination: &PathBuf, options: &CopyOptions) -> io::Result<()> {
+    let src = Path::new(source);
+    let dest = PathBuf::new().join(destination);
+
+    // If the source is a directory, create the destination directory
+    if src.is_dir() {
+        // Handle the rename case
+        let mut dest_copy = dest.clone();
+        // A source ending with '.' (like 'dir/.') means its contents, which are merged
+        let contents = source.as_os_str() == "." || source.as_os_str().as_bytes().ends_with(b"/.");
+        // If destination exists, add source name to the destination
+        if dest.exists() && !contents {
+            dest_copy = dest_copy.join(match src.file_name() {
                 Some(f) => f,
                 None => {
                     return Err(io::Error::new(
@@ -288,16 +13050,13 @@ fn cp(args: Vec<String>) -> Result<(), io::Error> {
                         "Invalid source file path.",
                     ))
                 }
-            };
-            let dest_file = dest.join(file_name);
-            fs::copy(src, dest_file)?;
+            });
         }
+        copy_tree(src, &dest_copy, options)?;
     } else {
-        // If '-r' is not an option, perform a regular copy
-        let src = Path::new(&args[2]);
-        let dest = Path::new(&args[3]);
-        // If destination is a directory, copy the entire file
+        // If the source is a file, copy it to the destination
         if dest.is_dir() {
+            // If the destination is a directory, create a file inside it
             let file_name = match src.file_name() {
                 Some(f) => f,
                 None => {
@@ -308,298 +13067,1272 @@ fn cp(args: Vec<String>) -> Result<(), io::Error> {
                 }
             };
             let dest_file = dest.join(file_name);
-            fs::copy(src, dest_file)?;
+            copy_file(src, &dest_file, options)?;
+        } else {
+            // If the destination is a file, perform a regular file copy
+            copy_file(src, &dest, options)?;
+        }
+    }
+    Ok(())
+}
+
+fn copy_tree(src: &Path, dest: &Path, options: &CopyOptions) -> io::Result<()> {
+    // Recreate the directory structure (it may already exist when copying again)
+    fs::create_dir_all(dest)?;
+    // Iterate over the entries
+    for entry in fs::read_dir(src)? {
+        // Add the entry name to the destination path
+        let entry = entry?;
+        let entry_path = entry.path();
+        let dest_path = dest.join(entry.file_name());
+        // The type of the entry itself, symbolic links are not followed
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            // If the entry is a subdirectory, recursively copy it
+            copy_tree(&entry_path, &dest_path, options)?;
+        } else if file_type.is_symlink() && !options.link && !options.symbolic {
+            // Copy the link itself, following it could lead back to a parent directory
+            if fs::symlink_metadata(&dest_path).is_ok_and(|m| !m.is_dir()) {
+                fs::remove_file(&dest_path)?;
+            }
+            other_fs::symlink(fs::read_link(&entry_path)?, &dest_path)?;
         } else {
-            // If destination is a file, rename the file and copy its contents
-            fs::copy(src, dest)?;
+            // If the entry is a file, copy or link it to the destination path
+            copy_file(&entry_path, &dest_path, options)?;
+        }
+    }
+    Ok(())
+}
+
+fn copy_file(src: &Path, dest: &Path, options: &CopyOptions) -> io::Result<()> {
+    if let Ok(dest_metadata) = fs::symlink_metadata(dest) {
+        if options.update {
+            // With '-u', skip destinations that are not older than the source
+            // (a link to the source has the same time, so copying again does nothing)
+            let src_time = fs::metadata(src)?.modified()?;
+            if let Ok(dest_time) = fs::metadata(dest).and_then(|m| m.modified()) {
+                if dest_time >= src_time {
+                    return Ok(());
+                }
+            }
+        }
+        if (options.link || options.symbolic) && options.force && !dest_metadata.is_dir() {
+            // A link can't be made over an existing file, so remove it first
+            fs::remove_file(dest)?;
+        }
+    }
+
+    if options.link {
+        // Hard link the file instead of copying its data
+        if let Err(e) = fs::hard_link(src, dest) {
+            eprintln!(
+                "cp: cannot create hard link '{}' to '{}': {}",
+                dest.display(),
+                src.display(),
+                strerror(&e)
+            );
+            return Err(e);
+        }
+    } else if options.symbolic {
+        // Point the symbolic link to the absolute source path, so it can be found from any directory
+        let target = std::path::absolute(src)?;
+        if let Err(e) = other_fs::symlink(&target, dest) {
+            eprintln!(
+                "cp: cannot create symbolic link '{}': {}",
+                dest.display(),
+                strerror(&e)
+            );
+            return Err(e);
+        }
+    } else {
+        match fs::copy(src, dest) {
+            // With '-f', remove a destination that can't be opened and try again
+            Err(e) if options.force && e.kind() == io::ErrorKind::PermissionDenied => {
+                fs::remove_file(dest)?;
+                fs::copy(src, dest)?;
+            }
+            Err(e) => return Err(e),
+            Ok(_) => (),
+        }
+    }
+    Ok(())
+}
+
+struct ChmodOptions {
+    recursive: bool,
+    // Report every file
+    verbose: bool,
+    // Report only the files whose mode changed
+    changes: bool,
+    // Don't print most error messages
+    quiet: bool,
+}
+
+fn chmod(args: Vec<String>) -> Result<(), io::Error> {
+    if args.len() < 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use 'chmod' like this.",
+        ));
+    }
+    let mut options = ChmodOptions {
+        recursive: false,
+        verbose: false,
+        changes: false,
+        quiet: false,
+    };
+    let mut reference: Option<String> = None;
+    let mut operands: Vec<&String> = Vec::new();
+    for arg in args.iter().skip(2) {
+        match arg.as_str() {
+            "--recursive" => options.recursive = true,
+            "--verbose" => options.verbose = true,
+            "--changes" => options.changes = true,
+            "--silent" | "--quiet" => options.quiet = true,
+            _ if arg.starts_with("--reference=") => {
+                reference = Some(arg["--reference=".len()..].to_string())
+            }
+            // Modes like '-w' are not options
+            _ if arg.starts_with('-') && is_mode(arg) => operands.push(arg),
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                // Short options can be grouped, like '-Rv'
+                for option in arg.chars().skip(1) {
+                    match option {
+                        'R' => options.recursive = true,
+                        'v' => options.verbose = true,
+                        'c' => options.changes = true,
+                        'f' => options.quiet = true,
+                        _ => {
+                            // Handle invalid options of 'chmod'
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "Can't use 'chmod' with this option.",
+                            ));
+                        }
+                    }
+                }
+            }
+            _ => operands.push(arg),
+        }
+    }
+
+    // The mode is the first operand, or the mode of the reference file
+    let mode = match reference {
+        Some(file) => match fs::metadata(&file) {
+            Ok(metadata) => format!("{:o}", metadata.permissions().mode() & 0o7777),
+            Err(e) => {
+                eprintln!(
+                    "chmod: failed to get attributes of '{}': {}",
+                    file,
+                    strerror(&e)
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        },
+        None if !operands.is_empty() => operands.remove(0).clone(),
+        None => String::new(),
+    };
+    if operands.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use 'chmod' like this.",
+        ));
+    }
+    // Check the mode before changing anything
+    if new_mode(&mode, 0, false).is_err() {
+        eprintln!("chmod: invalid mode: '{}'", mode);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid mode."));
+    }
+
+    // Change every file, report failures and continue with the next one
+    let mut failed = false;
+    for file in operands {
+        change_mode(Path::new(file), &mode, &options, &mut failed, true);
+    }
+    if failed {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Can't set permissions.",
+        ));
+    }
+    Ok(())
+}
+
+fn is_mode(arg: &str) -> bool {
+    // Check if an argument starting with '-' is a symbolic mode, like '-w' or '-x,u+r'
+    arg.len() > 1 && arg.chars().all(|c| "rwxXstugoa+-=,0123456789".contains(c))
+}
+
+fn new_mode(mode: &str, current: u32, is_dir: bool) -> io::Result<u32> {
+    if !mode.is_empty() && mode.chars().all(|c| c.is_digit(8)) {
+        // Permissions specified in numbers are transformed into octal base
+        match u32::from_str_radix(mode, 8) {
+            Ok(octal) if octal <= 0o7777 => Ok(octal),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid mode.")),
+        }
+    } else {
+        // Perform a transformation from symbolic permissions to octal
+        symbolic_to_octal(mode, current, is_dir)
+    }
+}
+
+fn mode_string(mode: u32) -> String {
+    // Show the permissions like 'ls -l' does, like 'rwxr-xr-x'
+    let mut result = String::new();
+    let special = [(0o4000, 's'), (0o2000, 's'), (0o1000, 't')];
+    for (index, shift) in [6, 3, 0].iter().enumerate() {
+        let bits = (mode >> shift) & 0o7;
+        result.push(if bits & 4 != 0 { 'r' } else { '-' });
+        result.push(if bits & 2 != 0 { 'w' } else { '-' });
+        let (special_bit, letter) = special[index];
+        result.push(match (mode & special_bit != 0, bits & 1 != 0) {
+            (true, true) => letter,
+            (true, false) => letter.to_ascii_uppercase(),
+            (false, true) => 'x',
+            (false, false) => '-',
+        });
+    }
+    result
+}
+
+fn change_mode(path: &Path, mode: &str, options: &ChmodOptions, failed: &mut bool, top: bool) {
+    // The arguments are followed if they are symbolic links, the entries in the tree are not
+    let metadata = if top {
+        fs::metadata(path)
+    } else {
+        fs::symlink_metadata(path)
+    };
+    let metadata = match metadata {
+        Ok(m) => m,
+        Err(e) => {
+            if !options.quiet {
+                eprintln!(
+                    "chmod: cannot access '{}': {}",
+                    path.display(),
+                    strerror(&e)
+                );
+            }
+            *failed = true;
+            return;
+        }
+    };
+    if metadata.file_type().is_symlink() {
+        return;
+    }
+
+    let current = metadata.permissions().mode() & 0o7777;
+    let new = match new_mode(mode, current, metadata.is_dir()) {
+        Ok(new) => new,
+        Err(_) => {
+            *failed = true;
+            return;
+        }
+    };
+    if new != current {
+        // Set the new permissions to the specified path and handle possible errors
+        if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(new)) {
+            if !options.quiet {
+                eprintln!(
+                    "chmod: changing permissions of '{}': {}",
+                    path.display(),
+                    strerror(&e)
+                );
+            }
+            *failed = true;
+            return;
+        }
+        if options.verbose || options.changes {
+            println!(
+                "mode of '{}' changed from {:04o} ({}) to {:04o} ({})",
+                path.display(),
+                current,
+                mode_string(current),
+                new,
+                mode_string(new)
+            );
+        }
+    } else if options.verbose {
+        println!(
+            "mode of '{}' retained as {:04o} ({})",
+            path.display(),
+            current,
+            mode_string(current)
+        );
+    }
+
+    if options.recursive && metadata.is_dir() {
+        // Change the entries of the directory too
+        match fs::read_dir(path) {
+            Ok(entries) => {
+                for entry in entries.flatten() {
+                    change_mode(&entry.path(), mode, options, failed, false);
+                }
+            }
+            Err(e) => {
+                if !options.quiet {
+                    eprintln!(
+                        "chmod: cannot read directory '{}': {}",
+                        path.display(),
+                        strerror(&e)
+                    );
+                }
+                *failed = true;
+            }
+        }
+    }
+}
+
+fn symbolic_to_octal(
+    symbolic_permissions: &str,
+    current_permissions: u32,
+    is_dir: bool,
+) -> Result<u32, io::Error> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "Invalid mode.");
+    let umask = current_umask();
+    let mut mode = current_permissions & 0o7777;
+
+    // The mode is a list of clauses separated by commas, like 'u+x,go-w'
+    for clause in symbolic_permissions.split(',') {
+        let mut chars = clause.chars().peekable();
+        // Get the user categories affected by the clause (with their special bit)
+        let mut user_category = 0;
+        while let Some(&char) = chars.peek() {
+            match char {
+                'u' => user_category |= 0o4700,
+                'g' => user_category |= 0o2070,
+                'o' => user_category |= 0o1007,
+                'a' => user_category |= 0o7777,
+                _ => break,
+            }
+            chars.next();
+        }
+        // Without a user category, the clause affects all of them except the bits in the umask
+        let (affected, omitted) = match user_category {
+            0 => (0o7777, umask),
+            _ => (user_category, 0),
+        };
+        if chars.peek().is_none() {
+            return Err(invalid());
+        }
+
+        // A clause can have several operations, like 'u+x-w'
+        while let Some(operation) = chars.next() {
+            if !matches!(operation, '+' | '-' | '=') {
+                return Err(invalid());
+            }
+            let mut permissions = 0;
+            match chars.peek() {
+                // Copy the permissions of another user category, like 'g=u'
+                Some(&category @ ('u' | 'g' | 'o')) => {
+                    chars.next();
+                    let bits = match category {
+                        'u' => (mode >> 6) & 0o7,
+                        'g' => (mode >> 3) & 0o7,
+                        _ => mode & 0o7,
+                    };
+                    permissions = bits * 0o111;
+                }
+                _ => {
+                    while let Some(&char) = chars.peek() {
+                        match char {
+                            'r' => permissions |= 0o444,
+                            'w' => permissions |= 0o222,
+                            'x' => permissions |= 0o111,
+                            // Execute only for directories and files that are already executable
+                            'X' => {
+                                if is_dir || mode & 0o111 != 0 {
+                                    permissions |= 0o111
+                                }
+                            }
+                            // Set user or group ID on execution
+                            's' => permissions |= 0o6000,
+                            // Sticky bit
+                            't' => permissions |= 0o1000,
+                            _ => break,
+                        }
+                        chars.next();
+                    }
+                }
+            }
+            // Keep only the bits of the affected user categories
+            permissions &= affected & !omitted;
+            mode = match operation {
+                // Add the new permissions to the current permissions
+                '+' => mode | permissions,
+                // Remove the new permissions from the current permissions
+                '-' => mode & !permissions,
+                // Replace the permissions of the affected user categories
+                _ => (mode & !affected) | permissions,
+            };
+        }
+    }
+    Ok(mode)
+}
+
+fn touch(args: Vec<String>) -> Result<(), io::Error> {
+    if args.len() == 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use just 'touch'.",
+        ));
+    }
+    let mut access = false;
+    let mut modify = false;
+    let mut no_create = false;
+    let mut no_dereference = false;
+    let mut reference: Option<String> = None;
+    let mut date: Option<String> = None;
+    let mut stamp: Option<String> = None;
+    let mut operands: Vec<&String> = Vec::new();
+    // Separate the options from the files
+    let mut iter = args.iter().skip(2);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--date" => match iter.next() {
+                // The date is given in the next argument
+                Some(value) => date = Some(value.clone()),
+                None => {
+                    eprintln!("touch: option '--date' requires an argument");
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Option requires a date.",
+                    ));
+                }
+            },
+            _ if arg.starts_with("--date=") => date = Some(arg["--date=".len()..].to_string()),
+            "--no-create" => no_create = true,
+            "--no-dereference" => no_dereference = true,
+            "--time=atime" | "--time=access" | "--time=use" => access = true,
+            "--time=mtime" | "--time=modify" => modify = true,
+            "--reference" => match iter.next() {
+                // The reference file is given in the next argument
+                Some(file) => reference = Some(file.clone()),
+                None => {
+                    eprintln!("touch: option '--reference' requires an argument");
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Option '-r' requires a file.",
+                    ));
+                }
+            },
+            _ if arg.starts_with("--reference=") => {
+                reference = Some(arg["--reference=".len()..].to_string())
+            }
+            _ if arg.starts_with("--") => {
+                eprintln!("touch: unrecognized option '{}'", arg);
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Can't use 'touch' like this.",
+                ));
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                // Short options can be grouped, like '-ac' or '-ct 202401010000'
+                for (index, option) in arg.char_indices().skip(1) {
+                    match option {
+                        'a' => access = true,
+                        'm' => modify = true,
+                        'c' => no_create = true,
+                        'h' => no_dereference = true,
+                        'd' | 't' | 'r' => {
+                            // The value is the rest of the argument or the next argument
+                            let rest = &arg[index + option.len_utf8()..];
+                            let value = if !rest.is_empty() {
+                                rest.to_string()
+                            } else {
+                                match iter.next() {
+                                    Some(value) => value.clone(),
+                                    None => {
+                                        eprintln!(
+                                            "touch: option requires an argument -- '{}'",
+                                            option
+                                        );
+                                        return Err(io::Error::new(
+                                            io::ErrorKind::InvalidInput,
+                                            "Option requires a value.",
+                                        ));
+                                    }
+                                }
+                            };
+                            match option {
+                                'd' => date = Some(value),
+                                't' => stamp = Some(value),
+                                _ => reference = Some(value),
+                            }
+                            break;
+                        }
+                        _ => {
+                            eprintln!("touch: invalid option -- '{}'", option);
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "Can't use 'touch' like this.",
+                            ));
+                        }
+                    }
+                }
+            }
+            _ => operands.push(arg),
+        }
+    }
+    if operands.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use 'touch' like this.",
+        ));
+    }
+    if stamp.is_some() && (date.is_some() || reference.is_some()) {
+        eprintln!("touch: cannot specify times from more than one source");
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Can't use '-t' with '-d' or '-r'.",
+        ));
+    }
+
+    // The new times are the current time, or the times of the reference file
+    let now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: libc::UTIME_NOW,
+    };
+    let mut times = [now, now];
+    let mut now = now_timestamp();
+    if let Some(file) = reference {
+        // With '-h', the times of a symbolic link are its own
+        let metadata = match no_dereference {
+            true => fs::symlink_metadata(&file),
+            false => fs::metadata(&file),
+        };
+        let metadata = match metadata {
+            Ok(m) => m,
+            Err(e) => {
+                eprintln!(
+                    "touch: failed to get attributes of '{}': {}",
+                    file,
+                    strerror(&e)
+                );
+                return Err(e);
+            }
+        };
+        times[0] = libc::timespec {
+            tv_sec: metadata.atime(),
+            tv_nsec: metadata.atime_nsec(),
+        };
+        times[1] = libc::timespec {
+            tv_sec: metadata.mtime(),
+            tv_nsec: metadata.mtime_nsec(),
+        };
+        // Relative dates are computed from the reference time
+        now = (metadata.mtime(), metadata.mtime_nsec());
+    }
+    if date.is_some() || stamp.is_some() {
+        // Dates are in local time (from TZ), unless they have a time zone
+        let zone = load_time_zone();
+        let parsed = match (&stamp, &date) {
+            (Some(stamp), _) => parse_touch_stamp(stamp, &zone),
+            (None, Some(date)) => parse_date(date, now, &zone),
+            (None, None) => None,
+        };
+        match parsed {
+            Some((seconds, nanoseconds)) => {
+                let time = libc::timespec {
+                    tv_sec: seconds,
+                    tv_nsec: nanoseconds,
+                };
+                times = [time, time];
+            }
+            None => {
+                let text = stamp.or(date).unwrap_or_default();
+                eprintln!("touch: invalid date format '{}'", text);
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid date format.",
+                ));
+            }
+        }
+    }
+    // With only '-a' or only '-m', the other time is not changed
+    let omit = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: libc::UTIME_OMIT,
+    };
+    if access && !modify {
+        times[1] = omit;
+    } else if modify && !access {
+        times[0] = omit;
+    }
+
+    // Touch every file, report failures and continue with the next one
+    let mut failed = false;
+    for file in operands {
+        if let Err(e) = touch_file(Path::new(file), &times, no_create, no_dereference) {
+            eprintln!("touch: cannot touch '{}': {}", file, strerror(&e));
+            failed = true;
+        }
+    }
+    if failed {
+        return Err(io::Error::other("Some files couldn't be touched."));
+    }
+    Ok(())
+}
+
+fn touch_file(
+    path: &Path,
+    times: &[libc::timespec; 2],
+    no_create: bool,
+    no_dereference: bool,
+) -> io::Result<()> {
+    // 'touch -' changes the times of the standard output
+    if path == Path::new("-") {
+        if unsafe { libc::futimens(libc::STDOUT_FILENO, times.as_ptr()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(());
+    }
+    // Create missing files without truncating existing ones (not with '-c' or '-h'),
+    // the target of a dangling symbolic link is created too
+    if !no_create && !no_dereference && fs::metadata(path).is_err() {
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+    }
+    // Change only the times, with nanosecond precision
+    let flags = if no_dereference {
+        libc::AT_SYMLINK_NOFOLLOW
+    } else {
+        0
+    };
+    let name = path_to_cstring(path)?;
+    if unsafe { libc::utimensat(libc::AT_FDCWD, name.as_ptr(), times.as_ptr(), flags) } == -1 {
+        let e = io::Error::last_os_error();
+        // Missing files are not an error with '-c'
+        if no_create && e.kind() == io::ErrorKind::NotFound {
+            return Ok(());
         }
+        return Err(e);
     }
     Ok(())
 }
 
-fn copy_r(source: &Path, destination: &PathBuf) -> io::Result<()> {
-    let src = Path::new(source);
-    let dest = PathBuf::new().join(destination);
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Count the days since 1970-01-01 (the month may be out of range, like month 13)
+    let year = year + (month - 1).div_euclid(12);
+    let month = (month - 1).rem_euclid(12) + 1;
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
 
-    // If the source is a directory, create the destination directory
-    if src.is_dir() {
-        // Handle the rename case
-        let mut dest_copy = dest.clone();
-        // If destination exists, add source name to the destination
-        if dest.exists() {
-            dest_copy = dest_copy.join(match src.file_name() {
-                Some(f) => f,
-                None => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "Invalid source file path.",
-                    ))
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    // Get the (year, month, day) of a day counted since 1970-01-01
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    days_from_civil(year, month + 1, 1) - days_from_civil(year, month, 1)
+}
+
+// The day of a DST transition in a POSIX TZ rule
+enum RuleDate {
+    // 'Jn': day 1 to 365, February 29 is never counted
+    Julian(i64),
+    // 'n': day 0 to 365, February 29 is counted in leap years
+    Zero(i64),
+    // 'Mm.w.d': day d (0 is Sunday) of week w (5 is the last one) of month m
+    Month(i64, i64, i64),
+}
+
+// The POSIX TZ rule, like 'EST5EDT,M3.2.0,M11.1.0'
+struct ZoneRule {
+    std_offset: i64,
+    // The DST offset, and when DST starts and ends (day and local time in seconds)
+    dst: Option<(i64, RuleDate, i64, RuleDate, i64)>,
+}
+
+// A time zone read from a TZif file or from the TZ variable
+struct TimeZone {
+    transitions: Vec<i64>,
+    // The local time type used after each transition
+    transition_types: Vec<usize>,
+    // The UTC offsets (in seconds) of the local time types
+    offsets: Vec<i64>,
+    // The rule used after the last transition
+    rule: Option<ZoneRule>,
+}
+
+impl RuleDate {
+    fn day(&self, year: i64) -> i64 {
+        // Get the day of the transition in the given year, counted since 1970-01-01
+        let first = days_from_civil(year, 1, 1);
+        match *self {
+            RuleDate::Julian(n) => {
+                let leap = days_in_month(year, 2) == 29;
+                first + n - 1 + if leap && n >= 60 { 1 } else { 0 }
+            }
+            RuleDate::Zero(n) => first + n,
+            RuleDate::Month(month, week, weekday) => {
+                let first_of_month = days_from_civil(year, month, 1);
+                let first_weekday = (first_of_month + 4).rem_euclid(7);
+                let mut day = 1 + (weekday - first_weekday).rem_euclid(7) + 7 * (week - 1);
+                while day > days_in_month(year, month) {
+                    day -= 7;
                 }
-            });
+                first_of_month + day - 1
+            }
         }
-        fs::create_dir_all(&dest_copy)?;
-        // Iterate over the entries
-        for entry in fs::read_dir(src)? {
-            // Add the entry name to the destination path
-            let entry = entry?;
-            let entry_path = entry.path();
-            let dest_path = dest_copy.join(match entry_path.file_name() {
-                Some(f) => f,
-                None => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "Invalid source file path.",
-                    ))
-                }
-            });
+    }
+}
 
-            if entry_path.is_dir() {
-                // If the entry is a subdirectory, recursively copy it
-                copy_r(&entry_path, &dest_path)?;
-            } else {
-                // If the entry is a file, copy it to the destination path
-                fs::copy(&entry_path, &dest_path)?;
+impl ZoneRule {
+    fn offset_at(&self, utc: i64) -> i64 {
+        let (dst_offset, start, start_time, end, end_time) = match &self.dst {
+            Some(dst) => dst,
+            None => return self.std_offset,
+        };
+        // Find the transitions of the (local) year and check if we are between them
+        let (year, _, _) = civil_from_days((utc + self.std_offset).div_euclid(86400));
+        let start_utc = start.day(year) * 86400 + start_time - self.std_offset;
+        let end_utc = end.day(year) * 86400 + end_time - dst_offset;
+        let in_dst = if start_utc < end_utc {
+            utc >= start_utc && utc < end_utc
+        } else {
+            // On the southern hemisphere DST goes over the new year
+            !(utc >= end_utc && utc < start_utc)
+        };
+        if in_dst {
+            *dst_offset
+        } else {
+            self.std_offset
+        }
+    }
+}
+
+impl TimeZone {
+    fn utc() -> TimeZone {
+        TimeZone {
+            transitions: Vec::new(),
+            transition_types: Vec::new(),
+            offsets: vec![0],
+            rule: None,
+        }
+    }
+
+    fn offset_at(&self, utc: i64) -> i64 {
+        // Use the rule after the last transition (or without transitions)
+        if let Some(rule) = &self.rule {
+            if self.transitions.last().is_none_or(|&last| utc >= last) {
+                return rule.offset_at(utc);
             }
         }
+        match self.transitions.partition_point(|&t| t <= utc) {
+            0 => self.offsets.first().copied().unwrap_or(0),
+            index => self.offsets[self.transition_types[index - 1]],
+        }
+    }
+
+    fn local_to_utc(&self, local: i64) -> i64 {
+        // Guess the offset from the local time, then correct it with the offset at that moment
+        let utc = local - self.offset_at(local);
+        local - self.offset_at(utc)
+    }
+}
+
+fn load_time_zone() -> TimeZone {
+    // The TZ variable names a zoneinfo file or is a POSIX TZ rule, else use /etc/localtime
+    let tz = match env::var("TZ") {
+        Ok(tz) => tz,
+        Err(_) => {
+            return fs::read("/etc/localtime")
+                .ok()
+                .and_then(|data| parse_tzif(&data))
+                .unwrap_or_else(TimeZone::utc)
+        }
+    };
+    let tz = tz.strip_prefix(':').unwrap_or(&tz);
+    if tz.is_empty() {
+        return TimeZone::utc();
+    }
+    let path = if tz.starts_with('/') {
+        PathBuf::from(tz)
     } else {
-        // If the source is a file, copy it to the destination
-        if dest.is_dir() {
-            // If the destination is a directory, create a file inside it
-            let file_name = match src.file_name() {
-                Some(f) => f,
-                None => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "Invalid source file path.",
-                    ))
-                }
-            };
-            let dest_file = dest.join(file_name);
-            fs::copy(src, dest_file)?;
-        } else {
-            // If the destination is a file, perform a regular file copy
-            fs::copy(src, dest)?;
+        let zoneinfo = env::var("TZDIR").unwrap_or_else(|_| String::from("/usr/share/zoneinfo"));
+        Path::new(&zoneinfo).join(tz)
+    };
+    if !tz.contains("..") {
+        if let Some(zone) = fs::read(path).ok().and_then(|data| parse_tzif(&data)) {
+            return zone;
         }
     }
-    Ok(())
+    match parse_posix_tz(tz) {
+        Some(rule) => TimeZone {
+            transitions: Vec::new(),
+            transition_types: Vec::new(),
+            offsets: vec![rule.std_offset],
+            rule: Some(rule),
+        },
+        None => TimeZone::utc(),
+    }
 }
 
-fn chmod(args: Vec<String>) -> Result<(), io::Error> {
-    if args.len() < 4 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Can't use 'chmod' like this.",
-        ));
+fn parse_tzif(data: &[u8]) -> Option<TimeZone> {
+    // Read the big endian numbers of the file
+    let number = |at: usize, size: usize| -> Option<i64> {
+        let bytes = data.get(at..at + size)?;
+        Some(match size {
+            4 => i32::from_be_bytes(bytes.try_into().ok()?) as i64,
+            _ => i64::from_be_bytes(bytes.try_into().ok()?),
+        })
+    };
+    let header = |at: usize, time_size: u64| -> Option<[usize; 6]> {
+        if data.get(at..at + 4)? != b"TZif" {
+            return None;
+        }
+        let mut counts = [0; 6];
+        for (index, count) in counts.iter_mut().enumerate() {
+            *count = usize::try_from(number(at + 20 + index * 4, 4)?).ok()?;
+        }
+        // The counts must describe data that fits in the rest of the file
+        let [utc, std, leap, time, types, chars] = counts.map(|count| count as u64);
+        let length =
+            time * (time_size + 1) + types * 6 + chars + leap * (time_size + 4) + std + utc;
+        if length > (data.len() - (at + 44).min(data.len())) as u64 {
+            return None;
+        }
+        Some(counts)
+    };
+
+    // Version 2 and later files have a second header with 64-bit times and a TZ rule at the end
+    let [utc_count, std_count, leap_count, time_count, type_count, char_count] = header(0, 4)?;
+    let (mut at, time_size) = (44, 4);
+    let (counts, time_size, version2) = if data.get(4).is_some_and(|&v| v >= b'2') {
+        at += time_count * 5 + type_count * 6 + char_count + leap_count * 8 + std_count + utc_count;
+        let counts = header(at, 8)?;
+        at += 44;
+        (counts, 8, true)
     } else {
-        // Handle invalid options of 'chmod'
-        if args[2].starts_with("-") {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Can't use 'chmod' with this option.",
-            ));
-        } else if args[2].parse::<u8>().is_ok() {
-            // Check if the permissions are specified in numbers and transform them into octal base
-            let octal_representation = u32::from_str_radix(&args[2], 8);
-            match octal_representation {
-                Ok(octal) => {
-                    let path = Path::new(&args[3]);
-                    let new_permissions = PermissionsExt::from_mode(octal);
-                    // Set the new permissions to the specified path and handle possible errors
-                    if let Err(_) = fs::set_permissions(path, new_permissions) {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "Can't set permissions.",
-                        ));
-                    }
-                }
-                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-            }
+        (
+            [
+                utc_count, std_count, leap_count, time_count, type_count, char_count,
+            ],
+            time_size,
+            false,
+        )
+    };
+    let [utc_count, std_count, leap_count, time_count, type_count, char_count] = counts;
+
+    let mut zone = TimeZone::utc();
+    for index in 0..time_count {
+        zone.transitions
+            .push(number(at + index * time_size, time_size)?);
+    }
+    at += time_count * time_size;
+    for index in 0..time_count {
+        zone.transition_types.push(*data.get(at + index)? as usize);
+    }
+    at += time_count;
+    zone.offsets.clear();
+    for index in 0..type_count {
+        zone.offsets.push(number(at + index * 6, 4)?);
+    }
+    at += type_count * 6 + char_count + leap_count * (time_size + 4) + std_count + utc_count;
+    if zone.offsets.is_empty() || zone.transition_types.iter().any(|&t| t >= type_count) {
+        return None;
+    }
+    if version2 {
+        // The footer is the TZ rule between two newlines
+        let footer = data.get(at + 1..)?;
+        let end = footer.iter().position(|&b| b == b'\n')?;
+        zone.rule = parse_posix_tz(std::str::from_utf8(&footer[..end]).ok()?);
+    }
+    Some(zone)
+}
+
+fn parse_posix_tz(text: &str) -> Option<ZoneRule> {
+    let mut rest = text;
+    // Names are letters, or anything between '<' and '>'
+    let name = |rest: &mut &str| -> Option<()> {
+        if let Some(inner) = rest.strip_prefix('<') {
+            let end = inner.find('>')?;
+            *rest = &inner[end + 1..];
         } else {
-            // Perform a transformation from symbolic permissions to octal
-            let octal_representation = symbolic_to_octal(&args[2], &args[3]);
-            match octal_representation {
-                Ok(octal) => {
-                    let path = std::path::Path::new(&args[3]);
-                    let new_permissions = PermissionsExt::from_mode(octal);
-                    // Set the new permissions to the specified path and handle possible errors
-                    if let Err(_) = fs::set_permissions(path, new_permissions) {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "Can't set permissions.",
-                        ));
-                    }
-                }
-                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(rest.len());
+            if end < 3 {
+                return None;
+            }
+            *rest = &rest[end..];
+        }
+        Some(())
+    };
+    // Times are [+-]hh[:mm[:ss]]
+    let time = |rest: &mut &str| -> Option<i64> {
+        let mut sign = 1;
+        if let Some(r) = rest.strip_prefix('-') {
+            sign = -1;
+            *rest = r;
+        } else if let Some(r) = rest.strip_prefix('+') {
+            *rest = r;
+        }
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit() && c != ':')
+            .unwrap_or(rest.len());
+        let mut seconds = 0;
+        for (index, part) in rest[..end].split(':').enumerate() {
+            let value: i64 = part.parse().ok()?;
+            seconds += value * [3600, 60, 1].get(index)?;
+        }
+        *rest = &rest[end..];
+        Some(sign * seconds)
+    };
+    let date = |rest: &mut &str| -> Option<(RuleDate, i64)> {
+        let end = rest.find([',', '/']).unwrap_or(rest.len());
+        let text = &rest[..end];
+        let day = if let Some(n) = text.strip_prefix('J') {
+            RuleDate::Julian(n.parse().ok()?)
+        } else if let Some(m) = text.strip_prefix('M') {
+            let parts: Vec<i64> = m
+                .split('.')
+                .map(|p| p.parse().ok())
+                .collect::<Option<_>>()?;
+            if parts.len() != 3 {
+                return None;
             }
+            RuleDate::Month(parts[0], parts[1], parts[2])
+        } else {
+            RuleDate::Zero(text.parse().ok()?)
+        };
+        *rest = &rest[end..];
+        // The transition happens at 02:00 local time by default
+        let mut at = 7200;
+        if let Some(r) = rest.strip_prefix('/') {
+            *rest = r;
+            at = time(rest)?;
         }
+        Some((day, at))
+    };
+
+    name(&mut rest)?;
+    // POSIX offsets are west of UTC, so 'EST5' is UTC-5
+    let std_offset = -time(&mut rest)?;
+    if rest.is_empty() {
+        return Some(ZoneRule {
+            std_offset,
+            dst: None,
+        });
     }
-    Ok(())
+    name(&mut rest)?;
+    let mut dst_offset = std_offset + 3600;
+    if !rest.is_empty() && !rest.starts_with(',') {
+        dst_offset = -time(&mut rest)?;
+    }
+    // Without a rule, use the United States one
+    let rule = rest.strip_prefix(',').unwrap_or("M3.2.0,M11.1.0");
+    let mut rest = rule;
+    let (start, start_time) = date(&mut rest)?;
+    rest = rest.strip_prefix(',')?;
+    let (end, end_time) = date(&mut rest)?;
+    Some(ZoneRule {
+        std_offset,
+        dst: Some((dst_offset, start, start_time, end, end_time)),
+    })
 }
 
-fn symbolic_to_octal(symbolic_permissions: &str, file: &str) -> Result<u32, io::Error> {
-    let mut user_category = String::from("");
-    let mut permissions = String::from("");
-    let mut sum_permissions = (0, 0, 0); // tuple to handle rwx
-    let mut operation = '+';
+fn now_timestamp() -> (i64, i64) {
+    // The current time as seconds and nanoseconds since the epoch
+    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos() as i64),
+        Err(_) => (0, 0),
+    }
+}
 
-    // Separate user category, the operation performed and permissions
-    for char in symbolic_permissions.chars() {
-        match char {
-            'u' | 'g' | 'o' | 'a' => user_category.push(char),
-            '+' | '-' => operation = char,
-            'r' | 'w' | 'x' => permissions.push(char),
-            _ => (),
+fn parse_nanoseconds(fraction: Option<regex::Match>) -> i64 {
+    // '.5' means 500000000 nanoseconds
+    match fraction {
+        Some(f) => format!("{:0<9}", f.as_str()).parse().unwrap_or(0),
+        None => 0,
+    }
+}
+
+fn parse_touch_stamp(stamp: &str, zone: &TimeZone) -> Option<(i64, i64)> {
+    // The format is [[CC]YY]MMDDhhmm[.ss], in local time
+    let (digits, seconds) = match stamp.split_once('.') {
+        Some((digits, seconds)) if seconds.len() == 2 => (digits, seconds.parse::<i64>().ok()?),
+        Some(_) => return None,
+        None => (stamp, 0),
+    };
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| digits[range].parse::<i64>().unwrap_or(0);
+    let (year, rest) = match digits.len() {
+        8 => {
+            let (now, _) = now_timestamp();
+            let (year, _, _) = civil_from_days((now + zone.offset_at(now)).div_euclid(86400));
+            (year, 0)
         }
+        // Two digit years from 69 are in the 1900s, the others in the 2000s
+        10 => match number(0..2) {
+            year if year >= 69 => (1900 + year, 2),
+            year => (2000 + year, 2),
+        },
+        12 => (number(0..4), 4),
+        _ => return None,
+    };
+    let (month, day) = (number(rest..rest + 2), number(rest + 2..rest + 4));
+    let (hour, minute) = (number(rest + 4..rest + 6), number(rest + 6..rest + 8));
+    if !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || seconds > 60
+    {
+        return None;
     }
+    let local = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + seconds;
+    Some((zone.local_to_utc(local), 0))
+}
 
-    // For every user category specified, match the permissions and perform a sum
-    for char in user_category.chars() {
-        match char {
-            'u' => {
-                // Iterate over each permissions and add it to the permissions tuple (user)
-                for item in permissions.chars() {
-                    match item {
-                        'r' => sum_permissions.0 += 4,
-                        'w' => sum_permissions.0 += 2,
-                        'x' => sum_permissions.0 += 1,
-                        _ => (),
-                    }
+fn month_number(name: &str) -> i64 {
+    // Match the first three letters of a month name
+    let months = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+    months
+        .iter()
+        .position(|m| name.starts_with(m))
+        .map_or(0, |index| index as i64 + 1)
+}
+
+fn parse_date(text: &str, now: (i64, i64), zone: &TimeZone) -> Option<(i64, i64)> {
+    let text = text.trim().to_lowercase();
+    // '@SECONDS' is a time since the epoch
+    let epoch = Regex::new(r"^@(-?\d+)(?:[.,](\d{1,9}))?$").ok()?;
+    if let Some(c) = epoch.captures(&text) {
+        return Some((c[1].parse().ok()?, parse_nanoseconds(c.get(2))));
+    }
+
+    let months = "(jan|feb|mar|apr|may|jun|jul|aug|sep|oct|nov|dec)[a-z]*\\.?";
+    let weekdays = "(sunday|monday|tuesday|tues|wednesday|wednes|thursday|thurs|thur|friday|saturday|sun|mon|tue|wed|thu|fri|sat)\\.?";
+    let units = "(years?|months?|fortnights?|weeks?|days?|hours?|minutes?|mins?|seconds?|secs?)";
+    let patterns = [
+        // ISO 8601, like 2024-01-02T03:04:05
+        String::from(
+            r"^(\d{4})-(\d{1,2})-(\d{1,2})(?:(?:t|\s+)(\d{1,2}):(\d{2})(?::(\d{2})(?:[.,](\d{1,9}))?)?)?",
+        ),
+        // Times, like 10:30, 10:30:15.5 or 10:30pm
+        String::from(r"^(\d{1,2}):(\d{2})(?::(\d{2})(?:[.,](\d{1,9}))?)?(?:\s*([ap])\.?m\.?\b)?"),
+        String::from(r"^(\d{1,2})\s*([ap])\.?m\.?\b"),
+        // Relative items, like '2 hours', '-3 days' or 'week'
+        format!(r"^([+-]?\s*\d+)?\s*{}\b(\s+ago\b)?", units),
+        // Time zones, like +0100, +01:00, Z, UTC or EST
+        String::from(r"^([+-])(\d{2}):?(\d{2})\b"),
+        String::from(r"^(z|utc|gmt|ut|est|edt|cst|cdt|mst|mdt|pst|pdt)\b"),
+        // Dates with month names, like '2 Jan 2024' (RFC 2822) or 'January 2, 2024'
+        format!(r"^(\d{{1,2}})[\s-]+{}[\s-]+(\d{{4}})\b", months),
+        format!(
+            r"^{}\s+(\d{{1,2}})(?:st|nd|rd|th)?(?:,?\s+(\d{{4}}))?\b",
+            months
+        ),
+        // Dates like 1/2/2024 (month first)
+        String::from(r"^(\d{1,2})/(\d{1,2})(?:/(\d{4}|\d{2}))?\b"),
+        // Days of the week, optionally with an ordinal, like 'next monday'
+        format!(r"^(next|last|previous|this)\s+{}\b", units),
+        format!(r"^(?:(next|last|previous|this)\s+)?{}(?:\s|,|$)", weekdays),
+        String::from(r"^(now|today|yesterday|tomorrow|midnight|noon)\b"),
+    ];
+    let regexes: Vec<Regex> = patterns
+        .iter()
+        .map(|p| Regex::new(p))
+        .collect::<Result<_, _>>()
+        .ok()?;
+
+    let mut date: Option<(i64, i64, i64)> = None;
+    let mut time: Option<(i64, i64, i64, i64)> = None;
+    let mut fixed_offset: Option<i64> = None;
+    let mut weekday: Option<(i64, i64)> = None;
+    let (mut months_added, mut days_added, mut seconds_added) = (0, 0, 0);
+    let mut add = |unit: &str, count: i64| match unit.trim_end_matches('s') {
+        "year" => months_added += 12 * count,
+        "month" => months_added += count,
+        "fortnight" => days_added += 14 * count,
+        "week" => days_added += 7 * count,
+        "day" => days_added += count,
+        "hour" => seconds_added += 3600 * count,
+        "minute" | "min" => seconds_added += 60 * count,
+        _ => seconds_added += count,
+    };
+    let am_pm = |hour: i64, marker: Option<regex::Match>| -> Option<i64> {
+        match marker.map(|m| m.as_str()) {
+            None => Some(hour),
+            Some(_) if !(1..=12).contains(&hour) => None,
+            Some("a") => Some(hour % 12),
+            Some(_) => Some(hour % 12 + 12),
+        }
+    };
+    let ordinal = |word: Option<regex::Match>| match word.map(|w| w.as_str()) {
+        Some("next") => 1,
+        Some("last") | Some("previous") => -1,
+        _ => 0,
+    };
+
+    // Parse the items one after the other
+    let mut rest = text.as_str();
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() {
+            break;
+        }
+        let (index, c) = regexes
+            .iter()
+            .enumerate()
+            .find_map(|(index, regex)| regex.captures(rest).map(|c| (index, c)))?;
+        let number = |group: usize| c.get(group).and_then(|m| m.as_str().parse::<i64>().ok());
+        match index {
+            0 => {
+                date = Some((number(1)?, number(2)?, number(3)?));
+                if c.get(4).is_some() {
+                    time = Some((
+                        number(4)?,
+                        number(5)?,
+                        number(6).unwrap_or(0),
+                        parse_nanoseconds(c.get(7)),
+                    ));
                 }
             }
-            'g' => {
-                // Iterate over each permissions and add it to the permissions tuple (groups)
-                for item in permissions.chars() {
-                    match item {
-                        'r' => sum_permissions.1 += 4,
-                        'w' => sum_permissions.1 += 2,
-                        'x' => sum_permissions.1 += 1,
-                        _ => (),
-                    }
-                }
+            1 => {
+                let hour = am_pm(number(1)?, c.get(5))?;
+                time = Some((
+                    hour,
+                    number(2)?,
+                    number(3).unwrap_or(0),
+                    parse_nanoseconds(c.get(4)),
+                ));
             }
-            'o' => {
-                // Iterate over each permissions and add it to the permissions tuple (other)
-                for item in permissions.chars() {
-                    match item {
-                        'r' => sum_permissions.2 += 4,
-                        'w' => sum_permissions.2 += 2,
-                        'x' => sum_permissions.2 += 1,
-                        _ => (),
-                    }
-                }
+            2 => time = Some((am_pm(number(1)?, c.get(2))?, 0, 0, 0)),
+            3 => {
+                let count = match c.get(1) {
+                    Some(n) => n.as_str().replace(char::is_whitespace, "").parse().ok()?,
+                    None => 1,
+                };
+                let sign = if c.get(3).is_some() { -1 } else { 1 };
+                add(&c[2], sign * count);
             }
-            'a' => {
-                // Iterate over each permissions and add it to the permissions tuple (all)
-                for item in permissions.chars() {
-                    match item {
-                        'r' => {
-                            sum_permissions.0 += 4;
-                            sum_permissions.1 += 4;
-                            sum_permissions.2 += 4;
-                        }
-                        'w' => {
-                            sum_permissions.0 += 2;
-                            sum_permissions.1 += 2;
-                            sum_permissions.2 += 2;
-                        }
-                        'x' => {
-                            sum_permissions.0 += 1;
-                            sum_permissions.1 += 1;
-                            sum_permissions.2 += 1;
-                        }
-                        _ => (),
-                    }
-                }
+            4 => {
+                let sign = if &c[1] == "-" { -1 } else { 1 };
+                fixed_offset = Some(sign * (number(2)? * 3600 + number(3)? * 60));
             }
-            _ => (),
+            5 => {
+                let hours = match &c[1] {
+                    "est" => -5,
+                    "edt" => -4,
+                    "cst" => -6,
+                    "cdt" => -5,
+                    "mst" => -7,
+                    "mdt" => -6,
+                    "pst" => -8,
+                    "pdt" => -7,
+                    _ => 0,
+                };
+                fixed_offset = Some(hours * 3600);
+            }
+            6 => date = Some((number(3)?, month_number(&c[2]), number(1)?)),
+            7 => {
+                let year = number(3).unwrap_or(0);
+                date = Some((year, month_number(&c[1]), number(2)?));
+            }
+            8 => {
+                let year = match number(3) {
+                    Some(y) if c[3].len() == 2 && y >= 69 => 1900 + y,
+                    Some(y) if c[3].len() == 2 => 2000 + y,
+                    Some(y) => y,
+                    None => 0,
+                };
+                date = Some((year, number(1)?, number(2)?));
+            }
+            9 => add(
+                &c[2],
+                if &c[1] == "this" {
+                    0
+                } else {
+                    ordinal(c.get(1))
+                },
+            ),
+            10 => {
+                let day = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"]
+                    .iter()
+                    .position(|d| c[2].starts_with(d))? as i64;
+                weekday = Some((ordinal(c.get(1)), day));
+            }
+            _ => match &c[1] {
+                "yesterday" => add("day", -1),
+                "tomorrow" => add("day", 1),
+                "midnight" => time = Some((0, 0, 0, 0)),
+                "noon" => time = Some((12, 0, 0, 0)),
+                _ => (),
+            },
         }
+        rest = &rest[c.get(0)?.end()..];
     }
-    // Concatenate the tuple into a string to transform it from str to octal
-    let octal_string = sum_permissions.0.to_string()
-        + &sum_permissions.1.to_string()
-        + &sum_permissions.2.to_string();
-    // Transform the string into octal base and handle possible errors
-    let octal_representation = match u32::from_str_radix(&octal_string, 8) {
-        Ok(v) => v,
-        Err(e) => {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
-        }
-    };
-    // Get current permissions of the file
-    let current_permissions = std::fs::metadata(file)?.permissions().mode();
-    let result = match operation {
-        // If we have to add the new permissions, perform OR bitwise operation between current permissions and new permissions
-        '+' => current_permissions | octal_representation,
-        // If we have to subtract the new permissions, perform XOR bitwise operation between current permissions and new permissions
-        '-' => current_permissions ^ octal_representation,
-        // Handle other operations
-        _ => {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Invalid operation.",
-            ))
-        }
-    };
-    Ok(result)
-}
 
-fn touch(args: Vec<String>) -> Result<(), io::Error> {
-    if args.len() == 2 {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Can't use just 'touch'.",
-        ));
-    } else if args.len() == 3 {
-        let file = Path::new(&args[2]);
-        if !file.exists() {
-            // If file doesn't exists, create it (which modifies 'modify time')
-            File::create(&args[2])?;
-        } else {
-            // If file exists, truncate it (which modifies 'modify time')
-            File::create(&args[2])?;
-            // And read the contents of it (which modifies 'access time')
-            fs::read_to_string(&args[2])?;
+    // Convert between UTC and local time in the given zone (or the TZ one)
+    let to_local = |utc: i64| utc + fixed_offset.unwrap_or_else(|| zone.offset_at(utc));
+    let to_utc = |local: i64| match fixed_offset {
+        Some(offset) => local - offset,
+        None => zone.local_to_utc(local),
+    };
+    let local_now = to_local(now.0);
+    let (mut year, mut month, mut day) = civil_from_days(local_now.div_euclid(86400));
+    let (mut seconds, mut nanoseconds) = (local_now.rem_euclid(86400), now.1);
+    if let Some((y, m, d)) = date {
+        // A date without a year is in the current year
+        if y != 0 {
+            year = y;
         }
-    } else if args.len() == 4 {
-        if args[2] == "-a" {
-            // Read the contents of the file (which modifies 'access time')
-            fs::read_to_string(&args[3])?;
-        } else if args[2] == "-m" {
-            // Read the contents of the file
-            let file = fs::read_to_string(&args[3])?;
-            // Create a new file with the same name
-            let mut new_file = File::create(&args[3])?;
-            // Write to the new file the contents of the original file (which modifies 'modify time')
-            new_file.write_all(file.as_bytes())?;
-        } else if args[2] == "-c" || args[2] == "--no-create" {
-            // If file exists, change modify time
-            if Path::new(&args[3]).exists() {
-                File::create(&args[3])?;
-            }
-        } else {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Can't use 'touch' like this.",
-            ));
+        (month, day) = (m, d);
+        if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+            return None;
         }
-    } else if args.len() == 5 {
-        // If path exists, change modify time
-        if Path::new(&args[4]).exists() {
-            if ((args[2] == "-c" || args[2] == "--no-create")
-                && (args[3] == "-a" || args[3] == "-m"))
-                || ((args[2] == "-a" || args[2] == "-m")
-                    && (args[3] == "-c" || args[3] == "--no-create"))
-            {
-                File::create(&args[3])?;
-            }
+    }
+    if let Some((hour, minute, second, nanosecond)) = time {
+        if hour > 23 || minute > 59 || second > 60 {
+            return None;
         }
-    } else {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Can't use 'touch' like this.",
-        ));
+        seconds = hour * 3600 + minute * 60 + second;
+        nanoseconds = nanosecond;
+    } else if date.is_some() || weekday.is_some() {
+        // A day without a time means its midnight
+        seconds = 0;
+        nanoseconds = 0;
     }
-    Ok(())
+
+    // Months are added to the calendar date, days to the day count (like mktime does)
+    let mut days = days_from_civil(year, month + months_added, 1) + day - 1;
+    if let Some((ordinal, wanted)) = weekday {
+        let current = (days + 4).rem_euclid(7);
+        let later = if ordinal > 0 && current != wanted {
+            1
+        } else {
+            0
+        };
+        days += (wanted - current).rem_euclid(7) + 7 * (ordinal - later);
+    }
+    days += days_added;
+    Some((to_utc(days * 86400 + seconds) + seconds_added, nanoseconds))
 }
 
 fn ls(args: Vec<String>) -> Result<(), io::Error> {
@@ -685,38 +14418,29 @@ fn ls(args: Vec<String>) -> Result<(), io::Error> {
             // Recursive print all entries of the given path
             recursive_ls(&args[3], false);
         }
-    } else if args.len() == 5 {
-        if (args[2] == "-a" || args[2] == "--all") && (args[3] == "-R" || args[3] == "-r")
-            || (args[3] == "-a" || args[3] == "--all") && (args[2] == "-R" || args[2] == "-r")
-        {
-            // Recursive print all entries of the given path (including hidden ones)
-            recursive_ls(&args[4], true);
-        }
+    } else if args.len() == 5
+        && ((args[2] == "-a" || args[2] == "--all") && (args[3] == "-R" || args[3] == "-r")
+            || (args[3] == "-a" || args[3] == "--all") && (args[2] == "-R" || args[2] == "-r"))
+    {
+        // Recursive print all entries of the given path (including hidden ones)
+        recursive_ls(&args[4], true);
     }
     Ok(())
 }
 
 fn recursive_ls(dir_path: &str, is_visible: bool) {
-    if let Ok(paths) = fs::read_dir(dir_path) {
-        // If the given path is a directory, print it with ":" and display all of its entries
-        if Path::new(dir_path).is_dir() {
-            println!("{}:", Path::new(dir_path).display());
-            display(&PathBuf::from(dir_path), is_visible);
-        }
-        // For each entry in the current path, check if it is a directory and perform a recursive call
-        for path in paths {
-            if let Ok(entry) = path {
-                let entry_path = entry.path();
-                if entry.path().is_dir() {
-                    // Call the function recursive with the entry path if it is a directory
-                    recursive_ls(&entry_path.to_string_lossy(), is_visible);
-                }
-            }
+    // Print every directory of the tree with ":" and display all of its entries
+    walk_tree(Path::new(dir_path), true, true, &mut |event| match event {
+        WalkEvent::Enter(path, metadata, _) if metadata.is_dir() => {
+            println!("{}:", path.display());
+            display(&path.to_path_buf(), is_visible);
+            true
         }
-    }
+        _ => false,
+    });
 }
 
-fn display(files: &PathBuf, is_visible: bool) -> () {
+fn display(files: &PathBuf, is_visible: bool) {
     // Print all the entries in the given path
     if let Ok(paths) = fs::read_dir(files) {
         for path in paths {
@@ -755,7 +14479,7 @@ fn grep(args: Vec<String>) -> Result<(), io::Error> {
         if args.len() == 4 {
             if let Ok(file) = File::open(&args[3]) {
                 // Check if regex is valid
-                let regex = match Regex::new(&(&args[2])) {
+                let regex = match Regex::new(&args[2]) {
                     Ok(r) => r,
                     Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
                 };
@@ -805,86 +14529,142 @@ fn main() {
     let args: Vec<String> = env::args().collect(); // Get the args
 
     // Match the command, match the call of the function and return the specific error code
-    if args[1] == "pwd" && args.len() == 2 {
-        let _ = pwd();
+    if args[1] == "pwd" {
+        if let Err(e) = pwd(args) {
+            match e.kind() {
+                ErrorKind::InvalidInput => {
+                    eprintln!("Invalid command");
+                    std::process::exit(-1);
+                }
+                _other_error => std::process::exit(-5),
+            }
+        }
+    } else if args[1] == "realpath" {
+        if let Err(_e) = realpath(args) {
+            std::process::exit(-110);
+        }
+    } else if args[1] == "readlink" {
+        if let Err(_e) = readlink(args) {
+            std::process::exit(-120);
+        }
     } else if args[1] == "echo" {
-        match echo(args) {
-            Err(_e) => std::process::exit(-10),
-            _ => (),
+        if let Err(_e) = echo(args) {
+            std::process::exit(-10);
         }
     } else if args[1] == "cat" {
-        match cat(args) {
-            Err(_e) => std::process::exit(-20),
-            _ => (),
+        if let Err(_e) = cat(args) {
+            std::process::exit(-20);
+        }
+    } else if args[1] == "head" {
+        if let Err(_e) = head(args) {
+            std::process::exit(-130);
+        }
+    } else if args[1] == "tail" {
+        if let Err(_e) = tail(args) {
+            std::process::exit(-140);
+        }
+    } else if args[1] == "wc" {
+        if let Err(_e) = wc(args) {
+            std::process::exit(-150);
+        }
+    } else if args[1] == "sort" {
+        if let Err(_e) = sort(args) {
+            std::process::exit(-160);
+        }
+    } else if args[1] == "uniq" {
+        if let Err(_e) = uniq(args) {
+            std::process::exit(-170);
+        }
+    } else if args[1] == "cut" {
+        if let Err(_e) = cut(args) {
+            std::process::exit(-180);
+        }
+    } else if args[1] == "paste" {
+        if let Err(_e) = paste(args) {
+            std::process::exit(-190);
+        }
+    } else if args[1] == "tr" {
+        if let Err(_e) = tr(args) {
+            std::process::exit(-200);
+        }
+    } else if args[1] == "sed" {
+        if let Err(_e) = sed(args) {
+            std::process::exit(-210);
+        }
+    } else if args[1] == "awk" {
+        if let Err(_e) = awk(args) {
+            std::process::exit(-220);
+        }
+    } else if args[1] == "diff" {
+        // 1 means the files differ, 2 is for trouble
+        if let Err(_e) = diff(args) {
+            std::process::exit(2);
+        }
+    } else if args[1] == "patch" {
+        // 1 means some hunks failed, 2 is for trouble
+        if let Err(_e) = patch(args) {
+            std::process::exit(2);
+        }
+    } else if args[1] == "find" {
+        if let Err(_e) = find(args) {
+            std::process::exit(1);
         }
     } else if args[1] == "mkdir" {
-        match mkdir(args) {
-            Err(_e) => std::process::exit(-30),
-            _ => (),
+        if let Err(_e) = mkdir(args) {
+            std::process::exit(-30);
         }
     } else if args[1] == "mv" {
-        match mv(args) {
-            Err(_e) => std::process::exit(-40),
-            _ => (),
+        if let Err(_e) = mv(args) {
+            std::process::exit(-40);
         }
     } else if args[1] == "rmdir" {
-        match rmdir(args) {
-            Err(_e) => std::process::exit(-60),
-            _ => (),
+        if let Err(_e) = rmdir(args) {
+            std::process::exit(-60);
         }
     } else if args[1] == "ln" {
-        match ln(args) {
-            Err(e) => match e.kind() {
+        if let Err(e) = ln(args) {
+            match e.kind() {
                 ErrorKind::InvalidInput => {
                     eprintln!("Invalid command");
                     std::process::exit(-1);
                 }
                 _other_error => std::process::exit(-50),
-            },
-            _ => (),
+            }
         }
     } else if args[1] == "rm" {
-        match rm(args) {
-            Err(e) => match e.kind() {
+        if let Err(e) = rm(args) {
+            match e.kind() {
                 ErrorKind::InvalidInput => {
                     eprintln!("Invalid command");
                     std::process::exit(-1);
                 }
                 _other_error => std::process::exit(-70),
-            },
-            _ => (),
+            }
         }
     } else if args[1] == "cp" {
-        match cp(args) {
-            Err(_e) => std::process::exit(-90),
-            _ => (),
+        if let Err(_e) = cp(args) {
+            std::process::exit(-90);
         }
     } else if args[1] == "chmod" {
-        match chmod(args) {
-            Err(e) => match e.kind() {
+        if let Err(e) = chmod(args) {
+            match e.kind() {
                 ErrorKind::InvalidInput => {
                     eprintln!("Invalid command");
                     std::process::exit(-1);
                 }
                 _other_error => std::process::exit(-25),
-            },
-            _ => (),
+            }
         }
     } else if args[1] == "touch" {
-        match touch(args) {
-            Err(_e) => std::process::exit(-100),
-            _ => (),
+        if let Err(_e) = touch(args) {
+            std::process::exit(-100);
         }
     } else if args[1] == "ls" {
-        match ls(args) {
-            Err(_e) => std::process::exit(-80),
-            _ => (),
+        if let Err(_e) = ls(args) {
+            std::process::exit(-80);
         }
     } else if args[1] == "grep" {
-        match grep(args) {
-            Err(_e) => (),
-            _ => (),
-        }
+        let _ = grep(args);
     } else {
         // Handle the case when command doesn't have an implementation
         println!("Invalid command");